@@ -0,0 +1,49 @@
+use crate::engine::Engine;
+use crate::error::{Result, TinyDbError};
+
+/// `Engine` for async callers (the `async` feature). Each call runs the blocking operation
+/// on tokio's blocking pool and awaits it there, so a `set` waiting on its WAL fsync never
+/// holds up a runtime worker. The engine underneath is the same one: writes still take its
+/// writer lock in turn, and concurrent sets share WAL fsyncs through its group commit.
+/// Must be used from within a tokio runtime.
+#[derive(Clone)]
+pub struct AsyncEngine {
+    db: Engine,
+}
+
+impl AsyncEngine {
+    pub fn new(db: Engine) -> Self {
+        AsyncEngine { db }
+    }
+
+    /// The engine underneath, for the calls there is no async version of.
+    pub fn engine(&self) -> &Engine {
+        &self.db
+    }
+
+    pub async fn set(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        let (key, val) = (key.to_vec(), val.to_vec());
+        self.run(move |db| db.set(&key, &val)).await
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        self.run(move |db| db.get(&key)).await
+    }
+
+    pub async fn delete(&self, key: &[u8]) -> Result<bool> {
+        let key = key.to_vec();
+        self.run(move |db| db.delete(&key)).await
+    }
+
+    /// Run `f` on the blocking pool. A panic in it resumes in the caller, as it would have
+    /// on a direct call; a runtime shutting down before it ran is an I/O error.
+    async fn run<T: Send + 'static>(&self, f: impl FnOnce(&Engine) -> Result<T> + Send + 'static) -> Result<T> {
+        let db = self.db.clone();
+        match tokio::task::spawn_blocking(move || f(&db)).await {
+            Ok(res) => res,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => Err(TinyDbError::Io(std::io::Error::other(e))),
+        }
+    }
+}
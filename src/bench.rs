@@ -1,43 +1,384 @@
 use std::time::{Instant};
 use std::path::Path;
-use crate::engine::Engine;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use crate::engine::{BatchOp, Engine, Options, SyncPolicy};
+use crate::util::{crc32, crc32_bitwise, crc32c, crc32c_hw, XorShift64, Zipf};
 
-/// Simple synchronous benchmark (single-threaded) that calls Engine::set repeatedly.
-/// Reports throughput and latency percentiles (p50/p95/p99).
-pub fn run_bench<P: AsRef<Path>>(dir: P, ops: usize, key_prefix: &str, val_size: usize) -> anyhow::Result<()> {
-    let mut engine = Engine::open(dir)?;
-    let mut latencies_ms = Vec::with_capacity(ops);
+/// Write benchmark: `threads` writer threads share one Engine and together perform `ops`
+/// `Engine::set` calls on distinct keys. Reports latency percentiles across all threads and
+/// throughput over wall-clock time. Each thread has its own handle, but writes still take
+/// turns on the engine's writer lock; that wait is part of each op's latency.
+/// `warmup` more sets, on keys of their own, run first and are left out of every figure, so
+/// cold caches and first allocations don't skew them. With `trim` the mean is also given
+/// without the slowest 0.1% of ops. Returns the latency figures printed, None for no ops.
+pub fn run_bench<P: AsRef<Path>>(dir: P, ops: usize, key_prefix: &str, val_size: usize, threads: usize, warmup: usize, trim: bool)
+    -> anyhow::Result<Option<LatencyStats>>
+{
+    let engine = Engine::open(dir)?;
+    let threads = threads.max(1);
+    let done = AtomicUsize::new(0);
 
     // prepare a value payload of the requested size
-    let val = vec!['x' as u8; val_size];
+    let val = vec![b'x'; val_size];
+
+    for i in 0..warmup {
+        engine.set(format!("{}warmup{:08}", key_prefix, i).as_bytes(), &val)?;
+    }
+    let (wal_before, data_before) = (engine.wal_bytes_written(), engine.data_bytes_written());
+
+    let total = Instant::now();
+    let mut latencies_ms = thread::scope(|s| -> anyhow::Result<Vec<f64>> {
+        let handles: Vec<_> = (0..threads).map(|t| {
+            // contiguous key range per thread; the first `ops % threads` take one extra op
+            let per = ops / threads;
+            let first = t * per + t.min(ops % threads);
+            let count = per + usize::from(t < ops % threads);
+            let (engine, done, val) = (engine.clone(), &done, &val);
+            s.spawn(move || -> anyhow::Result<Vec<f64>> {
+                let mut latencies_ms = Vec::with_capacity(count);
+                for i in first..first + count {
+                    let key = format!("{}{:08}", key_prefix, i);
+                    let start = Instant::now();
+                    engine.set(key.as_bytes(), val)?;
+                    latencies_ms.push(start.elapsed().as_secs_f64() * 1000.0);
+                    let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+                    if n % 1000 == 0 {
+                        eprintln!("progress: {}/{}", n, ops);
+                    }
+                }
+                Ok(latencies_ms)
+            })
+        }).collect();
+        let mut all = Vec::with_capacity(ops);
+        for h in handles {
+            all.extend(h.join().unwrap()?);
+        }
+        Ok(all)
+    })?;
+    let elapsed = total.elapsed().as_secs_f64();
+    let logical: u64 = (0..ops).map(|i| (format!("{}{:08}", key_prefix, i).len() + val_size) as u64).sum();
+
+    println!("ops: {}", ops);
+    println!("warmup ops: {}", warmup);
+    println!("threads: {}", threads);
+    println!("value size: {} bytes", val_size);
+    let stats = latency_stats(&mut latencies_ms);
+    print_stats(stats.as_ref(), trim);
+    print_throughput(ops, elapsed);
+    print_amplification(logical, engine.wal_bytes_written() - wal_before, engine.data_bytes_written() - data_before);
+
+    Ok(stats)
+}
+
+/// Sync policy comparison: the same `ops` single-threaded `set`s under each SyncPolicy, each
+/// run in a fresh subdirectory of `dir`, reporting throughput and WAL fsyncs per policy.
+pub fn run_sync_bench<P: AsRef<Path>>(dir: P, ops: usize, val_size: usize) -> anyhow::Result<()> {
+    let val = vec![b'x'; val_size];
+    println!("ops: {}", ops);
+    println!("value size: {} bytes", val_size);
+    for (name, sync) in [("always", SyncPolicy::Always), ("every_100", SyncPolicy::EveryN(100)), ("never", SyncPolicy::Never)] {
+        let sub = dir.as_ref().join(format!("bench_sync_{}", name));
+        if sub.exists() {
+            std::fs::remove_dir_all(&sub)?;
+        }
+        let engine = Engine::open_with_options(&sub, Options { sync, ..Options::default() })?;
+        let start = Instant::now();
+        for i in 0..ops {
+            engine.set(format!("sync{:08}", i).as_bytes(), &val)?;
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        println!("policy: {}", name);
+        print_throughput(ops, elapsed);
+        println!("wal fsyncs: {}", engine.wal_fsync_count());
+    }
+    Ok(())
+}
+
+/// Bulk-load comparison: `ops` keys written once by a loop of `set`s and once by one
+/// `Engine::bulk_load`, each into a fresh subdirectory of `dir`, reporting throughput and
+/// WAL fsyncs for both.
+pub fn run_bulk_bench<P: AsRef<Path>>(dir: P, ops: usize, val_size: usize) -> anyhow::Result<()> {
+    let val = vec![b'x'; val_size];
+    let keys: Vec<String> = (0..ops).map(|i| format!("bulk{:08}", i)).collect();
+    println!("ops: {}", ops);
+    println!("value size: {} bytes", val_size);
+    for name in ["set_loop", "bulk_load"] {
+        let sub = dir.as_ref().join(format!("bench_bulk_{}", name));
+        if sub.exists() {
+            std::fs::remove_dir_all(&sub)?;
+        }
+        let engine = Engine::open(&sub)?;
+        let start = Instant::now();
+        if name == "bulk_load" {
+            engine.bulk_load(keys.iter().map(|k| (k.as_bytes(), &val[..])))?;
+        } else {
+            for k in &keys {
+                engine.set(k.as_bytes(), &val)?;
+            }
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        println!("method: {}", name);
+        print_throughput(ops, elapsed);
+        println!("wal fsyncs: {}", engine.wal_fsync_count());
+    }
+    Ok(())
+}
 
+/// Read benchmark: populates `ops` keys, then times `Engine::get` on `ops` keys picked at
+/// random from them. The PRNG is seeded with a constant so runs pick the same keys.
+pub fn run_read_bench<P: AsRef<Path>>(dir: P, ops: usize, key_prefix: &str, dist: KeyDist) -> anyhow::Result<()> {
+    let engine = Engine::open(dir)?;
+    let key = |i: usize| format!("{}{:08}", key_prefix, i);
+
+    // populate in batches: one fsync per 1000 keys instead of one per key
+    let val = vec![b'x'; 100];
+    let keys: Vec<String> = (0..ops).map(key).collect();
+    for chunk in keys.chunks(1000) {
+        let batch: Vec<BatchOp> = chunk.iter().map(|k| BatchOp::Set(k.as_bytes(), &val)).collect();
+        engine.write_batch(&batch)?;
+    }
+
+    let mut pick = dist.sampler(ops, 42);
+    let mut counts = vec![0usize; ops];
+    let mut latencies_ms = Vec::with_capacity(ops);
+    let mut hits = 0usize;
+    let total = Instant::now();
     for i in 0..ops {
-        let key = format!("{}{:08}", key_prefix, i);
+        let k = pick();
+        counts[k] += 1;
+        let key = key(k);
         let start = Instant::now();
-        engine.set(&key, &val)?;
-        let dt = start.elapsed();
-        latencies_ms.push(dt.as_secs_f64() * 1000.0);
+        let found = engine.get(key.as_bytes())?;
+        latencies_ms.push(start.elapsed().as_secs_f64() * 1000.0);
+        if found.is_some() {
+            hits += 1;
+        }
         if (i+1) % 1000 == 0 {
             eprintln!("progress: {}/{}", i+1, ops);
         }
     }
-
-    // compute stats
-    latencies_ms.sort_by(|a,b| a.partial_cmp(b).unwrap());
-    let sum: f64 = latencies_ms.iter().sum();
-    let mean = sum / (latencies_ms.len() as f64);
-    let p50 = latencies_ms[latencies_ms.len() * 50 / 100];
-    let p95 = latencies_ms[latencies_ms.len() * 95 / 100];
-    let p99 = latencies_ms[latencies_ms.len() * 99 / 100];
-    let throughput = (ops as f64) / (latencies_ms.iter().sum::<f64>() / 1000.0);
+    let elapsed = total.elapsed().as_secs_f64();
 
     println!("ops: {}", ops);
+    println!("key distribution: {}", dist);
+    print_latency_stats(&mut latencies_ms);
+    print_throughput(ops, elapsed);
+    println!("hit rate: {:.1}%", if ops == 0 { 0.0 } else { hits as f64 * 100.0 / ops as f64 });
+    print_skew(&mut counts);
+    let cache = engine.cache_stats();
+    println!("page cache: {} hits, {} misses", cache.hits, cache.misses);
+
+    Ok(())
+}
+
+/// Mixed benchmark: `ops` operations over a pre-populated working set, each one a `get`
+/// with probability `read_pct`% and a `set` otherwise. Operation and key choices come from
+/// constant-seeded PRNGs so runs are comparable, keys as `dist` says. Reads and writes are
+/// reported separately.
+pub fn run_mixed_bench<P: AsRef<Path>>(dir: P, ops: usize, read_pct: u8, val_size: usize, dist: KeyDist) -> anyhow::Result<()> {
+    let engine = Engine::open(dir)?;
+    let working_set = ops.clamp(1, 1000);
+    let key = |i: usize| format!("mixed{:08}", i);
+
+    let val = vec![b'x'; val_size];
+    let keys: Vec<String> = (0..working_set).map(key).collect();
+    let batch: Vec<BatchOp> = keys.iter().map(|k| BatchOp::Set(k.as_bytes(), &val)).collect();
+    engine.write_batch(&batch)?;
+
+    let mut rng = XorShift64::new(42);
+    let mut pick = dist.sampler(working_set, 43);
+    let mut counts = vec![0usize; working_set];
+    let mut read_ms = Vec::new();
+    let mut write_ms = Vec::new();
+    let total = Instant::now();
+    for i in 0..ops {
+        let is_read = rng.next_u64() % 100 < read_pct as u64;
+        let k = pick();
+        counts[k] += 1;
+        let key = &keys[k];
+        let start = Instant::now();
+        if is_read {
+            engine.get(key.as_bytes())?;
+            read_ms.push(start.elapsed().as_secs_f64() * 1000.0);
+        } else {
+            engine.set(key.as_bytes(), &val)?;
+            write_ms.push(start.elapsed().as_secs_f64() * 1000.0);
+        }
+        if (i+1) % 1000 == 0 {
+            eprintln!("progress: {}/{}", i+1, ops);
+        }
+    }
+    let elapsed = total.elapsed().as_secs_f64();
+
+    println!("ops: {} ({}% reads requested)", ops, read_pct);
     println!("value size: {} bytes", val_size);
-    println!("mean latency (ms): {:.3}", mean);
-    println!("p50 (ms): {:.3}", p50);
-    println!("p95 (ms): {:.3}", p95);
-    println!("p99 (ms): {:.3}", p99);
-    println!("throughput (ops/sec): {:.1}", throughput);
+    println!("key distribution: {}", dist);
+    println!("reads: {}", read_ms.len());
+    print_latency_stats(&mut read_ms);
+    println!("writes: {}", write_ms.len());
+    print_latency_stats(&mut write_ms);
+    print_throughput(ops, elapsed);
 
     Ok(())
 }
+
+/// How the read and mixed benchmarks pick keys: `uniform`, or `zipf[:theta]` for a few hot
+/// keys (theta in (0, 1), default 0.99 as in YCSB; higher is more skewed).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyDist {
+    Uniform,
+    Zipf(f64),
+}
+
+impl KeyDist {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let (name, theta) = match s.split_once(':') {
+            Some((name, theta)) => (name, Some(theta)),
+            None => (s, None),
+        };
+        match (name, theta) {
+            ("uniform", None) => Ok(KeyDist::Uniform),
+            ("zipf", None) => Ok(KeyDist::Zipf(0.99)),
+            ("zipf", Some(theta)) => match theta.parse::<f64>() {
+                Ok(t) if t > 0.0 && t < 1.0 => Ok(KeyDist::Zipf(t)),
+                _ => Err(anyhow::anyhow!("zipf theta must be a number in (0, 1), got {}", theta)),
+            },
+            _ => Err(anyhow::anyhow!("unknown key distribution {}; expected uniform or zipf[:theta]", s)),
+        }
+    }
+
+    /// A seeded generator of key indices in `0..n`.
+    fn sampler(self, n: usize, seed: u64) -> Box<dyn FnMut() -> usize> {
+        let n = n.max(1);
+        match self {
+            KeyDist::Uniform => {
+                let mut rng = XorShift64::new(seed);
+                Box::new(move || rng.next_u64() as usize % n)
+            }
+            KeyDist::Zipf(theta) => {
+                let mut zipf = Zipf::new(n, theta, seed);
+                Box::new(move || zipf.next_rank())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for KeyDist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyDist::Uniform => write!(f, "uniform"),
+            KeyDist::Zipf(theta) => write!(f, "zipf (theta {})", theta),
+        }
+    }
+}
+
+/// Access skew from per-key access counts: the share of all accesses that went to the
+/// hottest 1% of keys (at least one key), and to the hottest key.
+fn print_skew(counts: &mut [usize]) {
+    let total: usize = counts.iter().sum();
+    if total == 0 {
+        return;
+    }
+    counts.sort_unstable_by(|a, b| b.cmp(a));
+    let top = counts.len().div_ceil(100);
+    let share = |n: usize| counts[..n].iter().sum::<usize>() as f64 * 100.0 / total as f64;
+    println!("top 1% of keys ({}): {:.1}% of accesses", top, share(top));
+    println!("hottest key: {:.1}% of accesses", share(1));
+}
+
+/// Latency figures of a benchmark run, in milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LatencyStats {
+    /// Ops measured; warmup ops aren't.
+    pub samples: usize,
+    pub mean: f64,
+    /// The mean without the slowest 0.1% of ops (none below 1000 samples). The percentiles
+    /// are of every sample.
+    pub trimmed_mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+/// The figures of `latencies_ms`, sorting it. None with no samples (`ops` of 0, or a mixed
+/// run that drew no op of one kind): there is nothing to index.
+pub fn latency_stats(latencies_ms: &mut [f64]) -> Option<LatencyStats> {
+    let n = latencies_ms.len();
+    if n == 0 {
+        return None;
+    }
+    latencies_ms.sort_by(|a,b| a.partial_cmp(b).unwrap());
+    let pct = |p: usize| latencies_ms[(n * p / 100).min(n - 1)];
+    let mean = |v: &[f64]| v.iter().sum::<f64>() / v.len() as f64;
+    Some(LatencyStats {
+        samples: n,
+        mean: mean(latencies_ms),
+        trimmed_mean: mean(&latencies_ms[..n - n / 1000]),
+        p50: pct(50),
+        p95: pct(95),
+        p99: pct(99),
+    })
+}
+
+/// Print mean/p50/p95/p99 of `latencies_ms`, saying so when there are none.
+fn print_latency_stats(latencies_ms: &mut [f64]) {
+    print_stats(latency_stats(latencies_ms).as_ref(), false);
+}
+
+/// Print `stats`, with the trimmed mean too if `trim`.
+fn print_stats(stats: Option<&LatencyStats>, trim: bool) {
+    let Some(st) = stats else {
+        println!("latency: no samples");
+        return;
+    };
+    println!("mean latency (ms): {:.3}", st.mean);
+    if trim {
+        println!("trimmed mean latency (ms, slowest 0.1% dropped): {:.3}", st.trimmed_mean);
+    }
+    println!("p50 (ms): {:.3}", st.p50);
+    println!("p95 (ms): {:.3}", st.p95);
+    println!("p99 (ms): {:.3}", st.p99);
+}
+
+/// Print the bytes the WAL and data file took for `logical` bytes of keys and values, and
+/// each as a multiple of it.
+fn print_amplification(logical: u64, wal: u64, data: u64) {
+    let ratio = |n: u64| if logical == 0 { 0.0 } else { n as f64 / logical as f64 };
+    println!("logical bytes: {}", logical);
+    println!("wal bytes: {} ({:.2}x)", wal, ratio(wal));
+    println!("data bytes: {} ({:.2}x)", data, ratio(data));
+}
+
+/// Print `ops` completed over `elapsed_secs` of wall-clock time as ops/sec.
+fn print_throughput(ops: usize, elapsed_secs: f64) {
+    let throughput = if elapsed_secs > 0.0 { ops as f64 / elapsed_secs } else { 0.0 };
+    println!("throughput (ops/sec): {:.1}", throughput);
+}
+
+/// Compare the table-driven and bitwise CRC-32, and CRC-32C, over a `buf_size` buffer, `iters` times each.
+pub fn run_crc_bench(buf_size: usize, iters: usize) {
+    // random bytes: a constant buffer lets the branch predictor flatter the bitwise loop
+    let mut buf = vec![0u8; buf_size];
+    XorShift64::new(42).fill(&mut buf);
+    let time = |f: fn(&[u8]) -> u32| -> f64 {
+        let start = Instant::now();
+        let mut acc = 0u32;
+        for _ in 0..iters {
+            acc ^= f(std::hint::black_box(&buf));
+        }
+        std::hint::black_box(acc);
+        start.elapsed().as_secs_f64()
+    };
+    let bitwise = time(crc32_bitwise);
+    let table = time(crc32);
+    let castagnoli = time(crc32c);
+    let mb = (buf_size * iters) as f64 / (1024.0 * 1024.0);
+
+    println!("buffer: {} bytes x {} iters", buf_size, iters);
+    println!("bitwise (MB/s): {:.1}", mb / bitwise);
+    println!("table   (MB/s): {:.1}", mb / table);
+    println!("speedup: {:.1}x", bitwise / table);
+    let hw = if crc32c_hw(&[]).is_some() { "sse4.2" } else { "table" };
+    println!("crc32c ({}) (MB/s): {:.1}", hw, mb / castagnoli);
+}
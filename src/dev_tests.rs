@@ -1,5 +1,259 @@
-use std::process::{Command};
+use std::process::{Command, Stdio};
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::bench;
+use crate::dump;
+use crate::repl;
+use crate::engine::{BatchOp, CommitEvent, Engine, Options, Repaired, SyncPolicy, WriteStall, DEFAULT_MAX_KEY_SIZE};
+use crate::error::{self, TinyDbError};
+use crate::util::{crc32, crc32_bitwise, crc32c, crc32c_hw, crc32c_sw, Checksum, XorShift64, Zipf};
+use crate::storage::{MemFile, Storage};
+use crate::event_log::EventLog;
+use crate::wal::Wal;
+use crate::pager::{CacheStats, Page, Pager, HDR_SZ, PAGE_SIZE};
+
+pub fn run_all() -> anyhow::Result<()> {
+    simple_crash_recovery()?;
+    delete_survives_reopen()?;
+    delete_then_set_replays_final_value()?;
+    binary_key_round_trip()?;
+    pager_cache_hits_and_evicts()?;
+    page_header_size_is_consistent()?;
+    scan_range_and_prefix()?;
+    checkpoint_truncates_wal()?;
+    wal_torn_tail_is_end_of_log()?;
+    crc32_table_matches_bitwise()?;
+    crc32c_hw_matches_sw()?;
+    compare_and_swap_cases()?;
+    set_if_absent_inserts_once()?;
+    write_batch_is_all_or_nothing()?;
+    wal_group_commit()?;
+    read_only_open()?;
+    compact_reclaims_overwrites()?;
+    compact_shrinks_file_to_live_pages()?;
+    ttl_expiry()?;
+    free_pages_are_reused()?;
+    wal_records_iterator()?;
+    dump_restore_round_trip()?;
+    contains_key_cases()?;
+    multi_get_reads_each_page_once()?;
+    verify_all_reports_corrupt_pages()?;
+    increment_counters()?;
+    wal_header_crc_catches_bad_length()?;
+    double_write_repairs_torn_page()?;
+    configurable_page_size()?;
+    superblock_survives_reopen()?;
+    in_memory_engine()?;
+    snapshot_sees_old_values()?;
+    wal_segments_rotate()?;
+    sync_policies()?;
+    rebuild_keeps_latest_version()?;
+    stats_track_dead_space()?;
+    delete_prefix_cases()?;
+    clean_shutdown_skips_replay()?;
+    #[cfg(target_os = "linux")]
+    direct_io_round_trip()?;
+    write_pages_in_bulk()?;
+    corruption_errors_are_typed()?;
+    repl_session()?;
+    merge_appends()?;
+    index_snapshot_skips_scan()?;
+    oversized_entries_are_rejected()?;
+    shared_handles_across_threads()?;
+    crc32c_database_round_trip()?;
+    replay_reports_progress()?;
+    storage_backed_engine()?;
+    background_checkpoint_shrinks_wal()?;
+    key_and_value_limits()?;
+    flush_makes_lazy_writes_durable()?;
+    bytes_written_counters()?;
+    keys_and_len_follow_the_index()?;
+    crash_between_wal_sync_and_page_write()?;
+    crash_tearing_a_multi_page_write()?;
+    wal_replay_from_lsn()?;
+    get_with_metadata_locates_entries()?;
+    named_databases_share_a_directory()?;
+    compact_if_needed_follows_dead_space()?;
+    other_format_versions_are_refused()?;
+    event_log_reads_from_an_lsn()?;
+    bulk_load_recovers_a_prefix()?;
+    iter_pages_walks_the_data_pages()?;
+    wal_append_is_one_write()?;
+    hashed_index_tells_colliding_keys_apart()?;
+    partial_tail_page_is_tolerated()?;
+    get_as_of_reads_old_versions()?;
+    second_writer_is_locked_out()?;
+    typed_number_values()?;
+    writes_stall_until_checkpoint()?;
+    cli_scan_filters_and_limits()?;
+    wal_is_written_ahead_of_pages()?;
+    empty_values_are_not_absent()?;
+    consistency_check_finds_bad_offsets()?;
+    zipf_keys_are_skewed()?;
+    rebuild_data_file_from_wal()?;
+    metrics_count_operations()?;
+    cursor_steps_both_ways()?;
+    replay_starts_at_checkpoint_record()?;
+    open_default_resolves_data_dir()?;
+    get_prefix_reads_each_page_once()?;
+    clear_empties_the_database()?;
+    malformed_bytes_are_errors()?;
+    reads_run_during_compaction()?;
+    wal_retention_keeps_newest_segments()?;
+    update_in_place_reuses_the_slot()?;
+    empty_page_between_used_ones()?;
+    bench_warmup_is_not_counted()?;
+    repair_rewrites_pages_from_the_wal()?;
+    commit_hook_sees_durable_writes()?;
+    reopen_replays_unless_closed()?;
+    write_batch_spills_across_pages()?;
+    reads_during_clear_see_old_or_nothing()?;
+    values_stream_through_readers()?;
+    #[cfg(feature = "compression")]
+    wal_compression_round_trip()?;
+    #[cfg(feature = "compression")]
+    value_compression_round_trip()?;
+    #[cfg(feature = "serde")]
+    json_values_round_trip()?;
+    #[cfg(feature = "serde")]
+    cli_json_output()?;
+    #[cfg(feature = "async")]
+    async_sets_and_gets()?;
+    Ok(())
+}
+
+/// Fresh, empty directory for a single test.
+fn test_dir(name: &str) -> anyhow::Result<PathBuf> {
+    let dir = PathBuf::from(format!("./tinydb_data_test_{}", name));
+    if dir.exists(){
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// What a checkpoint leaves of a WAL: the segment header, then the CKP record's header and payload.
+const CHECKPOINTED_WAL_BYTES: u64 = 12 /* segment header */ + 25 /* record header */ + 11 /* CKP payload */;
+
+/// Total size of the WAL segments in `dir`.
+fn wal_bytes(dir: &std::path::Path) -> anyhow::Result<u64> {
+    let mut total = 0;
+    for ent in fs::read_dir(dir)? {
+        let ent = ent?;
+        if ent.file_name().to_string_lossy().starts_with("wal-") {
+            total += ent.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
+
+/// In-memory storage that models a power cut: `after_power_cut` keeps only what the last
+/// `sync_all` made durable, dropping every write since. `crash_after` injects a process
+/// crash at a chosen byte of the writes to come; `after_crash` then keeps everything that
+/// got written, torn writes included, as the OS would.
+#[derive(Clone, Default)]
+struct PowerCut {
+    live: MemFile,
+    durable: Arc<Mutex<Vec<u8>>>,
+    // bytes still let through before the injected crash; None when none is armed
+    budget: Arc<Mutex<Option<usize>>>,
+    // write calls so far
+    writes: Arc<Mutex<usize>>,
+}
+
+impl PowerCut {
+    fn after_power_cut(&self) -> std::io::Result<PowerCut> {
+        Self::from_bytes(&self.durable.lock().unwrap())
+    }
+
+    fn after_crash(&self) -> std::io::Result<PowerCut> {
+        let mut f = self.live.clone();
+        let mut bytes = vec![0u8; f.len()? as usize];
+        f.read_at(&mut bytes, 0)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Let `bytes` more bytes be written, then fail every write and sync.
+    fn crash_after(&self, bytes: usize) {
+        *self.budget.lock().unwrap() = Some(bytes);
+    }
+
+    fn from_bytes(bytes: &[u8]) -> std::io::Result<PowerCut> {
+        let mut live = MemFile::default();
+        live.write_all(bytes)?;
+        Ok(PowerCut { live, durable: Arc::new(Mutex::new(bytes.to_vec())), budget: Arc::default(), writes: Arc::default() })
+    }
+
+    fn crashed(&self) -> std::io::Error {
+        std::io::Error::other("injected crash")
+    }
+}
+
+impl std::io::Read for PowerCut {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.live.read(buf)
+    }
+}
+
+impl Write for PowerCut {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        *self.writes.lock().unwrap() += 1;
+        let mut budget = self.budget.lock().unwrap();
+        match *budget {
+            None => self.live.write(buf),
+            Some(0) => Err(self.crashed()),
+            Some(left) => {
+                let n = self.live.write(&buf[..buf.len().min(left)])?;
+                *budget = Some(left - n);
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.live.flush()
+    }
+}
+
+impl std::io::Seek for PowerCut {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.live.seek(pos)
+    }
+}
+
+impl Storage for PowerCut {
+    fn len(&self) -> std::io::Result<u64> {
+        self.live.len()
+    }
+
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        self.live.set_len(len)
+    }
+
+    fn sync_all(&self) -> std::io::Result<()> {
+        if *self.budget.lock().unwrap() == Some(0) {
+            return Err(self.crashed());
+        }
+        // copy through a clone, putting back the cursor it shares with `live`
+        let mut f = self.live.clone();
+        let pos = std::io::Seek::stream_position(&mut f)?;
+        let mut bytes = vec![0u8; f.len()? as usize];
+        f.read_at(&mut bytes, 0)?;
+        std::io::Seek::seek(&mut f, std::io::SeekFrom::Start(pos))?;
+        *self.durable.lock().unwrap() = bytes;
+        Ok(())
+    }
+
+    fn try_clone(&self) -> std::io::Result<Box<dyn Storage>> {
+        Ok(Box::new(self.clone()))
+    }
+}
 
 pub fn simple_crash_recovery() -> anyhow::Result<()> {
     let dir = std::path::PathBuf::from("./tinydb_data_test");
@@ -16,8 +270,3561 @@ pub fn simple_crash_recovery() -> anyhow::Result<()> {
         .status()?;
     assert!(status.success());
 
-    let mut db = crate::engine::Engine::open(&dir)?;
-    let v = db.get("key1")?.expect("key1 should exist after recovery");
+    let db = crate::engine::Engine::open(&dir)?;
+    let v = db.get(b"key1")?.expect("key1 should exist after recovery");
     assert_eq!(v, b"value1");
     Ok(())
-}
\ No newline at end of file
+}
+
+pub fn delete_survives_reopen() -> anyhow::Result<()> {
+    let dir = test_dir("delete")?;
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"a", b"1")?;
+        db.set(b"b", b"2")?;
+        assert!(db.delete(b"a")?);
+        assert!(!db.delete(b"missing")?);
+        assert_eq!(db.get(b"a")?, None);
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"a")?, None);
+    assert_eq!(db.get(b"b")?.as_deref(), Some(&b"2"[..]));
+    Ok(())
+}
+
+pub fn delete_then_set_replays_final_value() -> anyhow::Result<()> {
+    let dir = test_dir("delete_then_set")?;
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"k", b"old")?;
+        db.delete(b"k")?;
+        db.set(b"k", b"new")?;
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"k")?.as_deref(), Some(&b"new"[..]));
+    Ok(())
+}
+
+pub fn binary_key_round_trip() -> anyhow::Result<()> {
+    let dir = test_dir("binary_key")?;
+    let key = [0xFFu8, 0x00, 0xFE];
+    let val = [0x00u8, 0xFF, 0x80, 0x7F];
+    {
+        let db = Engine::open(&dir)?;
+        db.set(&key, &val)?;
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(&key)?.as_deref(), Some(&val[..]));
+    // a lossy UTF-8 decode would have mapped the key to U+FFFD sequences
+    assert_eq!(db.get("\u{FFFD}\0\u{FFFD}".as_bytes())?, None);
+    Ok(())
+}
+
+pub fn pager_cache_hits_and_evicts() -> anyhow::Result<()> {
+    let dir = test_dir("pager_cache")?;
+    let path = dir.join("pages.db");
+    let mut p = Pager::open_with_cache(&path, 2)?;
+    for pid in 1..4 {
+        let mut page = Page::new(pid);
+        page.data[0] = pid as u8;
+        page.used = 1;
+        p.write_page(&page)?;
+    }
+    // pages 2 and 3 are cached (write-through); page 1 was evicted
+    assert_eq!(p.read_page(3)?.data[0], 3);
+    assert_eq!(p.read_page(2)?.data[0], 2);
+    assert_eq!(p.cache_stats(), CacheStats { hits: 2, misses: 0 });
+    assert_eq!(p.read_page(1)?.data[0], 1);
+    assert_eq!(p.cache_stats(), CacheStats { hits: 2, misses: 1 });
+    // reading page 1 evicted page 3, the least recently used
+    p.read_page(3)?;
+    assert_eq!(p.cache_stats(), CacheStats { hits: 2, misses: 2 });
+
+    // writes update the cached copy rather than leaving a stale one behind
+    let mut page = p.read_page(3)?;
+    page.data[0] = 42;
+    p.write_page(&page)?;
+    assert_eq!(p.read_page(3)?.data[0], 42);
+
+    // capacity 0 disables caching entirely
+    let mut uncached = Pager::open(&path)?;
+    uncached.read_page(2)?;
+    uncached.read_page(2)?;
+    assert_eq!(uncached.cache_stats().hits, 0);
+    assert_eq!(uncached.read_page(3)?.data[0], 42);
+    Ok(())
+}
+
+pub fn page_header_size_is_consistent() -> anyhow::Result<()> {
+    assert_eq!(Page::new(0).data.len() + HDR_SZ, PAGE_SIZE);
+
+    // two entries that pack the first data page exactly: a big one, then a 19-byte one flush with the end
+    // (entry header is key_len + val_len + expires_at = 16 bytes)
+    let dir = test_dir("hdr_sz")?;
+    let last_len = 16 + 2 + 1;
+    let big_val = vec![b'v'; PAGE_SIZE - HDR_SZ - last_len - 16 - 3];
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"big", &big_val)?;
+        db.set(b"zz", b"x")?;
+    }
+    let mut p = Pager::open(dir.join("tinydb_data.db"))?;
+    assert_eq!(p.read_page(1)?.used as usize, PAGE_SIZE - HDR_SZ);
+    assert_eq!(p.read_page(2)?.used, 0);
+
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"big")?, Some(big_val));
+    assert_eq!(db.get(b"zz")?.as_deref(), Some(&b"x"[..]));
+    Ok(())
+}
+
+pub fn scan_range_and_prefix() -> anyhow::Result<()> {
+    let dir = test_dir("scan")?;
+    let db = Engine::open(&dir)?;
+    for k in ["d", "b", "ab", "a", "c"] {
+        db.set(k.as_bytes(), format!("v-{}", k).as_bytes())?;
+    }
+    let keys = |it: &mut dyn Iterator<Item = error::Result<(Vec<u8>, Vec<u8>)>>| -> error::Result<Vec<String>> {
+        it.map(|r| r.map(|(k, _)| String::from_utf8(k).unwrap())).collect()
+    };
+
+    assert_eq!(keys(&mut db.scan(b"ab", b"d"))?, ["ab", "b", "c"]);
+    assert!(keys(&mut db.scan(b"d", b"b"))?.is_empty());
+    assert!(keys(&mut db.scan(b"b", b"b"))?.is_empty());
+    assert_eq!(keys(&mut db.scan_prefix(b"a"))?, ["a", "ab"]);
+    assert!(keys(&mut db.scan_prefix(b"x"))?.is_empty());
+
+    // values come back with their keys, in key order
+    let pairs: Vec<_> = db.scan_prefix(b"").collect::<Result<_, _>>()?;
+    assert_eq!(pairs.len(), 5);
+    assert_eq!(pairs[3], (b"c".to_vec(), b"v-c".to_vec()));
+
+    // the pager isn't held between items, so reads/writes can interleave with a scan
+    let mut it = db.scan(b"a", b"z");
+    assert_eq!(it.next().unwrap()?.0, b"a");
+    assert_eq!(db.get(b"d")?.as_deref(), Some(&b"v-d"[..]));
+    db.set(b"e", b"v-e")?;
+    assert_eq!(it.count(), 4);
+    Ok(())
+}
+
+pub fn checkpoint_truncates_wal() -> anyhow::Result<()> {
+    let dir = test_dir("checkpoint")?;
+    let n = 10_000;
+    {
+        let db = Engine::open(&dir)?;
+        for i in 0..n {
+            db.set(format!("key{:05}", i).as_bytes(), format!("val{}", i).as_bytes())?;
+        }
+        let before = wal_bytes(&dir)?;
+        db.checkpoint()?;
+        let after = wal_bytes(&dir)?;
+        assert!(after < before, "wal did not shrink: {} -> {}", before, after);
+        // writes after the checkpoint go to the fresh log
+        db.delete(b"key00000")?;
+        db.set(b"key00001", b"updated")?;
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"key00000")?, None);
+    assert_eq!(db.get(b"key00001")?.as_deref(), Some(&b"updated"[..]));
+    for i in 2..n {
+        let v = db.get(format!("key{:05}", i).as_bytes())?;
+        assert_eq!(v, Some(format!("val{}", i).into_bytes()));
+    }
+    Ok(())
+}
+
+pub fn wal_torn_tail_is_end_of_log() -> anyhow::Result<()> {
+    let dir = test_dir("torn_tail")?;
+    let path = dir.join("wal-000001.log");
+    let tear = |path: &std::path::Path| -> anyhow::Result<()> {
+        // half-written record: a length promising 100 bytes, then only a few of them
+        let mut f = fs::OpenOptions::new().append(true).open(path)?;
+        f.write_all(&100u64.to_le_bytes())?;
+        f.write_all(&[1, 2, 3])?;
+        Ok(())
+    };
+    {
+        let wal = Wal::open(&dir)?;
+        wal.append(b"good")?;
+        wal.sync()?;
+    }
+    tear(&path)?;
+    let mut seen = Vec::new();
+    Wal::replay_from_start(&dir, |lsn, payload| { seen.push((lsn, payload)); Ok(()) })?;
+    assert_eq!(seen, vec![(0, b"good".to_vec())]);
+
+    // reopening cuts the torn tail so later appends stay reachable
+    {
+        let wal = Wal::open(&dir)?;
+        assert_eq!(wal.append(b"next")?, 1);
+    }
+    let mut n = 0;
+    Wal::replay_from_start(&dir, |_, _| { n += 1; Ok(()) })?;
+    assert_eq!(n, 2);
+
+    // the engine recovers through a torn tail as well
+    let dir = test_dir("torn_tail_engine")?;
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"k", b"v")?;
+    }
+    tear(&dir.join("wal-000001.log"))?;
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"k")?.as_deref(), Some(&b"v"[..]));
+    Ok(())
+}
+
+pub fn crc32_table_matches_bitwise() -> anyhow::Result<()> {
+    // standard check value for CRC-32/IEEE
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    assert_eq!(crc32(&[]), crc32_bitwise(&[]));
+    let mut rng = XorShift64::new(0x7157);
+    for _ in 0..200 {
+        let len = (rng.next_u64() % 9000) as usize;
+        let mut buf = vec![0u8; len];
+        rng.fill(&mut buf);
+        assert_eq!(crc32(&buf), crc32_bitwise(&buf), "len {}", len);
+    }
+    Ok(())
+}
+
+pub fn crc32c_hw_matches_sw() -> anyhow::Result<()> {
+    // standard check value for CRC-32C
+    assert_eq!(crc32c_sw(b"123456789"), 0xE3069283);
+    assert_eq!(crc32c(b"123456789"), 0xE3069283);
+    let Some(hw) = crc32c_hw(b"123456789") else {
+        println!("  (no SSE4.2, hardware CRC32C not checked)");
+        return Ok(());
+    };
+    assert_eq!(hw, 0xE3069283);
+    let mut rng = XorShift64::new(0xc32c);
+    for _ in 0..200 {
+        // odd lengths too, so the byte-at-a-time tail gets exercised
+        let len = (rng.next_u64() % 9000) as usize;
+        let mut buf = vec![0u8; len];
+        rng.fill(&mut buf);
+        assert_eq!(crc32c_hw(&buf), Some(crc32c_sw(&buf)), "len {}", len);
+    }
+    Ok(())
+}
+
+pub fn compare_and_swap_cases() -> anyhow::Result<()> {
+    let dir = test_dir("cas")?;
+    let db = Engine::open(&dir)?;
+    // absent: only `None` matches
+    assert!(!db.compare_and_swap(b"lock", Some(b"x"), b"owner-a")?);
+    assert_eq!(db.get(b"lock")?, None);
+    assert!(db.compare_and_swap(b"lock", None, b"owner-a")?);
+    // present: `None` and a wrong value both fail and leave the value alone
+    assert!(!db.compare_and_swap(b"lock", None, b"owner-b")?);
+    assert!(!db.compare_and_swap(b"lock", Some(b"owner-b"), b"owner-c")?);
+    assert_eq!(db.get(b"lock")?.as_deref(), Some(&b"owner-a"[..]));
+    // match swaps, and the swap is durable
+    assert!(db.compare_and_swap(b"lock", Some(b"owner-a"), b"owner-b")?);
+    drop(db);
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"lock")?.as_deref(), Some(&b"owner-b"[..]));
+    Ok(())
+}
+
+pub fn set_if_absent_inserts_once() -> anyhow::Result<()> {
+    let dir = test_dir("set_if_absent")?;
+    let db = Engine::open(&dir)?;
+    assert!(db.set_if_absent(b"init", b"first")?);
+    // present: left alone
+    assert!(!db.set_if_absent(b"init", b"second")?);
+    assert_eq!(db.get(b"init")?.as_deref(), Some(&b"first"[..]));
+    // a deleted key is absent again
+    db.delete(b"init")?;
+    assert!(db.set_if_absent(b"init", b"third")?);
+    drop(db);
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"init")?.as_deref(), Some(&b"third"[..]));
+
+    // of many racing inserts exactly one wins, and its value is the one kept
+    let racers: Vec<_> = (0..8).map(|t| {
+        let db = db.clone();
+        thread::spawn(move || -> anyhow::Result<Option<String>> {
+            let val = format!("racer-{}", t);
+            Ok(db.set_if_absent(b"race", val.as_bytes())?.then_some(val))
+        })
+    }).collect();
+    let mut winners = Vec::new();
+    for t in racers {
+        winners.extend(t.join().unwrap()?);
+    }
+    assert_eq!(winners.len(), 1);
+    assert_eq!(db.get(b"race")?, Some(winners[0].clone().into_bytes()));
+    Ok(())
+}
+
+pub fn write_batch_is_all_or_nothing() -> anyhow::Result<()> {
+    let dir = test_dir("batch")?;
+    let data_path = dir.join("tinydb_data.db");
+    let wal_path = dir.join("wal-000001.log");
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"base", b"0")?;
+        db.set(b"gone", b"0")?;
+    }
+    // simulate a crash where the batch's page writes never reached disk
+    let pages_before = fs::read(&data_path)?;
+    {
+        let db = Engine::open(&dir)?;
+        db.write_batch(&[
+            BatchOp::Set(b"a", b"1"),
+            BatchOp::Set(b"b", b"2"),
+            BatchOp::Delete(b"gone"),
+            BatchOp::Delete(b"never-existed"),
+            BatchOp::Set(b"base", b"3"),
+        ])?;
+        assert_eq!(db.get(b"gone")?, None);
+        assert_eq!(db.get(b"base")?.as_deref(), Some(&b"3"[..]));
+    }
+    fs::write(&data_path, &pages_before)?;
+    let wal_full = fs::read(&wal_path)?;
+
+    // intact batch record: replay applies every op
+    {
+        let db = Engine::open(&dir)?;
+        assert_eq!(db.get(b"a")?.as_deref(), Some(&b"1"[..]));
+        assert_eq!(db.get(b"b")?.as_deref(), Some(&b"2"[..]));
+        assert_eq!(db.get(b"gone")?, None);
+        assert_eq!(db.get(b"base")?.as_deref(), Some(&b"3"[..]));
+    }
+
+    // batch cut off just before its commit marker: replay applies none of it
+    fs::write(&data_path, &pages_before)?;
+    fs::write(&wal_path, &wal_full[..wal_full.len() - 3])?;
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"a")?, None);
+    assert_eq!(db.get(b"b")?, None);
+    assert_eq!(db.get(b"gone")?.as_deref(), Some(&b"0"[..]));
+    assert_eq!(db.get(b"base")?.as_deref(), Some(&b"0"[..]));
+    Ok(())
+}
+
+pub fn wal_group_commit() -> anyhow::Result<()> {
+    let dir = test_dir("group_commit")?;
+    let (threads, per_thread) = (8, 1000);
+    let wal = Arc::new(Wal::open(&dir)?);
+    let handles: Vec<_> = (0..threads).map(|t| {
+        let wal = Arc::clone(&wal);
+        thread::spawn(move || -> anyhow::Result<()> {
+            for i in 0..per_thread {
+                let payload = format!("SET t{} k{}", t, i);
+                let lsn = wal.append(payload.as_bytes())?;
+                wal.sync_to(lsn + 1)?;
+            }
+            Ok(())
+        })
+    }).collect();
+    for h in handles {
+        h.join().unwrap()?;
+    }
+    let total = threads * per_thread;
+    let fsyncs = wal.fsync_count();
+    assert!(fsyncs < total as u64 / 2, "{} fsyncs for {} commits", fsyncs, total);
+
+    let mut n = 0;
+    Wal::replay_from_start(&dir, |lsn, _| { assert_eq!(lsn, n); n += 1; Ok(()) })?;
+    assert_eq!(n, total as u64);
+    Ok(())
+}
+
+pub fn read_only_open() -> anyhow::Result<()> {
+    let dir = test_dir("read_only")?;
+    let data_path = dir.join("tinydb_data.db");
+    let writer = Engine::open(&dir)?;
+    writer.set(b"a", b"1")?;
+    let pages_before = fs::read(&data_path)?;
+    writer.set(b"b", b"2")?;
+    // pretend the writer crashed before writing b's page: only the WAL has it
+    drop(writer);
+    fs::write(&data_path, &pages_before)?;
+
+    let reader = Engine::open_read_only(&dir)?;
+    assert_eq!(reader.get(b"a")?.as_deref(), Some(&b"1"[..]));
+    assert_eq!(reader.get(b"b")?.as_deref(), Some(&b"2"[..]));
+    assert!(reader.set(b"c", b"3").is_err());
+    assert!(reader.delete(b"a").is_err());
+    assert!(reader.checkpoint().is_err());
+    assert_eq!(reader.get(b"c")?, None);
+    // the reader replayed b into memory only; the data file is untouched
+    assert_eq!(fs::read(&data_path)?, pages_before);
+
+    // a reader's view is fixed at open time
+    let writer = Engine::open(&dir)?;
+    writer.set(b"d", b"4")?;
+    assert_eq!(reader.get(b"d")?, None);
+    assert_eq!(Engine::open_read_only(&dir)?.get(b"d")?.as_deref(), Some(&b"4"[..]));
+
+    // nothing to read from an empty directory
+    assert!(Engine::open_read_only(test_dir("read_only_missing")?).is_err());
+    Ok(())
+}
+
+pub fn compact_reclaims_overwrites() -> anyhow::Result<()> {
+    let dir = test_dir("compact")?;
+    let data_path = dir.join("tinydb_data.db");
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"other", b"kept")?;
+        db.set(b"doomed", b"x")?;
+        for i in 0..1000 {
+            db.set(b"hot", format!("value-{:04}-{}", i, "p".repeat(64)).as_bytes())?;
+        }
+        db.delete(b"doomed")?;
+        let before = fs::metadata(&data_path)?.len();
+        assert!(before > 10 * PAGE_SIZE as u64);
+
+        db.compact()?;
+        let after = fs::metadata(&data_path)?.len();
+        assert_eq!(after, 2 * PAGE_SIZE as u64, "live data should pack into one page after the metadata page");
+        assert_eq!(db.get(b"hot")?, Some(format!("value-0999-{}", "p".repeat(64)).into_bytes()));
+        assert_eq!(db.get(b"other")?.as_deref(), Some(&b"kept"[..]));
+        // writes keep working on top of the packed pages
+        db.set(b"new", b"after")?;
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"hot")?, Some(format!("value-0999-{}", "p".repeat(64)).into_bytes()));
+    assert_eq!(db.get(b"other")?.as_deref(), Some(&b"kept"[..]));
+    assert_eq!(db.get(b"new")?.as_deref(), Some(&b"after"[..]));
+    assert_eq!(db.get(b"doomed")?, None);
+
+    // compacting an empty database leaves the metadata page and one empty data page
+    db.delete(b"hot")?;
+    db.delete(b"other")?;
+    db.delete(b"new")?;
+    db.compact()?;
+    assert_eq!(fs::metadata(&data_path)?.len(), 2 * PAGE_SIZE as u64);
+    drop(db);
+    assert_eq!(Engine::open(&dir)?.get(b"hot")?, None);
+    Ok(())
+}
+
+pub fn compact_shrinks_file_to_live_pages() -> anyhow::Result<()> {
+    // many live keys, each overwritten a few times: the file ends at the pages they pack into
+    let dir = test_dir("compact_shrink")?;
+    let data_path = dir.join("tinydb_data.db");
+    let db = Engine::open(&dir)?;
+    let (keys, val_len) = (600usize, 100usize);
+    for round in 0..5u8 {
+        for i in 0..keys {
+            db.set(format!("key{:05}", i).as_bytes(), &vec![round; val_len])?;
+        }
+    }
+    let before = fs::metadata(&data_path)?.len();
+    db.compact()?;
+    let per_page = (PAGE_SIZE - HDR_SZ) / (16 + 8 + val_len);
+    let expected = 1 + keys.div_ceil(per_page);
+    let after = fs::metadata(&data_path)?.len();
+    assert_eq!(after, (expected * PAGE_SIZE) as u64);
+    assert!(after * 4 < before, "{} -> {}", before, after);
+    assert_eq!(db.get(b"key00599")?, Some(vec![4; val_len]));
+    Ok(())
+}
+
+pub fn ttl_expiry() -> anyhow::Result<()> {
+    let dir = test_dir("ttl")?;
+    {
+        let db = Engine::open(&dir)?;
+        db.set_with_ttl(b"short", b"v", Duration::from_secs(1))?;
+        db.set_with_ttl(b"long", b"w", Duration::from_secs(3600))?;
+        db.set(b"forever", b"x")?;
+        assert_eq!(db.get(b"short")?.as_deref(), Some(&b"v"[..]));
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(db.get(b"short")?, None);
+        assert!(!db.delete(b"short")?);
+    }
+
+    // reopen: the expired key isn't loaded, the others are
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"short")?, None);
+    assert_eq!(db.get(b"long")?.as_deref(), Some(&b"w"[..]));
+    assert_eq!(db.get(b"forever")?.as_deref(), Some(&b"x"[..]));
+    let keys: Vec<Vec<u8>> = db.scan_prefix(b"").map(|r| r.map(|(k, _)| k)).collect::<Result<_, _>>()?;
+    assert_eq!(keys, vec![b"forever".to_vec(), b"long".to_vec()]);
+
+    // a ttl survives compaction
+    db.compact()?;
+    drop(db);
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"long")?.as_deref(), Some(&b"w"[..]));
+    Ok(())
+}
+
+pub fn free_pages_are_reused() -> anyhow::Result<()> {
+    // pager level: a freed id survives reopen and is handed out before the file grows
+    let dir = test_dir("free_pages")?;
+    let path = dir.join("pages.db");
+    {
+        let mut p = Pager::open(&path)?;
+        let ids = [p.allocate_page()?, p.allocate_page()?, p.allocate_page()?];
+        assert_eq!(ids, [1, 2, 3]);
+        for pid in ids {
+            let mut page = Page::new(pid);
+            page.data[0] = 7;
+            page.used = 1;
+            page.lsn = 1;
+            p.write_page(&page)?;
+        }
+        p.free_page(2)?;
+        assert!(p.free_page(2).is_err());
+        assert!(p.free_page(9).is_err());
+        p.sync()?;
+    }
+    let len = fs::metadata(&path)?.len();
+    let mut p = Pager::open(&path)?;
+    assert!(p.is_free(2));
+    assert_eq!(p.allocate_page()?, 2);
+    assert_eq!(fs::metadata(&path)?.len(), len);
+    // handed out empty, not with the freed contents
+    assert_eq!(p.read_page(2)?.used, 0);
+    assert!(!Pager::open(&path)?.is_free(2));
+    assert_eq!(p.allocate_page()?, 4);
+
+    // engine level: a page left holding only overwritten values is reused after a checkpoint
+    let dir = test_dir("free_pages_engine")?;
+    let data_path = dir.join("tinydb_data.db");
+    // two entries per page
+    let val = |c: u8| vec![c; 3000];
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"a", &val(1))?;
+        db.set(b"b", &val(1))?;
+        db.set(b"a", &val(2))?;
+        db.set(b"b", &val(2))?;
+        db.checkpoint()?;
+        let len = fs::metadata(&data_path)?.len();
+        assert_eq!(len, 3 * PAGE_SIZE as u64);
+        db.set(b"c", &val(3))?;
+        db.set(b"d", &val(4))?;
+        assert_eq!(fs::metadata(&data_path)?.len(), len, "freed page 1 should be reused");
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"a")?, Some(val(2)));
+    assert_eq!(db.get(b"b")?, Some(val(2)));
+    assert_eq!(db.get(b"c")?, Some(val(3)));
+    assert_eq!(db.get(b"d")?, Some(val(4)));
+    Ok(())
+}
+
+pub fn wal_records_iterator() -> anyhow::Result<()> {
+    let dir = test_dir("wal_records")?;
+    let path = dir.join("wal-000001.log");
+    {
+        let wal = Wal::open(&dir)?;
+        for p in [&b"one"[..], b"two", b"three"] {
+            wal.append(p)?;
+        }
+        assert_eq!(wal.next_lsn(), 3);
+        wal.sync()?;
+    }
+    // a torn tail ends the iteration without an error
+    fs::OpenOptions::new().append(true).open(&path)?.write_all(&[9, 0, 0])?;
+    let recs: Vec<(u64, Vec<u8>)> = Wal::records(&dir).collect::<Result<_, _>>()?;
+    assert_eq!(recs, vec![(0, b"one".to_vec()), (1, b"two".to_vec()), (2, b"three".to_vec())]);
+
+    assert!(Wal::records(dir.join("missing.log")).next().unwrap().is_err());
+    Ok(())
+}
+
+pub fn dump_restore_round_trip() -> anyhow::Result<()> {
+    let src = test_dir("dump_src")?;
+    let db = Engine::open(&src)?;
+    db.set(b"plain", b"value")?;
+    db.set(b"\0bin\xff", &[0, 1, 2, 0, 255])?;
+    db.set(b"empty", b"")?;
+    db.set(b"gone", b"x")?;
+    db.delete(b"gone")?;
+    let mut out = Vec::new();
+    assert_eq!(dump::dump(&db, &mut out)?, 3);
+
+    let dst = test_dir("dump_dst")?;
+    let restored = Engine::open(&dst)?;
+    assert_eq!(dump::restore(&restored, &mut out.as_slice())?, 3);
+    let all = |e: &Engine| e.scan_prefix(b"").collect::<error::Result<Vec<_>>>();
+    assert_eq!(all(&restored)?, all(&db)?);
+
+    // a dump cut off mid-record is rejected
+    let cut = Engine::open(test_dir("dump_cut")?)?;
+    assert!(dump::restore(&cut, &mut &out[..out.len() - 2]).is_err());
+    // and so is a header claiming more than a key or value can hold, before allocating it
+    let mut huge = b"TDBDUMP1".to_vec();
+    huge.extend_from_slice(&4u32.to_le_bytes());
+    huge.extend_from_slice(&u32::MAX.to_le_bytes());
+    huge.extend_from_slice(b"key!");
+    let fresh = Engine::open(test_dir("dump_huge")?)?;
+    let err = dump::restore(&fresh, &mut huge.as_slice()).unwrap_err();
+    assert!(err.to_string().contains("corrupt dump: record 0"), "{}", err);
+    assert!(fresh.is_empty());
+    Ok(())
+}
+
+pub fn contains_key_cases() -> anyhow::Result<()> {
+    let dir = test_dir("contains_key")?;
+    let db = Engine::open(&dir)?;
+    db.set(b"here", b"1")?;
+    db.set(b"short", b"2")?;
+    db.set(b"doomed", b"3")?;
+    db.delete(b"doomed")?;
+    db.set_with_ttl(b"short", b"2", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(5));
+
+    assert!(db.contains_key(b"here")?);
+    assert!(!db.contains_key(b"never")?);
+    assert!(!db.contains_key(b"doomed")?);
+    assert!(!db.contains_key(b"short")?);
+    drop(db);
+    assert!(Engine::open(&dir)?.contains_key(b"here")?);
+    Ok(())
+}
+
+pub fn multi_get_reads_each_page_once() -> anyhow::Result<()> {
+    let dir = test_dir("multi_get")?;
+    {
+        let db = Engine::open(&dir)?;
+        for k in [&b"a"[..], b"b", b"c"] {
+            db.set(k, &[k[0]; 10])?;
+        }
+        // "big" exactly fills the rest of page 1 (3 * 27 bytes used, then 16 + 3 + value),
+        // pushing "z" onto page 2
+        db.set(b"big", &vec![b'v'; PAGE_SIZE - HDR_SZ - 3 * 27 - 16 - 3])?;
+        db.set(b"z", b"zz")?;
+    }
+    // fresh engine so every page read goes through the cache counters
+    let db = Engine::open(&dir)?;
+    let before = db.cache_stats();
+    let got = db.multi_get(&[b"c", b"missing", b"a", b"z", b"b", b"a"])?;
+    let after = db.cache_stats();
+    assert_eq!(got, vec![
+        Some(vec![b'c'; 10]), None, Some(vec![b'a'; 10]), Some(b"zz".to_vec()), Some(vec![b'b'; 10]), Some(vec![b'a'; 10]),
+    ]);
+    let reads = (after.hits + after.misses) - (before.hits + before.misses);
+    assert_eq!(reads, 2, "one read per distinct page");
+    assert!(db.multi_get(&[])?.is_empty());
+    Ok(())
+}
+
+pub fn verify_all_reports_corrupt_pages() -> anyhow::Result<()> {
+    let dir = test_dir("verify_all")?;
+    let path = dir.join("pages.db");
+    {
+        let mut p = Pager::open(&path)?;
+        for pid in 1..4 {
+            let mut page = Page::new(pid);
+            page.used = 1;
+            p.write_page(&page)?;
+        }
+        assert!(p.verify_all()?.is_empty());
+    }
+    // flip one byte in the data region of page 2
+    let mut b = fs::read(&path)?;
+    b[2 * PAGE_SIZE + HDR_SZ + 100] ^= 0xff;
+    fs::write(&path, &b)?;
+    assert_eq!(Pager::open_read_only(&path)?.verify_all()?, vec![2]);
+
+    // a torn partial page at the end is reported too
+    fs::OpenOptions::new().append(true).open(&path)?.write_all(&[1, 2, 3])?;
+    assert_eq!(Pager::open_read_only(&path)?.verify_all()?, vec![2, 4]);
+    Ok(())
+}
+
+pub fn increment_counters() -> anyhow::Result<()> {
+    let dir = test_dir("increment")?;
+    {
+        let db = Engine::open(&dir)?;
+        assert_eq!(db.increment(b"n", 5)?, 5);
+        assert_eq!(db.increment(b"n", 1)?, 6);
+        assert_eq!(db.increment(b"n", -10)?, -4);
+        assert_eq!(db.increment(b"fresh", -1)?, -1);
+        db.set(b"text", b"abc")?;
+        assert!(db.increment(b"text", 1).is_err());
+        db.set(b"max", &i64::MAX.to_le_bytes())?;
+        assert!(db.increment(b"max", 1).is_err());
+        assert_eq!(db.get(b"max")?, Some(i64::MAX.to_le_bytes().to_vec()));
+    }
+    assert_eq!(Engine::open(&dir)?.get(b"n")?, Some((-4i64).to_le_bytes().to_vec()));
+
+    // concurrent increments from several threads never lose an update
+    let db = Arc::new(Mutex::new(Engine::open(&dir)?));
+    let threads: Vec<_> = (0..4).map(|_| {
+        let db = Arc::clone(&db);
+        thread::spawn(move || -> anyhow::Result<()> {
+            for _ in 0..50 {
+                db.lock().unwrap().increment(b"shared", 1)?;
+            }
+            Ok(())
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap()?;
+    }
+    assert_eq!(db.lock().unwrap().increment(b"shared", 0)?, 200);
+    Ok(())
+}
+
+pub fn wal_header_crc_catches_bad_length() -> anyhow::Result<()> {
+    let dir = test_dir("wal_hdr_crc")?;
+    let path = dir.join("wal-000001.log");
+    {
+        let wal = Wal::open(&dir)?;
+        for p in [&b"one"[..], b"two", b"six"] {
+            wal.append(p)?;
+        }
+        wal.sync()?;
+    }
+    let good = fs::read(&path)?;
+    // 12-byte file header, then 25-byte record headers + 3-byte payloads
+    let len_field = |rec: usize| 12 + rec * 28;
+    let corrupt = |rec: usize| -> anyhow::Result<()> {
+        let mut b = good.clone();
+        b[len_field(rec)] ^= 0x40;
+        fs::write(&path, &b)?;
+        Ok(())
+    };
+
+    // a bad length on the last record is a torn tail: the log ends before it
+    corrupt(2)?;
+    let recs: Vec<(u64, Vec<u8>)> = Wal::records(&path).collect::<Result<_, _>>()?;
+    assert_eq!(recs.len(), 2);
+    assert_eq!(Wal::open(&dir)?.append(b"new")?, 2);
+    let recs: Vec<(u64, Vec<u8>)> = Wal::records(&path).collect::<Result<_, _>>()?;
+    assert_eq!(recs.last(), Some(&(2, b"new".to_vec())));
+
+    // mid-log it is corruption, reported instead of misreading the rest of the log
+    corrupt(1)?;
+    let err = Wal::replay_from_start(&dir, |_, _| Ok(())).unwrap_err();
+    assert!(err.to_string().contains("corrupt WAL record header"), "{}", err);
+    assert!(Wal::open(&dir).is_err());
+
+    // a log without the file header is rejected rather than parsed as records
+    fs::write(&path, &good[12..])?;
+    assert!(Wal::records(&path).next().unwrap().is_err());
+    Ok(())
+}
+
+pub fn double_write_repairs_torn_page() -> anyhow::Result<()> {
+    let dir = test_dir("double_write")?;
+    let path = dir.join("pages.db");
+    {
+        let mut p = Pager::open_with_double_write(&path)?;
+        for pid in 1..4 {
+            let mut page = Page::new(pid);
+            page.data[0] = pid as u8;
+            page.used = 1;
+            p.write_page(&page)?;
+        }
+    }
+    // tear the main slot of the last page written, which the buffer still holds
+    let mut b = fs::read(&path)?;
+    b[3 * PAGE_SIZE + HDR_SZ + 10] ^= 0xff;
+    b[PAGE_SIZE + HDR_SZ + 10] ^= 0xff;
+    fs::write(&path, &b)?;
+
+    let mut p = Pager::open_with_double_write(&path)?;
+    assert_eq!(p.read_page(3)?.data[0], 3);
+    // page 1 isn't in the buffer, so it stays corrupt
+    assert_eq!(p.verify_all()?, vec![1]);
+    Ok(())
+}
+
+pub fn configurable_page_size() -> anyhow::Result<()> {
+    for page_size in [4096, 16384] {
+        let dir = test_dir(&format!("page_size_{}", page_size))?;
+        let path = dir.join("pages.db");
+        {
+            let mut p = Pager::open_with_page_size(&path, page_size)?;
+            assert_eq!(p.page_size(), page_size);
+            for pid in 1..4 {
+                let mut page = Page::with_size(pid, page_size);
+                page.data[page_size - HDR_SZ - 1] = pid as u8;
+                page.used = 1;
+                p.write_page(&page)?;
+            }
+            // a page of the default size doesn't belong in this file
+            assert!(p.write_page(&Page::new(4)).is_err());
+        }
+        assert_eq!(fs::metadata(&path)?.len(), 4 * page_size as u64);
+
+        // a plain open picks the size up from the metadata page
+        let mut p = Pager::open(&path)?;
+        assert_eq!(p.page_size(), page_size);
+        for pid in 1..4 {
+            assert_eq!(p.read_page(pid)?.data[page_size - HDR_SZ - 1], pid as u8);
+        }
+        drop(p);
+        assert!(Pager::open_with_page_size(&path, 8192).is_err());
+
+        // the engine packs entries to the file's page size
+        Pager::open_with_page_size(dir.join("tinydb_data.db"), page_size)?;
+        let val = vec![7u8; 1000];
+        {
+            let db = Engine::open(&dir)?;
+            for i in 0..40u32 {
+                db.set(format!("k{}", i).as_bytes(), &val)?;
+            }
+            db.checkpoint()?;
+        }
+        let db = Engine::open(&dir)?;
+        for i in 0..40u32 {
+            assert_eq!(db.get(format!("k{}", i).as_bytes())?, Some(val.clone()));
+        }
+        let data_pages = fs::metadata(dir.join("tinydb_data.db"))?.len() / page_size as u64 - 1;
+        let per_page = (page_size - HDR_SZ) / (16 + 3 + val.len());
+        assert_eq!(data_pages as usize, 40usize.div_ceil(per_page));
+    }
+    assert!(Pager::open_with_page_size(test_dir("page_size_bad")?.join("pages.db"), 1000).is_err());
+    Ok(())
+}
+
+pub fn superblock_survives_reopen() -> anyhow::Result<()> {
+    let dir = test_dir("superblock")?;
+    let path = dir.join("pages.db");
+    {
+        let mut p = Pager::open(&path)?;
+        assert_eq!(p.next_page(), 0);
+        let pids = [p.allocate_page()?, p.allocate_page()?, p.allocate_page()?];
+        assert_eq!(pids, [1, 2, 3]);
+        // page 2 is allocated but never written, leaving a hole of zeros
+        for pid in [1, 3] {
+            let mut page = Page::new(pid);
+            page.used = 1;
+            p.write_page(&page)?;
+        }
+        p.allocate_page()?;
+    }
+    let mut p = Pager::open(&path)?;
+    assert_eq!(p.next_page(), 4);
+    // page 4 never reached the file, but it was handed out
+    assert_eq!(p.page_count(), 5);
+    assert_eq!(p.read_page(2)?.used, 0);
+    assert_eq!(p.read_page(3)?.used, 1);
+    assert!(p.verify_all()?.is_empty());
+    drop(p);
+
+    // the engine scans past a zeroed page in the middle of the data
+    let dir = test_dir("superblock_engine")?;
+    let val = vec![1u8; 3000];
+    {
+        let db = Engine::open(&dir)?;
+        for i in 0..6u8 {
+            db.set(&[i], &val)?;
+        }
+        db.checkpoint()?;
+    }
+    let path = dir.join("tinydb_data.db");
+    let mut b = fs::read(&path)?;
+    b[2 * PAGE_SIZE..3 * PAGE_SIZE].fill(0);
+    fs::write(&path, &b)?;
+    // force the page scan; the index snapshot would still point into page 2
+    fs::remove_file(dir.join("index.snapshot"))?;
+
+    let db = Engine::open(&dir)?;
+    // two entries per page: page 2 held keys 2 and 3
+    for i in 0..6u8 {
+        let want = if i == 2 || i == 3 { None } else { Some(val.clone()) };
+        assert_eq!(db.get(&[i])?, want);
+    }
+    db.set(b"after", b"reopen")?;
+    drop(db);
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"after")?, Some(b"reopen".to_vec()));
+    assert_eq!(db.get(&[5])?, Some(val));
+    Ok(())
+}
+
+pub fn in_memory_engine() -> anyhow::Result<()> {
+    let db = Engine::open_in_memory()?;
+    db.set(b"a", b"1")?;
+    db.set(b"b", b"2")?;
+    assert_eq!(db.get(b"a")?, Some(b"1".to_vec()));
+    db.delete(b"a")?;
+    assert_eq!(db.get(b"a")?, None);
+
+    // enough to spill over several pages, then a checkpoint rewrites the in-memory log
+    let val = vec![9u8; 3000];
+    for i in 0..10u8 {
+        db.set(&[i], &val)?;
+    }
+    db.checkpoint()?;
+    db.set(b"b", b"3")?;
+    assert_eq!(db.get(b"b")?, Some(b"3".to_vec()));
+    for i in 0..10u8 {
+        assert_eq!(db.get(&[i])?, Some(val.clone()));
+    }
+    db.compact()?;
+    assert_eq!(db.get(&[9])?, Some(val));
+
+    // the log on its own: appends survive a truncation that keeps them
+    let wal = Wal::open_in_memory()?;
+    for i in 0..3u8 {
+        wal.append(&[i])?;
+    }
+    wal.truncate_before(1)?;
+    let mut seen = Vec::new();
+    wal.replay(|lsn, payload| { seen.push((lsn, payload)); Ok(()) })?;
+    assert_eq!(seen, vec![(1, vec![1]), (2, vec![2])]);
+    assert_eq!(wal.append(b"x")?, 3);
+    Ok(())
+}
+
+pub fn snapshot_sees_old_values() -> anyhow::Result<()> {
+    let dir = test_dir("snapshot")?;
+    let db = Engine::open(&dir)?;
+    db.set(b"k", b"old")?;
+    db.set(b"gone", b"here")?;
+    let snap = db.snapshot();
+    db.set(b"k", b"new")?;
+    db.delete(b"gone")?;
+    db.set(b"later", b"x")?;
+    assert_eq!(snap.get(b"k")?, Some(b"old".to_vec()));
+    assert_eq!(snap.get(b"gone")?, Some(b"here".to_vec()));
+    assert_eq!(snap.get(b"later")?, None);
+    assert_eq!(db.get(b"k")?, Some(b"new".to_vec()));
+    assert_eq!(db.get(b"gone")?, None);
+
+    drop(snap);
+
+    // "k" and "f" fill page 1; once both move on it is dead, and a checkpoint would
+    // normally hand it out again
+    let dir = test_dir("snapshot_pin")?;
+    let db = Engine::open(&dir)?;
+    db.set(b"k", b"old")?;
+    db.set(b"f", &vec![0u8; PAGE_SIZE - HDR_SZ - 20 - 17 - 5])?;
+    let snap = db.snapshot();
+    db.set(b"k", b"new")?;
+    db.set(b"f", b"small")?;
+    db.checkpoint()?;
+    let filler = vec![1u8; 8000];
+    db.set(b"g", &filler)?;
+    db.set(b"h", &filler)?;
+    assert_eq!(snap.get(b"k")?, Some(b"old".to_vec()));
+    assert!(db.compact().is_err());
+    drop(snap);
+    db.compact()?;
+    assert_eq!(db.get(b"k")?, Some(b"new".to_vec()));
+    assert_eq!(db.get(b"h")?, Some(filler));
+    Ok(())
+}
+
+pub fn wal_segments_rotate() -> anyhow::Result<()> {
+    let dir = test_dir("wal_segments")?;
+    {
+        // 8-byte header + one 34-byte record per segment before it counts as full
+        let wal = Wal::open_with_segment_size(&dir, 40)?;
+        for i in 0..5u8 {
+            wal.append(&[i; 10])?;
+        }
+        assert_eq!(wal.segment_count(), 5);
+    }
+    assert!(dir.join("wal-000005.log").exists());
+    let wal = Wal::open_with_segment_size(&dir, 40)?;
+    assert_eq!(wal.next_lsn(), 5);
+    let recs: Vec<(u64, Vec<u8>)> = Wal::records(&dir).collect::<Result<_, _>>()?;
+    assert_eq!(recs.iter().map(|r| r.0).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+    // whole segments below the cut go; the live one is closed first when nothing survives
+    wal.truncate_before(2)?;
+    assert_eq!(wal.segment_count(), 3);
+    assert!(!dir.join("wal-000002.log").exists());
+    wal.truncate_before(5)?;
+    assert_eq!(wal.segment_count(), 1);
+    assert!(Wal::records(&dir).next().is_none());
+    assert_eq!(wal.append(b"x")?, 5);
+    drop(wal);
+
+    // the engine: pages from before the writes, so every key has to come back through replay
+    let dir = test_dir("wal_segments_engine")?;
+    drop(Engine::open(&dir)?);
+    let data_path = dir.join("tinydb_data.db");
+    let pages_before = fs::read(&data_path)?;
+    {
+        let db = Engine::open_with_options(&dir, Options { wal_segment_bytes: 4096, ..Options::default() })?;
+        for i in 0..100u32 {
+            db.set(format!("key{:03}", i).as_bytes(), &[i as u8; 100])?;
+        }
+    }
+    fs::write(&data_path, &pages_before)?;
+    let segments = fs::read_dir(&dir)?.filter(|e| e.as_ref().is_ok_and(|e| e.file_name().to_string_lossy().starts_with("wal-"))).count();
+    assert!(segments >= 3, "{} segments", segments);
+    let db = Engine::open(&dir)?;
+    for i in 0..100u32 {
+        assert_eq!(db.get(format!("key{:03}", i).as_bytes())?, Some(vec![i as u8; 100]));
+    }
+    // a checkpoint retires every segment but the one it starts with its CKP record
+    db.checkpoint()?;
+    assert_eq!(wal_bytes(&dir)?, CHECKPOINTED_WAL_BYTES);
+    Ok(())
+}
+
+pub fn sync_policies() -> anyhow::Result<()> {
+    let n = 20u32;
+    for (policy, expect_fsyncs) in [
+        (SyncPolicy::Always, n as u64),
+        (SyncPolicy::EveryN(1), n as u64),
+        (SyncPolicy::EveryN(5), n as u64 / 5),
+        (SyncPolicy::Never, 0),
+    ] {
+        let dir = test_dir("sync_policy")?;
+        {
+            let db = Engine::open_with_options(&dir, Options { sync: policy, ..Options::default() })?;
+            for i in 0..n {
+                db.set(format!("k{}", i).as_bytes(), b"v")?;
+            }
+            // with fewer fsyncs than writes, the unsynced tail is only as safe as the OS cache
+            assert_eq!(db.wal_fsync_count(), expect_fsyncs, "{:?}", policy);
+        }
+        // a clean close leaves everything to recover, whatever the policy
+        let db = Engine::open(&dir)?;
+        for i in 0..n {
+            assert_eq!(db.get(format!("k{}", i).as_bytes())?, Some(b"v".to_vec()), "{:?}", policy);
+        }
+    }
+    Ok(())
+}
+
+pub fn rebuild_keeps_latest_version() -> anyhow::Result<()> {
+    let dir = test_dir("latest_version")?;
+    let big = vec![0u8; 6000];
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"k", b"v1")?;
+        // fill page 1 so the next version of "k" lands on page 2
+        db.set(b"pad", &big)?;
+        db.set(b"pad2", &big)?;
+        db.set(b"k", b"v2")?;
+        db.checkpoint()?;
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"k")?, Some(b"v2".to_vec()));
+    drop(db);
+
+    // a batch that starts on the current page and spills onto a reused page with a lower
+    // id: both pages carry the batch's LSN, yet the current page also holds an older "k"
+    let dir = test_dir("latest_version_batch")?;
+    {
+        let db = Engine::open(&dir)?;
+        // "x" leaves page 1 too full for the 20-byte "k" entry
+        db.set(b"x", &vec![0u8; PAGE_SIZE - HDR_SZ - 17 - 19])?;
+        db.set(b"k", b"old")?;
+        db.set(b"x", b"small")?;
+        // page 1 now holds nothing live and is freed for reuse
+        db.checkpoint()?;
+        // page 2 holds 42 bytes; "fill" fits behind them, the new "k" then doesn't
+        let fill = vec![1u8; PAGE_SIZE - HDR_SZ - 42 - 20 - 5];
+        db.write_batch(&[BatchOp::Set(b"fill", &fill), BatchOp::Set(b"k", b"new")])?;
+        assert_eq!(db.get(b"k")?, Some(b"new".to_vec()));
+        // nothing left to replay: the index has to come from the pages alone
+        db.checkpoint()?;
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"k")?, Some(b"new".to_vec()));
+    Ok(())
+}
+
+pub fn stats_track_dead_space() -> anyhow::Result<()> {
+    let dir = test_dir("stats")?;
+    let db = Engine::open(&dir)?;
+    let val = vec![7u8; 500];
+    db.set(b"k", &val)?;
+    let first = db.stats()?;
+    assert_eq!(first.live_keys, 1);
+    assert_eq!(first.live_bytes, 16 + 1 + 500);
+    assert_eq!(first.used_bytes, first.live_bytes);
+    for _ in 0..50 {
+        db.set(b"k", &val)?;
+    }
+    let after = db.stats()?;
+    assert_eq!(after.live_keys, 1);
+    assert_eq!(after.live_bytes, first.live_bytes);
+    assert_eq!(after.used_bytes, 51 * first.live_bytes);
+    assert!(after.dead_bytes > first.dead_bytes, "{:?} -> {:?}", first, after);
+    assert!(after.pages > first.pages);
+
+    db.compact()?;
+    let packed = db.stats()?;
+    assert_eq!(packed.used_bytes, packed.live_bytes);
+    assert!(packed.dead_bytes < after.dead_bytes);
+    Ok(())
+}
+
+pub fn delete_prefix_cases() -> anyhow::Result<()> {
+    let dir = test_dir("delete_prefix")?;
+    {
+        let db = Engine::open(&dir)?;
+        for k in [&b"user:1:a"[..], b"user:1:b", b"user:2:a", b"user:10:a"] {
+            db.set(k, b"v")?;
+        }
+        assert_eq!(db.delete_prefix(b"user:1:")?, 2);
+        assert_eq!(db.delete_prefix(b"user:1:")?, 0);
+        assert_eq!(db.delete_prefix(b"nobody:")?, 0);
+    }
+    // recovery replays the tombstones
+    let db = Engine::open(&dir)?;
+    let keys: Vec<Vec<u8>> = db.scan_prefix(b"user:").map(|r| r.map(|(k, _)| k)).collect::<Result<_, _>>()?;
+    assert_eq!(keys, vec![b"user:10:a".to_vec(), b"user:2:a".to_vec()]);
+    Ok(())
+}
+
+pub fn clean_shutdown_skips_replay() -> anyhow::Result<()> {
+    let dir = test_dir("clean_shutdown")?;
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"a", b"1")?;
+        db.set(b"b", b"2")?;
+        db.close()?;
+    }
+    {
+        let db = Engine::open(&dir)?;
+        assert_eq!(db.replayed_records(), 0);
+        assert_eq!(db.get(b"a")?.as_deref(), Some(&b"1"[..]));
+        // a write after the clean reopen, then a crash: the marker no longer matches
+        db.set(b"c", b"3")?;
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.replayed_records(), 1);
+    assert_eq!(db.get(b"c")?.as_deref(), Some(&b"3"[..]));
+    db.close()?;
+    let db = Engine::open_read_only(&dir)?;
+    assert_eq!(db.replayed_records(), 0);
+    db.close()?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn direct_io_round_trip() -> anyhow::Result<()> {
+    let dir = test_dir("direct_io")?;
+    let path = dir.join("pages.db");
+    {
+        let mut p = Pager::open_direct(&path)?;
+        for _ in 0..3 {
+            let pid = p.allocate_page()?;
+            let mut page = Page::new(pid);
+            page.data[..5].copy_from_slice(b"hello");
+            page.data[100] = pid as u8;
+            page.used = 101;
+            p.write_page(&page)?;
+        }
+        p.sync()?;
+    }
+    // read back both through O_DIRECT and through the page cache
+    let mut p = Pager::open_direct(&path)?;
+    assert_eq!(p.next_page(), 3);
+    assert!(p.verify_all()?.is_empty());
+    let page = p.read_page(2)?;
+    assert_eq!((&page.data[..5], page.data[100], page.used), (&b"hello"[..], 2, 101));
+    drop(p);
+    let mut p = Pager::open(&path)?;
+    assert_eq!(p.read_page(3)?.data[100], 3);
+    drop(p);
+
+    // pages smaller than the alignment can't be written directly
+    let small = dir.join("small.db");
+    drop(Pager::open_with_page_size(&small, 512)?);
+    assert!(Pager::open_direct(&small).is_err());
+    Ok(())
+}
+
+pub fn write_pages_in_bulk() -> anyhow::Result<()> {
+    let dir = test_dir("write_pages")?;
+    let path = dir.join("pages.db");
+    let page_for = |pid: u64, tag: u8| {
+        let mut page = Page::new(pid);
+        page.data[..8].copy_from_slice(&pid.to_le_bytes());
+        page.data[8] = tag;
+        page.used = 9;
+        page.lsn = pid;
+        page
+    };
+    {
+        let mut p = Pager::open(&path)?;
+        // out of order, with a gap at 50 splitting the ids into two runs, and 7 written twice
+        let mut pages: Vec<Page> = (1..=101).filter(|&pid| pid != 50).rev().map(|pid| page_for(pid, 1)).collect();
+        pages.push(page_for(7, 2));
+        p.write_pages(&pages)?;
+        assert_eq!(p.read_page(7)?.data[8], 2);
+        p.sync()?;
+        assert!(p.write_pages(&[Page::new(0)]).is_err());
+    }
+    let mut p = Pager::open(&path)?;
+    assert_eq!(p.page_count(), 102);
+    assert!(p.verify_all()?.is_empty());
+    for pid in (1..=101).filter(|&pid| pid != 50) {
+        let page = p.read_page(pid)?;
+        assert_eq!(page.data[..8], pid.to_le_bytes());
+        assert_eq!(page.data[8], if pid == 7 { 2 } else { 1 });
+    }
+    assert_eq!(p.read_page(50)?.used, 0);
+    Ok(())
+}
+
+pub fn corruption_errors_are_typed() -> anyhow::Result<()> {
+    let dir = test_dir("typed_errors")?;
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"k", b"v")?;
+        db.checkpoint()?;
+    }
+    // flip a byte in the data region of page 1
+    let path = dir.join("tinydb_data.db");
+    let mut b = fs::read(&path)?;
+    b[PAGE_SIZE + HDR_SZ + 100] ^= 0xff;
+    fs::write(&path, &b)?;
+    assert!(matches!(Pager::open_read_only(&path)?.read_page(1), Err(TinyDbError::PageCrcMismatch { id: 1 })));
+    // still matchable once it has become an anyhow::Error
+    let err = anyhow::Error::from(Engine::open(&dir)?.get(b"k").expect_err("get should fail"));
+    assert!(matches!(err.downcast_ref::<TinyDbError>(), Some(TinyDbError::PageCrcMismatch { id: 1 })), "{}", err);
+
+    assert!(matches!(Page::from_bytes(&[0u8; 10]), Err(TinyDbError::ShortRead { got: 10, .. })));
+    assert!(matches!(Page::from_bytes(&[0u8; PAGE_SIZE]), Err(TinyDbError::BadMagic { .. })));
+
+    let dir = test_dir("typed_errors_wal")?;
+    {
+        let wal = Wal::open(&dir)?;
+        wal.append(b"one")?;
+        wal.append(b"two")?;
+        wal.sync()?;
+    }
+    // the last byte is the payload of the record at LSN 1
+    let seg = dir.join("wal-000001.log");
+    let mut b = fs::read(&seg)?;
+    *b.last_mut().unwrap() ^= 0xff;
+    fs::write(&seg, &b)?;
+    let recs: Vec<_> = Wal::records(&dir).collect();
+    assert!(matches!(recs.last(), Some(Err(TinyDbError::WalCrcMismatch { lsn: 1 }))));
+    Ok(())
+}
+
+pub fn repl_session() -> anyhow::Result<()> {
+    assert_eq!(repl::split_args(r#"set k "a \"b\" c" 'x y' """#)?, ["set", "k", r#"a "b" c"#, "x y", ""]);
+    assert!(repl::split_args("get 'k").is_err());
+
+    let dir = test_dir("repl")?;
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg("repl")
+        .env("TINYDB_DATA_DIR", &dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(
+        b"set greeting \"hello world\"\nset gr2 x\nget greeting\n\nscan gr\ndel gr2\ndel gr2\nget gr2\nbogus\nset k\nquit\nset never run\n",
+    )?;
+    let out = child.wait_with_output()?;
+    assert!(out.status.success());
+    let lines: Vec<&str> = std::str::from_utf8(&out.stdout)?.lines().collect();
+    assert_eq!(lines, [
+        "OK",
+        "OK",
+        "Value: hello world",
+        "gr2 = x",
+        "greeting = hello world",
+        "(2 keys)",
+        "Deleted",
+        "Not found",
+        "Not found",
+        "Unknown Command bogus",
+        "Usage : set <key> <value>",
+    ]);
+    // quit closed the engine cleanly
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.replayed_records(), 0);
+    assert_eq!(db.get(b"greeting")?.as_deref(), Some(&b"hello world"[..]));
+    assert_eq!(db.get(b"never")?, None);
+    Ok(())
+}
+
+pub fn merge_appends() -> anyhow::Result<()> {
+    let dir = test_dir("merge")?;
+    let append = |cur: Option<&[u8]>, op: &[u8]| [cur.unwrap_or_default(), op].concat();
+    {
+        let db = Engine::open(&dir)?;
+        assert!(db.merge(b"list", b"a").is_err());
+        db.set_merge_operator(append);
+        for op in [&b"a"[..], b",b", b",c"] {
+            db.merge(b"list", op)?;
+        }
+        assert_eq!(db.get(b"list")?.as_deref(), Some(&b"a,b,c"[..]));
+    }
+    // replay restores the merged value without the operator registered
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"list")?.as_deref(), Some(&b"a,b,c"[..]));
+    Ok(())
+}
+
+pub fn index_snapshot_skips_scan() -> anyhow::Result<()> {
+    let dir = test_dir("index_snapshot")?;
+    let snap = dir.join("index.snapshot");
+    let val = vec![7u8; 1000];
+    let check = |db: &mut Engine| -> anyhow::Result<()> {
+        for i in 0..40 {
+            let want = if i % 10 == 0 { None } else { Some(val.clone()) };
+            assert_eq!(db.get(format!("k{}", i).as_bytes())?, want, "k{}", i);
+        }
+        assert_eq!(db.get(b"late")?.as_deref(), Some(&b"x"[..]));
+        Ok(())
+    };
+    {
+        let db = Engine::open(&dir)?;
+        for i in 0..40 {
+            db.set(format!("k{}", i).as_bytes(), &val)?;
+        }
+        for i in (0..40).step_by(10) {
+            db.delete(format!("k{}", i).as_bytes())?;
+        }
+        db.checkpoint()?;
+        // logged after the snapshot, so it comes from replay
+        db.set(b"late", b"x")?;
+    }
+    let mut db = Engine::open(&dir)?;
+    assert_eq!(db.pages_scanned(), 0);
+    assert_eq!(db.replayed_records(), 1);
+    check(&mut db)?;
+    db.checkpoint()?;
+    drop(db);
+    let scanned = |dir: &PathBuf| -> anyhow::Result<usize> { Ok(Engine::open(dir)?.pages_scanned()) };
+    let good = fs::read(&snap)?;
+
+    // a damaged, missing or outdated snapshot falls back to the full scan
+    let mut bad = good.clone();
+    bad[20] ^= 0xff;
+    fs::write(&snap, &bad)?;
+    let mut db = Engine::open(&dir)?;
+    assert!(db.pages_scanned() >= 5, "{}", db.pages_scanned());
+    check(&mut db)?;
+    drop(db);
+    // so does one whose CRC is what got hit
+    let mut bad = good.clone();
+    *bad.last_mut().unwrap() ^= 0x01;
+    fs::write(&snap, &bad)?;
+    let mut db = Engine::open(&dir)?;
+    assert!(db.pages_scanned() >= 5, "{}", db.pages_scanned());
+    check(&mut db)?;
+    drop(db);
+
+    fs::remove_file(&snap)?;
+    assert!(scanned(&dir)? > 0);
+
+    fs::write(&snap, &good)?;
+    assert_eq!(scanned(&dir)?, 0);
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"later", b"y")?;
+        db.checkpoint()?;
+    }
+    fs::write(&snap, &good)?;
+    let mut db = Engine::open(&dir)?;
+    assert!(db.pages_scanned() > 0);
+    check(&mut db)?;
+    assert_eq!(db.get(b"later")?.as_deref(), Some(&b"y"[..]));
+    Ok(())
+}
+
+pub fn oversized_entries_are_rejected() -> anyhow::Result<()> {
+    let dir = test_dir("oversized")?;
+    let db = Engine::open(&dir)?;
+    db.set(b"small", b"v")?;
+    // 16-byte entry header + 3-byte key
+    let max_val = PAGE_SIZE - HDR_SZ - 16 - 3;
+    let err = db.set(b"big", &vec![1u8; max_val + 1]).unwrap_err();
+    assert!(matches!(err, TinyDbError::EntryTooLarge { len, max } if len == max + 1 && max == PAGE_SIZE - HDR_SZ), "{}", err);
+    let too_big = vec![1u8; max_val + 1];
+    assert!(db.write_batch(&[BatchOp::Set(b"a", b"1"), BatchOp::Set(b"big", &too_big)]).is_err());
+    assert_eq!(db.get(b"a")?, None);
+    // a value that exactly fills a page still fits
+    db.set(b"big", &vec![2u8; max_val])?;
+    drop(db);
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"big")?, Some(vec![2u8; max_val]));
+    assert_eq!(db.get(b"small")?.as_deref(), Some(&b"v"[..]));
+    Ok(())
+}
+
+pub fn shared_handles_across_threads() -> anyhow::Result<()> {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Engine>();
+
+    let dir = test_dir("shared_handles")?;
+    let db = Engine::open(&dir)?;
+    db.set(b"counter", &0i64.to_le_bytes())?;
+    let writers: Vec<_> = (0..3).map(|t| {
+        let db = db.clone();
+        thread::spawn(move || -> anyhow::Result<()> {
+            for i in 0..50 {
+                db.set(format!("w{}:{}", t, i).as_bytes(), b"v")?;
+                db.increment(b"counter", 1)?;
+            }
+            Ok(())
+        })
+    }).collect();
+    let readers: Vec<_> = (0..3).map(|_| {
+        let db = db.clone();
+        thread::spawn(move || -> anyhow::Result<()> {
+            let mut last = 0;
+            for _ in 0..100 {
+                let n = i64::from_le_bytes(db.get(b"counter")?.unwrap().try_into().unwrap());
+                // increments are atomic and in order, so a reader never sees the count go back
+                assert!(n >= last, "{} < {}", n, last);
+                last = n;
+            }
+            Ok(())
+        })
+    }).collect();
+    for t in writers.into_iter().chain(readers) {
+        t.join().unwrap()?;
+    }
+    assert_eq!(db.increment(b"counter", 0)?, 150);
+    assert_eq!(db.scan_prefix(b"w").count(), 150);
+    // another live handle isn't a snapshot: compaction still runs
+    let other = db.clone();
+    db.compact()?;
+    assert_eq!(other.get(b"w2:49")?.as_deref(), Some(&b"v"[..]));
+    drop((db, other));
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.scan_prefix(b"w").count(), 150);
+    Ok(())
+}
+
+pub fn crc32c_database_round_trip() -> anyhow::Result<()> {
+    let dir = test_dir("crc32c")?;
+    {
+        let db = Engine::open_with_options(&dir, Options { checksum: Checksum::Crc32c, ..Options::default() })?;
+        for i in 0..200 {
+            db.set(format!("k{:03}", i).as_bytes(), &[b'v'; 100])?;
+        }
+        db.checkpoint()?;
+        db.set(b"after", b"ckpt")?;
+        // dropped without close: "after" has to come back from the WAL
+    }
+    // the checksum picked at creation sticks, whatever later opens ask for
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"after")?.as_deref(), Some(&b"ckpt"[..]));
+    assert_eq!(db.get(b"k123")?.as_deref(), Some(&[b'v'; 100][..]));
+    db.close()?;
+    let mut p = Pager::open_read_only(dir.join("tinydb_data.db"))?;
+    assert_eq!(p.checksum(), Checksum::Crc32c);
+    assert!(p.verify_all()?.is_empty());
+    // and the pages really are CRC32C: the IEEE decoder rejects them
+    let b = fs::read(dir.join("tinydb_data.db"))?;
+    assert!(Page::decode(&b[PAGE_SIZE..2 * PAGE_SIZE], Checksum::Crc32c).is_ok());
+    assert!(matches!(Page::from_bytes(&b[PAGE_SIZE..2 * PAGE_SIZE]), Err(TinyDbError::PageCrcMismatch { id: 1 })));
+    Ok(())
+}
+
+pub fn replay_reports_progress() -> anyhow::Result<()> {
+    let dir = test_dir("replay_progress")?;
+    {
+        let db = Engine::open_with_options(&dir, Options { sync: SyncPolicy::Never, ..Options::default() })?;
+        for i in 0..2500u32 {
+            db.set(format!("k{:04}", i).as_bytes(), b"v")?;
+        }
+        // dropped without close, so the next open replays all 2500 records
+    }
+    let wal_len = fs::metadata(dir.join("wal-000001.log"))?.len();
+    let reports = Mutex::new(Vec::new());
+    let db = Engine::open_with_progress(&dir, |p| reports.lock().unwrap().push(p))?;
+    let reports = reports.into_inner().unwrap();
+    // throttled: a report per 1000 records (or 100ms), not one per record, plus a final one
+    assert!((3..=25).contains(&reports.len()), "{} reports", reports.len());
+    assert!(reports.windows(2).all(|w| w[0].records < w[1].records && w[0].elapsed <= w[1].elapsed));
+    let last = reports.last().unwrap();
+    assert_eq!(last.records, 2500);
+    assert_eq!(db.replayed_records(), 2500);
+    assert_eq!(last.lsn, 2499);
+    // every byte after the 12-byte segment header
+    assert_eq!(last.bytes, wal_len - 12);
+    assert!(db.recovery_time() >= last.elapsed);
+    assert_eq!(db.get(b"k2499")?.as_deref(), Some(&b"v"[..]));
+
+    // nothing to replay after a clean close: no reports at all
+    db.close()?;
+    let calls = Mutex::new(0);
+    Engine::open_with_progress(&dir, |_| *calls.lock().unwrap() += 1)?;
+    assert_eq!(calls.into_inner().unwrap(), 0);
+    Ok(())
+}
+
+pub fn storage_backed_engine() -> anyhow::Result<()> {
+    // MemFile clones share one buffer, so these outlive each engine like files would
+    let (data, log) = (MemFile::default(), MemFile::default());
+    {
+        let db = Engine::open_with_storage(data.clone(), log.clone())?;
+        db.set(b"k", b"old")?;
+        db.set(b"gone", b"x")?;
+        // fill the rest of page 1 so the next version of "k" lands on a later page
+        db.set(b"fill", &vec![0u8; PAGE_SIZE - HDR_SZ - 3 * 19 - 5])?;
+        db.checkpoint()?;
+        db.delete(b"gone")?;
+        // dropped without close: the delete is only in the WAL
+    }
+    {
+        let db = Engine::open_with_storage(data.clone(), log.clone())?;
+        assert_eq!(db.replayed_records(), 1);
+        assert_eq!(db.get(b"gone")?, None);
+        assert_eq!(db.get(b"k")?.as_deref(), Some(&b"old"[..]));
+        db.checkpoint()?;
+    }
+    {
+        // opened on an empty WAL, yet the new version must still sort after the old one
+        let db = Engine::open_with_storage(data.clone(), log.clone())?;
+        db.set(b"k", b"new")?;
+        db.checkpoint()?;
+    }
+    let db = Engine::open_with_storage(data.clone(), log)?;
+    assert_eq!(db.get(b"k")?.as_deref(), Some(&b"new"[..]));
+    assert_eq!(db.get(b"gone")?, None);
+    assert!(Pager::open_with_storage(data)?.verify_all()?.is_empty());
+    Ok(())
+}
+
+pub fn background_checkpoint_shrinks_wal() -> anyhow::Result<()> {
+    let dir = test_dir("bg_checkpoint")?;
+    let db = Engine::open(&dir)?;
+    let ckpt = db.start_background_checkpoint(Duration::from_millis(20))?;
+    for i in 0..200u32 {
+        db.set(format!("k{:03}", i).as_bytes(), &[7u8; 100])?;
+    }
+    // ~25KB of records, but no checkpoint call: the thread gets the WAL back down to a
+    // checkpoint record within a few ticks
+    let mut waited = 0;
+    while wal_bytes(&dir)? > CHECKPOINTED_WAL_BYTES {
+        assert!(waited < 5000, "WAL still {} bytes", wal_bytes(&dir)?);
+        thread::sleep(Duration::from_millis(10));
+        waited += 10;
+    }
+
+    // writes keep going alongside it, and dropping the handle checkpoints one last time
+    for i in 0..50u32 {
+        db.set(format!("late{:02}", i).as_bytes(), b"v")?;
+    }
+    drop(ckpt);
+    assert_eq!(wal_bytes(&dir)?, CHECKPOINTED_WAL_BYTES);
+    drop(db);
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.replayed_records(), 0);
+    assert_eq!(db.get(b"k199")?.as_deref(), Some(&[7u8; 100][..]));
+    assert_eq!(db.get(b"late49")?.as_deref(), Some(&b"v"[..]));
+
+    db.start_background_checkpoint(Duration::from_secs(3600))?.stop()?;
+    assert!(matches!(Engine::open_read_only(&dir)?.start_background_checkpoint(Duration::from_millis(1)), Err(TinyDbError::ReadOnly)));
+    Ok(())
+}
+
+pub fn key_and_value_limits() -> anyhow::Result<()> {
+    let dir = test_dir("size_limits")?;
+    let db = Engine::open_with_options(&dir, Options { max_value_size: 100, ..Options::default() })?;
+    let invalid = |r: error::Result<()>, what: &str| match r {
+        Err(TinyDbError::Invalid(msg)) => assert!(msg.contains(what), "{}", msg),
+        other => panic!("expected an Invalid error about {}, got {:?}", what, other),
+    };
+
+    // an empty key would end the page scan on reopen, hiding everything after it
+    invalid(db.set(b"", b"v"), "empty key");
+    invalid(db.write_batch(&[BatchOp::Set(b"a", b"1"), BatchOp::Set(b"", b"2")]), "empty key");
+    assert_eq!(db.get(b"a")?, None, "a rejected batch writes none of its ops");
+
+    let max_key = vec![b'k'; DEFAULT_MAX_KEY_SIZE];
+    db.set(&max_key, b"v")?;
+    invalid(db.set(&vec![b'k'; DEFAULT_MAX_KEY_SIZE + 1], b"v"), "key is 1025 bytes, the limit is 1024");
+
+    db.set(b"v", &[1u8; 100])?;
+    invalid(db.set(b"v", &[1u8; 101]), "value is 101 bytes, the limit is 100");
+    invalid(db.set_with_ttl(b"t", &[1u8; 101], Duration::from_secs(60)), "value is 101 bytes");
+    assert_eq!(db.get(b"v")?.as_deref(), Some(&[1u8; 100][..]));
+    drop(db);
+
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(&max_key)?.as_deref(), Some(&b"v"[..]));
+    assert_eq!(db.get(b"v")?.as_deref(), Some(&[1u8; 100][..]));
+    assert_eq!(db.get(b"t")?, None);
+    Ok(())
+}
+
+pub fn flush_makes_lazy_writes_durable() -> anyhow::Result<()> {
+    let (data, log) = (PowerCut::default(), PowerCut::default());
+    let opts = Options { sync: SyncPolicy::Never, ..Options::default() };
+    let db = Engine::open_with_storage_options(data.clone(), log.clone(), opts)?;
+    let reopen = || -> anyhow::Result<Engine> {
+        Ok(Engine::open_with_storage(data.after_power_cut()?, log.after_power_cut()?)?)
+    };
+
+    db.set(b"a", b"1")?;
+    db.set(b"b", b"2")?;
+    // nothing has been fsynced since open: a power cut now takes both writes with it
+    assert_eq!(reopen()?.get(b"a")?, None);
+
+    let fsyncs = db.wal_fsync_count();
+    db.flush()?;
+    assert_eq!(db.wal_fsync_count(), fsyncs + 1);
+    db.set(b"c", b"3")?;
+    let after = reopen()?;
+    assert_eq!(after.get(b"a")?.as_deref(), Some(&b"1"[..]));
+    assert_eq!(after.get(b"b")?.as_deref(), Some(&b"2"[..]));
+    // written after the flush and never synced
+    assert_eq!(after.get(b"c")?, None);
+    // the flush didn't checkpoint: both records are still in the WAL, replayed on reopen
+    assert_eq!(after.replayed_records(), 2);
+
+    let dir = test_dir("flush_read_only")?;
+    Engine::open(&dir)?.close()?;
+    assert!(matches!(Engine::open_read_only(&dir)?.flush(), Err(TinyDbError::ReadOnly)));
+    Ok(())
+}
+
+pub fn bytes_written_counters() -> anyhow::Result<()> {
+    let dir = test_dir("bytes_written")?;
+    let wal = Wal::open(&dir)?;
+    assert_eq!(wal.bytes_written(), 0);
+    for i in 0..10u8 {
+        wal.append(&vec![i; 100 + i as usize])?;
+    }
+    // every appended byte is in the one segment, after its header
+    assert_eq!(wal.bytes_written(), wal_bytes(&dir)? - 12);
+    drop(wal);
+
+    let mut p = Pager::open(dir.join("pages.db"))?;
+    let ids = [p.allocate_page()?, p.allocate_page()?, p.allocate_page()?];
+    // allocation may rewrite the metadata page; count from here
+    let after_alloc = p.bytes_written();
+    p.write_page(&Page::new(ids[0]))?;
+    assert_eq!(p.bytes_written(), after_alloc + PAGE_SIZE as u64);
+    p.write_pages(&[Page::new(ids[1]), Page::new(ids[2])])?;
+    assert_eq!(p.bytes_written(), after_alloc + 3 * PAGE_SIZE as u64);
+
+    // each set appends one record and rewrites the page it landed on
+    let db = Engine::open(test_dir("bytes_written_engine")?)?;
+    db.set(b"key00", b"value")?;
+    let (per_set, data0) = (db.wal_bytes_written(), db.data_bytes_written());
+    for i in 1..=20 {
+        db.set(format!("key{:02}", i).as_bytes(), b"value")?;
+    }
+    assert!(per_set > 10, "a record carries the key and value");
+    assert_eq!(db.wal_bytes_written(), 21 * per_set);
+    assert!(db.data_bytes_written() >= data0 + 20 * PAGE_SIZE as u64);
+    assert_eq!(db.data_bytes_written() % PAGE_SIZE as u64, 0);
+    Ok(())
+}
+
+pub fn keys_and_len_follow_the_index() -> anyhow::Result<()> {
+    let dir = test_dir("keys_len")?;
+    let db = Engine::open(&dir)?;
+    assert!(db.is_empty());
+    assert_eq!(db.len(), 0);
+    for k in [&b"pear"[..], b"apple", b"fig", b"kiwi"] {
+        db.set(k, b"v")?;
+    }
+    db.set(b"fig", b"again")?;
+    db.delete(b"kiwi")?;
+    db.set_with_ttl(b"mayfly", b"v", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(5));
+
+    let keys = vec![b"apple".to_vec(), b"fig".to_vec(), b"pear".to_vec()];
+    assert_eq!(db.keys()?, keys);
+    assert_eq!(db.len(), 3);
+    assert!(!db.is_empty());
+    drop(db);
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.keys()?, keys);
+    assert_eq!(db.len(), 3);
+    Ok(())
+}
+
+pub fn crash_between_wal_sync_and_page_write() -> anyhow::Result<()> {
+    let (data, log) = (PowerCut::default(), PowerCut::default());
+    let db = Engine::open_with_storage(data.clone(), log.clone())?;
+    db.set(b"a", b"1")?;
+    // "b" fits on a's page, so the only data write left is that page: the WAL record goes
+    // out and is fsynced, then the process dies before the page reaches the file
+    data.crash_after(0);
+    assert!(db.set(b"b", b"2").is_err());
+    drop(db);
+
+    // the page never got written, and the WAL only holds what its fsync made durable
+    let db = Engine::open_with_storage(data.after_crash()?, log.after_power_cut()?)?;
+    assert_eq!(db.get(b"a")?.as_deref(), Some(&b"1"[..]));
+    assert_eq!(db.get(b"b")?.as_deref(), Some(&b"2"[..]));
+    assert_eq!(db.len(), 2);
+    Ok(())
+}
+
+pub fn crash_tearing_a_multi_page_write() -> anyhow::Result<()> {
+    // too big to share a page, so a batch of three lands on three new ones
+    let big = |c: u8| vec![c; PAGE_SIZE * 2 / 3];
+    let batch = [big(b'x'), big(b'y'), big(b'z')];
+    let ops: Vec<BatchOp> = [&b"x"[..], b"y", b"z"].iter().zip(&batch).map(|(k, v)| BatchOp::Set(k, v)).collect();
+
+    // a rehearsal on identical storage tells how many bytes the batch writes to the data
+    // file; its pages go out last, in one run
+    let rehearsal = Engine::open_in_memory()?;
+    rehearsal.set(b"before", b"1")?;
+    let start = rehearsal.data_bytes_written();
+    rehearsal.write_batch(&ops)?;
+    let batch_bytes = (rehearsal.data_bytes_written() - start) as usize;
+    assert!(batch_bytes >= 3 * PAGE_SIZE);
+
+    let (data, log) = (PowerCut::default(), PowerCut::default());
+    let db = Engine::open_with_storage(data.clone(), log.clone())?;
+    db.set(b"before", b"1")?;
+    // die halfway through the second of the three pages
+    data.crash_after(batch_bytes - PAGE_SIZE - PAGE_SIZE / 2);
+    assert!(db.write_batch(&ops).is_err());
+    drop(db);
+
+    let torn = Pager::open_with_storage(data.after_crash()?)?.verify_all()?;
+    assert_eq!(torn.len(), 1, "one page is torn: {:?}", torn);
+    let db = Engine::open_with_storage(data.after_crash()?, log.after_power_cut()?)?;
+    assert_eq!(db.get(b"before")?.as_deref(), Some(&b"1"[..]));
+    for (k, v) in [&b"x"[..], b"y", b"z"].iter().zip(&batch) {
+        assert_eq!(db.get(k)?.as_ref(), Some(v));
+    }
+    Ok(())
+}
+
+pub fn wal_replay_from_lsn() -> anyhow::Result<()> {
+    let dir = test_dir("wal_replay_from")?;
+    let wal = Wal::open_with_segment_size(&dir, 256)?;
+    for i in 0..100u64 {
+        wal.append(format!("record {}", i).as_bytes())?;
+    }
+    wal.sync()?;
+    drop(wal);
+
+    let mut seen = Vec::new();
+    Wal::replay_from(&dir, 50, |lsn, payload| {
+        assert_eq!(payload, format!("record {}", lsn).into_bytes());
+        seen.push(lsn);
+        Ok(())
+    })?;
+    assert_eq!(seen, (50..100).collect::<Vec<_>>());
+
+    // segments holding only older records aren't read: corrupting the first one goes unnoticed
+    let first = fs::read_dir(&dir)?.map(|e| e.map(|e| e.path())).collect::<Result<Vec<_>, _>>()?
+        .into_iter().min().unwrap();
+    let mut b = fs::read(&first)?;
+    let last = b.len() - 1;
+    b[last] ^= 0xff;
+    fs::write(&first, &b)?;
+    assert!(matches!(Wal::replay_from_start(&dir, |_, _| Ok(())), Err(TinyDbError::WalCrcMismatch { .. })));
+    let mut n = 0;
+    Wal::replay_from(&dir, 50, |_, _| { n += 1; Ok(()) })?;
+    assert_eq!(n, 50);
+    // past the end there is nothing to visit
+    Wal::replay_from(&dir, 100, |lsn, _| panic!("visited {}", lsn))?;
+    Ok(())
+}
+
+pub fn get_with_metadata_locates_entries() -> anyhow::Result<()> {
+    let dir = test_dir("get_metadata")?;
+    let db = Engine::open(&dir)?;
+    db.set(b"first", b"1")?;
+    db.set(b"second", b"22")?;
+    assert_eq!(db.get_with_metadata(b"missing")?, None);
+
+    let meta = db.get_with_metadata(b"second")?.unwrap();
+    assert_eq!(meta.value, b"22");
+    // right behind "first" on the first data page: a 16-byte entry header, key and value
+    assert_eq!((meta.page_id, meta.offset), (1, 16 + 5 + 1));
+    // the second WAL record wrote the page last
+    assert_eq!(meta.lsn, 1);
+    let page = Pager::open_read_only(dir.join("tinydb_data.db"))?.read_page(meta.page_id)?;
+    assert_eq!(&page.data[meta.offset as usize + 16..][..6], b"second");
+    // "first" shares the page, so it reports the same LSN
+    let first = db.get_with_metadata(b"first")?.unwrap();
+    assert_eq!((first.value, first.page_id, first.offset, first.lsn), (b"1".to_vec(), 1, 0, 1));
+    Ok(())
+}
+
+pub fn named_databases_share_a_directory() -> anyhow::Result<()> {
+    let dir = test_dir("named")?;
+    {
+        let (a, b, plain) = (Engine::open_named(&dir, "alpha")?, Engine::open_named(&dir, "beta")?, Engine::open(&dir)?);
+        a.set(b"k", b"from alpha")?;
+        b.set(b"k", b"from beta")?;
+        b.set(b"only_beta", b"1")?;
+        plain.set(b"k", b"unnamed")?;
+        a.checkpoint()?;
+        assert_eq!(a.keys()?, vec![b"k".to_vec()]);
+    }
+    for f in ["alpha.db", "alpha.wal", "beta.db", "beta.wal", "tinydb_data.db"] {
+        assert!(dir.join(f).exists(), "{} is missing", f);
+    }
+    let (a, b) = (Engine::open_named(&dir, "alpha")?, Engine::open_named(&dir, "beta")?);
+    assert_eq!(a.get(b"k")?.as_deref(), Some(&b"from alpha"[..]));
+    assert_eq!(b.get(b"k")?.as_deref(), Some(&b"from beta"[..]));
+    assert_eq!(a.get(b"only_beta")?, None);
+    assert_eq!(Engine::open(&dir)?.get(b"k")?.as_deref(), Some(&b"unnamed"[..]));
+    drop((a, b));
+
+    assert_eq!(Pager::open_read_only(dir.join("alpha.db"))?.name(), "alpha");
+    assert_eq!(Pager::open_read_only(dir.join("tinydb_data.db"))?.name(), "");
+    // a data file renamed to another database's name is caught
+    fs::copy(dir.join("alpha.db"), dir.join("gamma.db"))?;
+    assert!(matches!(Engine::open_named(&dir, "gamma"), Err(TinyDbError::Invalid(_))));
+    for bad in ["", "../escape", "has space", &"x".repeat(65)] {
+        assert!(matches!(Engine::open_named(&dir, bad), Err(TinyDbError::Invalid(_))), "{:?}", bad);
+    }
+    Ok(())
+}
+
+pub fn compact_if_needed_follows_dead_space() -> anyhow::Result<()> {
+    let dir = test_dir("compact_if_needed")?;
+    let data = dir.join("tinydb_data.db");
+    let db = Engine::open(&dir)?;
+    // nothing to reclaim in an empty database, whatever the threshold
+    assert!(!db.compact_if_needed(0.0)?);
+
+    let val = vec![b'v'; 200];
+    let batch: Vec<(String, Vec<u8>)> = (0..200).map(|i| (format!("key{:04}", i), val.clone())).collect();
+    let ops: Vec<BatchOp> = batch.iter().map(|(k, v)| BatchOp::Set(k.as_bytes(), v)).collect();
+    db.write_batch(&ops)?;
+    let (size, written) = (fs::metadata(&data)?.len(), db.data_bytes_written());
+    // freshly packed: well below half dead, so nothing is rewritten
+    assert!(!db.compact_if_needed(0.5)?);
+    assert_eq!(db.data_bytes_written(), written);
+    assert_eq!(fs::metadata(&data)?.len(), size);
+
+    // three more versions of every key leave three quarters of the entries dead
+    for _ in 0..3 {
+        db.write_batch(&ops)?;
+    }
+    let grown = fs::metadata(&data)?.len();
+    assert!(db.compact_if_needed(0.5)?);
+    assert!(fs::metadata(&data)?.len() < grown / 2);
+    assert_eq!(db.len(), 200);
+    assert_eq!(db.get(b"key0123")?.as_ref(), Some(&val));
+    // and now it is packed again
+    assert!(!db.compact_if_needed(0.5)?);
+    Ok(())
+}
+
+pub fn other_format_versions_are_refused() -> anyhow::Result<()> {
+    let dir = test_dir("format_version")?;
+    Engine::open(&dir)?.set(b"k", b"v")?;
+    // the version sits at bytes 8..12 of the superblock's data region
+    let data = dir.join("tinydb_data.db");
+    let good = fs::read(&data)?;
+    let mut b = good.clone();
+    b[HDR_SZ + 8..HDR_SZ + 12].copy_from_slice(&99u32.to_le_bytes());
+    fs::write(&data, &b)?;
+    let err = Engine::open(&dir).err().expect("a newer data file must not open");
+    assert!(matches!(err, TinyDbError::UnsupportedVersion { what: "data file", found: 99, .. }), "{}", err);
+    assert!(err.to_string().starts_with("unsupported data file format version 99, this build supports "), "{}", err);
+    assert!(matches!(Engine::open_read_only(&dir), Err(TinyDbError::UnsupportedVersion { found: 99, .. })));
+    fs::write(&data, &good)?;
+
+    // likewise a WAL segment, whose version follows its magic
+    let seg = dir.join("wal-000001.log");
+    let mut b = fs::read(&seg)?;
+    b[4..8].copy_from_slice(&99u32.to_le_bytes());
+    fs::write(&seg, &b)?;
+    assert!(matches!(Engine::open(&dir), Err(TinyDbError::UnsupportedVersion { what: "WAL", found: 99, .. })));
+    Ok(())
+}
+
+pub fn event_log_reads_from_an_lsn() -> anyhow::Result<()> {
+    let dir = test_dir("event_log")?;
+    {
+        let log = EventLog::open(&dir)?;
+        assert_eq!(log.append_event(b"created")?, 0);
+        assert_eq!(log.append_event(b"renamed")?, 1);
+        assert_eq!(log.append_event(b"deleted")?, 2);
+    }
+    let log = EventLog::open(&dir)?;
+    let events: Vec<(u64, Vec<u8>)> = log.read_events_from(1).collect::<Result<_, _>>()?;
+    assert_eq!(events, vec![(1, b"renamed".to_vec()), (2, b"deleted".to_vec())]);
+    assert_eq!(log.append_event(b"restored")?, 3);
+    assert_eq!(log.read_events_from(3).map(|e| e.map(|(lsn, _)| lsn)).collect::<Result<Vec<_>, _>>()?, vec![3]);
+    assert_eq!(log.read_events_from(4).count(), 0);
+
+    let mem = EventLog::open_in_memory()?;
+    for e in [&b"a"[..], b"b", b"c"] {
+        mem.append_event(e)?;
+    }
+    mem.truncate_before(1)?;
+    let events: Vec<(u64, Vec<u8>)> = mem.read_events_from(0).collect::<Result<_, _>>()?;
+    assert_eq!(events, vec![(1, b"b".to_vec()), (2, b"c".to_vec())]);
+    assert_eq!(mem.next_lsn(), 3);
+
+    // appending while iterating: the iterator sees what was there when it started, whole
+    let mut events = mem.read_events_from(0);
+    assert_eq!(events.next().transpose()?, Some((1, b"b".to_vec())));
+    assert_eq!(mem.append_event(b"d")?, 3);
+    assert_eq!(events.next().transpose()?, Some((2, b"c".to_vec())));
+    assert!(events.next().is_none());
+    drop(events);
+    // and from other threads, without the appends landing on top of each other
+    let mem = Arc::new(mem);
+    let appenders: Vec<_> = (0..4).map(|t| {
+        let mem = mem.clone();
+        thread::spawn(move || -> anyhow::Result<()> {
+            for i in 0..100 {
+                mem.append_event(format!("t{}-{}", t, i).as_bytes())?;
+                if i % 10 == 0 {
+                    mem.read_events_from(0).collect::<Result<Vec<_>, _>>()?;
+                }
+            }
+            Ok(())
+        })
+    }).collect();
+    for a in appenders {
+        a.join().unwrap()?;
+    }
+    let events: Vec<(u64, Vec<u8>)> = mem.read_events_from(0).collect::<Result<_, _>>()?;
+    assert_eq!(events.len(), 3 + 400);
+    assert!(events.windows(2).all(|w| w[1].0 == w[0].0 + 1));
+    Ok(())
+}
+
+pub fn bulk_load_recovers_a_prefix() -> anyhow::Result<()> {
+    let items: Vec<(String, Vec<u8>)> = (0..3500).map(|i| (format!("bulk{:05}", i), vec![b'v'; 100])).collect();
+    let loaded = |db: &Engine| -> anyhow::Result<usize> {
+        let n = db.len();
+        for (k, v) in &items[..n] {
+            assert_eq!(db.get(k.as_bytes())?.as_ref(), Some(v), "recovered keys are a prefix of the load");
+        }
+        Ok(n)
+    };
+
+    // one WAL fsync for the whole load, however many records it takes
+    let rehearsal = Engine::open_in_memory()?;
+    let (fsyncs, data_start) = (rehearsal.wal_fsync_count(), rehearsal.data_bytes_written());
+    assert_eq!(rehearsal.bulk_load(items.iter().cloned())?, items.len());
+    assert_eq!(rehearsal.wal_fsync_count(), fsyncs + 1);
+    assert_eq!(loaded(&rehearsal)?, items.len());
+    let wal_bytes = rehearsal.wal_bytes_written() as usize;
+    let data_bytes = (rehearsal.data_bytes_written() - data_start) as usize;
+
+    // dying halfway through the WAL keeps the first chunk, which was written in full
+    let (data, log) = (PowerCut::default(), PowerCut::default());
+    let db = Engine::open_with_storage(data.clone(), log.clone())?;
+    log.crash_after(wal_bytes / 2);
+    assert!(db.bulk_load(items.iter().cloned()).is_err());
+    drop(db);
+    let db = Engine::open_with_storage(data.after_power_cut()?, log.after_crash()?)?;
+    assert_eq!(loaded(&db)?, 1000);
+
+    // once the WAL is fsynced a crash while writing pages loses nothing; the pages go out
+    // last, after the superblock updates of allocating them
+    let (data, log) = (PowerCut::default(), PowerCut::default());
+    let db = Engine::open_with_storage(data.clone(), log.clone())?;
+    data.crash_after(data_bytes - PAGE_SIZE * 3);
+    assert!(db.bulk_load(items.iter().cloned()).is_err());
+    drop(db);
+    let db = Engine::open_with_storage(data.after_crash()?, log.after_power_cut()?)?;
+    assert_eq!(loaded(&db)?, items.len());
+    Ok(())
+}
+
+pub fn iter_pages_walks_the_data_pages() -> anyhow::Result<()> {
+    let dir = test_dir("iter_pages")?;
+    let path = dir.join("pages.db");
+    {
+        let mut p = Pager::open(&path)?;
+        for pid in 1..4 {
+            let mut page = Page::new(pid);
+            page.used = pid as u32;
+            p.write_page(&page)?;
+        }
+        let pages = p.iter_pages().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(pages.iter().map(|page| (page.id, page.used)).collect::<Vec<_>>(), vec![(1, 1), (2, 2), (3, 3)]);
+    }
+    // a corrupt page is an error in its place; the walk goes on past it
+    let mut b = fs::read(&path)?;
+    b[2 * PAGE_SIZE + HDR_SZ + 100] ^= 0xff;
+    fs::write(&path, &b)?;
+    let mut p = Pager::open_read_only(&path)?;
+    let pages: Vec<_> = p.iter_pages().map(|page| page.map(|page| page.id)).collect();
+    assert_eq!(pages.len(), 3);
+    assert!(matches!(pages[0], Ok(1)));
+    assert!(matches!(pages[1], Err(TinyDbError::PageCrcMismatch { id: 2 })));
+    assert!(matches!(pages[2], Ok(3)));
+    Ok(())
+}
+
+pub fn wal_append_is_one_write() -> anyhow::Result<()> {
+    let log = PowerCut::default();
+    let wal = Wal::open_with_storage(log.clone(), Checksum::default())?;
+    let payloads: Vec<Vec<u8>> = (0..200).map(|i| format!("event {}", i).repeat(i % 7 + 1).into_bytes()).collect();
+    for p in &payloads {
+        let writes = *log.writes.lock().unwrap();
+        wal.append(p)?;
+        assert_eq!(*log.writes.lock().unwrap(), writes + 1);
+    }
+    wal.sync()?;
+    drop(wal);
+
+    // the 12-byte segment header, then each record: a 25-byte header and its payload
+    let durable = log.durable.lock().unwrap().len();
+    assert_eq!(durable, 12 + payloads.iter().map(|p| 25 + p.len()).sum::<usize>());
+    let wal = Wal::open_with_storage(log.after_power_cut()?, Checksum::default())?;
+    let replayed = wal.records_from(0).collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(replayed, payloads.into_iter().enumerate().map(|(i, p)| (i as u64, p)).collect::<Vec<_>>());
+    Ok(())
+}
+
+pub fn hashed_index_tells_colliding_keys_apart() -> anyhow::Result<()> {
+    let dir = test_dir("hashed_index")?;
+    // every key shares one hash, so every lookup has to check the key on the page
+    let opts = Options { key_hash: Some(|_| 7), ..Options::default() };
+    {
+        let db = Engine::open_with_options(&dir, opts)?;
+        db.set(b"apple", b"1")?;
+        db.set(b"banana", b"2")?;
+        db.set(b"cherry", b"3")?;
+        assert_eq!(db.get(b"apple")?.as_deref(), Some(&b"1"[..]));
+        assert_eq!(db.get(b"banana")?.as_deref(), Some(&b"2"[..]));
+        assert_eq!(db.get(b"durian")?, None);
+        assert!(!db.contains_key(b"durian")?);
+
+        db.set(b"apple", b"one")?;
+        assert!(db.delete(b"banana")?);
+        assert!(!db.delete(b"banana")?);
+        assert_eq!(db.get(b"apple")?.as_deref(), Some(&b"one"[..]));
+        assert_eq!(db.get(b"banana")?, None);
+        assert_eq!(db.len(), 2);
+        // ordered access reads the keys back and sorts them
+        assert_eq!(db.keys()?, vec![b"apple".to_vec(), b"cherry".to_vec()]);
+        let scanned = db.scan(b"a", b"z").collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(scanned, vec![(b"apple".to_vec(), b"one".to_vec()), (b"cherry".to_vec(), b"3".to_vec())]);
+
+        db.compact()?;
+        assert_eq!(db.get(b"apple")?.as_deref(), Some(&b"one"[..]));
+        assert_eq!(db.get(b"cherry")?.as_deref(), Some(&b"3"[..]));
+        db.checkpoint()?;
+        assert!(!dir.join("index.snapshot").exists(), "a hashed index has no snapshot");
+    }
+    // rebuilt from the pages on open, with whatever hash this open asks for
+    for key_hash in [Some(crate::index::default_key_hash as fn(&[u8]) -> u64), None] {
+        let db = Engine::open_with_options(&dir, Options { key_hash, ..Options::default() })?;
+        assert_eq!(db.get(b"apple")?.as_deref(), Some(&b"one"[..]));
+        assert_eq!(db.get(b"banana")?, None);
+        assert_eq!(db.get(b"cherry")?.as_deref(), Some(&b"3"[..]));
+    }
+    Ok(())
+}
+
+pub fn partial_tail_page_is_tolerated() -> anyhow::Result<()> {
+    let dir = test_dir("partial_tail")?;
+    // too big to share a page: a, b and c land on pages 1, 2 and 3
+    let big = |c: u8| vec![c; PAGE_SIZE * 2 / 3];
+    {
+        let db = Engine::open(&dir)?;
+        for k in [b"a", b"b", b"c"] {
+            db.set(k, &big(k[0]))?;
+        }
+        db.checkpoint()?;
+    }
+    // cut the last page in half, and make open scan the pages
+    let data = dir.join("tinydb_data.db");
+    fs::OpenOptions::new().write(true).open(&data)?.set_len(3 * PAGE_SIZE as u64 + PAGE_SIZE as u64 / 2)?;
+    fs::remove_file(dir.join("index.snapshot"))?;
+
+    let strict = Options { tolerate_partial_tail: false, ..Options::default() };
+    assert!(matches!(Engine::open_with_options(&dir, strict), Err(TinyDbError::ShortRead { .. })));
+    {
+        let db = Engine::open(&dir)?;
+        assert_eq!(db.get(b"a")?, Some(big(b'a')));
+        assert_eq!(db.get(b"b")?, Some(big(b'b')));
+        assert_eq!(db.get(b"c")?, None);
+        db.set(b"d", b"after")?;
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"b")?, Some(big(b'b')));
+    assert_eq!(db.get(b"d")?.as_deref(), Some(&b"after"[..]));
+    Ok(())
+}
+
+pub fn get_as_of_reads_old_versions() -> anyhow::Result<()> {
+    let dir = test_dir("get_as_of")?;
+    let db = Engine::open(&dir)?;
+    db.set(b"other", b"x")?;
+    let mut lsns = Vec::new();
+    for v in [&b"v1"[..], b"v2", b"v3"] {
+        db.set(b"k", v)?;
+        lsns.push(db.get_with_metadata(b"k")?.unwrap().lsn);
+        db.set(b"other", v)?;
+    }
+    assert!(db.delete(b"k")?);
+    let deleted = lsns[2] + 2;
+
+    assert_eq!(db.get_as_of(b"k", lsns[0] - 1)?, None);
+    for (lsn, v) in lsns.iter().zip([&b"v1"[..], b"v2", b"v3"]) {
+        assert_eq!(db.get_as_of(b"k", *lsn)?.as_deref(), Some(v));
+        // the write of "other" after it doesn't change it
+        assert_eq!(db.get_as_of(b"k", *lsn + 1)?.as_deref(), Some(v));
+    }
+    assert_eq!(db.get_as_of(b"k", deleted)?, None);
+    assert_eq!(db.get_as_of(b"other", lsns[1])?.as_deref(), Some(&b"v1"[..]));
+
+    // a checkpoint takes the history with it; keys it doesn't touch after that still answer
+    db.checkpoint()?;
+    db.set(b"k", b"v4")?;
+    db.set(b"new", b"n")?;
+    assert!(matches!(db.get_as_of(b"k", lsns[1]), Err(TinyDbError::Invalid(_))));
+    assert_eq!(db.get_as_of(b"other", lsns[1])?.as_deref(), Some(&b"v3"[..]));
+    assert_eq!(db.get_as_of(b"k", db.get_with_metadata(b"k")?.unwrap().lsn)?.as_deref(), Some(&b"v4"[..]));
+
+    // an in-memory log is read from a copy, so writes going on meanwhile can't be misplaced
+    let db = Engine::open_in_memory()?;
+    db.set(b"k", b"first")?;
+    let first = db.get_with_metadata(b"k")?.unwrap().lsn;
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let reader = {
+        let (db, done) = (db.clone(), done.clone());
+        thread::spawn(move || -> anyhow::Result<()> {
+            while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                assert_eq!(db.get_as_of(b"k", first)?.as_deref(), Some(&b"first"[..]));
+            }
+            Ok(())
+        })
+    };
+    for i in 0..500u32 {
+        db.set(format!("w{}", i).as_bytes(), &i.to_le_bytes())?;
+    }
+    done.store(true, std::sync::atomic::Ordering::Relaxed);
+    reader.join().unwrap()?;
+    // every record still reads back whole, in place
+    let last = db.get_with_metadata(b"w499")?.unwrap().lsn;
+    for i in (0..500u32).step_by(50) {
+        assert_eq!(db.get_as_of(format!("w{}", i).as_bytes(), last)?, Some(i.to_le_bytes().to_vec()));
+    }
+    Ok(())
+}
+
+pub fn second_writer_is_locked_out() -> anyhow::Result<()> {
+    let dir = test_dir("lock")?;
+    let db = Engine::open(&dir)?;
+    db.set(b"k", b"v")?;
+    assert!(matches!(Engine::open(&dir), Err(TinyDbError::Locked(_))));
+    // readers don't need the lock, and another name has its own
+    assert_eq!(Engine::open_read_only(&dir)?.get(b"k")?.as_deref(), Some(&b"v"[..]));
+    drop(Engine::open_named(&dir, "other")?);
+
+    // held until the last clone is dropped
+    let clone = db.clone();
+    drop(db);
+    assert!(matches!(Engine::open(&dir), Err(TinyDbError::Locked(_))));
+    drop(clone);
+    assert_eq!(Engine::open(&dir)?.get(b"k")?.as_deref(), Some(&b"v"[..]));
+    Ok(())
+}
+
+pub fn typed_number_values() -> anyhow::Result<()> {
+    let db = Engine::open_in_memory()?;
+    for v in [0, 1, -1, i64::MIN, i64::MAX, -1234567890123] {
+        db.set_i64(b"i", v)?;
+        assert_eq!(db.get_i64(b"i")?, Some(v));
+    }
+    assert_eq!(db.get(b"i")?, Some((-1234567890123i64).to_le_bytes().to_vec()));
+    assert_eq!(db.increment(b"i", 3)?, -1234567890120);
+    assert_eq!(db.get_i64(b"missing")?, None);
+
+    for v in [0.0, -0.0, 1.5, -2.25e300, f64::MIN_POSITIVE, f64::INFINITY, f64::NEG_INFINITY] {
+        db.set_f64(b"f", v)?;
+        let got = db.get_f64(b"f")?.unwrap();
+        assert_eq!(got.to_bits(), v.to_bits());
+    }
+    let nan = f64::from_bits(0x7ff8_0000_dead_beef);
+    db.set_f64(b"f", nan)?;
+    assert_eq!(db.get_f64(b"f")?.map(f64::to_bits), Some(nan.to_bits()));
+
+    db.set(b"short", b"1234")?;
+    assert!(matches!(db.get_i64(b"short"), Err(TinyDbError::BadValueLength { len: 4, expected: 8 })));
+    assert!(matches!(db.get_f64(b"short"), Err(TinyDbError::BadValueLength { len: 4, expected: 8 })));
+    assert!(matches!(db.increment(b"short", 1), Err(TinyDbError::BadValueLength { len: 4, expected: 8 })));
+    Ok(())
+}
+
+pub fn writes_stall_until_checkpoint() -> anyhow::Result<()> {
+    let opts = Options { max_unckpt_bytes: Some(200), ..Options::default() };
+    let db = Engine::open_with_storage_options(MemFile::default(), MemFile::default(), opts)?;
+    let val = [b'v'; 100];
+    let (tx, rx) = std::sync::mpsc::channel();
+    let writer = {
+        let db = db.clone();
+        thread::spawn(move || -> Result<(), TinyDbError> {
+            for key in [b"a", b"b", b"c"] {
+                db.set(key, &val)?;
+                tx.send(key).unwrap();
+            }
+            Ok(())
+        })
+    };
+    // two sets fit under the limit, the third waits for a checkpoint
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5))?, b"a");
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5))?, b"b");
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    assert_eq!(db.get(b"c")?, None);
+    // a slow checkpointer lets it through
+    let ckpt = {
+        let db = db.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            db.checkpoint()
+        })
+    };
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5))?, b"c");
+    ckpt.join().unwrap()?;
+    writer.join().unwrap()?;
+    assert_eq!(db.get(b"c")?, Some(val.to_vec()));
+
+    let opts = Options { max_unckpt_bytes: Some(200), write_stall: WriteStall::Fail, ..Options::default() };
+    let db = Engine::open_with_storage_options(MemFile::default(), MemFile::default(), opts)?;
+    db.set(b"a", &val)?;
+    db.set(b"b", &val)?;
+    match db.set(b"c", &val) {
+        Err(TinyDbError::WouldStall { unckpt, limit: 200 }) => assert!(unckpt > 200),
+        other => panic!("expected WouldStall, got {:?}", other),
+    }
+    // reads and checkpoints never stall
+    assert_eq!(db.get(b"a")?, Some(val.to_vec()));
+    db.checkpoint()?;
+    db.set(b"c", &val)?;
+    assert_eq!(db.get(b"c")?, Some(val.to_vec()));
+    Ok(())
+}
+
+pub fn cli_scan_filters_and_limits() -> anyhow::Result<()> {
+    let dir = test_dir("cli_scan")?;
+    {
+        let db = Engine::open(&dir)?;
+        for (k, v) in [("user:3", "carol"), ("user:1", "alice"), ("item:1", "lamp"), ("user:2", "bob"), ("users", "x")] {
+            db.set(k.as_bytes(), v.as_bytes())?;
+        }
+        db.set(b"user:4", &[0xff, b'd', 0xfe])?;
+        db.delete(b"user:2")?;
+    }
+    let scan = |args: &[&str]| -> anyhow::Result<Vec<String>> {
+        let out = Command::new(std::env::current_exe()?)
+            .arg("scan")
+            .args(args)
+            .env("TINYDB_DATA_DIR", &dir)
+            .output()?;
+        assert!(out.status.success());
+        Ok(String::from_utf8(out.stdout)?.lines().map(String::from).collect())
+    };
+    assert_eq!(scan(&["user:"])?, [
+        "user:1 = alice",
+        "user:3 = carol",
+        "user:4 = \u{fffd}d\u{fffd} (non-UTF-8, 3 bytes)",
+        "(3 keys)",
+    ]);
+    assert_eq!(scan(&["user", "2"])?, ["user:1 = alice", "user:3 = carol", "(2 keys)"]);
+    assert_eq!(scan(&["", "0"])?, ["(0 keys)"]);
+    assert_eq!(scan(&["nope"])?, ["(0 keys)"]);
+    assert_eq!(scan(&["user", "many"])?, ["Usage : scan <prefix> [limit]"]);
+    Ok(())
+}
+
+pub fn wal_is_written_ahead_of_pages() -> anyhow::Result<()> {
+    // a WAL that can't take the record means the page is never touched
+    let (data, log) = (PowerCut::default(), PowerCut::default());
+    let db = Engine::open_with_storage(data.clone(), log.clone())?;
+    db.set(b"a", b"1")?;
+    let writes = *data.writes.lock().unwrap();
+    log.crash_after(0);
+    assert!(db.set(b"b", b"2").is_err());
+    assert_eq!(*data.writes.lock().unwrap(), writes);
+    drop(db);
+    let db = Engine::open_with_storage(data.after_crash()?, log.after_power_cut()?)?;
+    assert_eq!(db.get(b"b")?, None);
+    assert_eq!(db.get(b"a")?.as_deref(), Some(&b"1"[..]));
+
+    // sets don't fsync the data file, only the WAL: a power cut takes every page write
+    // since open, and replay brings the values back from the log
+    let (data, log) = (PowerCut::default(), PowerCut::default());
+    let db = Engine::open_with_storage(data.clone(), log.clone())?;
+    let durable = data.durable.lock().unwrap().clone();
+    let big = vec![b'x'; PAGE_SIZE / 2];
+    for (k, v) in [(&b"a"[..], &b"1"[..]), (b"b", &big), (b"c", &big), (b"d", b"4")] {
+        db.set(k, v)?;
+    }
+    assert!(db.delete(b"a")?);
+    assert_eq!(*data.durable.lock().unwrap(), durable);
+    drop(db);
+    let db = Engine::open_with_storage(data.after_power_cut()?, log.after_power_cut()?)?;
+    assert_eq!(db.get(b"a")?, None);
+    assert_eq!(db.get(b"b")?, Some(big.clone()));
+    assert_eq!(db.get(b"c")?, Some(big.clone()));
+    assert_eq!(db.get(b"d")?.as_deref(), Some(&b"4"[..]));
+
+    // the checkpoint is what makes the pages durable, before it drops the records: they
+    // read back with no WAL at all
+    let data = PowerCut::default();
+    let db = Engine::open_with_storage(data.clone(), PowerCut::default())?;
+    db.set(b"e", &big)?;
+    db.checkpoint()?;
+    drop(db);
+    let db = Engine::open_with_storage(data.after_power_cut()?, PowerCut::default())?;
+    assert_eq!(db.get(b"e")?, Some(big));
+    Ok(())
+}
+
+pub fn empty_values_are_not_absent() -> anyhow::Result<()> {
+    let dir = test_dir("empty_values")?;
+    let check = |db: &Engine| -> anyhow::Result<()> {
+        for key in [&b"e1"[..], b"e2", b"e3"] {
+            assert_eq!(db.get(key)?, Some(vec![]), "{:?}", key);
+            assert!(db.contains_key(key)?);
+        }
+        assert_eq!(db.get(b"missing")?, None);
+        assert!(!db.contains_key(b"missing")?);
+        // an empty value doesn't end the page scan: the entries after it are still there
+        assert_eq!(db.get(b"after")?.as_deref(), Some(&b"x"[..]));
+        let pairs: Vec<_> = db.scan_prefix(b"e").collect::<Result<_, _>>()?;
+        assert_eq!(pairs, [(b"e1".to_vec(), vec![]), (b"e2".to_vec(), vec![]), (b"e3".to_vec(), vec![])]);
+        assert_eq!(db.len(), 4);
+        Ok(())
+    };
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"e1", b"")?;
+        db.write_batch(&[BatchOp::Set(b"e2", b"")])?;
+        db.set(b"e3", b"was full")?;
+        assert!(db.compare_and_swap(b"e3", Some(b"was full"), b"")?);
+        // swapping from empty is not swapping from absent
+        assert!(!db.compare_and_swap(b"e3", None, b"y")?);
+        db.set(b"after", b"x")?;
+        assert_eq!(db.get_as_of(b"e3", 2)?, Some(b"was full".to_vec()));
+        assert_eq!(db.get_as_of(b"e3", 3)?, Some(vec![]));
+        check(&db)?;
+    }
+    // WAL replay, then the index snapshot, then the page scan alone
+    let db = Engine::open(&dir)?;
+    assert!(db.replayed_records() > 0);
+    check(&db)?;
+    db.checkpoint()?;
+    drop(db);
+    let db = Engine::open(&dir)?;
+    check(&db)?;
+    drop(db);
+    fs::remove_file(dir.join("index.snapshot"))?;
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.replayed_records(), 0);
+    check(&db)?;
+    db.compact()?;
+    db.close()?;
+    let db = Engine::open(&dir)?;
+    check(&db)?;
+    assert!(db.delete(b"e1")?);
+    assert_eq!(db.get(b"e1")?, None);
+    Ok(())
+}
+
+pub fn consistency_check_finds_bad_offsets() -> anyhow::Result<()> {
+    let dir = test_dir("consistency")?;
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"a", b"1")?;
+        db.set(b"b", b"2")?;
+        db.set(b"c", b"3")?;
+        assert!(db.check_consistency()?.is_empty());
+        db.close()?;
+    }
+    // point b at a's entry and c into the middle of its own, fixing up the checksum so
+    // the snapshot still loads: lsn, count, then key_len, key, page_id, offset, expires_at
+    let path = dir.join("index.snapshot");
+    let mut snap = fs::read(&path)?;
+    snap.truncate(snap.len() - 4);
+    let offset_at = |i: usize| 12 + i * 25 + 4 + 1 + 8;
+    let a_off = u32::from_le_bytes(snap[offset_at(0)..offset_at(0) + 4].try_into()?);
+    let c_off = u32::from_le_bytes(snap[offset_at(2)..offset_at(2) + 4].try_into()?);
+    snap[offset_at(1)..offset_at(1) + 4].copy_from_slice(&a_off.to_le_bytes());
+    snap[offset_at(2)..offset_at(2) + 4].copy_from_slice(&(c_off + 3).to_le_bytes());
+    let crc = crc32(&snap);
+    snap.extend_from_slice(&crc.to_le_bytes());
+    fs::write(&path, &snap)?;
+
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"b")?.as_deref(), Some(&b"1"[..]));
+    let problems = db.check_consistency()?;
+    assert_eq!(problems.len(), 2, "{:?}", problems);
+    assert!(problems[0].starts_with("key \"b\"") && problems[0].ends_with("holds key \"a\""), "{}", problems[0]);
+    assert!(problems[1].starts_with("key \"c\"") && problems[1].contains("no entry starts at"), "{}", problems[1]);
+    drop(db);
+
+    let out = Command::new(std::env::current_exe()?).arg("check").env("TINYDB_DATA_DIR", &dir).output()?;
+    assert!(out.status.success());
+    let out = String::from_utf8(out.stdout)?;
+    assert_eq!(out.lines().next_back(), Some("2 problems"));
+
+    // rebuilt from the pages, the index is right again
+    fs::remove_file(&path)?;
+    let db = Engine::open(&dir)?;
+    assert!(db.check_consistency()?.is_empty());
+    assert_eq!(db.get(b"b")?.as_deref(), Some(&b"2"[..]));
+    drop(db);
+    // a hashed index, all keys colliding, checks out too
+    let db = Engine::open_with_options(&dir, Options { key_hash: Some(|_| 7), ..Options::default() })?;
+    assert!(db.check_consistency()?.is_empty());
+    Ok(())
+}
+
+pub fn zipf_keys_are_skewed() -> anyhow::Result<()> {
+    let (n, samples) = (1000, 200_000);
+    for theta in [0.5, 0.99] {
+        let mut zipf = Zipf::new(n, theta, 7);
+        let mut counts = vec![0usize; n];
+        for _ in 0..samples {
+            counts[zipf.next_rank()] += 1;
+        }
+        // the two hottest ranks are exact; past them the method approximates, so the hottest
+        // 1% and 10% of keys get their expected share within a looser tolerance
+        let close = |got: usize, p: f64, tol: f64| (got as f64 / samples as f64 - p).abs() <= p * tol;
+        let share = |k: usize| (0..k).map(|i| zipf.probability(i)).sum::<f64>();
+        for (i, &c) in counts.iter().enumerate().take(2) {
+            assert!(close(c, zipf.probability(i), 0.05), "theta {} rank {}: {}", theta, i, c);
+        }
+        assert!(close(counts[..n / 100].iter().sum(), share(n / 100), 0.1), "theta {}: top 1% share", theta);
+        assert!(close(counts[..n / 10].iter().sum(), share(n / 10), 0.05), "theta {}: top 10% share", theta);
+        // ranks come out hottest first: tail ranks are rare but do come up
+        assert!(counts[0] > counts[9] && counts[9] > counts[99]);
+        assert!(counts[n / 2..].iter().any(|&c| c > 0));
+    }
+    // 0.99 is markedly more skewed than uniform: roughly a third of accesses hit the top 1%
+    let zipf = Zipf::new(n, 0.99, 7);
+    let top: f64 = (0..n / 100).map(|i| zipf.probability(i)).sum();
+    assert!(top > 0.3, "{}", top);
+
+    // seeded: the same seed gives the same ranks
+    let (mut a, mut b) = (Zipf::new(n, 0.8, 1), Zipf::new(n, 0.8, 1));
+    assert!((0..100).all(|_| a.next_rank() == b.next_rank()));
+    Ok(())
+}
+
+pub fn rebuild_data_file_from_wal() -> anyhow::Result<()> {
+    let dir = test_dir("rebuild")?;
+    {
+        let db = Engine::open(&dir)?;
+        for i in 0..50 {
+            db.set(format!("k{:02}", i).as_bytes(), format!("v{}", i).as_bytes())?;
+        }
+        db.write_batch(&[BatchOp::Set(b"k07", b"again"), BatchOp::Delete(b"k08")])?;
+        db.delete(b"k09")?;
+    }
+    fs::remove_file(dir.join("tinydb_data.db"))?;
+    let rebuilt = Engine::rebuild_from_wal(&dir)?;
+    assert_eq!((rebuilt.replayed, rebuilt.skipped, rebuilt.complete), (52, 0, true));
+    let db = Engine::open(&dir)?;
+    // the log went into the new data file and was checkpointed away
+    assert_eq!(db.replayed_records(), 0);
+    assert_eq!(db.len(), 48);
+    assert_eq!(db.get(b"k00")?.as_deref(), Some(&b"v0"[..]));
+    assert_eq!(db.get(b"k07")?.as_deref(), Some(&b"again"[..]));
+    assert_eq!(db.get(b"k08")?, None);
+    assert_eq!(db.get(b"k09")?, None);
+    assert_eq!(db.get(b"k49")?.as_deref(), Some(&b"v49"[..]));
+    assert!(db.check_consistency()?.is_empty());
+    // and it takes writes as usual, with LSNs carrying on
+    db.set(b"after", b"1")?;
+    drop(db);
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"after")?.as_deref(), Some(&b"1"[..]));
+    drop(db);
+
+    // a record with a bad checksum mid-log is skipped, and so are its writes, only
+    let dir = test_dir("rebuild_crc")?;
+    let lens: Vec<usize> = {
+        let db = Engine::open(&dir)?;
+        db.set(b"a", b"1")?;
+        db.set(b"b", b"2")?;
+        db.set(b"c", b"3")?;
+        drop(db);
+        Wal::records(&dir).map(|r| r.map(|(_, p)| p.len())).collect::<Result<_, _>>()?
+    };
+    let seg = dir.join("wal-000001.log");
+    let mut b = fs::read(&seg)?;
+    b[12 + 25 + lens[0] + 25 + lens[1] - 1] ^= 0xff;
+    fs::write(&seg, &b)?;
+    assert!(Engine::open(&dir).is_err());
+    let rebuilt = Engine::rebuild_from_wal(&dir)?;
+    assert_eq!((rebuilt.replayed, rebuilt.skipped, rebuilt.complete), (2, 1, false));
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"a")?.as_deref(), Some(&b"1"[..]));
+    assert_eq!(db.get(b"b")?, None);
+    assert_eq!(db.get(b"c")?.as_deref(), Some(&b"3"[..]));
+    drop(db);
+
+    // what a checkpoint dropped from the log was only in the lost data file
+    let dir = test_dir("rebuild_ckpt")?;
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"old", b"1")?;
+        db.checkpoint()?;
+        db.set(b"new", b"2")?;
+    }
+    fs::remove_file(dir.join("tinydb_data.db"))?;
+    let rebuilt = Engine::rebuild_from_wal(&dir)?;
+    assert_eq!((rebuilt.replayed, rebuilt.skipped, rebuilt.complete), (1, 0, false));
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"old")?, None);
+    assert_eq!(db.get(b"new")?.as_deref(), Some(&b"2"[..]));
+    assert!(matches!(Engine::rebuild_from_wal(&dir), Err(TinyDbError::Locked(_))));
+    Ok(())
+}
+
+pub fn metrics_count_operations() -> anyhow::Result<()> {
+    let db = Engine::open(test_dir("metrics")?)?;
+    db.set(b"warm", b"up")?;
+    let m0 = db.metrics();
+    db.set(b"a", b"1")?;
+    db.set(b"b", b"2")?;
+    assert!(db.get(b"a")?.is_some());
+    assert!(db.get(b"missing")?.is_none());
+    assert!(db.delete(b"a")?);
+    assert!(!db.delete(b"missing")?);
+    // the batch stores c once and deletes b
+    db.write_batch(&[BatchOp::Set(b"c", b"3"), BatchOp::Set(b"c", b"4"), BatchOp::Delete(b"b")])?;
+    db.multi_get(&[b"a", b"c", b"z"])?;
+    let m = db.metrics();
+    assert_eq!((m.sets - m0.sets, m.gets - m0.gets, m.deletes - m0.deletes), (3, 5, 2));
+    // two sets, a delete and the batch: one record and one fsync each
+    assert_eq!(m.wal_appends - m0.wal_appends, 4);
+    assert_eq!(m.wal_fsyncs - m0.wal_fsyncs, 4);
+    assert_eq!(m.wal_bytes - m0.wal_bytes, db.wal_bytes_written() - m0.wal_bytes);
+    // everything lands on the one data page: each write rewrites it
+    assert_eq!(m.page_writes - m0.page_writes, 4);
+    assert_eq!(m.data_bytes - m0.data_bytes, 4 * PAGE_SIZE as u64);
+    assert_eq!(m.data_bytes, db.data_bytes_written());
+    // and stays in the page cache, so nothing is read back from the file
+    assert_eq!((m.page_reads - m0.page_reads, m.cache_misses - m0.cache_misses), (0, 0));
+    assert!(m.cache_hits > m0.cache_hits);
+    Ok(())
+}
+
+pub fn cursor_steps_both_ways() -> anyhow::Result<()> {
+    let db = Engine::open(test_dir("cursor")?)?;
+    for k in [b"b", b"d", b"f"] {
+        db.set(k, &[k[0].to_ascii_uppercase()])?;
+    }
+    let rev: Vec<Vec<u8>> = db.scan_rev(b"e").map(|r| r.map(|(k, _)| k)).collect::<Result<_, _>>()?;
+    assert_eq!(rev, [b"d".to_vec(), b"b".to_vec()]);
+    // a missing key lands on the next one up
+    let mut c = db.seek(b"c")?;
+    assert_eq!(c.key(), Some(&b"d"[..]));
+    // the value is read when asked for, so a later write doesn't show
+    db.set(b"d", b"new")?;
+    assert_eq!(c.value()?, Some(b"D".to_vec()));
+    assert!(c.next());
+    assert_eq!(c.key(), Some(&b"f"[..]));
+    assert!(!c.next());
+    assert_eq!((c.key(), c.value()?), (None, None));
+    assert!(c.prev());
+    assert!(c.prev());
+    assert!(c.prev());
+    assert_eq!(c.key(), Some(&b"b"[..]));
+    assert!(!c.prev());
+    assert!(c.next());
+    assert_eq!(c.key(), Some(&b"b"[..]));
+    // an exact key is landed on, one past the last key is past the end
+    assert_eq!(db.seek(b"f")?.key(), Some(&b"f"[..]));
+    let mut c = db.seek(b"g")?;
+    assert_eq!(c.key(), None);
+    assert!(c.prev());
+    assert_eq!(c.key(), Some(&b"f"[..]));
+    Ok(())
+}
+
+pub fn replay_starts_at_checkpoint_record() -> anyhow::Result<()> {
+    let dir = test_dir("ckpt_record")?;
+    let seg = dir.join("wal-000001.log");
+    let old_segment = {
+        let db = Engine::open(&dir)?;
+        db.set(b"a", b"1")?;
+        db.set(b"b", b"2")?;
+        let old_segment = fs::read(&seg)?;
+        db.checkpoint()?;
+        db.set(b"c", b"3")?;
+        db.set(b"a", b"4")?;
+        old_segment
+    };
+    // the checkpoint started a segment with its record and dropped the one before it
+    assert!(!seg.exists());
+    let recs: Vec<(u64, Vec<u8>)> = Wal::records(&dir).collect::<Result<_, _>>()?;
+    assert_eq!(recs.len(), 3);
+    assert_eq!(recs[0].0, 2);
+    assert!(recs[0].1.starts_with(b"CKP"));
+    assert!(!dir.join("tinydb_ckpt").exists());
+    // a crash before the old segment was deleted: replay still starts at the record
+    fs::write(&seg, &old_segment)?;
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.replayed_records(), 2);
+    assert_eq!(db.get(b"a")?.as_deref(), Some(&b"4"[..]));
+    assert_eq!(db.get(b"b")?.as_deref(), Some(&b"2"[..]));
+    assert_eq!(db.get(b"c")?.as_deref(), Some(&b"3"[..]));
+    Ok(())
+}
+
+pub fn open_default_resolves_data_dir() -> anyhow::Result<()> {
+    let dir = test_dir("open_default")?;
+    let saved: Vec<_> = ["TINYDB_DATA_DIR", "XDG_DATA_HOME"].map(|v| (v, std::env::var_os(v))).into();
+    // SAFETY: run_all runs one test at a time, and nothing else touches the environment
+    let set = |name: &str, val: Option<&std::ffi::OsStr>| unsafe {
+        match val {
+            Some(val) => std::env::set_var(name, val),
+            None => std::env::remove_var(name),
+        }
+    };
+    let res = (|| -> anyhow::Result<()> {
+        let from_env = dir.join("from_env");
+        set("TINYDB_DATA_DIR", Some(from_env.as_os_str()));
+        assert_eq!(Engine::default_dir()?, from_env);
+        Engine::open_default()?.set(b"k", b"v")?;
+        assert_eq!(Engine::open(&from_env)?.get(b"k")?.as_deref(), Some(&b"v"[..]));
+
+        // unset, it falls back to the user data directory
+        set("TINYDB_DATA_DIR", None);
+        if cfg!(all(unix, not(target_os = "macos"))) {
+            let data_home = fs::canonicalize(&dir)?;
+            set("XDG_DATA_HOME", Some(data_home.as_os_str()));
+            assert_eq!(Engine::default_dir()?, data_home.join("tinydb"));
+            Engine::open_default()?;
+            assert!(data_home.join("tinydb").join("tinydb_data.db").exists());
+            // but only to an absolute one
+            set("XDG_DATA_HOME", Some("relative".as_ref()));
+            assert!(!matches!(Engine::default_dir(), Ok(d) if d.is_relative()));
+        }
+        Ok(())
+    })();
+    for (name, val) in &saved {
+        set(name, val.as_deref());
+    }
+    res
+}
+
+pub fn get_prefix_reads_each_page_once() -> anyhow::Result<()> {
+    let db = Engine::open_in_memory()?;
+    for i in 0..30u8 {
+        db.set(format!("user:{:02}", i).as_bytes(), &[i; 1000])?;
+        db.set(format!("item:{:02}", i).as_bytes(), b"other")?;
+    }
+    db.delete(b"user:07")?;
+    db.set_with_ttl(b"user:08", b"gone", Duration::ZERO)?;
+    db.set(b"zzz\xff", b"not UTF-8, but not under the prefix either")?;
+    let m0 = db.metrics();
+    let users = db.get_prefix("user:")?;
+    let m = db.metrics();
+    assert_eq!(users.len(), 28);
+    for i in (0..30u8).filter(|&i| i != 7 && i != 8) {
+        assert_eq!(users[&format!("user:{:02}", i)], [i; 1000], "user:{:02}", i);
+    }
+    // 28 values of 1000 bytes fill four pages, read once each
+    assert!(m.cache_hits + m.cache_misses - m0.cache_hits - m0.cache_misses <= 5);
+    assert!(db.get_prefix("nobody")?.is_empty());
+    assert_eq!(db.get_prefix("item:")?.len(), 30);
+    db.set(b"user:\xff", b"x")?;
+    assert!(matches!(db.get_prefix("user:"), Err(TinyDbError::Invalid(_))));
+    Ok(())
+}
+
+pub fn clear_empties_the_database() -> anyhow::Result<()> {
+    let dir = test_dir("clear")?;
+    let data = dir.join("tinydb_data.db");
+    {
+        let db = Engine::open(&dir)?;
+        for i in 0..100u32 {
+            db.set(format!("k{:03}", i).as_bytes(), &[1u8; 500])?;
+        }
+        db.checkpoint()?;
+        assert!(fs::metadata(&data)?.len() > 5 * PAGE_SIZE as u64);
+        db.clear()?;
+        assert!(db.keys()?.is_empty());
+        assert_eq!(db.get(b"k000")?, None);
+        // and it is usable afterwards
+        db.set(b"fresh", b"1")?;
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.keys()?, vec![b"fresh".to_vec()]);
+    assert_eq!(fs::metadata(&data)?.len(), 2 * PAGE_SIZE as u64);
+    db.clear()?;
+    drop(db);
+    assert!(Engine::open(&dir)?.keys()?.is_empty());
+
+    // a crash once the CLR record is durable, before any page is touched: replay drops
+    // everything logged before it
+    let (data, log) = (PowerCut::default(), PowerCut::default());
+    let db = Engine::open_with_storage(data.clone(), log.clone())?;
+    db.set(b"a", b"1")?;
+    db.set(b"b", b"2")?;
+    data.crash_after(0);
+    assert!(db.clear().is_err());
+    drop(db);
+    let db = Engine::open_with_storage(data.after_crash()?, log.after_power_cut()?)?;
+    assert!(db.keys()?.is_empty());
+    assert_eq!(db.replayed_records(), 3);
+    drop(db);
+
+    let db = Engine::open_in_memory()?;
+    db.set(b"a", b"1")?;
+    let snap = db.snapshot();
+    assert!(matches!(db.clear(), Err(TinyDbError::Invalid(_))));
+    drop(snap);
+    db.clear()?;
+
+    // a record type this build doesn't know fails the open instead of being passed over,
+    // as a build from before CLR would have passed over the clear
+    let log = MemFile::default();
+    Wal::open_with_storage(log.clone(), Checksum::Ieee)?.append(b"XYZ")?;
+    let res = Engine::open_with_storage(MemFile::default(), log);
+    assert!(matches!(&res, Err(TinyDbError::Corrupt(msg)) if msg.contains("unknown WAL record type \"XYZ\"")), "{:?}", res.err());
+    Ok(())
+}
+
+pub fn malformed_bytes_are_errors() -> anyhow::Result<()> {
+    // a page cut short anywhere fails to decode
+    let bytes = Page::new(1).encode(Checksum::Ieee);
+    for len in [0, 3, HDR_SZ, 511, 512, PAGE_SIZE - 1] {
+        assert!(Page::from_bytes(&bytes[..len]).is_err(), "{} bytes", len);
+    }
+
+    // a page that checks out but holds an entry longer than itself stops the page scan
+    let data = MemFile::default();
+    {
+        let mut p = Pager::open_with_storage(data.clone())?;
+        let mut page = Page::new(p.allocate_page()?);
+        page.data[..4].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        page.data[4] = 1;
+        page.used = 32;
+        page.lsn = 1;
+        p.write_page(&page)?;
+        p.sync()?;
+    }
+    let res = Engine::open_with_storage(data, MemFile::default());
+    assert!(matches!(res, Err(TinyDbError::Truncated { what: "page entry", .. })), "{:?}", res.err());
+
+    // and so do WAL records too short for what they claim to hold
+    for (payload, what) in [
+        (&b"SET\x01\x02"[..], "SET record"),
+        (b"DEL\0\0\0\0\0\0\0\0\0\0\0\0\xff\0\0\0k", "DEL record"),
+        (b"BAT\xff\xff\xff\x7fCMT", "batch"),
+        (b"CMPCMT", "CMP record"),
+    ] {
+        let log = MemFile::default();
+        Wal::open_with_storage(log.clone(), Checksum::Ieee)?.append(payload)?;
+        let res = Engine::open_with_storage(MemFile::default(), log);
+        assert!(matches!(res, Err(TinyDbError::Truncated { what: w, .. }) if w == what), "{:?}", res.err());
+    }
+
+    // a CMP record putting a key past the pages it keeps is refused before a page is touched
+    let dir = test_dir("malformed_cmp")?;
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"k", b"v")?;
+        db.checkpoint()?;
+    }
+    let data_path = dir.join("tinydb_data.db");
+    let before = fs::read(&data_path)?;
+    let mut set = b"SET".to_vec();
+    set.extend_from_slice(&5u64.to_le_bytes());
+    set.extend_from_slice(&0u32.to_le_bytes());
+    set.extend_from_slice(&1u32.to_le_bytes());
+    set.extend_from_slice(&1u32.to_le_bytes());
+    set.extend_from_slice(&0u64.to_le_bytes());
+    set.extend_from_slice(b"kx");
+    let mut cmp = b"CMP".to_vec();
+    cmp.extend_from_slice(&2u64.to_le_bytes());
+    cmp.extend_from_slice(&1u32.to_le_bytes());
+    cmp.extend_from_slice(&(set.len() as u32).to_le_bytes());
+    cmp.extend_from_slice(&set);
+    cmp.extend_from_slice(b"CMT");
+    {
+        let wal = Wal::open(&dir)?;
+        wal.append(&cmp)?;
+        wal.sync()?;
+    }
+    let res = Engine::open(&dir);
+    assert!(matches!(&res, Err(TinyDbError::Corrupt(msg)) if msg.contains("page 5 of 2")), "{:?}", res.err());
+    assert_eq!(fs::read(&data_path)?, before);
+    Ok(())
+}
+
+pub fn reads_run_during_compaction() -> anyhow::Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let dir = test_dir("compact_readers")?;
+    let data_path = dir.join("tinydb_data.db");
+    let db = Engine::open(&dir)?;
+    let val = |i: usize| format!("value-{:03}-{}", i, "r".repeat(40)).into_bytes();
+    let keys: Vec<Vec<u8>> = (0..300).map(|i| format!("key{:03}", i).into_bytes()).collect();
+    for (i, key) in keys.iter().enumerate() {
+        db.set(key, &val(i))?;
+    }
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let (db, keys, done) = (db.clone(), keys.clone(), done.clone());
+        thread::spawn(move || -> anyhow::Result<usize> {
+            let mut reads = 0;
+            while !done.load(Ordering::Relaxed) {
+                for (i, key) in keys.iter().enumerate().step_by(7) {
+                    // every key is set before the reader starts, and never changes value
+                    assert_eq!(db.get(key)?, Some(val(i)), "{:?}", String::from_utf8_lossy(key));
+                    reads += 1;
+                }
+                let some: Vec<&[u8]> = keys.iter().take(20).map(|k| &k[..]).collect();
+                let got = db.multi_get(&some)?;
+                assert!(got.iter().enumerate().all(|(i, v)| v.as_deref() == Some(&val(i)[..])));
+                // a scan pins the pages, so compaction waits for it or it for compaction
+                for (i, item) in db.scan_prefix(b"key").enumerate() {
+                    let (key, v) = item?;
+                    assert_eq!((key, v), (keys[i].clone(), val(i)));
+                }
+            }
+            Ok(reads)
+        })
+    };
+    for _ in 0..5 {
+        // rewrite every key with the value it already has, leaving the old copies dead
+        for (i, key) in keys.iter().enumerate() {
+            db.set(key, &val(i))?;
+        }
+        let before = fs::metadata(&data_path)?.len();
+        // refused while the reader is midway through a scan
+        let mut refused = 0;
+        while let Err(e) = db.compact() {
+            assert!(e.to_string().contains("scan is open"), "{}", e);
+            refused += 1;
+            assert!(refused < 10_000, "compaction never found a gap between scans");
+            thread::yield_now();
+        }
+        assert!(fs::metadata(&data_path)?.len() < before);
+    }
+    done.store(true, Ordering::Relaxed);
+    assert!(reader.join().unwrap()? > 0);
+    assert!(db.check_consistency()?.is_empty());
+    drop(db);
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(&keys[299])?, Some(val(299)));
+    Ok(())
+}
+
+pub fn wal_retention_keeps_newest_segments() -> anyhow::Result<()> {
+    let dir = test_dir("wal_retention")?;
+    let segments = |dir: &std::path::Path| -> anyhow::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for ent in fs::read_dir(dir)? {
+            let name = ent?.file_name().to_string_lossy().into_owned();
+            if name.starts_with("wal-") {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    };
+    let opts = Options { wal_retention: 3, ..Options::default() };
+    let mut lsns = Vec::new();
+    {
+        let db = Engine::open_with_options(&dir, opts)?;
+        // each checkpoint starts a segment with its CKP record
+        for round in 0..6 {
+            db.set(b"k", format!("v{}", round).as_bytes())?;
+            lsns.push(db.get_with_metadata(b"k")?.unwrap().lsn);
+            db.checkpoint()?;
+        }
+        let names = segments(&dir)?;
+        assert_eq!(names.len(), 3, "{:?}", names);
+        assert!(!names.contains(&"wal-000001.log".to_string()));
+        // the two writes in the retained segments still answer; the older ones went
+        assert_eq!(db.get_as_of(b"k", lsns[4])?.as_deref(), Some(&b"v4"[..]));
+        assert_eq!(db.get_as_of(b"k", lsns[5])?.as_deref(), Some(&b"v5"[..]));
+        assert!(matches!(db.get_as_of(b"k", lsns[3]), Err(TinyDbError::Invalid(_))));
+    }
+    // the retained records read back, and open still replays from the last CKP record only
+    let records: Vec<(u64, Vec<u8>)> = Wal::records(&dir).collect::<Result<_, _>>()?;
+    assert_eq!(records.iter().filter(|(_, p)| p.starts_with(b"SET")).count(), 2);
+    let db = Engine::open_with_options(&dir, opts)?;
+    assert_eq!(db.replayed_records(), 0);
+    assert_eq!(db.get(b"k")?.as_deref(), Some(&b"v5"[..]));
+    assert_eq!(db.get_as_of(b"k", lsns[4])?.as_deref(), Some(&b"v4"[..]));
+    drop(db);
+
+    // without retention a checkpoint leaves only its own segment
+    let db = Engine::open(&dir)?;
+    db.checkpoint()?;
+    assert_eq!(segments(&dir)?.len(), 1);
+    Ok(())
+}
+
+pub fn update_in_place_reuses_the_slot() -> anyhow::Result<()> {
+    let dir = test_dir("update_in_place")?;
+    let data_path = dir.join("tinydb_data.db");
+    let opts = Options { update_in_place: true, ..Options::default() };
+    let pages_before;
+    {
+        let db = Engine::open_with_options(&dir, opts)?;
+        db.set(b"hot", &[1; 100])?;
+        db.set(b"other", b"kept")?;
+        db.checkpoint()?;
+        pages_before = fs::read(&data_path)?;
+        let before = db.stats()?;
+        // the same length: nothing new is dead
+        db.set(b"hot", &[2; 100])?;
+        let same = db.stats()?;
+        assert_eq!((same.used_bytes, same.dead_bytes), (before.used_bytes, before.dead_bytes));
+        // shorter: the slot is reused, only the bytes it no longer needs are dead
+        db.set(b"hot", &[3; 60])?;
+        let smaller = db.stats()?;
+        assert_eq!(smaller.used_bytes, before.used_bytes);
+        assert_eq!(smaller.dead_bytes, before.dead_bytes + 40);
+        assert_eq!(db.get(b"hot")?, Some(vec![3; 60]));
+        // back up to the slot's first size still fits
+        db.set(b"hot", &[4; 100])?;
+        assert_eq!(db.stats()?.used_bytes, before.used_bytes);
+        db.set(b"hot", &[5; 70])?;
+        assert!(db.check_consistency()?.is_empty());
+    }
+    // the page writes are lost: replaying the updates rebuilds the slot, gap entry and all
+    fs::write(&data_path, &pages_before)?;
+    let db = Engine::open_with_options(&dir, opts)?;
+    assert_eq!(db.get(b"hot")?, Some(vec![5; 70]));
+    assert_eq!(db.get(b"other")?.as_deref(), Some(&b"kept"[..]));
+    assert!(db.check_consistency()?.is_empty());
+    let used = db.stats()?.used_bytes;
+
+    // longer than the slot: appended as usual
+    db.set(b"hot", &[6; 150])?;
+    assert!(db.stats()?.used_bytes > used);
+    // with a snapshot open the old value stays where the snapshot reads it
+    let used = db.stats()?.used_bytes;
+    let snap = db.snapshot();
+    db.set(b"hot", &[7; 150])?;
+    assert!(db.stats()?.used_bytes > used);
+    assert_eq!(snap.get(b"hot")?, Some(vec![6; 150]));
+    drop(snap);
+    drop(db);
+    // the index rebuilt from the pages alone agrees
+    fs::remove_file(dir.join("index.snapshot"))?;
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"hot")?, Some(vec![7; 150]));
+    assert!(db.check_consistency()?.is_empty());
+    drop(db);
+
+    // an UPD record only ever wraps a SET: one wrapping a clear, or UPDs nested however
+    // deep, is refused rather than applied
+    let mut nested = [&b"UPD"[..], &100u32.to_le_bytes()].concat().repeat(100_000);
+    nested.extend_from_slice(b"CLR");
+    for payload in [[&b"UPD"[..], &100u32.to_le_bytes(), b"CLR"].concat(), nested] {
+        let log = MemFile::default();
+        Wal::open_with_storage(log.clone(), Checksum::Ieee)?.append(&payload)?;
+        let res = Engine::open_with_storage(MemFile::default(), log);
+        assert!(matches!(&res, Err(TinyDbError::Corrupt(msg)) if msg.starts_with("UPD record wraps a")), "{:?}", res.err());
+    }
+    Ok(())
+}
+
+pub fn empty_page_between_used_ones() -> anyhow::Result<()> {
+    let dir = test_dir("empty_middle_page")?;
+    let data_path = dir.join("tinydb_data.db");
+    let val = |c: u8| vec![c; 1000];
+    let page_of = |db: &Engine, key: &[u8]| -> anyhow::Result<u64> { Ok(db.get_with_metadata(key)?.unwrap().page_id) };
+    {
+        let db = Engine::open(&dir)?;
+        for i in 0..4u8 {
+            db.set(&[b'a', i], &val(i))?;
+        }
+        // overwrite one key until it reaches page 3, leaving page 2 nothing but dead versions
+        db.set(b"b", &val(b'b'))?;
+        while page_of(&db, b"b")? < 3 {
+            db.set(b"b", &val(b'b'))?;
+        }
+        db.set(b"c", &val(b'c'))?;
+        assert_eq!(page_of(&db, b"a\x03")?, 1);
+        assert_eq!(page_of(&db, b"c")?, 3);
+        // frees page 2
+        db.checkpoint()?;
+    }
+    // an all-zero page between two used ones, and no index snapshot: open scans the pages
+    let mut bytes = fs::read(&data_path)?;
+    bytes[2 * PAGE_SIZE..3 * PAGE_SIZE].fill(0);
+    fs::write(&data_path, &bytes)?;
+    fs::remove_file(dir.join("index.snapshot"))?;
+    let db = Engine::open(&dir)?;
+    // appends carry on where the superblock says, on page 3, not in the empty page
+    db.set(b"d", b"new")?;
+    assert_eq!(page_of(&db, b"d")?, 3);
+    // and once page 3 is full, the freed page is reused without touching the others
+    for i in 0..8u8 {
+        db.set(&[b'e', i], &val(i))?;
+    }
+    assert_eq!(page_of(&db, &[b'e', 7])?, 2);
+    for i in 0..4u8 {
+        assert_eq!(db.get(&[b'a', i])?, Some(val(i)));
+    }
+    assert_eq!(db.get(b"b")?, Some(val(b'b')));
+    assert_eq!(db.get(b"c")?, Some(val(b'c')));
+    assert_eq!(db.get(b"d")?.as_deref(), Some(&b"new"[..]));
+    assert!(db.check_consistency()?.is_empty());
+    drop(db);
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get(b"c")?, Some(val(b'c')));
+    assert_eq!(db.get(&[b'e', 7])?, Some(val(7)));
+    Ok(())
+}
+
+pub fn bench_warmup_is_not_counted() -> anyhow::Result<()> {
+    let dir = test_dir("bench_warmup")?;
+    let stats = bench::run_bench(&dir, 40, "b", 16, 2, 25, true)?.expect("40 ops were measured");
+    assert_eq!(stats.samples, 40);
+    // fewer than 1000 samples: nothing to trim
+    assert_eq!(stats.trimmed_mean, stats.mean);
+    assert!(stats.p50 <= stats.p99);
+    // the warmup sets ran all the same
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.len(), 65);
+    assert_eq!(db.scan_prefix(b"bwarmup").count(), 25);
+    drop(db);
+    assert_eq!(bench::run_bench(test_dir("bench_no_ops")?, 0, "b", 16, 1, 5, false)?, None);
+
+    // with 1000 samples the slowest one goes from the trimmed mean, but not from the
+    // percentiles: one planted outlier moves the mean and nothing else
+    let base: Vec<f64> = (0..1000).map(|i| 1.0 + (i % 50) as f64 / 100.0).collect();
+    let mut planted = base.clone();
+    planted[500] = 10_000.0;
+    let clean = bench::latency_stats(&mut base.clone()).expect("1000 samples");
+    let st = bench::latency_stats(&mut planted).expect("1000 samples");
+    assert_eq!(st.samples, 1000);
+    assert!(st.mean > 10.0, "{:?}", st);
+    let without = (base.iter().sum::<f64>() - base[500]) / 999.0;
+    assert!((st.trimmed_mean - without).abs() < 1e-9, "{:?}", st);
+    assert_eq!((st.p50, st.p95, st.p99), (clean.p50, clean.p95, clean.p99));
+    Ok(())
+}
+
+pub fn repair_rewrites_pages_from_the_wal() -> anyhow::Result<()> {
+    let dir = test_dir("repair")?;
+    let corrupt_page_1 = |dir: &Path| -> anyhow::Result<()> {
+        let path = dir.join("tinydb_data.db");
+        let mut b = fs::read(&path)?;
+        b[PAGE_SIZE + HDR_SZ + 100] ^= 0xff;
+        fs::write(&path, &b)?;
+        Ok(())
+    };
+    {
+        let db = Engine::open(&dir)?;
+        for i in 0..200u32 {
+            db.set(format!("key{:03}", i).as_bytes(), &[i as u8; 64])?;
+        }
+        db.delete(b"key007")?;
+        db.set(b"key008", b"rewritten")?;
+    }
+    assert_eq!(Engine::repair(&dir)?, Repaired::default(), "nothing to do on a healthy file");
+    corrupt_page_1(&dir)?;
+    assert!(Engine::open(&dir).and_then(|db| db.get(b"key000")).is_err());
+    assert_eq!(Engine::repair(&dir)?, Repaired { repaired: vec![1], lost: vec![] });
+    {
+        // keeping the checkpointed segments keeps the history of every page
+        let db = Engine::open_with_options(&dir, Options { wal_retention: 100, ..Options::default() })?;
+        assert_eq!(db.get(b"key000")?, Some(vec![0; 64]));
+        assert_eq!(db.get(b"key007")?, None);
+        assert_eq!(db.get(b"key008")?, Some(b"rewritten".to_vec()));
+        assert_eq!(db.get(b"key199")?, Some(vec![199; 64]));
+        assert!(db.check_consistency()?.is_empty());
+        // and compaction lays every page out anew, as its CMP record says
+        db.compact()?;
+    }
+    corrupt_page_1(&dir)?;
+    assert_eq!(Engine::repair(&dir)?, Repaired { repaired: vec![1], lost: vec![] });
+    assert_eq!(Engine::open(&dir)?.get(b"key001")?, Some(vec![1; 64]));
+
+    // once a checkpoint has dropped the writes that filled page 1, it can't be rebuilt
+    Engine::open(&dir)?.checkpoint()?;
+    corrupt_page_1(&dir)?;
+    assert_eq!(Engine::repair(&dir)?, Repaired { repaired: vec![], lost: vec![1] });
+    Ok(())
+}
+
+pub fn commit_hook_sees_durable_writes() -> anyhow::Result<()> {
+    let dir = test_dir("commit_hook")?;
+    let db = Engine::open(&dir)?;
+    db.set(b"before", b"not reported")?;
+    let seen: Arc<Mutex<Vec<(u64, CommitEvent)>>> = Arc::default();
+    {
+        let (seen, db2) = (seen.clone(), db.clone());
+        db.set_commit_hook(move |lsn, ev| {
+            seen.lock().unwrap().push((lsn, ev.clone()));
+            // writing from the hook is queued for it rather than deadlocking
+            if let CommitEvent::Set { key, .. } = ev
+                && key.as_slice() == b"a"
+            {
+                db2.set(b"echo", b"from the hook").unwrap();
+            }
+        });
+    }
+    db.set(b"a", b"1")?;
+    db.set_with_ttl(b"b", b"2", Duration::from_secs(60))?;
+    db.delete(b"b")?;
+    db.write_batch(&[BatchOp::Set(b"c", b"3"), BatchOp::Delete(b"a"), BatchOp::Set(b"c", b"4")])?;
+    let set = |key: &[u8], value: &[u8]| CommitEvent::Set { key: key.to_vec(), value: value.to_vec(), expires_at: 0 };
+    let seen_now: Vec<(u64, CommitEvent)> = seen.lock().unwrap().clone();
+    let events: Vec<&CommitEvent> = seen_now.iter().map(|(_, ev)| ev).collect();
+    assert_eq!(events.len(), 6, "{:?}", events);
+    assert_eq!(*events[0], set(b"a", b"1"));
+    assert_eq!(*events[1], set(b"echo", b"from the hook"));
+    assert!(matches!(events[2], CommitEvent::Set { key, value, expires_at } if key == b"b" && value == b"2" && *expires_at > 0));
+    assert_eq!(*events[3], CommitEvent::Delete { key: b"b".to_vec() });
+    // a batch keeps each key's last op, and they all share its record's LSN
+    assert_eq!(events[4..], [&CommitEvent::Delete { key: b"a".to_vec() }, &set(b"c", b"4")]);
+    assert_eq!(seen_now[4].0, seen_now[5].0);
+    // each LSN is the record that holds the write
+    let wal: BTreeMap<u64, Vec<u8>> = Wal::records(&dir).collect::<Result<_, _>>()?;
+    for (lsn, ev) in &seen_now {
+        let (CommitEvent::Set { key, .. } | CommitEvent::Delete { key }) = ev else { continue };
+        assert!(wal[lsn].windows(key.len()).any(|w| w == key.as_slice()), "record {} lacks {:?}", lsn, key);
+    }
+    let lsns: Vec<u64> = seen_now.iter().map(|&(lsn, _)| lsn).collect();
+    assert!(lsns.windows(2).all(|w| w[0] <= w[1]));
+    // a clear is one event, after every write it wiped
+    db.clear()?;
+    let last = seen.lock().unwrap().last().cloned();
+    assert!(matches!(last, Some((lsn, CommitEvent::Clear)) if lsn > lsns[5]), "{:?}", last);
+    assert_eq!(seen.lock().unwrap().len(), 7);
+    drop(db);
+
+    // without an fsync per write, events wait for the one that makes them durable
+    let dir = test_dir("commit_hook_never")?;
+    let db = Engine::open_with_options(&dir, Options { sync: SyncPolicy::Never, ..Options::default() })?;
+    let seen: Arc<Mutex<Vec<CommitEvent>>> = Arc::default();
+    {
+        let seen = seen.clone();
+        db.set_commit_hook(move |_, ev| seen.lock().unwrap().push(ev.clone()));
+    }
+    db.set(b"k", b"v")?;
+    assert!(seen.lock().unwrap().is_empty());
+    db.flush()?;
+    assert_eq!(*seen.lock().unwrap(), [set(b"k", b"v")]);
+    Ok(())
+}
+
+pub fn reopen_replays_unless_closed() -> anyhow::Result<()> {
+    let dir = test_dir("reopen")?;
+    let db = Engine::open_with_options(&dir, Options { max_value_size: 100, ..Options::default() })?;
+    for i in 0..10u32 {
+        db.set(format!("k{}", i).as_bytes(), &i.to_le_bytes())?;
+    }
+    db.delete(b"k3")?;
+
+    // dropped as a crash leaves it: the WAL brings the writes back
+    let db = db.reopen(false)?;
+    assert_eq!(db.replayed_records(), 11);
+    assert_eq!(db.get(b"k9")?, Some(9u32.to_le_bytes().to_vec()));
+    assert_eq!(db.get(b"k3")?, None);
+    // the options came along
+    assert!(db.set(b"big", &[0; 101]).is_err());
+
+    db.set(b"k3", b"back")?;
+    let db = db.reopen(true)?;
+    assert_eq!(db.replayed_records(), 0, "a clean close leaves nothing to replay");
+    assert_eq!(db.get(b"k3")?.as_deref(), Some(&b"back"[..]));
+    assert_eq!(db.get(b"k0")?, Some(0u32.to_le_bytes().to_vec()));
+
+    // a clone still holds the lock
+    let other = db.clone();
+    assert!(matches!(db.reopen(false), Err(TinyDbError::Locked(_))));
+    drop(other);
+    assert_eq!(Engine::open(&dir)?.get(b"k1")?, Some(1u32.to_le_bytes().to_vec()));
+    assert!(Engine::open_in_memory()?.reopen(false).is_err());
+    Ok(())
+}
+
+pub fn write_batch_spills_across_pages() -> anyhow::Result<()> {
+    let cap = PAGE_SIZE - HDR_SZ;
+    let page_of = |db: &Engine, key: &[u8]| -> anyhow::Result<(u64, u32)> {
+        let meta = db.get_with_metadata(key)?.ok_or_else(|| anyhow::anyhow!("{:?} is missing", key))?;
+        Ok((meta.page_id, meta.offset))
+    };
+
+    // one giant value: refused whole, before anything is logged, unless it just fits a page
+    let dir = test_dir("batch_giant")?;
+    let db = Engine::open(&dir)?;
+    let max_val = cap - 16 - 5;
+    let too_big = vec![1u8; max_val + 1];
+    let err = db.write_batch(&[BatchOp::Set(b"small", b"v"), BatchOp::Set(b"giant", &too_big)]).unwrap_err();
+    assert!(matches!(err, TinyDbError::EntryTooLarge { len, max } if len == cap + 1 && max == cap), "{}", err);
+    assert_eq!(db.len(), 0);
+    db.write_batch(&[BatchOp::Set(b"small", b"v"), BatchOp::Set(b"giant", &vec![2u8; max_val])])?;
+    assert_eq!(page_of(&db, b"small")?, (1, 0));
+    assert_eq!(page_of(&db, b"giant")?, (2, 0));
+    let db = db.reopen(false)?;
+    assert_eq!(db.get(b"giant")?, Some(vec![2u8; max_val]));
+    drop(db);
+
+    // a batch that fills page 1 to the byte stays on it; the next write starts page 2
+    let dir = test_dir("batch_boundary")?;
+    let db = Engine::open(&dir)?;
+    let last_val = cap - 3 * 2000 - 16 - 2;
+    let vals = [vec![1u8; 2000 - 16 - 2], vec![2u8; 2000 - 16 - 2], vec![3u8; 2000 - 16 - 2], vec![4u8; last_val]];
+    let ops: Vec<BatchOp> = [b"b0", b"b1", b"b2", b"b3"].iter().zip(&vals).map(|(k, v)| BatchOp::Set(&k[..], v)).collect();
+    db.write_batch(&ops)?;
+    assert_eq!(page_of(&db, b"b3")?, (1, 6000));
+    assert_eq!(db.stats()?.used_bytes, cap as u64);
+    db.set(b"after", b"x")?;
+    assert_eq!(page_of(&db, b"after")?, (2, 0));
+    let db = db.reopen(false)?;
+    for (i, v) in vals.iter().enumerate() {
+        assert_eq!(db.get(format!("b{}", i).as_bytes())?.as_ref(), Some(v));
+    }
+    drop(db);
+
+    // sets and deletes mixed over several pages, recovered from the WAL and then the pages
+    let dir = test_dir("batch_mixed_pages")?;
+    let db = Engine::open(&dir)?;
+    for i in 0..10 {
+        db.set(format!("old{}", i).as_bytes(), b"doomed")?;
+    }
+    let keys: Vec<String> = (0..20).map(|i| format!("new{:02}", i)).collect();
+    let val = vec![7u8; 1000];
+    let doomed: Vec<String> = (0..10).map(|i| format!("old{}", i)).collect();
+    let mut ops = Vec::new();
+    for (i, key) in keys.iter().enumerate() {
+        ops.push(BatchOp::Set(key.as_bytes(), &val));
+        if let Some(d) = doomed.get(i) {
+            ops.push(BatchOp::Delete(d.as_bytes()));
+        }
+    }
+    db.write_batch(&ops)?;
+    assert!(page_of(&db, b"new19")?.0 > page_of(&db, b"new00")?.0, "the batch should span pages");
+    let check = |db: &Engine| -> anyhow::Result<()> {
+        assert_eq!(db.len(), keys.len());
+        for key in &keys {
+            assert_eq!(db.get(key.as_bytes())?.as_ref(), Some(&val));
+        }
+        for d in &doomed {
+            assert_eq!(db.get(d.as_bytes())?, None);
+        }
+        Ok(())
+    };
+    let db = db.reopen(false)?;
+    check(&db)?;
+    db.checkpoint()?;
+    let db = db.reopen(false)?;
+    assert_eq!(db.replayed_records(), 0);
+    check(&db)?;
+    Ok(())
+}
+
+pub fn reads_during_clear_see_old_or_nothing() -> anyhow::Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let db = Engine::open(test_dir("clear_readers")?)?;
+    let keys: Vec<Vec<u8>> = (0..50).map(|i| format!("key{:02}", i).into_bytes()).collect();
+    let val = vec![b'v'; 100];
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let (db, keys, val, done) = (db.clone(), keys.clone(), val.clone(), done.clone());
+        thread::spawn(move || -> anyhow::Result<()> {
+            while !done.load(Ordering::Relaxed) {
+                for key in &keys {
+                    let got = db.get(key)?;
+                    assert!(got.is_none() || got.as_ref() == Some(&val), "{:?}", got);
+                }
+            }
+            Ok(())
+        })
+    };
+    for _ in 0..20 {
+        for key in &keys {
+            db.set(key, &val)?;
+        }
+        db.clear()?;
+    }
+    done.store(true, Ordering::Relaxed);
+    reader.join().unwrap()?;
+    assert!(db.is_empty());
+    Ok(())
+}
+
+pub fn values_stream_through_readers() -> anyhow::Result<()> {
+    use std::io::Read;
+    // `n` xorshift bytes, made as they are read, so the value is never in memory whole
+    struct Noise(XorShift64, usize);
+    impl Read for Noise {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.1);
+            self.0.fill(&mut buf[..n]);
+            self.1 -= n;
+            Ok(n)
+        }
+    }
+    struct Untouchable;
+    impl Read for Untouchable {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            panic!("an oversized value must be refused before it is read");
+        }
+    }
+    // FNV-1a rolled over everything `r` yields, 64KB at a time, and how many bytes that was
+    fn rolling_hash(mut r: impl Read) -> anyhow::Result<(u64, usize)> {
+        let (mut h, mut total) = (0xcbf29ce484222325u64, 0);
+        let mut buf = vec![0; 64 << 10];
+        loop {
+            let n = r.read(&mut buf)?;
+            if n == 0 {
+                return Ok((h, total));
+            }
+            for &b in &buf[..n] {
+                h = (h ^ b as u64).wrapping_mul(0x100000001b3);
+            }
+            total += n;
+        }
+    }
+    const LEN: usize = 10 << 20;
+    let want = rolling_hash(Noise(XorShift64::new(7), LEN))?;
+    let dir = test_dir("value_readers")?;
+    let opts = Options { max_value_size: 16 << 20, ..Options::default() };
+    let db = Engine::open_with_options(&dir, opts)?;
+    db.set(b"before", b"small")?;
+    db.set_reader(b"blob", std::io::BufReader::new(Noise(XorShift64::new(7), LEN)), LEN)?;
+    db.set(b"after", b"small")?;
+    let check = |db: &Engine| -> anyhow::Result<()> {
+        let r = db.get_reader(b"blob")?.expect("blob is set");
+        assert_eq!(r.len(), LEN as u64);
+        assert!(!r.is_empty());
+        assert_eq!(rolling_hash(r)?, want);
+        assert_eq!(db.get(b"before")?.as_deref(), Some(&b"small"[..]));
+        assert_eq!(db.get(b"after")?.as_deref(), Some(&b"small"[..]));
+        assert!(db.check_consistency()?.is_empty());
+        Ok(())
+    };
+    check(&db)?;
+    assert_eq!(rolling_hash(&db.get(b"blob")?.unwrap()[..])?, want);
+    assert!(db.stats()?.live_bytes > LEN as u64);
+    // the OVF records put the chain back, then from the pages, with and without the index snapshot
+    let db = db.reopen(false)?;
+    check(&db)?;
+    db.checkpoint()?;
+    let db = db.reopen(false)?;
+    assert_eq!(db.replayed_records(), 0);
+    check(&db)?;
+    fs::remove_file(dir.join("index.snapshot"))?;
+    let db = db.reopen(false)?;
+    assert!(db.pages_scanned() > 0);
+    check(&db)?;
+
+    // a value that fits a page is stored with its key, and reads back either way
+    let db = db.reopen(true)?;
+    db.set_reader(b"tiny", &b"abc"[..], 3)?;
+    assert_eq!(db.get(b"tiny")?.as_deref(), Some(&b"abc"[..]));
+    let mut tiny = String::new();
+    db.get_reader(b"tiny")?.unwrap().read_to_string(&mut tiny)?;
+    assert_eq!(tiny, "abc");
+    assert!(db.get_reader(b"missing")?.is_none());
+    // only `len` bytes are taken from the reader
+    let src = vec![5u8; 200_000];
+    let mut rest = &src[..];
+    db.set_reader(b"half", &mut rest, 100_000)?;
+    assert_eq!(rest.len(), 100_000);
+    assert_eq!(db.get(b"half")?, Some(vec![5u8; 100_000]));
+    let err = db.set_reader(b"huge", Untouchable, 17 << 20).unwrap_err();
+    assert!(matches!(err, TinyDbError::Invalid(_)), "{}", err);
+
+    // an overwritten chain goes back at the next checkpoint, and the next chain reuses it
+    db.set_reader(b"mid", Noise(XorShift64::new(8), 1 << 20), 1 << 20)?;
+    let pages = db.stats()?.pages;
+    db.set(b"mid", b"gone")?;
+    db.checkpoint()?;
+    db.set_reader(b"mid2", Noise(XorShift64::new(9), 1 << 20), 1 << 20)?;
+    assert_eq!(db.stats()?.pages, pages);
+    // so do the pages of a reader that ran dry, which sets nothing
+    let err = db.set_reader(b"dry", Noise(XorShift64::new(10), 100_000), 200_000).unwrap_err();
+    assert!(matches!(&err, TinyDbError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof), "{}", err);
+    assert_eq!(db.get(b"dry")?, None);
+    let pages = db.stats()?.pages;
+    db.checkpoint()?;
+    db.set_reader(b"wet", Noise(XorShift64::new(10), 100_000), 100_000)?;
+    assert_eq!(db.stats()?.pages, pages);
+
+    // an open reader keeps its value, overwritten or not, and holds off compaction
+    let mut r = db.get_reader(b"mid2")?.unwrap();
+    let mut head = [0u8; 100];
+    r.read_exact(&mut head)?;
+    db.set(b"mid2", b"replaced")?;
+    db.checkpoint()?;
+    db.set_reader(b"mid3", Noise(XorShift64::new(11), 1 << 20), 1 << 20)?;
+    assert!(db.compact().is_err());
+    assert_eq!(rolling_hash((&head[..]).chain(r))?, rolling_hash(Noise(XorShift64::new(9), 1 << 20))?);
+
+    // only the current version of an overflow value is left to read as of an LSN
+    let lsn = db.get_with_metadata(b"mid3")?.unwrap().lsn;
+    assert_eq!(db.get_as_of(b"mid3", lsn)?, db.get(b"mid3")?);
+    db.set(b"mid3", b"plain")?;
+    assert!(db.get_as_of(b"mid3", lsn).is_err());
+
+    // compaction chains the values again, on pages of their own before the entries
+    db.compact()?;
+    check(&db)?;
+    assert_eq!(rolling_hash(db.get_reader(b"wet")?.unwrap())?, rolling_hash(Noise(XorShift64::new(10), 100_000))?);
+    // blob, plus wet and half
+    let chunk = PAGE_SIZE - HDR_SZ - 16;
+    let chain_pages = (LEN.div_ceil(chunk) + 2 * 100_000usize.div_ceil(chunk)) as u64;
+    assert!(db.stats()?.pages <= 1 + chain_pages + 2, "{} pages", db.stats()?.pages);
+    let db = db.reopen(false)?;
+    check(&db)?;
+
+    // a dump carries it over, and the restore streams it back in
+    let mut out = Vec::new();
+    dump::dump(&db, &mut out)?;
+    let restored = Engine::open_with_options(test_dir("value_readers_restore")?, opts)?;
+    dump::restore(&restored, &mut out.as_slice())?;
+    check(&restored)?;
+    Ok(())
+}
+
+#[cfg(feature = "compression")]
+pub fn wal_compression_round_trip() -> anyhow::Result<()> {
+    let big: Vec<u8> = (0..64 * 1024).map(|i| b"tinydb "[i % 7]).collect();
+    let plain_dir = test_dir("wal_plain")?;
+    let packed_dir = test_dir("wal_zstd")?;
+    for (dir, on) in [(&plain_dir, false), (&packed_dir, true)] {
+        let wal = Wal::open_with_compression(dir, on)?;
+        wal.append(&big)?;
+        // short payloads are never worth compressing
+        wal.append(b"small")?;
+        wal.sync()?;
+    }
+    let plain = wal_bytes(&plain_dir)?;
+    let packed = wal_bytes(&packed_dir)?;
+    assert!(packed * 20 < plain, "compressed WAL is {} bytes, plain {}", packed, plain);
+
+    let recs: Vec<(u64, Vec<u8>)> = Wal::records(&packed_dir).collect::<Result<_, _>>()?;
+    assert_eq!(recs, vec![(0, big.clone()), (1, b"small".to_vec())]);
+    // reopened without compression: old records still read, new ones are stored plain
+    let wal = Wal::open(&packed_dir)?;
+    wal.append(&big)?;
+    let mut seen = Vec::new();
+    wal.replay(|lsn, payload| { seen.push((lsn, payload.len())); Ok(()) })?;
+    assert_eq!(seen, vec![(0, big.len()), (1, 5), (2, big.len())]);
+    assert!(wal_bytes(&packed_dir)? > big.len() as u64);
+
+    // and through the engine
+    let dir = test_dir("engine_zstd")?;
+    let val = &big[..4000];
+    {
+        let db = Engine::open_with_options(&dir, Options { compress_wal: true, ..Options::default() })?;
+        db.set(b"k", val)?;
+        assert!(wal_bytes(&dir)? < 1000);
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.replayed_records(), 1);
+    assert_eq!(db.get(b"k")?.as_deref(), Some(val));
+    Ok(())
+}
+
+#[cfg(feature = "compression")]
+pub fn value_compression_round_trip() -> anyhow::Result<()> {
+    // several pages' worth of text, but it packs into a fraction of one
+    let big: Vec<u8> = (0..3 * PAGE_SIZE).map(|i| b"lorem ipsum "[i % 12]).collect();
+    let dir = test_dir("value_zstd")?;
+    let opts = Options { compress_values: true, ..Options::default() };
+    {
+        let db = Engine::open_with_options(&dir, opts)?;
+        db.set(b"big", &big)?;
+        db.set(b"small", b"too short to bother")?;
+        db.write_batch(&[BatchOp::Set(b"batched", &big)])?;
+        let stats = db.stats()?;
+        assert!(stats.live_bytes < big.len() as u64 / 4, "live bytes {}", stats.live_bytes);
+        assert_eq!(db.get(b"big")?.as_ref(), Some(&big));
+        assert_eq!(db.get(b"small")?.as_deref(), Some(&b"too short to bother"[..]));
+        assert_eq!(db.multi_get(&[b"batched"])?, vec![Some(big.clone())]);
+    }
+    // replayed from the WAL, which carried the compressed bytes, by an engine that doesn't
+    // compress new values; then compacted, which moves them as they are
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.replayed_records(), 3);
+    assert_eq!(db.get(b"big")?.as_ref(), Some(&big));
+    db.compact()?;
+    assert_eq!(db.get(b"batched")?.as_ref(), Some(&big));
+    drop(db);
+    assert_eq!(Engine::open(&dir)?.get(b"big")?.as_ref(), Some(&big));
+
+    // uncompressed, the same value doesn't fit in a page
+    let plain = Engine::open(test_dir("value_plain")?)?;
+    assert!(matches!(plain.set(b"big", &big), Err(TinyDbError::EntryTooLarge { .. })));
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+pub fn json_values_round_trip() -> anyhow::Result<()> {
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct Address { city: String, zip: Option<u32> }
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct User { name: String, tags: Vec<String>, home: Address }
+
+    let dir = test_dir("json")?;
+    let user = User {
+        name: "ada".into(),
+        tags: vec!["admin".into(), "ops".into()],
+        home: Address { city: "London".into(), zip: None },
+    };
+    {
+        let db = Engine::open(&dir)?;
+        db.set_json(b"user:1", &user)?;
+        db.set(b"raw", b"not json")?;
+    }
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.get_json::<User>(b"user:1")?, Some(user));
+    assert_eq!(db.get_json::<User>(b"missing")?, None);
+    assert!(db.get_json::<User>(b"raw").is_err());
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+pub fn cli_json_output() -> anyhow::Result<()> {
+    let dir = test_dir("cli_json")?;
+    {
+        let db = Engine::open(&dir)?;
+        db.set(b"a", b"1")?;
+        db.set(b"b", b"2")?;
+        db.delete(b"b")?;
+    }
+    let run = |cmd: &str| -> anyhow::Result<serde_json::Value> {
+        let out = Command::new(std::env::current_exe()?).args([cmd, "--json"]).env("TINYDB_DATA_DIR", &dir).output()?;
+        assert!(out.status.success(), "{}", cmd);
+        Ok(serde_json::from_slice(&out.stdout)?)
+    };
+    let stats = run("stats")?;
+    assert_eq!(stats["live_keys"], 1);
+    assert_eq!(stats["pages"], 2);
+    let verify = run("verify")?;
+    assert_eq!(verify["pages"], 2);
+    assert_eq!(verify["corrupt"], serde_json::json!([]));
+    // a fresh process has done nothing yet but open the database
+    let metrics = run("metrics")?;
+    assert_eq!(metrics["sets"], 0);
+    assert!(metrics["page_reads"].is_u64());
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+pub fn async_sets_and_gets() -> anyhow::Result<()> {
+    use crate::async_engine::AsyncEngine;
+
+    let dir = test_dir("async")?;
+    let db = AsyncEngine::new(Engine::open(&dir)?);
+    let rt = tokio::runtime::Builder::new_multi_thread().worker_threads(2).build()?;
+    rt.block_on(async {
+        // many more tasks than workers: blocking on the engine would starve the runtime
+        let tasks: Vec<_> = (0..16).map(|t| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for i in 0..25 {
+                    let key = format!("t{}:{}", t, i);
+                    db.set(key.as_bytes(), format!("v{}", i).as_bytes()).await?;
+                    assert_eq!(db.get(key.as_bytes()).await?, Some(format!("v{}", i).into_bytes()));
+                }
+                assert!(db.delete(format!("t{}:0", t).as_bytes()).await?);
+                Ok::<_, TinyDbError>(())
+            })
+        }).collect();
+        for task in tasks {
+            task.await??;
+        }
+        assert_eq!(db.get(b"t3:7").await?, Some(b"v7".to_vec()));
+        assert_eq!(db.get(b"t3:0").await?, None);
+        assert!(!db.delete(b"t3:0").await?);
+        Ok::<_, anyhow::Error>(())
+    })?;
+    assert_eq!(db.engine().len(), 16 * 24);
+    drop(rt);
+    drop(db);
+    let db = Engine::open(&dir)?;
+    assert_eq!(db.len(), 16 * 24);
+    assert_eq!(db.get(b"t15:24")?, Some(b"v24".to_vec()));
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use std::io::{ErrorKind, Read, Write};
+
+use crate::engine::Engine;
+use crate::error::TinyDbError;
+
+/*
+Portable export of a database: every live pair in key order, binary-safe.
+dump = MAGIC, then per pair <u32 key_len><u32 val_len><key><val>, until EOF.
+Expiry times are not carried over; a restored key with a TTL simply never expires.
+*/
+
+const MAGIC: &[u8; 8] = b"TDBDUMP1";
+
+/// Write every live pair of `engine` to `out`. Returns the number of pairs written.
+pub fn dump<W: Write>(engine: &Engine, out: &mut W) -> anyhow::Result<u64> {
+    out.write_all(MAGIC)?;
+    let mut n = 0;
+    for kv in engine.scan_prefix(b"") {
+        let (key, val) = kv?;
+        out.write_all(&(key.len() as u32).to_le_bytes())?;
+        out.write_all(&(val.len() as u32).to_le_bytes())?;
+        out.write_all(&key)?;
+        out.write_all(&val)?;
+        n += 1;
+    }
+    out.flush()?;
+    Ok(n)
+}
+
+/// Read a dump produced by `dump` and set every pair into `engine`, each value streamed in
+/// through `set_reader`. Returns the number of pairs restored. A dump cut off mid-record is
+/// an error, not a silent short restore, and so is a record longer than `engine`'s key and
+/// value limits, before it is read.
+pub fn restore<R: Read>(engine: &Engine, input: &mut R) -> anyhow::Result<u64> {
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(anyhow::anyhow!("not a tinydb dump"));
+    }
+    let opts = engine.options();
+    let mut n = 0;
+    loop {
+        let mut hdr = [0u8; 8];
+        // a clean EOF is only allowed on a record boundary
+        let got = read_up_to(input, &mut hdr)?;
+        if got == 0 { break; }
+        if got < hdr.len() {
+            return Err(anyhow::anyhow!("truncated dump after {} records", n));
+        }
+        let key_len = u32::from_le_bytes(hdr[0..4].try_into().unwrap()) as usize;
+        let val_len = u32::from_le_bytes(hdr[4..8].try_into().unwrap()) as usize;
+        // a damaged header could ask for gigabytes; nothing over the limits could be set anyway
+        if key_len > opts.max_key_size || val_len > opts.max_value_size {
+            return Err(anyhow::anyhow!(
+                "corrupt dump: record {} claims a {}-byte key and a {}-byte value, the limits are {} and {}",
+                n, key_len, val_len, opts.max_key_size, opts.max_value_size));
+        }
+        let truncated = || anyhow::anyhow!("truncated dump after {} records", n);
+        let mut key = vec![0u8; key_len];
+        input.read_exact(&mut key).map_err(|_| truncated())?;
+        // streamed into overflow pages if it is too long for one
+        match engine.set_reader(&key, input.take(val_len as u64), val_len) {
+            Err(TinyDbError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Err(truncated()),
+            r => r?,
+        }
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// Fill as much of `buf` as the reader has left; returns how many bytes were read.
+fn read_up_to<R: Read>(r: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut got = 0;
+    while got < buf.len() {
+        match r.read(&mut buf[got..])? {
+            0 => break,
+            k => got += k,
+        }
+    }
+    Ok(got)
+}
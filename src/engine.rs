@@ -1,204 +1,3137 @@
-#[allow(unused_variables)]
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::ops::Bound;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-
-use crate::wal::Wal;
-use crate::pager::{Pager, Page, PAGE_SIZE};
+use crate::util::{crc32, now_millis, read_bytes, read_u32_le, read_u64_le, sync_parent_dir, user_data_dir, Checksum};
+use crate::wal::{Lsn, ReplayProgress, Wal, DEFAULT_SEGMENT_BYTES};
+use crate::pager::{CacheStats, Pager, Page, PageId, HDR_SZ, DEFAULT_CACHE_PAGES, MAX_NAME_LEN, META_PAGE};
+use crate::storage::{MemFile, Storage};
+use crate::index::{Index, IndexEntry, KeyHash, KeySource};
 
 /// Very small single-file KV engine on top of pages.
-/// Layout: each page stores multiple kvs as:
-/// [u32: key_len][u32: val_len][key..][val..] repeated
-/// We keep a small in-memory index mapping key -> (page_id, offset, val_len).
+/// Layout: page 0 is the pager's metadata page; each data page after it stores multiple kvs as:
+/// [u32: key_len][u32: val_len][u64: expires_at][key..][val..] repeated
+/// Keys and values are arbitrary bytes. expires_at is unix-epoch millis, 0 meaning never.
+/// Integers here and in WAL payloads are little-endian, like the pager's and the WAL's own.
+/// The top bit of val_len (VAL_ZSTD) marks a zstd-compressed value; the rest of val_len is
+/// then the size of the compressed bytes that follow the key.
+/// A value too long for one page (only `Engine::set_reader` writes one) goes to a chain of
+/// overflow pages instead, each holding [u32: 0][u32: chunk_len][u64: next page, 0 at the
+/// end][chunk..]; its entry has VAL_OVERFLOW set in val_len and <u64 first page><u64 value len>
+/// for a value. The zero where a key_len would be makes a page scan see no entries there.
+/// We keep a small in-memory index mapping key -> (page_id, offset, expires_at), ordered by key
+/// (or by a hash of the key, see `Options::key_hash` and `index.rs`).
 ///
 /// WAL payload types: simple encoded op:
-/// "SET"<u64 page_id><u32 off><u32 key_len><u32 val_len><key><val>
-/// For simplicity we allocate a new page when current doesn't fit; no deletion compaction.
-use anyhow::Context;
+/// "SET"<u64 page_id><u32 off><u32 key_len><u32 val_len><u64 expires_at><key><val>  (val as stored,
+/// val_len flagged as on the page)
+/// "DEL"<u64 page_id><u32 off><u32 key_len><key>
+/// "BAT"<u32 count>(<u32 op_len><SET or DEL payload>)*"CMT"
+/// "CMP"<u64 page_count><u32 count>(<u32 op_len><OVF or SET payload>)*"CMT"  (compaction)
+/// "CLR"  (clear: every key before it is gone)
+/// "UPD"<u32 slot_len><SET payload>  (in-place update, see `Options::update_in_place`)
+/// "OVF"<u64 page_id><u64 next page><chunk>  (one overflow page, written whole)
+/// "CKP"<u64 checkpoint LSN>  (checkpoint)
+/// A delete writes a tombstone entry ([u32: key_len][u32: TOMBSTONE][u64: 0][key..]) into the page
+/// so the delete survives even once its WAL record is gone.
+/// An in-place update that shrinks an entry fills the rest of its slot with a gap entry
+/// ([u32: len][u32: GAP][u64: 0][len zero bytes]), which reading a page skips.
+/// We allocate a new page from the pager when current doesn't fit; `compact` reclaims dead entries.
+/// The WAL lives next to the data file as segments wal-000001.log, wal-000002.log, ...
+/// `checkpoint` bounds the WAL: it starts a segment with a CKP record and deletes the ones
+/// before it, and recovery replays from the last CKP record in the log. It also frees pages
+/// left with nothing live on them for reuse. Since a reused page can hold newer data than pages
+/// with higher ids, the index is rebuilt by visiting pages in page-LSN order, not id order.
+use crate::error::{Result, TinyDbError};
 
-const WAL_FILE: &str = "tinydb_wal.log";
 const DATA_FILE: &str = "tinydb_data.db";
+/// Where checkpoints used to be recorded before the WAL carried CKP records:
+/// [u64: checkpoint LSN][u32: crc of the LSN bytes]. Only read now, for directories written
+/// before then; the start point is the later of it and the log's last CKP record.
+const CKPT_FILE: &str = "tinydb_ckpt";
+/// Index snapshot, written at each checkpoint:
+/// [u64: checkpoint LSN][u32: count](<u32 key_len><key><u64 page_id><u32 offset><u64 expires_at>)*count
+/// [u32: count](<u64 dead page_id>)*count[u32: crc of everything before it]
+const INDEX_FILE: &str = "index.snapshot";
+/// Held with an exclusive OS file lock by the one writer of the database; empty.
+const LOCK_FILE: &str = "LOCK";
+/// Environment variable naming the directory `Engine::open_default` opens.
+pub const DATA_DIR_ENV: &str = "TINYDB_DATA_DIR";
+
+/// Where the files of one database live. `open` puts them at the fixed names above, the WAL
+/// segments straight in the directory; `open_named` derives them from the database name so
+/// several databases can share a directory.
+#[derive(Clone)]
+struct Files {
+    name: String,
+    data: PathBuf,
+    // segment directory
+    wal: PathBuf,
+    // read only, see CKPT_FILE
+    ckpt: PathBuf,
+    index: PathBuf,
+    lock: PathBuf,
+}
+
+impl Files {
+    fn unnamed(dir: &Path) -> Self {
+        Self {
+            name: String::new(),
+            data: dir.join(DATA_FILE),
+            wal: dir.to_path_buf(),
+            ckpt: dir.join(CKPT_FILE),
+            index: dir.join(INDEX_FILE),
+            lock: dir.join(LOCK_FILE),
+        }
+    }
+
+    /// `<name>.db`, `<name>.wal/` for the segments, `<name>.ckpt` (old directories only),
+    /// `<name>.index` and `<name>.lock`.
+    fn named(dir: &Path, name: &str) -> Result<Self> {
+        let ok = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if name.is_empty() || name.len() > MAX_NAME_LEN || !name.chars().all(ok) {
+            return Err(TinyDbError::Invalid(format!(
+                "database name {:?} must be 1 to {} letters, digits, '_' or '-'", name, MAX_NAME_LEN)));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            data: dir.join(format!("{}.db", name)),
+            wal: dir.join(format!("{}.wal", name)),
+            ckpt: dir.join(format!("{}.ckpt", name)),
+            index: dir.join(format!("{}.index", name)),
+            lock: dir.join(format!("{}.lock", name)),
+        })
+    }
+}
+
+/// Trailing marker of a batch record; a batch without it is discarded on replay.
+const BATCH_COMMIT: &[u8] = b"CMT";
+
+/// Sets per BAT record in `Engine::bulk_load`: the granularity of what a crash can lose.
+const BULK_CHUNK: usize = 1000;
+
+/// val_len value marking an on-page entry as a delete tombstone.
+const TOMBSTONE: u32 = u32::MAX;
+
+/// Bytes of an on-page entry before the key: key_len + val_len + expires_at.
+const ENTRY_HDR_SZ: usize = 4 + 4 + 8;
+
+/// val_len of a gap entry, the filler after an entry an in-place update shrank.
+const GAP: u32 = u32::MAX - 1;
+
+/// val_len flag: the value is stored zstd-compressed. Never set on a TOMBSTONE.
+const VAL_ZSTD: u32 = 1 << 31;
+
+/// val_len flag: the value is in overflow pages, and the entry holds a pointer to them.
+/// Never set together with VAL_ZSTD.
+const VAL_OVERFLOW: u32 = 1 << 30;
+
+/// The flag bits of a val_len; the rest is the length of the bytes after the key.
+const VAL_FLAGS: u32 = VAL_ZSTD | VAL_OVERFLOW;
+
+/// Bytes of an overflow page's data region before its chunk: a zero, chunk_len, next page.
+const OVF_HDR_SZ: usize = 4 + 4 + 8;
+
+/// Bytes of an overflow entry's value: first page and value length.
+const OVF_PTR_SZ: usize = 8 + 8;
+
+/// With value compression on, values shorter than this are stored as they are.
+#[cfg(feature = "compression")]
+const COMPRESS_MIN_BYTES: usize = 128;
+
+/// Default `Options::max_key_size` and `Options::max_value_size`.
+pub const DEFAULT_MAX_KEY_SIZE: usize = 1024;
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 1 << 20;
+
+/// When writes fsync. Every policy fsyncs on `checkpoint` and `compact`, whose correctness
+/// depends on it; they differ in what a write has done by the time it returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Fsync the WAL before acknowledging. An acknowledged write survives a power loss. The
+    /// default. Like the other policies it leaves the data file to the next checkpoint: the
+    /// fsynced WAL record is what makes the write durable, and replay rewrites the page.
+    Always,
+    /// Fsync the WAL on every Nth write only, and leave the data file to the next checkpoint.
+    /// A power loss can take the up to N-1 most recent acknowledged writes with it (never
+    /// part of one write, or a later write without an earlier one). `EveryN(1)` keeps every
+    /// write durable, just without the data-file fsync.
+    EveryN(usize),
+    /// Never fsync on writes; the OS flushes when it likes. A process crash loses nothing,
+    /// since the writes already reached the OS, but a power loss can lose every write since
+    /// the last checkpoint.
+    Never,
+}
+
+/// What a write does when the WAL holds more than `Options::max_unckpt_bytes` of records no
+/// checkpoint has truncated yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteStall {
+    /// Wait for a checkpoint (from `start_background_checkpoint` or another thread) to bring
+    /// it back under the limit. The default.
+    Block,
+    /// Fail with `WouldStall` at once, leaving the caller to back off or checkpoint.
+    Fail,
+}
+
+/// What `Engine::rebuild_from_wal` recovered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rebuilt {
+    /// Records applied to the new data file.
+    pub replayed: u64,
+    /// Records left out: unreadable (a bad checksum, say) or impossible to apply.
+    pub skipped: u64,
+    /// Whether the log held every write back to LSN 0 and none was skipped, so that
+    /// nothing was lost with the old data file.
+    pub complete: bool,
+}
+
+/// What `Engine::repair` did with the pages that failed their checksum.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Repaired {
+    /// Pages rewritten from the WAL (or, for free pages, as empty), in id order.
+    pub repaired: Vec<PageId>,
+    /// Pages the WAL doesn't cover back to their first entry, left as they are.
+    pub lost: Vec<PageId>,
+}
+
+/// Settings for `Engine::open_with_options`.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub sync: SyncPolicy,
+    /// Start a new WAL segment once the current one reaches this many bytes.
+    pub wal_segment_bytes: u64,
+    /// Keep the newest this many WAL segments when a checkpoint truncates the log, rather
+    /// than only the one its CKP record starts (see `Wal::with_retention`). Every checkpoint
+    /// starts a segment, so this is roughly how many checkpoints back `get_as_of` and
+    /// `rebuild_from_wal` can reach. Replay on open still starts at the last CKP record.
+    /// 0, the default, keeps no history.
+    pub wal_retention: usize,
+    /// Checksum for pages and WAL records. Only used when the database is created; after
+    /// that the one recorded in the data file wins.
+    pub checksum: Checksum,
+    /// Longest key a write accepts. Keys must also be non-empty.
+    pub max_key_size: usize,
+    /// Longest value a write accepts. An entry must also fit in one page, whatever this says,
+    /// unless `Engine::set_reader` writes it: that one spills into overflow pages.
+    pub max_value_size: usize,
+    /// Let a `set` of a key whose entry is on the page appends go to overwrite that entry
+    /// where it is, when the new one is no longer, instead of appending another and leaving
+    /// the old one dead. Hot keys end up updated in place until their page fills. Skipped
+    /// while a snapshot, cursor or scan is open, since those read the old value where it is.
+    pub update_in_place: bool,
+    /// zstd-compress large WAL payloads (see `Wal::open_with_compression`).
+    #[cfg(feature = "compression")]
+    pub compress_wal: bool,
+    /// zstd-compress large values before they go into a page (and into the WAL record that
+    /// writes it). Only affects new writes; compressed values read back either way.
+    #[cfg(feature = "compression")]
+    pub compress_values: bool,
+    /// Key the in-memory index by this hash of each key (`index::default_key_hash` is the
+    /// one to use) instead of by the key itself, so key bytes stay on the pages only. Keys
+    /// sharing a hash are told apart by reading them back from their pages, which also
+    /// makes every lookup read a page. Ordered operations (scans, `keys`, `compact`) read
+    /// every key back and sort them, the index snapshot isn't kept, so open rebuilds the
+    /// index from the pages. None, the default, keeps full keys.
+    pub key_hash: Option<KeyHash>,
+    /// Read a partial page at the end of the data file, left by a crash while a write was
+    /// extending the file onto it, as an empty page instead of failing (with `ShortRead`)
+    /// the open or read that reaches it. See `Pager::set_tolerate_partial_tail`.
+    pub tolerate_partial_tail: bool,
+    /// Backpressure on writes: once the WAL holds more than this many bytes of records
+    /// since the last checkpoint (`Wal::unckpt_bytes`), writes stall as `write_stall` says.
+    /// `checkpoint` and `compact` never stall. None, the default, lets the WAL grow freely.
+    pub max_unckpt_bytes: Option<u64>,
+    pub write_stall: WriteStall,
+}
 
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            sync: SyncPolicy::Always,
+            wal_segment_bytes: DEFAULT_SEGMENT_BYTES,
+            wal_retention: 0,
+            checksum: Checksum::default(),
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            update_in_place: false,
+            #[cfg(feature = "compression")]
+            compress_wal: false,
+            #[cfg(feature = "compression")]
+            compress_values: false,
+            key_hash: None,
+            tolerate_partial_tail: true,
+            max_unckpt_bytes: None,
+            write_stall: WriteStall::Block,
+        }
+    }
+}
+
+/// A value and where it lives, from `Engine::get_with_metadata`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValueMetadata {
+    pub value: Vec<u8>,
+    pub page_id: PageId,
+    /// Offset of the entry in the page's data region.
+    pub offset: u32,
+    /// LSN of the page holding the entry.
+    pub lsn: Lsn,
+}
+
+/// Size and fragmentation figures from `Engine::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Stats {
+    /// Keys with an unexpired value.
+    pub live_keys: usize,
+    /// Pages allocated in the data file, metadata page included.
+    pub pages: u64,
+    /// Entry bytes written to data pages, live or not.
+    pub used_bytes: u64,
+    /// Bytes of the live keys' entries, plus their values in overflow pages.
+    pub live_bytes: u64,
+    /// Data file size minus `live_bytes`: overwritten and deleted entries plus page headers
+    /// and unused page tails. Roughly what `compact` could give back.
+    pub dead_bytes: u64,
+}
+
+/// Cumulative counters from `Engine::metrics`, counted since open. They only ever grow,
+/// so a monitoring system can export them as-is and take rates from the deltas.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Metrics {
+    /// Values written: by `set`, `set_with_ttl` and `set_reader`, each key of a
+    /// `write_batch` or `bulk_load` (its last op when repeated), and the writes of
+    /// `compare_and_swap`, `set_if_absent`, `merge` and `increment`.
+    pub sets: u64,
+    /// Keys looked up: each `get` and `get_reader` (the typed getters and the
+    /// read-modify-write calls do a `get` too) and each key of a `multi_get`.
+    pub gets: u64,
+    /// Keys deleted, by `delete`, `write_batch` or `delete_prefix`. Deleting a missing key
+    /// deletes nothing and isn't counted.
+    pub deletes: u64,
+    pub wal_appends: u64,
+    pub wal_fsyncs: u64,
+    /// Bytes appended to the WAL, record headers included.
+    pub wal_bytes: u64,
+    /// Pages read from the data file; a page cache hit reads none.
+    pub page_reads: u64,
+    pub page_writes: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Bytes written to the data file.
+    pub data_bytes: u64,
+}
+
+// the engine's own counters behind `Metrics`; the WAL and pager keep theirs
+#[derive(Default)]
+struct Counters {
+    sets: AtomicU64,
+    gets: AtomicU64,
+    deletes: AtomicU64,
+}
+
+/// One operation of an atomic `write_batch`.
+pub enum BatchOp<'a> {
+    Set(&'a [u8], &'a [u8]),
+    Delete(&'a [u8]),
+}
+
+/// Combines a key's current value (`None` if absent) with a merge operand into its new value.
+pub type MergeFn = dyn Fn(Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync;
+
+/// A write handed to the commit hook, see `Engine::set_commit_hook`. Values are as the
+/// caller passed them, never compressed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommitEvent {
+    Set { key: Vec<u8>, value: Vec<u8>, expires_at: u64 },
+    Delete { key: Vec<u8> },
+    /// `Engine::clear`: every key is gone.
+    Clear,
+}
+
+/// Called with each durable write and the LSN of the WAL record holding it.
+pub type CommitHook = dyn Fn(Lsn, &CommitEvent) + Send + Sync;
+
+/// A handle on an open database. Clones are cheap and share everything, so threads can
+/// each hold one: reads run concurrently, and writes take turns on an internal writer lock.
+#[derive(Clone)]
 pub struct Engine {
+    // None for an in-memory engine
+    files: Option<Files>,
     wal: Arc<Wal>,
     pager: Arc<Mutex<Pager>>,
     // in-memory index
-    index: Arc<Mutex<HashMap<String, (u64, u32, u32)>>>,
+    index: Arc<Mutex<Index>>,
     // next page to append
     next_page: Arc<Mutex<u64>>,
+    // pages that lost a live entry; checked for reuse at the next checkpoint
+    dead_pages: Arc<Mutex<BTreeSet<u64>>>,
+    // every Snapshot, Cursor and scan holds a Weak of this (see `pin`); while any is alive no
+    // page is freed or rewritten. Weak, so that cloning the engine doesn't count as a snapshot.
+    snapshots: Arc<()>,
+    // set while `compact` or `clear` rewrites the pages; new pins wait on `rewritten` meanwhile
+    rewriting: Arc<Mutex<bool>>,
+    rewritten: Arc<Condvar>,
+    // held for the whole of every write, so read-modify-writes like `increment` are atomic
+    writer: Arc<Mutex<()>>,
+    sync_policy: SyncPolicy,
+    // from Options, checked by every write
+    max_key_size: usize,
+    max_value_size: usize,
+    update_in_place: bool,
+    #[cfg(feature = "compression")]
+    compress_values: bool,
+    // writes since the last WAL fsync, for SyncPolicy::EveryN
+    unsynced: Arc<Mutex<usize>>,
+    // from Options: see `lock_for_write`
+    max_unckpt_bytes: Option<u64>,
+    write_stall: WriteStall,
+    // stalled writers wait on `checkpointed`, notified after every checkpoint
+    stall: Arc<Mutex<()>>,
+    checkpointed: Arc<Condvar>,
+    // WAL records applied while opening
+    replayed: usize,
+    // data pages read while opening to rebuild the index; 0 if the index snapshot was used
+    pages_scanned: usize,
+    // time `open` took to load the index and replay the WAL
+    recovery_time: Duration,
+    // set by `set_merge_operator`
+    merge_op: Arc<Mutex<Option<Arc<MergeFn>>>>,
+    // set by `set_commit_hook`; writes queue their events in `commits` (in LSN order, under
+    // the writer lock) while one is set, and whoever holds `delivering` hands them over
+    commit_hook: Arc<Mutex<Option<Arc<CommitHook>>>>,
+    commits: Arc<Mutex<VecDeque<(Lsn, CommitEvent)>>>,
+    delivering: Arc<Mutex<()>>,
+    // the locked LOCK file of a writer opened on a directory; unlocked once the last clone goes
+    lock: Option<Arc<File>>,
+    counters: Arc<Counters>,
+    // what it was opened with, for `reopen`
+    opts: Options,
+}
+
+/// The writer lock of an engine. Once released, what the write made durable goes to the
+/// commit hook, so the hook runs with the lock free.
+struct WriteGuard<'a> {
+    engine: &'a Engine,
+    guard: Option<MutexGuard<'a, ()>>,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        self.engine.deliver_commits();
+    }
+}
+
+/// Held by `compact` and `clear` while they rewrite the pages; see `Engine::begin_rewrite`.
+struct RewriteGuard<'a> {
+    engine: &'a Engine,
+}
+
+impl Drop for RewriteGuard<'_> {
+    fn drop(&mut self) {
+        *self.engine.rewriting.lock().unwrap() = false;
+        self.engine.rewritten.notify_all();
+    }
+}
+
+/// A frozen view of the database, from `Engine::snapshot`. Writes append new entries rather
+/// than overwriting old ones, so the captured index keeps pointing at the versions that were
+/// live at capture, as long as their pages aren't reused or compacted away; the engine holds
+/// off on both while a snapshot exists.
+pub struct Snapshot {
+    index: Index,
+    pager: Arc<Mutex<Pager>>,
+    _pin: Weak<()>,
+}
+
+impl Snapshot {
+    /// The value `key` had when the snapshot was taken. Entries that had expired by then are
+    /// absent; ones that expire afterwards stay visible.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(e) = self.index.get(key, &*self.pager)? else { return Ok(None) };
+        let mut p = self.pager.lock().unwrap();
+        Ok(Some(read_value(&mut p, e.page_id, e.offset)?))
+    }
+}
+
+/// A position among the keys of the database, from `Engine::seek`, that steps forward with
+/// `next` and back with `prev`. The keys, and where their values were, are captured by the
+/// seek, so like a `Snapshot` the cursor sees the database as it was then and holds off page
+/// reuse and `compact` until dropped. Values are only read from the pager by `value`.
+/// Before the first key or past the last one the cursor is on no key; stepping from there
+/// moves onto the nearest one.
+pub struct Cursor {
+    entries: Vec<(Vec<u8>, IndexEntry)>,
+    // 0 is before the first entry, i + 1 is entry i, entries.len() + 1 is past the last
+    pos: usize,
+    pager: Arc<Mutex<Pager>>,
+    _pin: Weak<()>,
+}
+
+impl Cursor {
+    /// Move to the next key up. Returns whether the cursor is on a key, false once it is
+    /// past the last.
+    pub fn next(&mut self) -> bool {
+        self.pos = (self.pos + 1).min(self.entries.len() + 1);
+        self.entry().is_some()
+    }
+
+    /// Move to the next key down. Returns whether the cursor is on a key, false once it is
+    /// before the first.
+    pub fn prev(&mut self) -> bool {
+        self.pos = self.pos.saturating_sub(1);
+        self.entry().is_some()
+    }
+
+    /// The key the cursor is on.
+    pub fn key(&self) -> Option<&[u8]> {
+        self.entry().map(|(k, _)| &k[..])
+    }
+
+    /// The value of the key the cursor is on, read from its page now.
+    pub fn value(&self) -> Result<Option<Vec<u8>>> {
+        let Some((_, e)) = self.entry() else { return Ok(None) };
+        let mut p = self.pager.lock().unwrap();
+        Ok(Some(read_value(&mut p, e.page_id, e.offset)?))
+    }
+
+    fn entry(&self) -> Option<&(Vec<u8>, IndexEntry)> {
+        self.entries.get(self.pos.checked_sub(1)?)
+    }
+}
+
+/// A value read a page at a time, from `Engine::get_reader`. Like a `Snapshot` it holds
+/// off page reuse and `compact` until dropped, so the overflow pages it has yet to read
+/// keep the value it found, whatever is written to the key meanwhile.
+pub struct ValueReader {
+    pager: Arc<Mutex<Pager>>,
+    // the bytes of the page read last, handed out up to `pos`
+    chunk: Vec<u8>,
+    pos: usize,
+    // the overflow page to read next, and how much of the value is left for it and the rest
+    next: PageId,
+    left: u64,
+    len: u64,
+    _pin: Weak<()>,
+}
+
+impl ValueReader {
+    /// Length of the whole value, however much of it has been read.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.chunk.len() && self.left > 0 {
+            let mut p = self.pager.lock().unwrap();
+            let (chunk, next) = chain_link(&mut p, self.next, self.left).map_err(std::io::Error::other)?;
+            self.left -= chunk.len() as u64;
+            (self.chunk, self.pos, self.next) = (chunk, 0, next);
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// The thread started by `Engine::start_background_checkpoint`. Dropping it stops the thread
+/// after one last checkpoint; `stop` does the same and reports how it went.
+pub struct CheckpointHandle {
+    // dropped to wake the thread and tell it to finish
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl CheckpointHandle {
+    /// Stop the thread, waiting for its final checkpoint. Returns the first error any of
+    /// its checkpoints hit.
+    pub fn stop(mut self) -> Result<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.stop.take();
+        match self.thread.take() {
+            Some(t) => t.join().unwrap_or_else(|_| Err(TinyDbError::Invalid("checkpoint thread panicked".into()))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CheckpointHandle {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
 }
 
 impl Engine {
-    pub fn open<P: AsRef<Path>>(dir: P) -> anyhow::Result<Self> {
-        let mut dirp = dir.as_ref().to_path_buf();
-        dirp.push(WAL_FILE);
-        let wal = Arc::new(Wal::open(&dirp).context("open wal")?);
-
-        let mut datap = dir.as_ref().to_path_buf();
-        datap.push(DATA_FILE);
-        let pager = Arc::new(Mutex::new(Pager::open(&datap).context("open pager")?));
-
-        // simple: reconstruct index by scanning all pages and reading kvs.
-        let mut idx = HashMap::new();
-        let mut next_page = 0u64;
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        Self::open_with_options(dir, Options::default())
+    }
+
+    /// Open (or create) the database in `default_dir`, creating the directory first if it
+    /// doesn't exist.
+    pub fn open_default() -> Result<Self> {
+        let dir = Self::default_dir()?;
+        std::fs::create_dir_all(&dir)?;
+        Self::open(dir)
+    }
+
+    /// Where `open_default` keeps the database: `$TINYDB_DATA_DIR` if it is set, otherwise
+    /// `tinydb` in the user's data directory (`~/.local/share` or `$XDG_DATA_HOME` on Linux,
+    /// `~/Library/Application Support` on macOS, `%APPDATA%` on Windows).
+    pub fn default_dir() -> Result<PathBuf> {
+        if let Some(dir) = std::env::var_os(DATA_DIR_ENV).filter(|v| !v.is_empty()) {
+            return Ok(PathBuf::from(dir));
+        }
+        user_data_dir().map(|dir| dir.join("tinydb")).ok_or_else(|| {
+            TinyDbError::Invalid(format!("no user data directory to default to; set {}", DATA_DIR_ENV))
+        })
+    }
+
+    /// Like `open`, with a non-default sync policy or WAL segment size.
+    pub fn open_with_options<P: AsRef<Path>>(dir: P, opts: Options) -> Result<Self> {
+        Self::open_mode(&Files::unnamed(dir.as_ref()), false, opts, &|_| {})
+    }
+
+    /// Open (or create) the database called `name` in `dir`. Its files are named after it
+    /// (`<name>.db`, a `<name>.wal` directory of segments, `<name>.index`, `<name>.lock`),
+    /// so databases of different names, and the unnamed one `open` uses, can share a
+    /// directory without seeing each other's keys. Names are up to MAX_NAME_LEN ASCII
+    /// letters, digits, '_' or '-'. The data file records its name, and opening it under
+    /// another is an error.
+    pub fn open_named<P: AsRef<Path>>(dir: P, name: &str) -> Result<Self> {
+        Self::open_mode(&Files::named(dir.as_ref(), name)?, false, Options::default(), &|_| {})
+    }
+
+    /// Like `open`, calling `progress` now and then while the WAL is replayed (see
+    /// `Wal::replay_from_start_with_progress`), e.g. to show a progress bar on a slow start.
+    pub fn open_with_progress<P: AsRef<Path>>(dir: P, progress: impl Fn(ReplayProgress)) -> Result<Self> {
+        Self::open_mode(&Files::unnamed(dir.as_ref()), false, Options::default(), &progress)
+    }
+
+    /// Open an existing database for reading, e.g. from a second process while another
+    /// one writes. The index is a snapshot taken at open time (page scan + WAL replay) and
+    /// is not refreshed afterwards; reopen to see newer writes. Replayed WAL records are kept
+    /// in memory rather than written back, and `set`/`delete`/... return an error.
+    pub fn open_read_only<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        Self::open_mode(&Files::unnamed(dir.as_ref()), true, Options::default(), &|_| {})
+    }
+
+    /// Disaster recovery for a lost or damaged data file in `dir`: rebuild it from the WAL
+    /// alone and swap it in. The old data file is never read. Every record the log still
+    /// holds is replayed, oldest first, into a new data file next to it, which is compacted
+    /// and then renamed over the old one; records that fail their checksum or can't be
+    /// applied are skipped and counted. The log is then checkpointed away, since the new
+    /// file holds all of it. Writes that an earlier checkpoint dropped from the log were
+    /// only in the old data file, so the result is `complete` only if the log went back to
+    /// LSN 0. Takes the lock file like a writer, so no one may have `dir` open.
+    pub fn rebuild_from_wal<P: AsRef<Path>>(dir: P) -> Result<Rebuilt> {
+        let files = Files::unnamed(dir.as_ref());
+        let _lock = lock_file(&files.lock)?;
+        let ckpt_lsn = read_checkpoint(&files.ckpt)?;
+        let sum = Wal::open_read_only(&files.wal)?.checksum();
+        let mut rebuilt = Rebuilt::default();
+        let mut first = None;
+        let mut next = ckpt_lsn;
+
+        let mut tmp = files.data.clone().into_os_string();
+        tmp.push(".rebuild");
+        let tmp = PathBuf::from(tmp);
+        remove_if_present(&tmp)?;
+        {
+            // replay into the new file; the engine's own log is a throwaway in memory
+            let wal = Wal::open_with_storage(MemFile::default(), sum)?;
+            let pager = Pager::open_with_checksum(&tmp, DEFAULT_CACHE_PAGES, sum)?;
+            let engine = Self::from_parts(None, wal, pager, 0, false, Options::default(), &|_| {})?;
+            for rec in Wal::salvage_records(&files.wal) {
+                let lsn = match rec {
+                    Ok((lsn, payload)) if payload.starts_with(b"CKP") => Some(lsn),
+                    Ok((lsn, payload)) => {
+                        match engine.replay_record(lsn, &payload) {
+                            Ok(()) => rebuilt.replayed += 1,
+                            Err(_) => rebuilt.skipped += 1,
+                        }
+                        Some(lsn)
+                    }
+                    Err(TinyDbError::WalCrcMismatch { lsn }) => {
+                        rebuilt.skipped += 1;
+                        Some(lsn)
+                    }
+                    Err(_) => {
+                        rebuilt.skipped += 1;
+                        None
+                    }
+                };
+                if let Some(lsn) = lsn {
+                    first.get_or_insert(lsn);
+                    next = next.max(lsn + 1);
+                }
+            }
+            // a skipped record can leave a gap on its page that would end a page scan early,
+            // so pack the entries; the pages get LSNs past the log's
+            engine.wal.ensure_next_lsn(next);
+            engine.compact()?;
+        }
+        rebuilt.complete = rebuilt.skipped == 0 && first.map_or(ckpt_lsn == 0, |lsn| lsn == 0);
+
+        // the snapshot indexes the old pages and might match the checkpoint written below
+        remove_if_present(&files.index)?;
+        std::fs::rename(&tmp, &files.data)?;
+        sync_parent_dir(&files.data)?;
+        let wal = Wal::open_with_checksum(&files.wal, DEFAULT_SEGMENT_BYTES, sum)?;
+        wal.ensure_next_lsn(next);
+        wal.append_checkpoint(&encode_checkpoint(next))?;
+        Ok(rebuilt)
+    }
+
+    /// Rewrite the pages of the data file in `dir` that fail their checksum (see
+    /// `Pager::verify_all`) from the WAL. A page can be rebuilt when the log still holds
+    /// the record that wrote its first entry, at offset 0 of a fresh page: then every later
+    /// write to it is logged too, and replaying those onto an empty page gives back exactly
+    /// what it held. Pages whose early history went with a checkpoint are reported `lost`,
+    /// as is a damaged superblock; free pages are just rewritten empty. Meant for a database
+    /// that no longer opens, so like `rebuild_from_wal` it takes the lock file and no one
+    /// may have `dir` open.
+    pub fn repair<P: AsRef<Path>>(dir: P) -> Result<Repaired> {
+        let files = Files::unnamed(dir.as_ref());
+        let _lock = lock_file(&files.lock)?;
+        let mut pager = Pager::open(&files.data)?;
+        let mut out = Repaired::default();
+        let bad = pager.verify_all()?;
+        if bad.is_empty() {
+            return Ok(out);
+        }
+        // None until a write to the page at offset 0 shows where its history starts
+        let mut pages: BTreeMap<PageId, Option<Page>> = bad.iter().filter(|&&pid| pid != META_PAGE).map(|&pid| (pid, None)).collect();
+        for rec in Wal::records(&files.wal) {
+            let (lsn, payload) = rec?;
+            redo_page_writes(&mut pages, lsn, &payload, pager.page_size())?;
+        }
+        for pid in bad {
+            let page = if pager.is_free(pid) {
+                Some(Page::with_size(pid, pager.page_size()))
+            } else {
+                pages.remove(&pid).flatten()
+            };
+            match page {
+                Some(page) => {
+                    pager.write_page(&page)?;
+                    out.repaired.push(pid);
+                }
+                None => out.lost.push(pid),
+            }
+        }
+        pager.sync()?;
+        Ok(out)
+    }
+
+    /// An engine backed by in-memory buffers instead of files, for tests. It behaves like
+    /// one opened on an empty directory, and everything is dropped with it.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::open_with_storage(MemFile::default(), MemFile::default())
+    }
+
+    /// An engine whose data file and WAL live in `data` and `wal` rather than in a
+    /// directory, e.g. a custom storage layer. Both empty is a new database; storage from an
+    /// earlier engine opens like a directory does, pages first and then WAL replay. With no
+    /// directory there is no index snapshot, so the index is always rebuilt from the pages.
+    pub fn open_with_storage(data: impl Storage + 'static, wal: impl Storage + 'static) -> Result<Self> {
+        Self::open_with_storage_options(data, wal, Options::default())
+    }
+
+    /// `open_with_storage` with non-default options; `wal_segment_bytes` and
+    /// `wal_retention` don't apply.
+    pub fn open_with_storage_options(data: impl Storage + 'static, wal: impl Storage + 'static, opts: Options) -> Result<Self> {
+        let pager = Pager::open_with_storage(data)?;
+        let wal = Wal::open_with_storage(wal, pager.checksum())?;
+        #[cfg(feature = "compression")]
+        let wal = wal.with_compression(opts.compress_wal);
+        Self::from_parts(None, wal, pager, 0, false, opts, &|_| {})
+    }
+
+    /// Open on `files`. A writer first takes the lock file, so a second writer fails with
+    /// `Locked` instead of interleaving its appends with ours; readers don't take it.
+    fn open_mode(files: &Files, read_only: bool, opts: Options, progress: &dyn Fn(ReplayProgress)) -> Result<Self> {
+        let mut lock = None;
+        let (wal, mut pager) = if read_only {
+            let wal = Wal::open_read_only(&files.wal)?;
+            (wal, Pager::open_read_only(&files.data)?)
+        } else {
+            if let Some(dir) = files.data.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            lock = Some(lock_file(&files.lock)?);
+            // the data file holds the checksum, so it goes first and the WAL follows it
+            let pager = Pager::open_with_checksum(&files.data, DEFAULT_CACHE_PAGES, opts.checksum)?;
+            let wal = Wal::open_with_checksum(&files.wal, opts.wal_segment_bytes, pager.checksum())?
+                .with_retention(opts.wal_retention);
+            #[cfg(feature = "compression")]
+            let wal = wal.with_compression(opts.compress_wal);
+            (wal, pager)
+        };
+        pager.claim_name(&files.name)?;
+        let ckpt_lsn = read_checkpoint(&files.ckpt)?;
+        let mut engine = Self::from_parts(Some(files.clone()), wal, pager, ckpt_lsn, read_only, opts, progress)?;
+        engine.lock = lock.map(Arc::new);
+        Ok(engine)
+    }
+
+    /// Rebuild the index from the pages, then replay the WAL on top.
+    fn from_parts(
+        files: Option<Files>,
+        wal: Wal,
+        mut pager: Pager,
+        ckpt_lsn: Lsn,
+        read_only: bool,
+        opts: Options,
+        progress: &dyn Fn(ReplayProgress),
+    ) -> Result<Self> {
+        let start = Instant::now();
+        pager.set_tolerate_partial_tail(opts.tolerate_partial_tail);
+        let ckpt_lsn = ckpt_lsn.max(replay_start(&wal)?.unwrap_or(0));
+        // the WAL may have been truncated empty; keep LSNs increasing past the checkpoint
+        wal.ensure_next_lsn(ckpt_lsn);
+        let wal = Arc::new(wal);
+        let pager = Arc::new(Mutex::new(pager));
+
+        // take the index saved by the last checkpoint if it's intact and current; otherwise
+        // reconstruct it by scanning all pages and reading kvs.
+        let snapshot = match &files {
+            Some(files) => read_index_snapshot(&files.index, ckpt_lsn)?,
+            None => None,
+        };
+        let now = now_millis();
+        let mut idx = Index::new(opts.key_hash);
+        let mut dead = BTreeSet::new();
+        let mut pages_scanned = 0;
+        let next_page;
         {
             let mut p = pager.lock().unwrap();
-            // naive scan: read pages until read_page returns Page::new (empty)
-            loop {
-                let page = p.read_page(next_page)?;
-                // if page is new (lsn==0 and used==0) break
-                if page.used == 0 && page.lsn == 0 {
-                    break;
+            if let Some((saved, saved_dead)) = snapshot {
+                idx = Index::from_map(saved, opts.key_hash);
+                // the snapshot may predate a later checkpoint (with no writes between) freeing some
+                dead = saved_dead.into_iter().filter(|&pid| !p.is_free(pid)).collect();
+            } else {
+                // pages that are empty, freed or holes are skipped, not taken as the end of the data
+                // order the data pages oldest first: every entry on a page was written before
+                // any entry on a page with a higher LSN. Pages sharing an LSN were filled by one
+                // batch (see `reserve_many`) and hold distinct keys, so their order doesn't matter.
+                let mut order = Vec::new();
+                for page in p.iter_pages() {
+                    let page = page?;
+                    pages_scanned += 1;
+                    // never written (lsn==0 and used==0)
+                    if page.used == 0 && page.lsn == 0 { continue; }
+                    order.push((page.lsn, page.id));
+                }
+                order.sort();
+                // without a directory there is no checkpoint file to carry the LSN counter
+                // past a WAL that checkpoints emptied; the pages still have it
+                if let Some(&(lsn, _)) = order.last() {
+                    wal.ensure_next_lsn(lsn + 1);
                 }
-                // parse kvs
-                let mut off = 0usize;
-                let payload = &page.data;
-                while off + 12 <= payload.len() {
-                    let key_len = u32::from_le_bytes(payload[off..off+4].try_into().unwrap()) as usize;
-                    let val_len = u32::from_le_bytes(payload[off+4..off+8].try_into().unwrap()) as usize;
-                    let total = 8 + key_len + val_len;
-                    if key_len == 0 || off + total > payload.len() { break; }
-                    let key = String::from_utf8_lossy(&payload[off+8..off+8+key_len]).to_string();
-                    // store location
-                    idx.insert(key, (next_page, off as u32, val_len as u32));
-                    off += total;
+                for &(_, pid) in &order {
+                    let page = p.read_page(pid)?;
+                    for e in page_entries(&page.data)? {
+                        let entry = IndexEntry { page_id: pid, offset: e.offset, expires_at: e.expires_at };
+                        // a delete, or a version that has since expired, hides older versions
+                        let old = if e.val_len == TOMBSTONE || entry.is_expired(now) {
+                            idx.remove(e.key, &mut *p)?
+                        } else {
+                            // store location
+                            idx.insert(e.key, entry, &mut *p)?
+                        };
+                        // the chain of a displaced overflow value isn't looked for: it may have
+                        // been freed and reused long ago. `compact` reclaims one that wasn't
+                        dead.extend(old.map(|o| o.page_id));
+                    }
                 }
-                next_page += 1;
             }
+            // keep appending where the superblock says the last writer left off
+            next_page = match p.next_page() {
+                META_PAGE if read_only => META_PAGE + 1,
+                META_PAGE => p.allocate_page()?,
+                pid => pid,
+            };
         }
 
-        let engine = Self {
+        let mut engine = Self {
+            files,
             wal,
             pager,
             index: Arc::new(Mutex::new(idx)),
             next_page: Arc::new(Mutex::new(next_page)),
+            dead_pages: Arc::new(Mutex::new(dead)),
+            snapshots: Arc::new(()),
+            rewriting: Arc::default(),
+            rewritten: Arc::default(),
+            writer: Arc::new(Mutex::new(())),
+            sync_policy: opts.sync,
+            max_key_size: opts.max_key_size,
+            max_value_size: opts.max_value_size,
+            update_in_place: opts.update_in_place,
+            #[cfg(feature = "compression")]
+            compress_values: opts.compress_values,
+            unsynced: Arc::new(Mutex::new(0)),
+            max_unckpt_bytes: opts.max_unckpt_bytes,
+            write_stall: opts.write_stall,
+            stall: Arc::new(Mutex::new(())),
+            checkpointed: Arc::new(Condvar::new()),
+            replayed: 0,
+            pages_scanned,
+            recovery_time: Duration::ZERO,
+            merge_op: Arc::new(Mutex::new(None)),
+            commit_hook: Arc::new(Mutex::new(None)),
+            commits: Arc::default(),
+            delivering: Arc::default(),
+            lock: None,
+            counters: Arc::default(),
+            opts,
         };
 
-        // Replay WAL from start to ensure we incorporate recent changes (recovery)
-        let mut walpath = dir.as_ref().to_path_buf();
-        walpath.push(WAL_FILE);
-        Wal::replay_from_start(&walpath, |lsn, payload| {
-            // decode payload: first 3 bytes are type ascii "SET"
-            if payload.len() < 3 { return Ok(()); }
-            let t = &payload[0..3];
-            if t == b"SET" {
-                // parse
-                let mut off = 3;
-                let page_id = u64::from_le_bytes(payload[off..off+8].try_into().unwrap()); off += 8;
-                let offset = u32::from_le_bytes(payload[off..off+4].try_into().unwrap()); off += 4;
-                let key_len = u32::from_le_bytes(payload[off..off+4].try_into().unwrap()) as usize; off += 4;
-                let val_len = u32::from_le_bytes(payload[off..off+4].try_into().unwrap()) as usize; off += 4;
-                let key = String::from_utf8_lossy(&payload[off..off+key_len]).to_string(); off += key_len;
-                let val = &payload[off..off+val_len];
-                // apply into pager
-                let mut pg = engine.pager.lock().unwrap();
-                // ensure page exists
-                let mut page = pg.read_page(page_id)?;
-                // write kv bytes into page.data at offset
-                let dest_off = offset as usize;
-                // re-encode the kv entry: key_len u32, val_len u32, key, val
-                let mut entry = Vec::with_capacity(8 + key_len + val_len);
-                entry.extend_from_slice(&(key_len as u32).to_le_bytes());
-                entry.extend_from_slice(&(val_len as u32).to_le_bytes());
-                entry.extend_from_slice(key.as_bytes());
-                entry.extend_from_slice(val);
-                page.data[dest_off..dest_off+entry.len()].copy_from_slice(&entry);
-                page.used = page.used.max((dest_off + entry.len()) as u32);
-                page.lsn = lsn;
-                pg.write_page(&page)?;
-                // update in-memory index
-                engine.index.lock().unwrap().insert(key, (page_id, dest_off as u32, val_len as u32));
-            }
-            Ok(())
+        // the last writer closed cleanly and nothing was logged since: the pages are complete.
+        // Any later write takes a new LSN, so a stale marker can never match.
+        if engine.pager.lock().unwrap().clean_shutdown_lsn() == Some(engine.wal.next_lsn()) {
+            engine.recovery_time = start.elapsed();
+            return Ok(engine);
+        }
+
+        // Replay WAL to incorporate changes made after the last checkpoint (recovery)
+        let mut replayed = 0;
+        engine.wal.replay_with_progress(progress, |lsn, payload| {
+            // already durable in pages; only present if we crashed mid-truncation
+            if lsn < ckpt_lsn { return Ok(()); }
+            // a checkpoint only marks where to start
+            if payload.starts_with(b"CKP") { return Ok(()); }
+            replayed += 1;
+            engine.replay_record(lsn, &payload)
         })?;
+        engine.replayed = replayed;
+        engine.recovery_time = start.elapsed();
 
-        // Note: no checkpointing on open here; in a real system you'd examine WAL LSN and pageLSNs.
         Ok(engine)
     }
 
+    /// Make every applied change durable in the data file, then discard the WAL records
+    /// that covered them. This is the only data-file fsync writes get: until it runs, every
+    /// page change since the last checkpoint is also in a WAL record at or above the
+    /// checkpoint LSN, written (and under `SyncPolicy::Always` fsynced) before the page, so a
+    /// crash that loses the page write is repaired by replaying that record. (Tearing a page
+    /// is another matter; see `Pager::open_with_double_write`.) Order matters for crash
+    /// safety:
+    /// 1) fsync the data file
+    /// 2) log a CKP record with the checkpoint LSN (all records below it are now redundant)
+    ///    as the first record of a new segment, and fsync it
+    /// 3) delete the older segments; if we crash before this, open finds the CKP record and
+    ///    just skips the old records
+    /// 4) free dead pages; no record that replay would still apply can target them now
+    /// 5) save the index, tagged with the checkpoint LSN, so the next open can skip the page
+    ///    scan. A snapshot left behind by a crash before this has an older LSN and is ignored.
+    pub fn checkpoint(&self) -> Result<()> {
+        let _w = self.lock_writer();
+        self.checkpoint_locked()
+    }
+
+    fn checkpoint_locked(&self) -> Result<()> {
+        self.check_writable()?;
+        self.pager.lock().unwrap().sync()?;
+        // every page is in the file now, so replay can start at the next record
+        let ckpt_lsn = self.wal.next_lsn();
+        self.wal.append_checkpoint(&encode_checkpoint(ckpt_lsn))?;
+        {
+            // wake writers stalled on the bytes just truncated
+            let _stall = self.stall.lock().unwrap();
+            self.checkpointed.notify_all();
+        }
+        self.free_dead_pages()?;
+        if let Some(files) = &self.files {
+            let idx = self.index.lock().unwrap();
+            match idx.as_map() {
+                Some(map) => write_index_snapshot(&files.index, ckpt_lsn, map, &self.dead_pages.lock().unwrap())?,
+                // a hashed index has no keys to save
+                None => remove_if_present(&files.index)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Checkpoint every `interval` on a thread of its own until the returned handle is
+    /// dropped or stopped, which runs one more. Each checkpoint takes the writer lock like
+    /// `checkpoint` does, so it slots in between writes rather than racing them. A failed
+    /// checkpoint is retried at the next tick; `CheckpointHandle::stop` reports it.
+    pub fn start_background_checkpoint(&self, interval: Duration) -> Result<CheckpointHandle> {
+        self.check_writable()?;
+        let (tx, rx) = mpsc::channel::<()>();
+        let db = self.clone();
+        let thread = std::thread::spawn(move || {
+            let mut first_err = None;
+            // nothing is ever sent: the handle dropping its sender is the stop signal
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                if let Err(e) = db.checkpoint() {
+                    first_err.get_or_insert(e);
+                }
+            }
+            let last = db.checkpoint();
+            first_err.map_or(last, Err)
+        });
+        Ok(CheckpointHandle { stop: Some(tx), thread: Some(thread) })
+    }
+
+    /// Shut down cleanly: checkpoint, then mark the superblock with the WAL position so the
+    /// next open can skip replay. Dropping an engine without calling this is treated like a
+    /// crash. Read-only engines just close.
+    pub fn close(self) -> Result<()> {
+        if self.pager.lock().unwrap().is_read_only() {
+            return Ok(());
+        }
+        let _w = self.lock_writer();
+        self.checkpoint_locked()?;
+        let lsn = self.wal.next_lsn();
+        self.pager.lock().unwrap().set_clean_shutdown(Some(lsn))
+    }
+
+    /// Let go of this engine and open its directory again with the same options, read-only
+    /// if it was, for recovery tests without a new process. With `clean` it is closed first,
+    /// so the new engine skips replay; without, it is dropped as it is, as a crash would
+    /// leave it, and the WAL is replayed. Any other clone must be gone by then, or the new
+    /// open finds the directory locked. The merge operator and commit hook don't carry over.
+    /// An in-memory engine has nothing to reopen from.
+    pub fn reopen(self, clean: bool) -> Result<Engine> {
+        let Some(files) = self.files.clone() else {
+            return Err(TinyDbError::Invalid("an in-memory engine can't be reopened".into()));
+        };
+        let (read_only, opts) = (self.pager.lock().unwrap().is_read_only(), self.opts);
+        if clean {
+            self.close()?;
+        } else {
+            drop(self);
+        }
+        Self::open_mode(&files, read_only, opts, &|_| {})
+    }
+
+    /// The options this engine was opened with.
+    pub fn options(&self) -> Options {
+        self.opts
+    }
+
+    /// How long opening took to rebuild the index and replay the WAL.
+    pub fn recovery_time(&self) -> Duration {
+        self.recovery_time
+    }
+
+    /// Number of WAL records applied when this engine was opened.
+    pub fn replayed_records(&self) -> usize {
+        self.replayed
+    }
+
+    /// Number of data pages read to rebuild the index when this engine was opened; 0 when
+    /// it was loaded from the checkpoint's index snapshot.
+    pub fn pages_scanned(&self) -> usize {
+        self.pages_scanned
+    }
+
+    /// Give pages that lost live entries back to the pager, if nothing on them is still
+    /// needed. A page is kept while the index points into it, and for good if it holds a
+    /// tombstone or an expiring entry: those hide older versions of their key when the index
+    /// is rebuilt, so dropping them could bring a deleted value back. Compaction reclaims those.
+    /// An overflow page is the first of a chain that lost its entry, and goes with the rest
+    /// of the chain unless the index still points at it: replay can displace an entry that
+    /// it then puts back.
+    fn free_dead_pages(&self) -> Result<()> {
+        if Arc::weak_count(&self.snapshots) > 0 {
+            // a snapshot may still read the old versions on these pages
+            return Ok(());
+        }
+        let candidates = std::mem::take(&mut *self.dead_pages.lock().unwrap());
+        if candidates.is_empty() {
+            return Ok(());
+        }
+        let current = *self.next_page.lock().unwrap();
+        let idx = self.index.lock().unwrap();
+        let referenced: HashSet<u64> = idx.values().map(|e| e.page_id).collect();
+        let mut p = self.pager.lock().unwrap();
+        // first pages of the chains still in use, only looked up if a chain is up for freeing
+        let mut chains = None;
+        for pid in candidates {
+            if pid == current {
+                // still being appended to; look again next time
+                self.dead_pages.lock().unwrap().insert(pid);
+                continue;
+            }
+            if referenced.contains(&pid) { continue; }
+            let page = p.read_page(pid)?;
+            if is_overflow_page(&page)? {
+                let chains = match &mut chains {
+                    Some(chains) => chains,
+                    None => chains.insert(live_chains(&idx, &mut p)?),
+                };
+                if !chains.contains(&pid) {
+                    free_chain(&mut p, page)?;
+                }
+                continue;
+            }
+            if page_entries(&page.data)?.iter().any(|e| e.val_len == TOMBSTONE || e.expires_at != 0) { continue; }
+            p.free_page(pid)?;
+        }
+        p.sync()
+    }
+
     /// single-writer SET. Steps:
     /// 1) find a page & offset to store kv (simple append)
     /// 2) build WAL payload describing SET with page/offset/key/val
     /// 3) append WAL -> get LSN
     /// 4) sync WAL (fsync)
     /// 5) apply to page in-memory and write page (lazy flush could be later; here we write immediately for simplicity)
-    pub fn set(&mut self, key: &str, val: &[u8]) -> anyhow::Result<()> {
+    ///
+    /// `val` may be empty: that is a stored value like any other, so `get` returns
+    /// `Some(vec![])` and `contains_key` true, unlike for a key never set.
+    pub fn set(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        let _w = self.lock_for_write()?;
+        self.set_expiring(key, val, 0)
+    }
+
+    /// SET whose key disappears once `ttl` has elapsed. Expired keys read as absent and
+    /// are dropped from the index lazily (on `get`) or when the index is rebuilt on open.
+    pub fn set_with_ttl(&self, key: &[u8], val: &[u8], ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64).max(1);
+        let _w = self.lock_for_write()?;
+        self.set_expiring(key, val, expires_at)
+    }
+
+    /// Set `key` to the `len` bytes read from `r`, for values that come from a file or a
+    /// socket. A value too long to share a page with its key goes to a chain of overflow
+    /// pages a page at a time: each page is logged (as an OVF record) and written as soon as
+    /// its chunk is read, and the entry pointing at the chain goes in last, so memory holds
+    /// one page of the value however long it is. Overflow values aren't compressed. `len`
+    /// is checked against `Options::max_value_size` before anything is read. A reader that
+    /// fails or runs dry leaves the key as it was; the pages it got to are freed at the next
+    /// checkpoint, or after a crash by the next `compact`.
+    pub fn set_reader(&self, key: &[u8], mut r: impl Read, len: usize) -> Result<()> {
+        let _w = self.lock_for_write()?;
+        self.check_writable()?;
+        self.check_sizes(key, len)?;
+        let cap = self.pager.lock().unwrap().page_size() - HDR_SZ;
+        if ENTRY_HDR_SZ + key.len() + len <= cap {
+            let mut val = vec![0; len];
+            r.read_exact(&mut val)?;
+            return self.set_expiring(key, &val, 0);
+        }
+        let first = self.pager.lock().unwrap().allocate_detached_page()?;
+        if let Err(e) = self.write_chain(first, r, len, cap - OVF_HDR_SZ) {
+            self.dead_pages.lock().unwrap().insert(first);
+            return Err(e);
+        }
+
+        let ptr = encode_pointer(first, len as u64);
+        let entry = encode_entry(key, OVF_PTR_SZ as u32 | VAL_OVERFLOW, 0, &ptr);
+        let (pid, off) = self.reserve(entry.len())?;
+        let lsn = self.wal.append(&encode_set(pid, off, key, &ptr, VAL_OVERFLOW, 0))?;
+        // covers the OVF records too
+        self.sync_wal(lsn)?;
+        self.apply_entry(pid, off, &entry, lsn)?;
+        let ie = IndexEntry { page_id: pid, offset: off as u32, expires_at: 0 };
+        let old = self.index.lock().unwrap().insert(key, ie, &*self.pager)?;
+        self.note_displaced(old)?;
+        if self.commit_hook.lock().unwrap().is_some() {
+            // the hook gets whole values, so this one is read back for it
+            let value = read_chain(&mut self.pager.lock().unwrap(), first, len as u64)?;
+            self.note_commits(lsn, || vec![CommitEvent::Set { key: key.to_vec(), value, expires_at: 0 }]);
+        }
+        self.counters.sets.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Log and write the overflow pages of a `len`-byte value read from `r`, `chunk_cap`
+    /// bytes to a page, starting at the page `first`. Each next page is allocated only once
+    /// the chunk before it is read.
+    fn write_chain(&self, first: PageId, mut r: impl Read, len: usize, chunk_cap: usize) -> Result<()> {
+        let mut chunk = vec![0; chunk_cap];
+        let (mut pid, mut left) = (first, len);
+        while left > 0 {
+            let n = chunk_cap.min(left);
+            r.read_exact(&mut chunk[..n])?;
+            left -= n;
+            let next = if left > 0 { self.pager.lock().unwrap().allocate_detached_page()? } else { 0 };
+            let lsn = self.wal.append(&encode_overflow(pid, next, &chunk[..n]))?;
+            self.apply_overflow(pid, next, &chunk[..n], lsn)?;
+            pid = next;
+        }
+        Ok(())
+    }
+
+    /// The body of `set`; the caller holds the writer lock.
+    fn set_expiring(&self, key: &[u8], val: &[u8], expires_at: u64) -> Result<()> {
+        self.check_writable()?;
+        self.check_sizes(key, val.len())?;
         // encode entry
-        let key_b = key.as_bytes();
-        let key_len = key_b.len();
-        let val_len = val.len();
-        let entry_len = 8 + key_len + val_len; // keylen+vallen header + key + val
+        let (stored, flags) = self.pack_value(val);
+        let entry = encode_entry(key, stored.len() as u32 | flags, expires_at, &stored);
+        if self.update_in_place && let Some(lsn) = self.update_entry(key, &entry, &stored, flags, expires_at)? {
+            self.note_commits(lsn, || vec![CommitEvent::Set { key: key.to_vec(), value: val.to_vec(), expires_at }]);
+            self.counters.sets.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
 
         // find page with enough space
-        let mut pid = *self.next_page.lock().unwrap();
-        let page = {
-            let mut p = self.pager.lock().unwrap();
-            let mut page = p.read_page(pid)?;
-            if (PAGE_SIZE - pager_hdr_sz()) < (page.used as usize + entry_len) {
-                // allocate new page
-                pid += 1;
-                *self.next_page.lock().unwrap() = pid;
-                page = Page::new(pid);
-            }
-            page
-        };
+        let (pid, off) = self.reserve(entry.len())?;
 
-        // offset where kv will be written
-        let off = page.used as usize;
         // craft WAL payload
-        // payload = b"SET" + page_id(8) + offset(4) + key_len(4) + val_len(4) + key + val
-        let mut payload = Vec::with_capacity(3 + 8 + 4 + 4 + 4 + key_len + val_len);
-        payload.extend_from_slice(b"SET");
-        payload.extend_from_slice(&pid.to_le_bytes());
-        payload.extend_from_slice(&(off as u32).to_le_bytes());
-        payload.extend_from_slice(&(key_len as u32).to_le_bytes());
-        payload.extend_from_slice(&(val_len as u32).to_le_bytes());
-        payload.extend_from_slice(key_b);
-        payload.extend_from_slice(val);
+        let payload = encode_set(pid, off, key, &stored, flags, expires_at);
 
         // append wal
         let lsn = self.wal.append(&payload)?;
-        self.wal.sync()?; // fsync the WAL before ack
+        self.sync_wal(lsn)?; // fsync the WAL before ack, as the policy asks
 
         // apply to page and write page to disk
-        {
-            let mut pgr = self.pager.lock().unwrap();
-            let mut page = pgr.read_page(pid)?;
-            // recompose entry
-            let mut entry = Vec::with_capacity(8 + key_len + val_len);
-            entry.extend_from_slice(&(key_len as u32).to_le_bytes());
-            entry.extend_from_slice(&(val_len as u32).to_le_bytes());
-            entry.extend_from_slice(key_b);
-            entry.extend_from_slice(val);
-            page.data[off..off+entry.len()].copy_from_slice(&entry);
-            page.used = (off + entry.len()) as u32;
-            page.lsn = lsn;
-            pgr.write_page(&page)?;
-            pgr.sync()?;
-            // update index
-            self.index.lock().unwrap().insert(key.to_string(), (pid, off as u32, val_len as u32));
-        }
+        self.apply_entry(pid, off, &entry, lsn)?;
+        // update index
+        let ie = IndexEntry { page_id: pid, offset: off as u32, expires_at };
+        let old = self.index.lock().unwrap().insert(key, ie, &*self.pager)?;
+        self.note_displaced(old)?;
+        self.note_commits(lsn, || vec![CommitEvent::Set { key: key.to_vec(), value: val.to_vec(), expires_at }]);
+        self.counters.sets.fetch_add(1, Ordering::Relaxed);
 
         Ok(())
     }
 
-    pub fn get(&mut self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
-        if let Some((pid, off, _val_len)) = self.index.lock().unwrap().get(key).cloned() {
-            let mut p = self.pager.lock().unwrap();
+    /// Overwrite the live entry of `key` with `entry` where it is, if that is safe: see
+    /// `Options::update_in_place`. Returns the LSN it was logged at if it did; if not, the
+    /// caller appends.
+    fn update_entry(&self, key: &[u8], entry: &[u8], stored: &[u8], flags: u32, expires_at: u64) -> Result<Option<Lsn>> {
+        if Arc::weak_count(&self.snapshots) > 0 {
+            return Ok(None);
+        }
+        let Some(e) = self.index.lock().unwrap().get(key, &*self.pager)? else { return Ok(None) };
+        // the append page has the highest LSN, so the index rebuild visits it last and the
+        // entries after this one, written later, still beat every other version of their key
+        if e.page_id != *self.next_page.lock().unwrap() {
+            return Ok(None);
+        }
+        let page = self.pager.lock().unwrap().read_page(e.page_id)?;
+        // overwriting an overflow entry would leave its chain with nothing to free it
+        if overflow_pointer(&page, e.offset)?.is_some() {
+            return Ok(None);
+        }
+        let slot = slot_len(&page, e.offset)?;
+        // what is left over has to hold a gap entry's header and at least one byte
+        if entry.len() > slot || (entry.len() < slot && slot - entry.len() <= ENTRY_HDR_SZ) {
+            return Ok(None);
+        }
+        let set = encode_set(e.page_id, e.offset as usize, key, stored, flags, expires_at);
+        let lsn = self.wal.append(&encode_update(slot, &set))?;
+        self.sync_wal(lsn)?;
+        self.apply_entry(e.page_id, e.offset as usize, &fill_slot(entry, slot), lsn)?;
+        self.index.lock().unwrap().insert(key, IndexEntry { expires_at, ..e }, &*self.pager)?;
+        Ok(Some(lsn))
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.counters.gets.fetch_add(1, Ordering::Relaxed);
+        let mut idx = self.index.lock().unwrap();
+        let e = match idx.get(key, &*self.pager)? {
+            Some(e) if e.is_expired(now_millis()) => {
+                idx.remove(key, &*self.pager)?;
+                None
+            }
+            e => e,
+        };
+        let Some(e) = e else { return Ok(None) };
+        // take the pager before letting go of the index, so `compact` can't move the entry
+        // away between finding it and reading it
+        let mut p = self.pager.lock().unwrap();
+        drop(idx);
+        Ok(Some(read_value(&mut p, e.page_id, e.offset)?))
+    }
+
+    /// The value of `key` as a reader, the counterpart of `set_reader`. A value in overflow
+    /// pages is read from them a page at a time as the reader is read, so it is never in
+    /// memory whole; any other is read with its entry. See `ValueReader` for what the reader
+    /// holds off while it lives.
+    pub fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader>> {
+        self.counters.gets.fetch_add(1, Ordering::Relaxed);
+        let pin = self.pin();
+        let idx = self.index.lock().unwrap();
+        let Some(e) = idx.get(key, &*self.pager)?.filter(|e| !e.is_expired(now_millis())) else { return Ok(None) };
+        // as in `get`, the pager is taken before the index is let go
+        let mut p = self.pager.lock().unwrap();
+        drop(idx);
+        let page = p.read_page(e.page_id)?;
+        let (chunk, next, len) = match overflow_pointer(&page, e.offset)? {
+            Some((first, len)) => (Vec::new(), first, len),
+            None => {
+                let val = entry_value(&mut p, &page, e.offset)?;
+                let len = val.len() as u64;
+                (val, 0, len)
+            }
+        };
+        let left = len - chunk.len() as u64;
+        Ok(Some(ValueReader { pager: Arc::clone(&self.pager), chunk, pos: 0, next, left, len, _pin: pin }))
+    }
+
+    /// Like `get`, also returning where the value lives: its page, its offset in the page's
+    /// data region, and the page's LSN. That is the LSN of the last write to the page, so it
+    /// is at or after the WAL record that wrote this key, for correlating keys with the log.
+    pub fn get_with_metadata(&self, key: &[u8]) -> Result<Option<ValueMetadata>> {
+        let idx = self.index.lock().unwrap();
+        let entry = idx.get(key, &*self.pager)?.filter(|e| !e.is_expired(now_millis()));
+        let Some(e) = entry else { return Ok(None) };
+        // as in `get`, the pager is taken before the index is let go
+        let mut p = self.pager.lock().unwrap();
+        drop(idx);
+        let page = p.read_page(e.page_id)?;
+        Ok(Some(ValueMetadata { value: entry_value(&mut p, &page, e.offset)?, page_id: e.page_id, offset: e.offset, lsn: page.lsn }))
+    }
+
+    /// The value `key` had as of `lsn`: after every WAL record up to and including `lsn`
+    /// was applied. Old versions are found by reading the WAL from its oldest record, so
+    /// this costs a scan of the log and only reaches back as far as the log does: if `key`
+    /// changed after `lsn` and no earlier record of it is left, that history went with a
+    /// checkpoint and this fails. A key no record touches has its current value. TTLs are
+    /// ignored for versions taken from the log. A value in overflow pages (see `set_reader`)
+    /// is only in the WAL as a pointer to its chain, and a chain is freed once its value is
+    /// overwritten, so such a version is an error unless it is still the current one.
+    pub fn get_as_of(&self, key: &[u8], lsn: Lsn) -> Result<Option<Vec<u8>>> {
+        // the version's record and its value as stored; unpacked once it is the answer
+        let mut found: Option<Option<(Lsn, Vec<u8>, u32)>> = None;
+        let unpack = |version: Option<(Lsn, Vec<u8>, u32)>| version.map(|(at, val, flags)| {
+            if flags & VAL_OVERFLOW != 0 {
+                return Err(TinyDbError::Invalid(format!(
+                    "the version of the key at LSN {} was in overflow pages, which are gone", at
+                )));
+            }
+            unpack_value(val, flags, format_args!("WAL record {}", at))
+        }).transpose();
+        // whether the log still starts at LSN 0, so that nothing before `found` is missing
+        let mut whole = None;
+        for rec in self.wal.records_from(0) {
+            let (rec_lsn, payload) = rec?;
+            let whole = *whole.get_or_insert(rec_lsn == 0);
+            let Some(effect) = record_effect(&payload, key)? else { continue };
+            if rec_lsn > lsn {
+                if found.is_none() && !whole {
+                    return Err(TinyDbError::Invalid(format!(
+                        "history of the key as of LSN {} is no longer in the WAL (oldest record of it is {})", lsn, rec_lsn
+                    )));
+                }
+                return unpack(found.flatten());
+            }
+            found = Some(effect.map(|op| (rec_lsn, op.val.to_vec(), op.flags)));
+        }
+        match found {
+            // the last version logged is the current one, so its chain is still there
+            Some(Some((_, _, flags))) if flags & VAL_OVERFLOW != 0 => self.get(key),
+            Some(version) => unpack(version),
+            None => self.get(key),
+        }
+    }
+
+    /// Look up several keys at once, reading each page they live on only once. The result
+    /// lines up with `keys`; absent (or expired) keys are `None`.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.counters.gets.fetch_add(keys.len() as u64, Ordering::Relaxed);
+        let mut out = vec![None; keys.len()];
+        // page -> (position in keys, entry offset)
+        let mut by_page: BTreeMap<u64, Vec<(usize, u32)>> = BTreeMap::new();
+        let now = now_millis();
+        let idx = self.index.lock().unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            if let Some(e) = idx.get(key, &*self.pager)?.filter(|e| !e.is_expired(now)) {
+                by_page.entry(e.page_id).or_default().push((i, e.offset));
+            }
+        }
+        // as in `get`, the pager is taken before the index is let go
+        let mut p = self.pager.lock().unwrap();
+        drop(idx);
+        for (pid, wanted) in by_page {
             let page = p.read_page(pid)?;
-            let off = off as usize;
-            let key_len = u32::from_le_bytes(page.data[off..off+4].try_into().unwrap()) as usize;
-            let val_len = u32::from_le_bytes(page.data[off+4..off+8].try_into().unwrap()) as usize;
-            let val_start = off + 8 + key_len;
-            let val = page.data[val_start..val_start+val_len].to_vec();
-            return Ok(Some(val));
+            for (i, off) in wanted {
+                out[i] = Some(entry_value(&mut p, &page, off)?);
+            }
         }
-        Ok(None)
+        Ok(out)
+    }
+
+    /// Capture the current contents for consistent reads while writes go on. Costs a copy
+    /// of the index; dead pages are kept from reuse, and `compact` refused, until every
+    /// snapshot is dropped.
+    pub fn snapshot(&self) -> Snapshot {
+        let pin = self.pin();
+        let now = now_millis();
+        let mut index = self.index.lock().unwrap().clone();
+        index.retain(|e| !e.is_expired(now));
+        Snapshot { index, pager: self.pager.clone(), _pin: pin }
+    }
+
+    /// Keep the pages as they are for a reader that will read from entries it captures from
+    /// the index next: while the returned pin lives no page is freed or rewritten, and
+    /// `compact` and `clear` are refused. Waits out a compaction or clear under way, since
+    /// the entries would move under the reader.
+    fn pin(&self) -> Weak<()> {
+        let mut rewriting = self.rewriting.lock().unwrap();
+        while *rewriting {
+            rewriting = self.rewritten.wait(rewriting).unwrap();
+        }
+        Arc::downgrade(&self.snapshots)
+    }
+
+    /// Mark the pages as being rewritten by `what` (a compaction or clear) until the guard
+    /// drops, unless something is pinned. Checked and marked under one lock, so no pin can
+    /// slip in between.
+    fn begin_rewrite(&self, what: &str) -> Result<RewriteGuard<'_>> {
+        let mut rewriting = self.rewriting.lock().unwrap();
+        if Arc::weak_count(&self.snapshots) > 0 {
+            return Err(TinyDbError::Invalid(format!("cannot {} while a snapshot, cursor or scan is open", what)));
+        }
+        *rewriting = true;
+        Ok(RewriteGuard { engine: self })
+    }
+
+    /// Count live keys and bytes. Reads every data page, so it costs a full scan.
+    pub fn stats(&self) -> Result<Stats> {
+        let now = now_millis();
+        let idx = self.index.lock().unwrap();
+        let mut p = self.pager.lock().unwrap();
+        let mut stats = Stats { pages: p.page_count(), ..Stats::default() };
+        let mut live_by_page: BTreeMap<u64, Vec<u32>> = BTreeMap::new();
+        for e in idx.values().filter(|e| !e.is_expired(now)) {
+            stats.live_keys += 1;
+            live_by_page.entry(e.page_id).or_default().push(e.offset);
+        }
+        for page in p.iter_pages() {
+            let page = page?;
+            stats.used_bytes += page.used as u64;
+            for &off in live_by_page.get(&page.id).into_iter().flatten() {
+                stats.live_bytes += entry_len(&page, off)? as u64;
+                if let Some((_, len)) = overflow_pointer(&page, off)? {
+                    stats.live_bytes += len;
+                }
+            }
+        }
+        let file_bytes = p.page_count() * p.page_size() as u64;
+        stats.dead_bytes = file_bytes.saturating_sub(stats.live_bytes);
+        Ok(stats)
     }
+
+    /// Cross-check the in-memory index against the pages: every entry must point at the
+    /// start of an entry on its page, inside the page's used bytes, that is a value (not a
+    /// tombstone) for the same key with the same expiry, and whose overflow chain, if its
+    /// value has one, links up page by page. A hashed index has no keys to compare, so there
+    /// the key found on the page must lead back to the same entry.
+    /// Returns one line per problem, empty if the index and pages agree; an unreadable page
+    /// is reported rather than returned as an error.
+    pub fn check_consistency(&self) -> Result<Vec<String>> {
+        let idx = self.index.lock().unwrap();
+        let mut p = self.pager.lock().unwrap();
+        let show = |k: &[u8]| format!("{:?}", String::from_utf8_lossy(k));
+        let entries: Vec<(Option<&[u8]>, IndexEntry)> = match idx.as_map() {
+            Some(map) => map.iter().map(|(k, e)| (Some(&k[..]), *e)).collect(),
+            None => idx.values().map(|e| (None, *e)).collect(),
+        };
+        let mut problems = Vec::new();
+        for (key, e) in entries {
+            let at = format!("page {} offset {}", e.page_id, e.offset);
+            let name = key.map_or_else(|| format!("entry at {}", at), |k| format!("key {}", show(k)));
+            let page = match p.read_page(e.page_id) {
+                Ok(page) => page,
+                Err(err) => {
+                    problems.push(format!("{}: cannot read page {}: {}", name, e.page_id, err));
+                    continue;
+                }
+            };
+            let used = (page.used as usize).min(page.data.len());
+            let raw = match page_entries(&page.data[..used]) {
+                Ok(entries) => entries.into_iter().find(|r| r.offset == e.offset),
+                Err(err) => {
+                    problems.push(format!("{}: page {} is damaged: {}", name, e.page_id, err));
+                    continue;
+                }
+            };
+            let Some(raw) = raw else {
+                problems.push(format!("{}: no entry starts at {}", name, at));
+                continue;
+            };
+            if raw.val_len == TOMBSTONE {
+                problems.push(format!("{}: {} is a tombstone", name, at));
+            } else if key.is_some_and(|k| k != raw.key) {
+                problems.push(format!("{}: {} holds key {}", name, at, show(raw.key)));
+            } else if raw.expires_at != e.expires_at {
+                problems.push(format!("{}: expires at {} on the page, {} in the index", name, raw.expires_at, e.expires_at));
+            } else if let Err(err) = check_chain(&mut p, &page, e.offset) {
+                problems.push(format!("{}: its overflow chain is damaged: {}", name, err));
+            } else if key.is_none() {
+                let key = raw.key.to_vec();
+                let back = idx.get(&key, &mut *p)?;
+                if back.is_none_or(|b| (b.page_id, b.offset) != (e.page_id, e.offset)) {
+                    problems.push(format!("{}: its key {} is not indexed there", name, show(&key)));
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Page cache counters of the underlying pager; every page read is a hit or a miss.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.pager.lock().unwrap().cache_stats()
+    }
+
+    /// Whether `key` currently has a value. Answered from the in-memory index alone,
+    /// without reading any page, unless the index is hashed (see `Options::key_hash`).
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.index.lock().unwrap().get(key, &*self.pager)?.is_some_and(|e| !e.is_expired(now_millis())))
+    }
+
+    /// Every live key, in order, taken from the index without reading any value.
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        let now = now_millis();
+        let idx = self.index.lock().unwrap();
+        Ok(idx.range(Bound::Unbounded, Bound::Unbounded, &*self.pager)?
+            .filter(|(_, e)| !e.is_expired(now))
+            .map(|(k, _)| k.into_owned())
+            .collect())
+    }
+
+    /// Number of live keys. Expired keys still in the index are skipped, so this walks the
+    /// whole index rather than returning its size.
+    pub fn len(&self) -> usize {
+        let now = now_millis();
+        self.index.lock().unwrap().values().filter(|e| !e.is_expired(now)).count()
+    }
+
+    /// Whether there are no live keys.
+    pub fn is_empty(&self) -> bool {
+        let now = now_millis();
+        !self.index.lock().unwrap().values().any(|e| !e.is_expired(now))
+    }
+
+    /// Apply `ops` all-or-nothing. The whole batch is one WAL record ending in a commit
+    /// marker, so one fsync covers every op and recovery either sees all of them or none.
+    /// Deletes of keys that don't exist (at that point in the batch) are skipped. Every op is
+    /// checked against the size limits, and against the page it has to fit in, before anything
+    /// is logged; the entries are then placed as `set` places one, spilling onto new pages.
+    pub fn write_batch(&self, ops: &[BatchOp]) -> Result<()> {
+        let _w = self.lock_for_write()?;
+        self.write_batch_locked(ops)
+    }
+
+    fn write_batch_locked(&self, ops: &[BatchOp]) -> Result<()> {
+        self.check_writable()?;
+        // resolve deletes against the index plus the batch's own earlier ops
+        let mut present: BTreeMap<&[u8], bool> = BTreeMap::new();
+        let mut entries = Vec::with_capacity(ops.len());
+        {
+            let idx = self.index.lock().unwrap();
+            let now = now_millis();
+            for op in ops {
+                match *op {
+                    BatchOp::Set(key, val) => {
+                        self.check_sizes(key, val.len())?;
+                        present.insert(key, true);
+                        let (stored, flags) = self.pack_value(val);
+                        let entry = encode_entry(key, stored.len() as u32 | flags, 0, &stored);
+                        entries.push((key, Some((stored, flags)), entry));
+                    }
+                    BatchOp::Delete(key) => {
+                        let exists = match present.get(key) {
+                            Some(&exists) => exists,
+                            None => idx.get(key, &*self.pager)?.is_some_and(|e| !e.is_expired(now)),
+                        };
+                        if exists {
+                            present.insert(key, false);
+                            entries.push((key, None, encode_entry(key, TOMBSTONE, 0, &[])));
+                        }
+                    }
+                }
+            }
+        }
+        // keep only the last op per key: the batch's pages share one LSN, so a rebuild can
+        // only order two versions of a key from the same batch if they share a page
+        let last: HashMap<&[u8], usize> = entries.iter().enumerate().map(|(i, (key, _, _))| (*key, i)).collect();
+        let entries: Vec<_> = entries.into_iter().enumerate()
+            .filter(|(i, (key, _, _))| last[key] == *i)
+            .map(|(_, e)| e)
+            .collect();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let lens: Vec<usize> = entries.iter().map(|(_, _, e)| e.len()).collect();
+        let slots = self.reserve_many(&lens)?;
+
+        let lsn = self.wal.append(&encode_batch(&entries, &slots))?;
+        self.sync_wal(lsn)?;
+
+        self.apply_entries(entries.iter().zip(&slots).map(|((_, _, entry), &(pid, off))| (pid, off, &entry[..])), lsn)?;
+        self.index_batch(&entries, &slots)?;
+        self.note_commits(lsn, || {
+            // the values as given, not as packed: each key's last set in `ops`
+            let vals: HashMap<&[u8], &[u8]> = ops.iter().filter_map(|op| match *op {
+                BatchOp::Set(key, val) => Some((key, val)),
+                BatchOp::Delete(_) => None,
+            }).collect();
+            entries.iter().map(|(key, val, _)| commit_event(key, val.as_ref().map(|_| vals[key]), 0)).collect()
+        });
+        Ok(())
+    }
+
+    /// Set every `(key, value)` of `items`, in order, paying for one fsync in all instead of
+    /// one per op: every entry is placed up front, the WAL records are appended unsynced and
+    /// fsynced once, then the pages are written. The items
+    /// are logged as batches of `BULK_CHUNK` sets, each all-or-nothing, so a crash before
+    /// the load returns recovers a prefix of it: whole chunks, in order. Keys repeated in
+    /// `items` keep their last value. The items pass through memory before any page is
+    /// written, as with `compact`. Returns the number of items loaded.
+    pub fn bulk_load<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, items: impl IntoIterator<Item = (K, V)>) -> Result<usize> {
+        let _w = self.lock_for_write()?;
+        self.check_writable()?;
+        let items: Vec<(K, V)> = items.into_iter().collect();
+        let mut entries = Vec::with_capacity(items.len());
+        for (key, val) in &items {
+            let (key, val) = (key.as_ref(), val.as_ref());
+            self.check_sizes(key, val.len())?;
+            let (stored, flags) = self.pack_value(val);
+            let entry = encode_entry(key, stored.len() as u32 | flags, 0, &stored);
+            entries.push((key, Some((stored, flags)), entry));
+        }
+        // keep each key's last version, as write_batch does: versions sharing a chunk share an LSN
+        let last: HashMap<&[u8], usize> = entries.iter().enumerate().map(|(i, (key, _, _))| (*key, i)).collect();
+        let entries: Vec<_> = entries.into_iter().enumerate()
+            .filter(|(i, (key, _, _))| last[key] == *i)
+            .map(|(_, e)| e)
+            .collect();
+        if entries.is_empty() {
+            return Ok(items.len());
+        }
+        let lens: Vec<usize> = entries.iter().map(|(_, _, e)| e.len()).collect();
+        let slots = self.reserve_many(&lens)?;
+
+        let mut lsns = Vec::new();
+        for (chunk, chunk_slots) in entries.chunks(BULK_CHUNK).zip(slots.chunks(BULK_CHUNK)) {
+            lsns.push(self.wal.append(&encode_batch(chunk, chunk_slots))?);
+        }
+        *self.unsynced.lock().unwrap() = 0;
+        self.wal.sync()?;
+
+        for ((chunk, chunk_slots), &lsn) in entries.chunks(BULK_CHUNK).zip(slots.chunks(BULK_CHUNK)).zip(&lsns) {
+            self.apply_entries(chunk.iter().zip(chunk_slots).map(|((_, _, entry), &(pid, off))| (pid, off, &entry[..])), lsn)?;
+        }
+        self.index_batch(&entries, &slots)?;
+        let vals: HashMap<&[u8], &[u8]> = items.iter().map(|(key, val)| (key.as_ref(), val.as_ref())).collect();
+        for (chunk, lsn) in entries.chunks(BULK_CHUNK).zip(lsns) {
+            self.note_commits(lsn, || chunk.iter().map(|(key, _, _)| commit_event(key, Some(vals[key]), 0)).collect());
+        }
+        Ok(items.len())
+    }
+
+    /// Point the index at a batch's entries, now in their `slots` on the pages.
+    fn index_batch(&self, entries: &[BatchEntry], slots: &[(u64, usize)]) -> Result<()> {
+        let mut idx = self.index.lock().unwrap();
+        for ((key, val, _), &(pid, off)) in entries.iter().zip(slots) {
+            let (old, counter) = match val {
+                Some(_) => (idx.insert(key, IndexEntry { page_id: pid, offset: off as u32, expires_at: 0 }, &*self.pager)?, &self.counters.sets),
+                None => (idx.remove(key, &*self.pager)?, &self.counters.deletes),
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            self.note_displaced(old)?;
+        }
+        Ok(())
+    }
+
+    /// Reclaim the space of overwritten and deleted entries: live entries are rewritten
+    /// packed into pages 1..n, in key order, and the data file is truncated to n pages.
+    /// Crash safety comes from the WAL: after a checkpoint, one committed CMP record holding
+    /// every live entry is fsynced before any page is touched, and replaying it redoes the
+    /// whole rewrite. A final checkpoint drops that record once the new pages are durable.
+    /// The live data passes through memory and the WAL, values in overflow pages included,
+    /// so this is meant for modest sizes.
+    ///
+    /// Reads go on meanwhile, each blocked for at most one page write or index swap. The
+    /// packed pages are first written past the end of the file, where nothing live is, and
+    /// the index swapped over to them; only then are pages 1..n overwritten with the same
+    /// entries, the index swapped back and the copies cut off. A read that found its entry
+    /// before a swap holds the pager until it is done, so the page it reads is never one
+    /// being rewritten. Snapshots, cursors and scans read later from entries they capture,
+    /// so compaction is refused while one is open, and one started meanwhile waits for it.
+    pub fn compact(&self) -> Result<()> {
+        let _w = self.lock_writer();
+        self.check_writable()?;
+        let _r = self.begin_rewrite("compact")?;
+        self.checkpoint_locked()?;
+        let now = now_millis();
+        // the writer lock keeps the pages still, so the index only needs holding to list them
+        let entries: Vec<(Vec<u8>, IndexEntry)> = {
+            let idx = self.index.lock().unwrap();
+            idx.range(Bound::Unbounded, Bound::Unbounded, &*self.pager)?
+                .filter(|(_, e)| !e.is_expired(now))
+                .map(|(k, e)| (k.into_owned(), e))
+                .collect()
+        };
+        let cap = self.pager.lock().unwrap().page_size() - HDR_SZ;
+        // overflow values are chained again from page 1 on, the entries packed after them, so
+        // the last page is always one appends can go to
+        let mut next = META_PAGE + 1;
+        let mut ops = Vec::new();
+        let mut live = Vec::with_capacity(entries.len());
+        for (k, e) in entries {
+            // values move as stored, compressed or not
+            let mut p = self.pager.lock().unwrap();
+            let page = p.read_page(e.page_id)?;
+            let (mut stored, flags) = stored_value(&page, e.offset)?;
+            if let Some((first, len)) = overflow_pointer(&page, e.offset)? {
+                let val = read_chain(&mut p, first, len)?;
+                let chunks = val.len().div_ceil(cap - OVF_HDR_SZ) as u64;
+                for (i, chunk) in val.chunks(cap - OVF_HDR_SZ).enumerate() {
+                    let link = if i as u64 + 1 < chunks { next + 1 } else { 0 };
+                    ops.push(encode_overflow(next, link, chunk));
+                    next += 1;
+                }
+                stored = encode_pointer(next - chunks, len);
+            }
+            live.push((k, stored, flags, e.expires_at));
+        }
+        let lens: Vec<usize> = live.iter().map(|(k, v, _, _)| ENTRY_HDR_SZ + k.len() + v.len()).collect();
+        let (slots, last_pid) = place_entries(next, 0, cap, &lens, || { next += 1; Ok(next) })?;
+        // always keep at least one (possibly empty) data page to append to
+        let page_count = last_pid + 1;
+
+        ops.extend(live.iter().zip(&slots)
+            .map(|((k, v, flags, exp), &(pid, off))| encode_set(pid, off, k, v, *flags, *exp)));
+        let mut payload = b"CMP".to_vec();
+        payload.extend_from_slice(&page_count.to_le_bytes());
+        payload.extend_from_slice(&encode_ops(&ops));
+        payload.extend_from_slice(BATCH_COMMIT);
+        // fsynced whatever the sync policy: the rewrite below destroys the old layout
+        let lsn = self.wal.append(&payload)?;
+        self.wal.sync_to(lsn + 1)?;
+
+        let ops: Vec<&[u8]> = ops.iter().map(|op| op.as_slice()).collect();
+        // the copies go past both the old pages and the packed ones; they aren't logged,
+        // since replaying the CMP record goes straight to pages 1..n and cuts them off
+        let copy_first = self.pager.lock().unwrap().page_count().max(page_count);
+        let hash = self.index.lock().unwrap().key_hash();
+        let (pages, idx) = compacted_pages(lsn, page_count, &ops, copy_first, cap + HDR_SZ, hash)?;
+        for page in &pages {
+            self.pager.lock().unwrap().write_page(page)?;
+        }
+        *self.index.lock().unwrap() = idx;
+        self.apply_compaction(lsn, page_count, &ops)?;
+        self.checkpoint_locked()
+    }
+
+    /// Delete every key at once and shrink the data file back to the superblock and one
+    /// empty data page, as a new database has. A committed CLR record is fsynced first, and
+    /// replaying it drops everything logged before it, so a crash midway recovers an empty
+    /// database too; a checkpoint then drops the log. Like `compact`, it is refused while a
+    /// snapshot is open.
+    pub fn clear(&self) -> Result<()> {
+        let _w = self.lock_writer();
+        self.check_writable()?;
+        let _r = self.begin_rewrite("clear")?;
+        // fsynced whatever the sync policy, as for CMP: the pages are about to go
+        let lsn = self.wal.append(b"CLR")?;
+        self.wal.sync_to(lsn + 1)?;
+        self.apply_compaction(lsn, META_PAGE + 2, &[])?;
+        self.note_commits(lsn, || vec![CommitEvent::Clear]);
+        self.checkpoint_locked()
+    }
+
+    /// `compact`, but only when `stats` says more than `threshold` of the data file is dead
+    /// space (`dead_bytes` over the file size), and compacting could give back at least one
+    /// page: a small database is mostly page headers, tails and the superblock, which would
+    /// otherwise clear any threshold every time. Returns whether it compacted.
+    pub fn compact_if_needed(&self, threshold: f64) -> Result<bool> {
+        self.check_writable()?;
+        let stats = self.stats()?;
+        let total = stats.live_bytes + stats.dead_bytes;
+        if total == 0 || (stats.dead_bytes as f64 / total as f64) <= threshold {
+            return Ok(false);
+        }
+        let cap = (self.pager.lock().unwrap().page_size() - HDR_SZ) as u64;
+        // the superblock plus the fewest data pages the live entries pack into
+        let packed_pages = 1 + stats.live_bytes.div_ceil(cap).max(1);
+        if stats.pages <= packed_pages {
+            return Ok(false);
+        }
+        self.compact()?;
+        Ok(true)
+    }
+
+    /// Set `key` to `new` only if its current value equals `expected` (`None` = key must be
+    /// absent). Returns whether the swap happened. The writer lock is held for the whole
+    /// read-compare-write, so no other write can slip in between.
+    pub fn compare_and_swap(&self, key: &[u8], expected: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        let _w = self.lock_for_write()?;
+        self.check_writable()?;
+        let current = self.get(key)?;
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        self.set_expiring(key, new, 0)?;
+        Ok(true)
+    }
+
+    /// Set `key` to `val` only if it has no live value, for idempotent initialization.
+    /// Returns whether it inserted. Like `compare_and_swap` with `None` expected: the check
+    /// and the write happen under one hold of the writer lock, so of several racing calls
+    /// exactly one wins.
+    pub fn set_if_absent(&self, key: &[u8], val: &[u8]) -> Result<bool> {
+        let _w = self.lock_for_write()?;
+        self.check_writable()?;
+        if self.contains_key(key)? {
+            return Ok(false);
+        }
+        self.set_expiring(key, val, 0)?;
+        Ok(true)
+    }
+
+    /// Register the function `merge` combines values with, replacing any earlier one. It is
+    /// not persisted: register it again after every open.
+    /// Shared by every clone of this engine.
+    pub fn set_merge_operator(&self, f: impl Fn(Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static) {
+        *self.merge_op.lock().unwrap() = Some(Arc::new(f));
+    }
+
+    /// Register `hook` to be told of every set and delete once it is durable, i.e. once the
+    /// WAL fsync covering its record is done, replacing any earlier hook. That is as soon as
+    /// the write returns under `SyncPolicy::Always`; under `EveryN` and `Never` events wait
+    /// for the next fsync, by a later write, `flush` or a checkpoint. Each op of a batch or
+    /// `bulk_load` is its own event, sharing its record's LSN, and `clear` is one `Clear`
+    /// event. Compaction, which changes no value, isn't reported. Not persisted, and writes
+    /// made before it is set are never reported.
+    ///
+    /// Events arrive one at a time and in LSN order, on whichever writing thread finds them
+    /// durable, after it has let go of every engine lock. So the hook may read and write the
+    /// engine: its own writes are queued like any other and handed to it once it returns,
+    /// rather than deadlocking. It should be quick, as the write it runs on waits for it.
+    /// A `set_reader` value is read back whole for its event, so with a hook set a value in
+    /// overflow pages does pass through memory. Shared by every clone of this engine.
+    pub fn set_commit_hook(&self, hook: impl Fn(Lsn, &CommitEvent) + Send + Sync + 'static) {
+        *self.commit_hook.lock().unwrap() = Some(Arc::new(hook));
+    }
+
+    /// Queue the events of the write logged at `lsn` for the commit hook, if there is one.
+    /// The caller holds the writer lock, so the queue stays in LSN order.
+    fn note_commits(&self, lsn: Lsn, events: impl FnOnce() -> Vec<CommitEvent>) {
+        if self.commit_hook.lock().unwrap().is_none() {
+            return;
+        }
+        self.commits.lock().unwrap().extend(events().into_iter().map(|ev| (lsn, ev)));
+    }
+
+    /// Hand the queued events that are now durable to the commit hook. Runs with no other
+    /// engine lock held; if another thread (or a hook on this one) is already delivering, it
+    /// goes on to pick up what was queued, so this returns at once.
+    fn deliver_commits(&self) {
+        loop {
+            let Ok(delivering) = self.delivering.try_lock() else { return };
+            let Some(hook) = self.commit_hook.lock().unwrap().clone() else { return };
+            loop {
+                let synced = self.wal.synced_lsn();
+                let next = {
+                    let mut q = self.commits.lock().unwrap();
+                    match q.front() {
+                        Some(&(lsn, _)) if lsn < synced => q.pop_front(),
+                        _ => None,
+                    }
+                };
+                let Some((lsn, ev)) = next else { break };
+                hook(lsn, &ev);
+            }
+            drop(delivering);
+            // an event queued after the last look, by a writer that found `delivering` taken
+            let synced = self.wal.synced_lsn();
+            if self.commits.lock().unwrap().front().is_none_or(|&(lsn, _)| lsn >= synced) {
+                return;
+            }
+        }
+    }
+
+    /// Replace the value of `key` with the merge operator applied to it and `operand`,
+    /// e.g. to append to a list without a separate `get`. Like `compare_and_swap`, the writer
+    /// lock keeps the read-merge-write atomic, so merges on the same key apply one after another.
+    /// The WAL records the merged value as a plain SET, so replay doesn't need the operator.
+    /// An error if no operator is registered.
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<()> {
+        let _w = self.lock_for_write()?;
+        self.check_writable()?;
+        let Some(f) = self.merge_op.lock().unwrap().clone() else {
+            return Err(TinyDbError::Invalid("no merge operator registered".into()));
+        };
+        let current = self.get(key)?;
+        let merged = f(current.as_deref(), operand);
+        self.set_expiring(key, &merged, 0)
+    }
+
+    /// Add `delta` to the counter stored at `key` (a little-endian i64, 0 when absent) and
+    /// return the new value. Like `compare_and_swap`, the writer lock keeps the read-add-write
+    /// atomic; the write goes through the normal WAL path. A value that isn't 8 bytes, or an
+    /// overflow, is an error and leaves the counter unchanged.
+    pub fn increment(&self, key: &[u8], delta: i64) -> Result<i64> {
+        let _w = self.lock_for_write()?;
+        self.check_writable()?;
+        let current = match self.get(key)? {
+            Some(v) => i64::from_le_bytes(fixed_width(&v)?),
+            None => 0,
+        };
+        let new = current.checked_add(delta).ok_or_else(|| TinyDbError::Invalid("counter overflow".into()))?;
+        self.set_expiring(key, &new.to_le_bytes(), 0)?;
+        Ok(new)
+    }
+
+    /// Store `v` at `key` as a little-endian i64, the encoding `get_i64` and `increment` read.
+    pub fn set_i64(&self, key: &[u8], v: i64) -> Result<()> {
+        self.set(key, &v.to_le_bytes())
+    }
+
+    /// The i64 stored at `key` by `set_i64` or `increment`. A value that isn't 8 bytes is a
+    /// `BadValueLength` error.
+    pub fn get_i64(&self, key: &[u8]) -> Result<Option<i64>> {
+        self.get(key)?.map(|v| Ok(i64::from_le_bytes(fixed_width(&v)?))).transpose()
+    }
+
+    /// Store `v` at `key` as a little-endian IEEE 754 f64. Every bit pattern round-trips,
+    /// NaN payloads included.
+    pub fn set_f64(&self, key: &[u8], v: f64) -> Result<()> {
+        self.set(key, &v.to_le_bytes())
+    }
+
+    /// The f64 stored at `key` by `set_f64`; like `get_i64`, 8 bytes or an error.
+    pub fn get_f64(&self, key: &[u8]) -> Result<Option<f64>> {
+        self.get(key)?.map(|v| Ok(f64::from_le_bytes(fixed_width(&v)?))).transpose()
+    }
+
+    /// Ordered scan over keys in `[start, end)`. An empty or inverted range yields nothing.
+    /// The matching keys are captured up front; values are then read lazily, locking the
+    /// pager only per item, so gets and sets can run between iterations. Like a `Cursor`, the
+    /// iterator holds off page reuse and `compact` until it is dropped.
+    pub fn scan(&self, start: &[u8], end: &[u8]) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + use<> {
+        let pin = self.pin();
+        let now = now_millis();
+        let entries: Result<Vec<(Vec<u8>, IndexEntry)>> = if start < end {
+            let idx = self.index.lock().unwrap();
+            idx.range(Bound::Included(start), Bound::Excluded(end), &*self.pager).map(|range| range
+                .filter(|(_, e)| !e.is_expired(now))
+                .map(|(k, e)| (k.into_owned(), e))
+                .collect())
+        } else {
+            Ok(Vec::new())
+        };
+        self.read_values(pin, entries)
+    }
+
+    /// Scan down from `start`: every key at or below it, in descending order. Keys are
+    /// captured and values read as in `scan`.
+    pub fn scan_rev(&self, start: &[u8]) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + use<> {
+        let pin = self.pin();
+        let now = now_millis();
+        let entries: Result<Vec<(Vec<u8>, IndexEntry)>> = {
+            let idx = self.index.lock().unwrap();
+            idx.range(Bound::Unbounded, Bound::Included(start), &*self.pager).map(|range| {
+                let mut entries: Vec<_> = range
+                    .filter(|(_, e)| !e.is_expired(now))
+                    .map(|(k, e)| (k.into_owned(), e))
+                    .collect();
+                entries.reverse();
+                entries
+            })
+        };
+        self.read_values(pin, entries)
+    }
+
+    /// A cursor on the first key at or above `key`, or past the last key if there is none.
+    /// It captures every live key, so it costs a copy of the keys like `snapshot` does.
+    pub fn seek(&self, key: &[u8]) -> Result<Cursor> {
+        let pin = self.pin();
+        let now = now_millis();
+        let entries: Vec<(Vec<u8>, IndexEntry)> = {
+            let idx = self.index.lock().unwrap();
+            idx.range(Bound::Unbounded, Bound::Unbounded, &*self.pager)?
+                .filter(|(_, e)| !e.is_expired(now))
+                .map(|(k, e)| (k.into_owned(), e))
+                .collect()
+        };
+        let pos = entries.partition_point(|(k, _)| &k[..] < key) + 1;
+        Ok(Cursor { entries, pos, pager: self.pager.clone(), _pin: pin })
+    }
+
+    /// Ordered scan over every key starting with `prefix`.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + use<> {
+        let pin = self.pin();
+        let now = now_millis();
+        let entries: Result<Vec<(Vec<u8>, IndexEntry)>> = {
+            let idx = self.index.lock().unwrap();
+            idx.range(Bound::Included(prefix), Bound::Unbounded, &*self.pager).map(|range| range
+                .take_while(|(k, _)| k.starts_with(prefix))
+                .filter(|(_, e)| !e.is_expired(now))
+                .map(|(k, e)| (k.into_owned(), e))
+                .collect())
+        };
+        self.read_values(pin, entries)
+    }
+
+    /// Every key starting with `prefix` and its value, as a map, e.g. to render a list. Unlike
+    /// `scan_prefix` the values are read eagerly, grouped by page as in `multi_get`, so each
+    /// page is read at most once. Keys have to be UTF-8; a key that isn't is an error.
+    pub fn get_prefix(&self, prefix: &str) -> Result<BTreeMap<String, Vec<u8>>> {
+        let now = now_millis();
+        // page -> (key, entry offset)
+        let mut by_page: BTreeMap<u64, Vec<(String, u32)>> = BTreeMap::new();
+        let idx = self.index.lock().unwrap();
+        let range = idx.range(Bound::Included(prefix.as_bytes()), Bound::Unbounded, &*self.pager)?;
+        for (key, e) in range.take_while(|(k, _)| k.starts_with(prefix.as_bytes())) {
+            if e.is_expired(now) {
+                continue;
+            }
+            let key = String::from_utf8(key.into_owned()).map_err(|err| {
+                TinyDbError::Invalid(format!("key {:?} is not UTF-8", String::from_utf8_lossy(err.as_bytes())))
+            })?;
+            by_page.entry(e.page_id).or_default().push((key, e.offset));
+        }
+        let mut out = BTreeMap::new();
+        // as in `get`, the pager is taken before the index is let go
+        let mut p = self.pager.lock().unwrap();
+        drop(idx);
+        for (pid, wanted) in by_page {
+            let page = p.read_page(pid)?;
+            for (key, off) in wanted {
+                out.insert(key, entry_value(&mut p, &page, off)?);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Read the values of `entries` one at a time, keeping the pages still with `pin` until
+    /// the iterator is dropped. Failing to list them is the only item.
+    fn read_values(&self, pin: Weak<()>, entries: Result<Vec<(Vec<u8>, IndexEntry)>>)
+        -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + use<>
+    {
+        let pager = Arc::clone(&self.pager);
+        let (entries, err) = match entries {
+            Ok(entries) => (entries, None),
+            Err(e) => (Vec::new(), Some(Err(e))),
+        };
+        err.into_iter().chain(entries.into_iter().map(move |(key, e)| {
+            let _pin = &pin;
+            let mut p = pager.lock().unwrap();
+            let val = read_value(&mut p, e.page_id, e.offset)?;
+            Ok((key, val))
+        }))
+    }
+
+    /// Delete every key starting with `prefix`, returning how many there were. The deletes
+    /// go through `write_batch`, so they are one WAL record of tombstones: recovery redoes
+    /// all of them or, if the record never committed, none.
+    pub fn delete_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        let _w = self.lock_for_write()?;
+        self.check_writable()?;
+        let now = now_millis();
+        let keys: Vec<Vec<u8>> = {
+            let idx = self.index.lock().unwrap();
+            idx.range(Bound::Included(prefix), Bound::Unbounded, &*self.pager)?
+                .take_while(|(k, _)| k.starts_with(prefix))
+                .filter(|(_, e)| !e.is_expired(now))
+                .map(|(k, _)| k.into_owned())
+                .collect()
+        };
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let ops: Vec<BatchOp> = keys.iter().map(|k| BatchOp::Delete(k)).collect();
+        self.write_batch_locked(&ops)?;
+        Ok(keys.len())
+    }
+
+    /// DELETE. Same WAL-first steps as `set`, but the page receives a tombstone entry
+    /// instead of a value. Returns whether the key existed; deleting a missing key is a no-op.
+    pub fn delete(&self, key: &[u8]) -> Result<bool> {
+        let _w = self.lock_for_write()?;
+        self.check_writable()?;
+        let live = self.index.lock().unwrap().get(key, &*self.pager)?.is_some_and(|e| !e.is_expired(now_millis()));
+        if !live {
+            return Ok(false);
+        }
+        let entry = encode_entry(key, TOMBSTONE, 0, &[]);
+        let (pid, off) = self.reserve(entry.len())?;
+
+        let payload = encode_del(pid, off, key);
+
+        let lsn = self.wal.append(&payload)?;
+        self.sync_wal(lsn)?;
+
+        self.apply_entry(pid, off, &entry, lsn)?;
+        let old = self.index.lock().unwrap().remove(key, &*self.pager)?;
+        self.note_displaced(old)?;
+        self.note_commits(lsn, || vec![CommitEvent::Delete { key: key.to_vec() }]);
+        self.counters.deletes.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Remember the page of an index entry that was just replaced or removed, and the first
+    /// overflow page of its value if it has one.
+    fn note_displaced(&self, old: Option<IndexEntry>) -> Result<()> {
+        let Some(old) = old else { return Ok(()) };
+        let chain = overflow_pointer(&self.pager.lock().unwrap().read_page(old.page_id)?, old.offset)?;
+        let mut dead = self.dead_pages.lock().unwrap();
+        dead.insert(old.page_id);
+        dead.extend(chain.map(|(first, _)| first));
+        Ok(())
+    }
+
+    /// Fsync the WAL through the record at `lsn` if the sync policy calls for it now.
+    fn sync_wal(&self, lsn: Lsn) -> Result<()> {
+        match self.sync_policy {
+            SyncPolicy::Always => self.wal.sync_to(lsn + 1),
+            SyncPolicy::EveryN(n) => {
+                let mut unsynced = self.unsynced.lock().unwrap();
+                *unsynced += 1;
+                if *unsynced < n.max(1) {
+                    return Ok(());
+                }
+                *unsynced = 0;
+                drop(unsynced);
+                self.wal.sync_to(lsn + 1)
+            }
+            SyncPolicy::Never => Ok(()),
+        }
+    }
+
+    /// Make every write that has returned so far durable, whatever the sync policy: fsync
+    /// the WAL, then the data file. Unlike `checkpoint` nothing is truncated or rewritten,
+    /// so it costs just the two fsyncs (the WAL's shared with concurrent `set`s, as group
+    /// commit does), e.g. before acknowledging a request under `EveryN` or `Never`.
+    pub fn flush(&self) -> Result<()> {
+        self.check_writable()?;
+        *self.unsynced.lock().unwrap() = 0;
+        self.wal.sync()?;
+        self.deliver_commits();
+        self.pager.lock().unwrap().sync()
+    }
+
+    /// Number of fsyncs issued on the WAL since open.
+    pub fn wal_fsync_count(&self) -> u64 {
+        self.wal.fsync_count()
+    }
+
+    /// Bytes appended to the WAL since open, record headers included.
+    pub fn wal_bytes_written(&self) -> u64 {
+        self.wal.bytes_written()
+    }
+
+    /// Bytes written to the data file since open, whole pages at a time.
+    pub fn data_bytes_written(&self) -> u64 {
+        self.pager.lock().unwrap().bytes_written()
+    }
+
+    /// A snapshot of the cumulative counters, cheap enough to poll: the counters are
+    /// relaxed atomics or sit behind the locks the engine takes anyway.
+    pub fn metrics(&self) -> Metrics {
+        let (page_reads, page_writes, cache, data_bytes) = {
+            let p = self.pager.lock().unwrap();
+            (p.page_reads(), p.page_writes(), p.cache_stats(), p.bytes_written())
+        };
+        Metrics {
+            sets: self.counters.sets.load(Ordering::Relaxed),
+            gets: self.counters.gets.load(Ordering::Relaxed),
+            deletes: self.counters.deletes.load(Ordering::Relaxed),
+            wal_appends: self.wal.append_count(),
+            wal_fsyncs: self.wal.fsync_count(),
+            wal_bytes: self.wal.bytes_written(),
+            page_reads,
+            page_writes,
+            cache_hits: cache.hits,
+            cache_misses: cache.misses,
+            data_bytes,
+        }
+    }
+
+    /// Taken by every write for its whole duration, before any other lock.
+    fn lock_writer(&self) -> WriteGuard<'_> {
+        WriteGuard { engine: self, guard: Some(self.writer.lock().unwrap()) }
+    }
+
+    /// The writer lock, for a write: first wait out (or under `WriteStall::Fail` refuse,
+    /// with `WouldStall`) a WAL over `Options::max_unckpt_bytes`. The wait happens before
+    /// taking the lock, since the checkpoint that ends it needs the lock too.
+    fn lock_for_write(&self) -> Result<WriteGuard<'_>> {
+        if let Some(limit) = self.max_unckpt_bytes {
+            let mut stall = self.stall.lock().unwrap();
+            loop {
+                let unckpt = self.wal.unckpt_bytes();
+                if unckpt <= limit {
+                    break;
+                }
+                if self.write_stall == WriteStall::Fail {
+                    return Err(TinyDbError::WouldStall { unckpt, limit });
+                }
+                stall = self.checkpointed.wait(stall).unwrap();
+            }
+        }
+        Ok(self.lock_writer())
+    }
+
+    /// Reject an empty key (the page scan stops at a zero key length, so the entry and
+    /// everything after it on the page would be lost on reopen) and keys or values over the
+    /// configured limits.
+    fn check_sizes(&self, key: &[u8], val_len: usize) -> Result<()> {
+        if key.is_empty() {
+            return Err(TinyDbError::Invalid("empty key".into()));
+        }
+        if key.len() > self.max_key_size {
+            return Err(TinyDbError::Invalid(format!("key is {} bytes, the limit is {}", key.len(), self.max_key_size)));
+        }
+        if val_len > self.max_value_size {
+            return Err(TinyDbError::Invalid(format!("value is {} bytes, the limit is {}", val_len, self.max_value_size)));
+        }
+        Ok(())
+    }
+
+    /// The bytes to store for `val` and the val_len flag saying how to read them back.
+    fn pack_value<'a>(&self, val: &'a [u8]) -> (Cow<'a, [u8]>, u32) {
+        #[cfg(feature = "compression")]
+        if self.compress_values
+            && val.len() >= COMPRESS_MIN_BYTES
+            && let Ok(packed) = zstd::bulk::compress(val, 0)
+            && packed.len() < val.len()
+        {
+            return (Cow::Owned(packed), VAL_ZSTD);
+        }
+        (Cow::Borrowed(val), 0)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.pager.lock().unwrap().is_read_only() {
+            return Err(TinyDbError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Find a page with room for `entry_len` bytes, allocating a new page when the
+    /// current one is full. Returns (page_id, offset).
+    fn reserve(&self, entry_len: usize) -> Result<(u64, usize)> {
+        Ok(self.reserve_many(&[entry_len])?[0])
+    }
+
+    /// Place several entries back to back, in order, spilling onto new pages as needed.
+    /// Placement happens before anything is written, so it can't rely on page.used
+    /// advancing; the cursor is tracked locally instead.
+    /// Every page an op touches gets its LSN. A batch that spills over several pages must
+    /// not include one holding older entries: the rebuild on open could not tell whether
+    /// those come before the batch's entries on the other pages (a reused page can have a
+    /// lower id). Such a batch starts on a fresh page instead, abandoning the current one's tail.
+    fn reserve_many(&self, entry_lens: &[usize]) -> Result<Vec<(u64, usize)>> {
+        let mut pid = *self.next_page.lock().unwrap();
+        let mut p = self.pager.lock().unwrap();
+        let cap = p.page_size() - HDR_SZ;
+        // no overflow pages: an entry has to fit in one page's data region
+        if let Some(&len) = entry_lens.iter().find(|&&len| len > cap) {
+            return Err(TinyDbError::EntryTooLarge { len, max: cap });
+        }
+        let mut used = p.read_page(pid)?.used as usize;
+        if used > 0 && used + entry_lens.iter().sum::<usize>() > cap && entry_lens.len() > 1 {
+            pid = p.allocate_page()?;
+            used = 0;
+        }
+        let (slots, last_pid) = place_entries(pid, used, cap, entry_lens, || p.allocate_page())?;
+        *self.next_page.lock().unwrap() = last_pid;
+        Ok(slots)
+    }
+
+    /// Apply one decoded WAL record to pages and the index.
+    fn replay_record(&self, lsn: Lsn, payload: &[u8]) -> Result<()> {
+        // decode payload: first 3 bytes are type ascii "SET" / "DEL" / "BAT" / ...
+        let t = payload.get(..3).unwrap_or(payload);
+        if t == b"SET" {
+            let op = decode_set(payload)?;
+            self.replay_set(lsn, &op, 0)?;
+        } else if t == b"DEL" {
+            let (page_id, offset, key) = decode_del(payload)?;
+            let entry = encode_entry(key, TOMBSTONE, 0, &[]);
+            self.apply_entry(page_id, offset as usize, &entry, lsn)?;
+            let old = self.index.lock().unwrap().remove(key, &*self.pager)?;
+            self.note_displaced(old)?;
+        } else if t == b"BAT" {
+            // all-or-nothing: without the trailing commit marker none of the ops apply
+            let Some(body) = committed_body(payload) else { return Ok(()) };
+            for op in decode_ops(body)? {
+                self.replay_record(lsn, op)?;
+            }
+        } else if t == b"CMP" {
+            let Some(body) = committed_body(payload) else { return Ok(()) };
+            let page_count = read_u64_le(body, 0, "CMP record")?;
+            self.apply_compaction(lsn, page_count, &decode_ops(&body[8..])?)?;
+        } else if t == b"CLR" {
+            self.apply_compaction(lsn, META_PAGE + 2, &[])?;
+        } else if t == b"UPD" {
+            let (slot, op) = decode_update(payload)?;
+            self.replay_set(lsn, &op, slot)?;
+        } else if t == b"OVF" {
+            let (pid, next, chunk) = decode_overflow(payload)?;
+            self.apply_overflow(pid, next, chunk, lsn)?;
+        } else {
+            // a record this build can't apply; passing over it would lose what it did
+            return Err(TinyDbError::Corrupt(format!("unknown WAL record type {:?}", String::from_utf8_lossy(t))));
+        }
+        Ok(())
+    }
+
+    /// Replay a SET: re-encode its entry at its original offset, followed by a gap entry
+    /// filling the rest of a `slot`-byte slot if it is an in-place update that shrank the
+    /// entry (0 for a plain SET), and point the index at it.
+    fn replay_set(&self, lsn: Lsn, op: &SetOp, slot: usize) -> Result<()> {
+        let entry = encode_entry(op.key, op.val.len() as u32 | op.flags, op.expires_at, op.val);
+        self.apply_entry(op.page_id, op.offset as usize, &fill_slot(&entry, slot), lsn)?;
+        let ie = IndexEntry { page_id: op.page_id, offset: op.offset, expires_at: op.expires_at };
+        let mut idx = self.index.lock().unwrap();
+        let old = if ie.is_expired(now_millis()) {
+            idx.remove(op.key, &*self.pager)?
+        } else {
+            idx.insert(op.key, ie, &*self.pager)?
+        };
+        self.note_displaced(old)?;
+        Ok(())
+    }
+
+    /// Install the result of a compaction: pages 1..page_count rebuilt from scratch out of
+    /// `ops` (OVF and SET payloads), the index replaced wholesale, then any later pages cut off and
+    /// the free list emptied (every page left is in use). Ops placing a key at or past
+    /// page_count are refused by `compacted_pages` before any page is written, so the cut
+    /// never drops a page the new index points into.
+    /// Shared by `compact`, `clear` (with no ops) and WAL replay. Pages are rebuilt rather
+    /// than patched so no leftover bytes of the old layout survive between the packed entries.
+    fn apply_compaction(&self, lsn: Lsn, page_count: u64, ops: &[&[u8]]) -> Result<()> {
+        let page_size = self.pager.lock().unwrap().page_size();
+        let hash = self.index.lock().unwrap().key_hash();
+        let (pages, idx) = compacted_pages(lsn, page_count, ops, META_PAGE + 1, page_size, hash)?;
+        // with nothing left (a clear) the empty index goes in first, so no read finds a key on
+        // a page about to be emptied. Otherwise readers are already pointed elsewhere (compact
+        // swapped them over to copies) and the new index waits for its pages.
+        let mut idx = Some(idx);
+        if ops.is_empty() {
+            *self.index.lock().unwrap() = idx.take().unwrap();
+        }
+        for page in pages {
+            let mut pg = self.pager.lock().unwrap();
+            if pg.is_read_only() {
+                pg.stage_page(page);
+            } else {
+                pg.write_page(&page)?;
+            }
+        }
+        if let Some(idx) = idx {
+            *self.index.lock().unwrap() = idx;
+        }
+        {
+            let mut pg = self.pager.lock().unwrap();
+            if !pg.is_read_only() {
+                pg.clear_free_list()?;
+                pg.set_next_page(page_count - 1)?;
+                pg.sync()?;
+                pg.truncate_to(page_count)?;
+            }
+        }
+        *self.next_page.lock().unwrap() = page_count - 1;
+        self.dead_pages.lock().unwrap().clear();
+        Ok(())
+    }
+
+
+    /// Write an encoded entry into page `pid` at `off` and stamp the page with `lsn`.
+    /// Used both by the live write path and WAL replay (where it is idempotent).
+    fn apply_entry(&self, pid: u64, off: usize, entry: &[u8], lsn: Lsn) -> Result<()> {
+        let mut pg = self.pager.lock().unwrap();
+        // ensure page exists
+        let mut page = pg.read_page(pid)?;
+        put_entry(&mut page, off, entry)?;
+        page.lsn = lsn;
+        if pg.is_read_only() {
+            // replay in a read-only engine: keep the rebuilt page in memory only
+            pg.stage_page(page);
+        } else {
+            pg.write_page(&page)?;
+            // a no-op on the live path, where the page came from allocate_page; on replay the
+            // superblock may predate the allocation if its write was lost in a crash
+            pg.set_next_page(pid)?;
+        }
+        // the page written last is the newest one, so appends continue there. On the live
+        // path this is already the reserved page; on replay it may lie past the scanned pages.
+        *self.next_page.lock().unwrap() = pid;
+        Ok(())
+    }
+
+    /// Write the overflow page `pid`, holding `chunk` and linking to `next`, whole. Unlike
+    /// `apply_entry` this leaves the append page alone, as entries never go on one.
+    fn apply_overflow(&self, pid: u64, next: u64, chunk: &[u8], lsn: Lsn) -> Result<()> {
+        let mut pg = self.pager.lock().unwrap();
+        let page = overflow_page(pid, next, chunk, lsn, pg.page_size())?;
+        if pg.is_read_only() {
+            pg.stage_page(page);
+        } else {
+            pg.write_page(&page)?;
+        }
+        Ok(())
+    }
+
+    /// `apply_entry` for the entries of one live batch: each page they touch is patched in
+    /// memory once, then all of them go out in a single `write_pages`.
+    fn apply_entries<'a>(&self, entries: impl Iterator<Item = (u64, usize, &'a [u8])>, lsn: Lsn) -> Result<()> {
+        let mut pg = self.pager.lock().unwrap();
+        let mut pages: Vec<Page> = Vec::new();
+        let mut last = None;
+        for (pid, off, entry) in entries {
+            let i = match pages.iter().position(|p| p.id == pid) {
+                Some(i) => i,
+                None => {
+                    pages.push(pg.read_page(pid)?);
+                    pages.len() - 1
+                }
+            };
+            let page = &mut pages[i];
+            put_entry(page, off, entry)?;
+            page.lsn = lsn;
+            last = Some(pid);
+        }
+        let Some(last) = last else { return Ok(()) };
+        pg.write_pages(&pages)?;
+        pg.set_next_page(last)?;
+        *self.next_page.lock().unwrap() = last;
+        Ok(())
+    }
+}
+
+/// Open (creating it if need be) the lock file at `path` and take an exclusive lock on it,
+/// held until the file is closed. Fails with `Locked` if someone else holds it.
+fn lock_file(path: &Path) -> Result<File> {
+    let f = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+    match f.try_lock() {
+        Ok(()) => Ok(f),
+        Err(std::fs::TryLockError::WouldBlock) => Err(TinyDbError::Locked(path.to_path_buf())),
+        Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+fn read_checkpoint(path: &Path) -> Result<Lsn> {
+    let b = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    if b.len() != 12 || crc32(&b[0..8]) != read_u32_le(&b, 8, "checkpoint file")? {
+        return Err(TinyDbError::Corrupt("corrupt checkpoint file".into()));
+    }
+    read_u64_le(&b, 0, "checkpoint file")
+}
+
+/// Where replay of `wal` has to start according to its last CKP record, if it has one: the
+/// checkpoint LSN it holds. Reads the whole log, ahead of the replay that reads it again.
+fn replay_start(wal: &Wal) -> Result<Option<Lsn>> {
+    let mut start = None;
+    for rec in wal.records_from(0) {
+        let (_, payload) = rec?;
+        if payload.starts_with(b"CKP") {
+            start = Some(decode_checkpoint(&payload)?);
+        }
+    }
+    Ok(start)
+}
+
+/// Save the index and dead-page set as of checkpoint `lsn`, atomically like the checkpoint.
+fn write_index_snapshot(path: &Path, lsn: Lsn, idx: &BTreeMap<Vec<u8>, IndexEntry>, dead: &BTreeSet<u64>) -> Result<()> {
+    let mut b = Vec::new();
+    b.extend_from_slice(&lsn.to_le_bytes());
+    b.extend_from_slice(&(idx.len() as u32).to_le_bytes());
+    for (key, e) in idx {
+        b.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        b.extend_from_slice(key);
+        b.extend_from_slice(&e.page_id.to_le_bytes());
+        b.extend_from_slice(&e.offset.to_le_bytes());
+        b.extend_from_slice(&e.expires_at.to_le_bytes());
+    }
+    b.extend_from_slice(&(dead.len() as u32).to_le_bytes());
+    for pid in dead {
+        b.extend_from_slice(&pid.to_le_bytes());
+    }
+    b.extend_from_slice(&crc32(&b).to_le_bytes());
+    write_atomically(path, &b)
+}
+
+/// Replace the file at `path` with `b` via a temp file next to it and a rename.
+fn write_atomically(path: &Path, b: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    {
+        let mut f = File::create(&tmp)?;
+        f.write_all(b)?;
+        f.sync_all()?;
+    }
+    std::fs::rename(&tmp, path)?;
+    sync_parent_dir(path)?;
+    Ok(())
+}
+
+/// Delete the file at `path`, if there is one.
+fn remove_if_present(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+type IndexSnapshot = (BTreeMap<Vec<u8>, IndexEntry>, BTreeSet<u64>);
+
+/// The index and dead pages saved at checkpoint `ckpt_lsn`. None if there is no snapshot,
+/// it fails its checksum, or it was taken at a different checkpoint. A stale snapshot is
+/// what a crash mid-checkpoint leaves behind, but a damaged one shouldn't happen, so that
+/// gets a warning on stderr; either way the caller falls back to the page scan.
+fn read_index_snapshot(path: &Path, ckpt_lsn: Lsn) -> Result<Option<IndexSnapshot>> {
+    let b = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let damaged = || {
+        eprintln!("warning: index snapshot {} is damaged; rebuilding the index from the pages", path.display());
+        Ok(None)
+    };
+    let Some((body, crc)) = b.split_last_chunk::<4>() else { return damaged() };
+    if crc32(body) != u32::from_le_bytes(*crc) {
+        return damaged();
+    }
+    if body.get(..8) != Some(&ckpt_lsn.to_le_bytes()[..]) {
+        return Ok(None);
+    }
+    let mut rest = &body[8..];
+    let mut take = |n: usize| -> Option<&[u8]> {
+        let (head, tail) = rest.split_at_checked(n)?;
+        rest = tail;
+        Some(head)
+    };
+    let parsed = (|| {
+        let u32_at = |b: &[u8]| read_u32_le(b, 0, "index snapshot").ok();
+        let u64_at = |b: &[u8]| read_u64_le(b, 0, "index snapshot").ok();
+        let mut idx = BTreeMap::new();
+        for _ in 0..u32_at(take(4)?)? {
+            let key_len = u32_at(take(4)?)? as usize;
+            let key = take(key_len)?.to_vec();
+            let page_id = u64_at(take(8)?)?;
+            let offset = u32_at(take(4)?)?;
+            let expires_at = u64_at(take(8)?)?;
+            idx.insert(key, IndexEntry { page_id, offset, expires_at });
+        }
+        let mut dead = BTreeSet::new();
+        for _ in 0..u32_at(take(4)?)? {
+            dead.insert(u64_at(take(8)?)?);
+        }
+        Some((idx, dead))
+    })();
+    match parsed {
+        Some(snapshot) => Ok(Some(snapshot)),
+        None => damaged(),
+    }
+}
+
+impl KeySource for &mut Pager {
+    fn key_at(&mut self, e: &IndexEntry) -> Result<Vec<u8>> {
+        let page = self.read_page(e.page_id)?;
+        let off = e.offset as usize;
+        let key_len = read_u32_le(&page.data, off, "page entry")? as usize;
+        Ok(read_bytes(&page.data, off + ENTRY_HDR_SZ, key_len, "page entry")?.to_vec())
+    }
+}
+
+impl KeySource for &Mutex<Pager> {
+    fn key_at(&mut self, e: &IndexEntry) -> Result<Vec<u8>> {
+        (&mut *self.lock().unwrap()).key_at(e)
+    }
+}
+
+/// Read the value of the entry stored at `off` in page `pid`.
+fn read_value(p: &mut Pager, pid: u64, off: u32) -> Result<Vec<u8>> {
+    let page = p.read_page(pid)?;
+    entry_value(p, &page, off)
+}
+
+/// `v` as the fixed-width encoding of a number, if it has that width.
+fn fixed_width<const N: usize>(v: &[u8]) -> Result<[u8; N]> {
+    v.try_into().map_err(|_| TinyDbError::BadValueLength { len: v.len(), expected: N })
+}
+
+/// Size of the whole entry stored at `off` in `page`, header included.
+fn entry_len(page: &Page, off: u32) -> Result<usize> {
+    let off = off as usize;
+    let key_len = read_u32_le(&page.data, off, "page entry")? as usize;
+    let val_len = read_u32_le(&page.data, off + 4, "page entry")? & !VAL_FLAGS;
+    Ok(ENTRY_HDR_SZ + key_len + val_len as usize)
+}
+
+/// The value of the entry stored at `off` in `page`, decompressed, or read from `p` if it
+/// is in overflow pages.
+fn entry_value(p: &mut Pager, page: &Page, off: u32) -> Result<Vec<u8>> {
+    if let Some((first, len)) = overflow_pointer(page, off)? {
+        return read_chain(p, first, len);
+    }
+    let (stored, flags) = stored_value(page, off)?;
+    unpack_value(stored, flags, format_args!("page {} offset {}", page.id, off))
+}
+
+/// The first page and length of the value of the entry at `off` in `page`, if it is in
+/// overflow pages.
+fn overflow_pointer(page: &Page, off: u32) -> Result<Option<(u64, u64)>> {
+    let val_len = read_u32_le(&page.data, off as usize + 4, "page entry")?;
+    if val_len & VAL_OVERFLOW == 0 || val_len == TOMBSTONE || val_len == GAP {
+        return Ok(None);
+    }
+    decode_pointer(&stored_value(page, off)?.0).map(Some)
+}
+
+/// Whether `page` is an overflow page: written, with no entry at its start.
+fn is_overflow_page(page: &Page) -> Result<bool> {
+    Ok(page.used > 0 && read_u32_le(&page.data, 0, "overflow page")? == 0)
+}
+
+/// The chunk of the overflow page `pid` and the page after it, checked against the `left`
+/// bytes of its value yet to read: a chunk is never empty or longer than that, and the
+/// chain ends exactly where the value does.
+fn chain_link(p: &mut Pager, pid: u64, left: u64) -> Result<(Vec<u8>, u64)> {
+    if pid == META_PAGE {
+        return Err(TinyDbError::Corrupt(format!("overflow chain runs into page {}", pid)));
+    }
+    let page = p.read_page(pid)?;
+    if !is_overflow_page(&page)? {
+        return Err(TinyDbError::Corrupt(format!("page {} in an overflow chain is not an overflow page", pid)));
+    }
+    let len = read_u32_le(&page.data, 4, "overflow page")? as u64;
+    let next = read_u64_le(&page.data, 8, "overflow page")?;
+    if len == 0 || len > left || (next == 0) != (len == left) {
+        return Err(TinyDbError::Corrupt(format!(
+            "overflow page {} holds {} bytes and links to page {} with {} bytes of its value left", pid, len, next, left
+        )));
+    }
+    let chunk = read_bytes(&page.data, OVF_HDR_SZ, len as usize, "overflow page")?;
+    Ok((chunk.to_vec(), next))
+}
+
+/// The `len`-byte value in the overflow chain starting at page `first`.
+fn read_chain(p: &mut Pager, first: u64, len: u64) -> Result<Vec<u8>> {
+    // not sized up front: a damaged pointer could ask for anything
+    let mut val = Vec::new();
+    let mut pid = first;
+    while (val.len() as u64) < len {
+        let (chunk, next) = chain_link(p, pid, len - val.len() as u64)?;
+        val.extend_from_slice(&chunk);
+        pid = next;
+    }
+    Ok(val)
+}
+
+/// Walk the overflow chain of the entry at `off` in `page`, if its value has one, checking
+/// every link of it as `read_chain` does without keeping the value.
+fn check_chain(p: &mut Pager, page: &Page, off: u32) -> Result<()> {
+    let Some((mut pid, mut left)) = overflow_pointer(page, off)? else { return Ok(()) };
+    while left > 0 {
+        let (chunk, next) = chain_link(p, pid, left)?;
+        left -= chunk.len() as u64;
+        pid = next;
+    }
+    Ok(())
+}
+
+/// First pages of the overflow chains the entries of `idx` point at.
+fn live_chains(idx: &Index, p: &mut Pager) -> Result<HashSet<u64>> {
+    let mut by_page: BTreeMap<u64, Vec<u32>> = BTreeMap::new();
+    for e in idx.values() {
+        by_page.entry(e.page_id).or_default().push(e.offset);
+    }
+    let mut chains = HashSet::new();
+    for (pid, offs) in by_page {
+        let page = p.read_page(pid)?;
+        for off in offs {
+            chains.extend(overflow_pointer(&page, off)?.map(|(first, _)| first));
+        }
+    }
+    Ok(chains)
+}
+
+/// Free the overflow page `page` and the rest of its chain. The walk also takes a last page
+/// left empty, allocated for a `set_reader` chunk that never came, and stops at anything
+/// else, or at a page already free.
+fn free_chain(p: &mut Pager, mut page: Page) -> Result<()> {
+    loop {
+        let next = if is_overflow_page(&page)? { read_u64_le(&page.data, 8, "overflow page")? } else { 0 };
+        p.free_page(page.id)?;
+        if next == META_PAGE || next >= p.page_count() || p.is_free(next) {
+            return Ok(());
+        }
+        page = p.read_page(next)?;
+        if page.used != 0 && !is_overflow_page(&page)? {
+            return Ok(());
+        }
+    }
+}
+
+/// The overflow page `pid` of a chain, holding `chunk` and linking to `next`.
+fn overflow_page(pid: u64, next: u64, chunk: &[u8], lsn: Lsn, page_size: usize) -> Result<Page> {
+    let mut data = Vec::with_capacity(OVF_HDR_SZ + chunk.len());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+    data.extend_from_slice(&next.to_le_bytes());
+    data.extend_from_slice(chunk);
+    let mut page = Page::with_size(pid, page_size);
+    put_entry(&mut page, 0, &data)?;
+    page.lsn = lsn;
+    Ok(page)
+}
+
+/// A value as stored, with the flag bits of its val_len, decompressed. `at` says where it
+/// came from, for errors.
+fn unpack_value(stored: Vec<u8>, flags: u32, at: std::fmt::Arguments) -> Result<Vec<u8>> {
+    if flags & VAL_ZSTD == 0 {
+        return Ok(stored);
+    }
+    #[cfg(feature = "compression")]
+    return zstd::decode_all(&stored[..])
+        .map_err(|e| TinyDbError::Corrupt(format!("value at {} doesn't decompress: {}", at, e)));
+    #[cfg(not(feature = "compression"))]
+    Err(TinyDbError::Invalid(format!("value at {} is compressed; build with the compression feature to read it", at)))
+}
+
+/// The value bytes of the entry stored at `off` in `page` as they are on the page, and the
+/// flag bits of its val_len.
+fn stored_value(page: &Page, off: u32) -> Result<(Vec<u8>, u32)> {
+    let off = off as usize;
+    let key_len = read_u32_le(&page.data, off, "page entry")? as usize;
+    let val_len = read_u32_le(&page.data, off + 4, "page entry")?;
+    let (len, flags) = ((val_len & !VAL_FLAGS) as usize, val_len & VAL_FLAGS);
+    let val = read_bytes(&page.data, off + ENTRY_HDR_SZ + key_len, len, "page entry")?;
+    Ok((val.to_vec(), flags))
+}
+
+/// Copy `entry` into `page`'s data region at `off`, moving `used` past it. An offset from a
+/// damaged WAL record that would run off the page is an error, not a panic.
+fn put_entry(page: &mut Page, off: usize, entry: &[u8]) -> Result<()> {
+    let len = page.data.len();
+    let dst = page.data.get_mut(off..).and_then(|d| d.get_mut(..entry.len()))
+        .ok_or(TinyDbError::Truncated { what: "page entry", off, need: entry.len(), len })?;
+    dst.copy_from_slice(entry);
+    page.used = page.used.max((off + entry.len()) as u32);
+    Ok(())
+}
+
+/// Bytes the entry at `off` in `page` can take when it is updated in place: its own, and
+/// those of the gap entry an earlier update that shrank it left behind.
+fn slot_len(page: &Page, off: u32) -> Result<usize> {
+    let len = entry_len(page, off)?;
+    let next = off as usize + len;
+    if next + ENTRY_HDR_SZ <= page.data.len() && read_u32_le(&page.data, next + 4, "page entry")? == GAP {
+        return Ok(len + ENTRY_HDR_SZ + read_u32_le(&page.data, next, "page entry")? as usize);
+    }
+    Ok(len)
+}
+
+/// A gap entry taking exactly `len` bytes; `len` must be more than ENTRY_HDR_SZ.
+fn gap_entry(len: usize) -> Vec<u8> {
+    encode_entry(&vec![0; len - ENTRY_HDR_SZ], GAP, 0, &[])
+}
+
+/// `entry` followed by the gap entry that takes up the rest of a `slot`-byte slot.
+fn fill_slot(entry: &[u8], slot: usize) -> Vec<u8> {
+    let mut out = entry.to_vec();
+    if entry.len() < slot {
+        out.extend_from_slice(&gap_entry(slot - entry.len()));
+    }
+    out
+}
+
+/// The pages 1..page_count of a compaction laid out by `ops` (OVF and SET payloads),
+/// stamped with `lsn`, and an index pointing into them, hashed with `hash` if given. The
+/// pages are numbered from `first` rather than 1, to build them somewhere else in the file;
+/// the links of overflow chains are moved along with them.
+fn compacted_pages(lsn: Lsn, page_count: u64, ops: &[&[u8]], first: u64, page_size: usize, hash: Option<KeyHash>)
+    -> Result<(Vec<Page>, Index)>
+{
+    let base = META_PAGE + 1;
+    let mut pages: Vec<Page> = (base..page_count).map(|pid| Page::with_size(pid - base + first, page_size)).collect();
+    // where page `pid` of the layout goes among `pages`
+    let n = pages.len() as u64;
+    let slot = |pid: u64, what: &str| pid.checked_sub(base).filter(|&i| i < n).ok_or_else(|| {
+        TinyDbError::Corrupt(format!("CMP record {} page {} of {}", what, pid, page_count))
+    });
+    let mut idx = BTreeMap::new();
+    for op in ops {
+        if op.starts_with(b"OVF") {
+            let (pid, next, chunk) = decode_overflow(op)?;
+            let i = slot(pid, "places an overflow page at")?;
+            let next = if next == 0 { 0 } else { slot(next, "links to")? + first };
+            pages[i as usize] = overflow_page(i + first, next, chunk, lsn, page_size)?;
+            continue;
+        }
+        let op = decode_set(op)?;
+        let val = match op.flags & VAL_OVERFLOW {
+            0 => Cow::Borrowed(op.val),
+            _ => {
+                let (head, len) = decode_pointer(op.val)?;
+                Cow::Owned(encode_pointer(slot(head, "links to")? + first, len))
+            }
+        };
+        let entry = encode_entry(op.key, val.len() as u32 | op.flags, op.expires_at, &val);
+        let i = slot(op.page_id, "places a key on")?;
+        let page = &mut pages[i as usize];
+        put_entry(page, op.offset as usize, &entry)?;
+        let ie = IndexEntry { page_id: page.id, offset: op.offset, expires_at: op.expires_at };
+        idx.insert(op.key.to_vec(), ie);
+    }
+    for page in &mut pages {
+        page.lsn = lsn;
+    }
+    Ok((pages, Index::from_map(idx, hash)))
+}
+
+/// Lay out entries back to back starting at (`pid`, `used`), moving to a page from `alloc`
+/// whenever one doesn't fit in `cap` data bytes. Returns each entry's (page_id, offset) and
+/// the last page used.
+fn place_entries(
+    mut pid: u64,
+    mut used: usize,
+    cap: usize,
+    entry_lens: &[usize],
+    mut alloc: impl FnMut() -> Result<u64>,
+) -> Result<(Vec<(u64, usize)>, u64)> {
+    let mut slots = Vec::with_capacity(entry_lens.len());
+    for &entry_len in entry_lens {
+        if cap < (used + entry_len) {
+            // allocate new page
+            pid = alloc()?;
+            used = 0;
+        }
+        slots.push((pid, used));
+        used += entry_len;
+    }
+    Ok((slots, pid))
+}
+
+/// One kv entry parsed out of a page.
+struct RawEntry<'a> {
+    offset: u32,
+    key: &'a [u8],
+    val_len: u32,
+    expires_at: u64,
+}
+
+/// Parse the entries of a page's data region, in the order they were written. An entry
+/// running past the end of the region means the page is damaged, and is an error.
+fn page_entries(data: &[u8]) -> Result<Vec<RawEntry<'_>>> {
+    let mut entries = Vec::new();
+    let mut off = 0usize;
+    while off + ENTRY_HDR_SZ <= data.len() {
+        let key_len = read_u32_le(data, off, "page entry")? as usize;
+        let val_len = read_u32_le(data, off + 4, "page entry")?;
+        let expires_at = read_u64_le(data, off + 8, "page entry")?;
+        // keys are never empty, so key_len 0 is the zeroed space past the last entry; a
+        // val_len of 0 is just an empty value
+        if key_len == 0 { break; }
+        if val_len == GAP {
+            off += ENTRY_HDR_SZ + read_bytes(data, off + ENTRY_HDR_SZ, key_len, "gap entry")?.len();
+            continue;
+        }
+        let stored_len = if val_len == TOMBSTONE { 0 } else { (val_len & !VAL_FLAGS) as usize };
+        let key = read_bytes(data, off + ENTRY_HDR_SZ, key_len, "page entry")?;
+        read_bytes(data, off + ENTRY_HDR_SZ + key_len, stored_len, "page entry")?;
+        entries.push(RawEntry { offset: off as u32, key, val_len, expires_at });
+        off += ENTRY_HDR_SZ + key_len + stored_len;
+    }
+    Ok(entries)
+}
+
+/// One resolved op of a batch: key, stored value and its flags (`None` for a delete), and
+/// the encoded page entry.
+type BatchEntry<'a> = (&'a [u8], Option<(Cow<'a, [u8]>, u32)>, Vec<u8>);
+
+/// The committed BAT record for `entries` placed at `slots`.
+fn encode_batch(entries: &[BatchEntry], slots: &[(u64, usize)]) -> Vec<u8> {
+    let ops: Vec<Vec<u8>> = entries.iter().zip(slots).map(|((key, val, _), &(pid, off))| match val {
+        Some((stored, flags)) => encode_set(pid, off, key, stored, *flags, 0),
+        None => encode_del(pid, off, key),
+    }).collect();
+    let mut payload = b"BAT".to_vec();
+    payload.extend_from_slice(&encode_ops(&ops));
+    payload.extend_from_slice(BATCH_COMMIT);
+    payload
+}
+
+/// <u32 count>(<u32 op_len><op payload>)* -- the op list inside BAT and CMP records
+fn encode_ops(ops: &[Vec<u8>]) -> Vec<u8> {
+    let mut b = Vec::new();
+    b.extend_from_slice(&(ops.len() as u32).to_le_bytes());
+    for op in ops {
+        b.extend_from_slice(&(op.len() as u32).to_le_bytes());
+        b.extend_from_slice(op);
+    }
+    b
+}
+
+/// The payload of a BAT or CMP record between its type and its commit marker, None if it
+/// never committed.
+fn committed_body(payload: &[u8]) -> Option<&[u8]> {
+    payload.get(3..)?.strip_suffix(BATCH_COMMIT)
+}
+
+fn decode_ops(body: &[u8]) -> Result<Vec<&[u8]>> {
+    let count = read_u32_le(body, 0, "batch")?;
+    let mut off = 4;
+    // not sized up front: a damaged count could ask for anything
+    let mut ops = Vec::new();
+    for _ in 0..count {
+        let op_len = read_u32_le(body, off, "batch")? as usize; off += 4;
+        ops.push(read_bytes(body, off, op_len, "batch")?);
+        off += op_len;
+    }
+    Ok(ops)
+}
+
+/// A decoded SET payload. `val` is as stored; `flags` are the val_len flag bits.
+struct SetOp<'a> {
+    page_id: u64,
+    offset: u32,
+    expires_at: u64,
+    key: &'a [u8],
+    val: &'a [u8],
+    flags: u32,
+}
+
+/// What the WAL record `payload` last does to `key`: Some(Some(op)) if it sets it, Some(None)
+/// if it deletes it, None if it leaves it alone. Batches without their commit marker never
+/// applied, so they leave every key alone.
+fn record_effect<'a>(payload: &'a [u8], key: &[u8]) -> Result<Option<Option<SetOp<'a>>>> {
+    let committed = |skip: usize| -> Result<Option<Option<SetOp<'a>>>> {
+        let Some(body) = committed_body(payload) else { return Ok(None) };
+        let mut last = None;
+        for op in decode_ops(read_bytes(body, skip, body.len().saturating_sub(skip), "batch")?)? {
+            last = record_effect(op, key)?.or(last);
+        }
+        Ok(last)
+    };
+    Ok(match payload.get(..3) {
+        Some(b"SET") => Some(decode_set(payload)?).filter(|op| op.key == key).map(Some),
+        Some(b"DEL") => (decode_del(payload)?.2 == key).then_some(None),
+        Some(b"BAT") => committed(0)?,
+        Some(b"CMP") => committed(8)?,
+        Some(b"CLR") => Some(None),
+        Some(b"UPD") => Some(decode_update(payload)?.1).filter(|op| op.key == key).map(Some),
+        _ => None,
+    })
+}
+
+/// The commit hook's event for a set of `key` to `val`, or a delete if None.
+fn commit_event(key: &[u8], val: Option<&[u8]>, expires_at: u64) -> CommitEvent {
+    match val {
+        Some(val) => CommitEvent::Set { key: key.to_vec(), value: val.to_vec(), expires_at },
+        None => CommitEvent::Delete { key: key.to_vec() },
+    }
+}
+
+/// Redo what the WAL record `payload` wrote to the pages in `pages`, for `Engine::repair`.
+/// A SET or DEL at offset 0 starts its page afresh, as a new or reused page is empty then;
+/// a write to a page whose start hasn't been seen is passed over. An OVF record writes its page
+/// whole, so it starts it afresh too. CMP and CLR rebuild or cut off every page, so after
+/// them each page in range starts empty.
+fn redo_page_writes(pages: &mut BTreeMap<PageId, Option<Page>>, lsn: Lsn, payload: &[u8], page_size: usize) -> Result<()> {
+    let write = |pages: &mut BTreeMap<PageId, Option<Page>>, pid: u64, off: u32, bytes: &[u8], fresh: bool| -> Result<()> {
+        let Some(slot) = pages.get_mut(&pid) else { return Ok(()) };
+        if fresh && off == 0 {
+            *slot = Some(Page::with_size(pid, page_size));
+        }
+        if let Some(page) = slot {
+            put_entry(page, off as usize, bytes)?;
+            page.lsn = lsn;
+        }
+        Ok(())
+    };
+    match payload.get(..3) {
+        Some(b"SET") => {
+            let op = decode_set(payload)?;
+            write(pages, op.page_id, op.offset, &encode_entry(op.key, op.val.len() as u32 | op.flags, op.expires_at, op.val), true)?;
+        }
+        Some(b"DEL") => {
+            let (pid, off, key) = decode_del(payload)?;
+            write(pages, pid, off, &encode_entry(key, TOMBSTONE, 0, &[]), true)?;
+        }
+        Some(b"UPD") => {
+            let (slot, op) = decode_update(payload)?;
+            let entry = encode_entry(op.key, op.val.len() as u32 | op.flags, op.expires_at, op.val);
+            write(pages, op.page_id, op.offset, &fill_slot(&entry, slot), false)?;
+        }
+        Some(b"OVF") => {
+            let (pid, next, chunk) = decode_overflow(payload)?;
+            if let Some(slot) = pages.get_mut(&pid) {
+                *slot = Some(overflow_page(pid, next, chunk, lsn, page_size)?);
+            }
+        }
+        Some(b"BAT") => {
+            let Some(body) = committed_body(payload) else { return Ok(()) };
+            for op in decode_ops(body)? {
+                redo_page_writes(pages, lsn, op, page_size)?;
+            }
+        }
+        Some(b"CMP") => {
+            let Some(body) = committed_body(payload) else { return Ok(()) };
+            let page_count = read_u64_le(body, 0, "CMP record")?;
+            for (&pid, slot) in pages.iter_mut() {
+                *slot = (pid < page_count).then(|| Page::with_size(pid, page_size));
+            }
+            for op in decode_ops(&body[8..])? {
+                if op.starts_with(b"OVF") {
+                    redo_page_writes(pages, lsn, op, page_size)?;
+                    continue;
+                }
+                let op = decode_set(op)?;
+                write(pages, op.page_id, op.offset, &encode_entry(op.key, op.val.len() as u32 | op.flags, op.expires_at, op.val), false)?;
+            }
+        }
+        Some(b"CLR") => {
+            for (&pid, slot) in pages.iter_mut() {
+                *slot = (pid == META_PAGE + 1).then(|| Page::with_size(pid, page_size));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn decode_set(payload: &[u8]) -> Result<SetOp<'_>> {
+    let what = "SET record";
+    let page_id = read_u64_le(payload, 3, what)?;
+    let offset = read_u32_le(payload, 11, what)?;
+    let key_len = read_u32_le(payload, 15, what)? as usize;
+    let val_len = read_u32_le(payload, 19, what)?;
+    let expires_at = read_u64_le(payload, 23, what)?;
+    let key = read_bytes(payload, 31, key_len, what)?;
+    let (len, flags) = ((val_len & !VAL_FLAGS) as usize, val_len & VAL_FLAGS);
+    let val = read_bytes(payload, 31 + key_len, len, what)?;
+    Ok(SetOp { page_id, offset, expires_at, key, val, flags })
+}
+
+/// The page, offset and key of a DEL payload.
+fn decode_del(payload: &[u8]) -> Result<(u64, u32, &[u8])> {
+    let what = "DEL record";
+    let key_len = read_u32_le(payload, 15, what)? as usize;
+    Ok((read_u64_le(payload, 3, what)?, read_u32_le(payload, 11, what)?, read_bytes(payload, 19, key_len, what)?))
+}
+
+/// payload = b"SET" + page_id(8) + offset(4) + key_len(4) + val_len(4) + expires_at(8) + key + val,
+/// with val as stored and `flags` (0, VAL_ZSTD or VAL_OVERFLOW) or'ed into val_len
+fn encode_set(pid: u64, off: usize, key: &[u8], val: &[u8], flags: u32, expires_at: u64) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(3 + 8 + 4 + 4 + 4 + 8 + key.len() + val.len());
+    payload.extend_from_slice(b"SET");
+    payload.extend_from_slice(&pid.to_le_bytes());
+    payload.extend_from_slice(&(off as u32).to_le_bytes());
+    payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    payload.extend_from_slice(&(val.len() as u32 | flags).to_le_bytes());
+    payload.extend_from_slice(&expires_at.to_le_bytes());
+    payload.extend_from_slice(key);
+    payload.extend_from_slice(val);
+    payload
+}
+
+/// payload = b"CKP" + ckpt_lsn(8): every record below ckpt_lsn is in the data file. There
+/// are no dirty pages to list, since a checkpoint fsyncs the data file before logging this
+fn encode_checkpoint(ckpt_lsn: Lsn) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(3 + 8);
+    payload.extend_from_slice(b"CKP");
+    payload.extend_from_slice(&ckpt_lsn.to_le_bytes());
+    payload
+}
+
+fn decode_checkpoint(payload: &[u8]) -> Result<Lsn> {
+    read_u64_le(payload, 3, "CKP record")
+}
+
+/// payload = b"UPD" + slot_len(4) + the SET payload of the entry written into the slot
+fn encode_update(slot_len: usize, set: &[u8]) -> Vec<u8> {
+    let mut payload = b"UPD".to_vec();
+    payload.extend_from_slice(&(slot_len as u32).to_le_bytes());
+    payload.extend_from_slice(set);
+    payload
+}
+
+/// The slot length and the SET of an UPD record. Anything but a SET it wraps, or a slot its
+/// entry can't fill exactly (with a gap entry, if it is shorter), is refused.
+fn decode_update(payload: &[u8]) -> Result<(usize, SetOp<'_>)> {
+    let slot = read_u32_le(payload, 3, "UPD record")? as usize;
+    let set = &payload[7..];
+    if !set.starts_with(b"SET") {
+        return Err(TinyDbError::Corrupt(format!("UPD record wraps a {:?} record", String::from_utf8_lossy(&set[..set.len().min(3)]))));
+    }
+    let op = decode_set(set)?;
+    let len = ENTRY_HDR_SZ + op.key.len() + op.val.len();
+    if slot < len || (slot > len && slot - len <= ENTRY_HDR_SZ) {
+        return Err(TinyDbError::Corrupt(format!("UPD record puts a {}-byte entry in a {}-byte slot", len, slot)));
+    }
+    Ok((slot, op))
+}
+
+/// payload = b"OVF" + page_id(8) + next(8) + chunk
+fn encode_overflow(pid: u64, next: u64, chunk: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(3 + 8 + 8 + chunk.len());
+    payload.extend_from_slice(b"OVF");
+    payload.extend_from_slice(&pid.to_le_bytes());
+    payload.extend_from_slice(&next.to_le_bytes());
+    payload.extend_from_slice(chunk);
+    payload
+}
+
+/// The page, next page and chunk of an OVF payload.
+fn decode_overflow(payload: &[u8]) -> Result<(u64, u64, &[u8])> {
+    let what = "OVF record";
+    Ok((read_u64_le(payload, 3, what)?, read_u64_le(payload, 11, what)?, &payload[19..]))
+}
+
+/// The value of an overflow entry: first page(8) + value length(8)
+fn encode_pointer(first: u64, len: u64) -> Vec<u8> {
+    let mut val = Vec::with_capacity(OVF_PTR_SZ);
+    val.extend_from_slice(&first.to_le_bytes());
+    val.extend_from_slice(&len.to_le_bytes());
+    val
+}
+
+fn decode_pointer(val: &[u8]) -> Result<(u64, u64)> {
+    if val.len() != OVF_PTR_SZ {
+        return Err(TinyDbError::Corrupt(format!("overflow pointer is {} bytes, not {}", val.len(), OVF_PTR_SZ)));
+    }
+    Ok((read_u64_le(val, 0, "overflow pointer")?, read_u64_le(val, 8, "overflow pointer")?))
+}
+
+/// payload = b"DEL" + page_id(8) + offset(4) + key_len(4) + key
+fn encode_del(pid: u64, off: usize, key: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(3 + 8 + 4 + 4 + key.len());
+    payload.extend_from_slice(b"DEL");
+    payload.extend_from_slice(&pid.to_le_bytes());
+    payload.extend_from_slice(&(off as u32).to_le_bytes());
+    payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    payload.extend_from_slice(key);
+    payload
 }
 
-fn pager_hdr_sz() -> usize {
-    // PAGE_SIZE - data len = hdr
-    PAGE_SIZE - (PAGE_SIZE - 32)
+/// kv entry as stored in a page: key_len u32, val_len u32, expires_at u64, key, val
+fn encode_entry(key: &[u8], val_len: u32, expires_at: u64, val: &[u8]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(ENTRY_HDR_SZ + key.len() + val.len());
+    entry.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    entry.extend_from_slice(&val_len.to_le_bytes());
+    entry.extend_from_slice(&expires_at.to_le_bytes());
+    entry.extend_from_slice(key);
+    entry.extend_from_slice(val);
+    entry
 }
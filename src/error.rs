@@ -0,0 +1,53 @@
+use crate::wal::Lsn;
+
+/// Errors from the pager, the WAL and the engine. The corruption cases get their own
+/// variants so callers can tell them apart; they convert into `anyhow::Error` like any
+/// other error.
+#[derive(Debug, thiserror::Error)]
+pub enum TinyDbError {
+    #[error("page crc mismatch id={id}")]
+    PageCrcMismatch { id: u64 },
+    #[error("WAL payload crc mismatch at LSN {lsn}")]
+    WalCrcMismatch { lsn: Lsn },
+    #[error("short read {got} != {expected}")]
+    ShortRead { got: usize, expected: usize },
+    /// `what` names the structure whose magic number didn't match, e.g. "page".
+    #[error("bad {what} magic")]
+    BadMagic { what: &'static str },
+    /// A file laid out by a build with a different on-disk format; `what` names it, e.g.
+    /// "data file". Caught from the header before anything else in the file is read.
+    #[error("unsupported {what} format version {found}, this build supports {supported}")]
+    UnsupportedVersion { what: &'static str, found: u32, supported: u32 },
+    /// A field of `what` (e.g. "page entry") runs past the end of the bytes it is read
+    /// from: `need` bytes at offset `off` of a buffer of `len`.
+    #[error("{what} truncated: {need} bytes at offset {off} overrun its {len} bytes")]
+    Truncated { what: &'static str, off: usize, need: usize, len: usize },
+    /// Other damage to on-disk structures: unknown checksums, impossible lengths, ...
+    #[error("{0}")]
+    Corrupt(String),
+    /// An entry (header, key and value) bigger than a page's data region.
+    #[error("value too large for page size ({len} > {max} bytes)")]
+    EntryTooLarge { len: usize, max: usize },
+    /// A typed getter (`get_i64`, `get_f64`, `increment`) found a value of the wrong size.
+    #[error("value of length {len} is not a {expected}-byte number")]
+    BadValueLength { len: usize, expected: usize },
+    /// A write refused under `WriteStall::Fail`: the WAL holds `unckpt` bytes no checkpoint
+    /// has truncated yet, over the configured `limit`.
+    #[error("write would stall: {unckpt} WAL bytes await a checkpoint, over the limit of {limit}")]
+    WouldStall { unckpt: u64, limit: u64 },
+    #[error("database is opened read-only")]
+    ReadOnly,
+    /// Another writer, in this process or another, holds the lock file at this path.
+    #[error("database is locked: {0} is held by another writer")]
+    Locked(std::path::PathBuf),
+    /// The call can't be carried out as asked: bad arguments, or the wrong state for it.
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "serde")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, TinyDbError>;
@@ -0,0 +1,49 @@
+use std::path::Path;
+
+use crate::error::Result;
+use crate::wal::{Lsn, Wal};
+
+/// The WAL on its own, as an ordered log of opaque events: no pages, no index, no
+/// checkpoints. Each event is one record, numbered by its LSN, and reads go through the
+/// same replay code recovery uses, so a torn last event is dropped the same way.
+pub struct EventLog {
+    wal: Wal,
+}
+
+impl EventLog {
+    /// Open (or create) the log in directory `dir`, kept as WAL segments.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        Ok(Self { wal: Wal::open(dir)? })
+    }
+
+    /// A log in memory, gone once dropped.
+    pub fn open_in_memory() -> Result<Self> {
+        Ok(Self { wal: Wal::open_in_memory()? })
+    }
+
+    /// Append `payload` and return its LSN once it is durable. Concurrent appends share
+    /// fsyncs through the WAL's group commit.
+    pub fn append_event(&self, payload: &[u8]) -> Result<Lsn> {
+        let lsn = self.wal.append(payload)?;
+        self.wal.sync_to(lsn + 1)?;
+        Ok(lsn)
+    }
+
+    /// Events with an LSN of at least `lsn`, oldest first. Ends cleanly at a torn tail;
+    /// a corrupt event is an error item, after which the iterator stops. Appends may go on
+    /// meanwhile; an in-memory log yields just the events appended before the call.
+    pub fn read_events_from(&self, lsn: Lsn) -> impl Iterator<Item = Result<(Lsn, Vec<u8>)>> {
+        self.wal.records_from(lsn)
+    }
+
+    /// LSN the next event will get.
+    pub fn next_lsn(&self) -> Lsn {
+        self.wal.next_lsn()
+    }
+
+    /// Forget the events below `lsn`, a whole segment at a time (see `Wal::truncate_before`),
+    /// so some older events may still be read back.
+    pub fn truncate_before(&self, lsn: Lsn) -> Result<()> {
+        self.wal.truncate_before(lsn)
+    }
+}
@@ -0,0 +1,233 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hasher};
+use std::ops::{Bound, RangeBounds};
+
+use crate::error::Result;
+
+/// index value: where the live version of a key sits, and when it expires
+#[derive(Clone, Copy)]
+pub struct IndexEntry {
+    pub page_id: u64,
+    pub offset: u32,
+    // unix millis, 0 = never
+    pub expires_at: u64,
+}
+
+impl IndexEntry {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+}
+
+/// Hash keying a hashed index; see `Options::key_hash`.
+pub type KeyHash = fn(&[u8]) -> u64;
+
+/// The hash `Options::key_hash` is meant to be set to. Only held in memory, so it doesn't
+/// have to be stable across builds.
+pub fn default_key_hash(key: &[u8]) -> u64 {
+    let mut h = DefaultHasher::new();
+    h.write(key);
+    h.finish()
+}
+
+/// Reads back the key of the entry an IndexEntry points at. A hashed index keeps no keys,
+/// so it asks for them to tell apart keys that share a hash.
+pub trait KeySource {
+    fn key_at(&mut self, e: &IndexEntry) -> Result<Vec<u8>>;
+}
+
+/// The in-memory index: key -> IndexEntry, either ordered by key or, to keep key bytes out
+/// of memory, keyed by a 64-bit hash of the key (see `Options::key_hash`).
+///
+/// A hashed index holds every entry whose key has a given hash under that hash. A lookup
+/// reads the key of each of them back from its page and only takes the one that matches,
+/// so colliding keys cost an extra page read but never return each other's value. With no
+/// keys in memory, ordered access (scans, `keys`, compaction) reads every key back from
+/// the pages and sorts them first.
+#[derive(Clone)]
+pub struct Index(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Keys(BTreeMap<Vec<u8>, IndexEntry>),
+    Hashed { hash: KeyHash, slots: HashMap<u64, Slot> },
+}
+
+/// The entries under one hash: nearly always exactly one.
+#[derive(Clone)]
+enum Slot {
+    One(IndexEntry),
+    Many(Vec<IndexEntry>),
+}
+
+impl Slot {
+    fn entries(&self) -> &[IndexEntry] {
+        match self {
+            Slot::One(e) => std::slice::from_ref(e),
+            Slot::Many(v) => v,
+        }
+    }
+
+    fn push(&mut self, e: IndexEntry) {
+        match self {
+            Slot::One(first) => *self = Slot::Many(vec![*first, e]),
+            Slot::Many(v) => v.push(e),
+        }
+    }
+}
+
+impl Index {
+    /// An empty index, hashed with `hash` if given.
+    pub fn new(hash: Option<KeyHash>) -> Self {
+        Self::from_map(BTreeMap::new(), hash)
+    }
+
+    /// The index holding exactly the entries of `map`.
+    pub fn from_map(map: BTreeMap<Vec<u8>, IndexEntry>, hash: Option<KeyHash>) -> Self {
+        let Some(hash) = hash else { return Index(Repr::Keys(map)) };
+        let mut slots: HashMap<u64, Slot> = HashMap::with_capacity(map.len());
+        // the keys are distinct, so there is nothing to compare
+        for (key, e) in map {
+            match slots.get_mut(&hash(&key)) {
+                Some(slot) => slot.push(e),
+                None => {
+                    slots.insert(hash(&key), Slot::One(e));
+                }
+            }
+        }
+        Index(Repr::Hashed { hash, slots })
+    }
+
+    /// The hash keying this index, None if it is ordered by key.
+    pub fn key_hash(&self) -> Option<KeyHash> {
+        match &self.0 {
+            Repr::Keys(_) => None,
+            Repr::Hashed { hash, .. } => Some(*hash),
+        }
+    }
+
+    /// The ordered map of keys, unless this is a hashed index.
+    pub fn as_map(&self) -> Option<&BTreeMap<Vec<u8>, IndexEntry>> {
+        match &self.0 {
+            Repr::Keys(map) => Some(map),
+            Repr::Hashed { .. } => None,
+        }
+    }
+
+    pub fn get(&self, key: &[u8], mut src: impl KeySource) -> Result<Option<IndexEntry>> {
+        match &self.0 {
+            Repr::Keys(map) => Ok(map.get(key).copied()),
+            Repr::Hashed { hash, slots } => {
+                let Some(slot) = slots.get(&hash(key)) else { return Ok(None) };
+                Ok(position(slot, key, &mut src)?.map(|i| slot.entries()[i]))
+            }
+        }
+    }
+
+    /// Point `key` at `e`, returning the entry it replaces. In a hashed index the entries
+    /// already under the key's hash must still be where they point, `e` need not be yet.
+    pub fn insert(&mut self, key: &[u8], e: IndexEntry, mut src: impl KeySource) -> Result<Option<IndexEntry>> {
+        let (hash, slots) = match &mut self.0 {
+            Repr::Keys(map) => return Ok(map.insert(key.to_vec(), e)),
+            Repr::Hashed { hash, slots } => (*hash, slots),
+        };
+        let h = hash(key);
+        let Some(slot) = slots.get_mut(&h) else {
+            slots.insert(h, Slot::One(e));
+            return Ok(None);
+        };
+        if let Some(i) = position(slot, key, &mut src)? {
+            return Ok(Some(match slot {
+                Slot::One(old) => std::mem::replace(old, e),
+                Slot::Many(v) => std::mem::replace(&mut v[i], e),
+            }));
+        }
+        slot.push(e);
+        Ok(None)
+    }
+
+    /// Drop `key`, returning its entry.
+    pub fn remove(&mut self, key: &[u8], mut src: impl KeySource) -> Result<Option<IndexEntry>> {
+        let (hash, slots) = match &mut self.0 {
+            Repr::Keys(map) => return Ok(map.remove(key)),
+            Repr::Hashed { hash, slots } => (*hash, slots),
+        };
+        let h = hash(key);
+        let Some(slot) = slots.get_mut(&h) else { return Ok(None) };
+        let Some(i) = position(slot, key, &mut src)? else { return Ok(None) };
+        let old = match slot {
+            Slot::One(e) => {
+                let e = *e;
+                slots.remove(&h);
+                e
+            }
+            Slot::Many(v) => {
+                let e = v.remove(i);
+                if let [last] = v[..] {
+                    *slot = Slot::One(last);
+                }
+                e
+            }
+        };
+        Ok(Some(old))
+    }
+
+    /// Every entry, in no particular order.
+    pub fn values(&self) -> Box<dyn Iterator<Item = &IndexEntry> + '_> {
+        match &self.0 {
+            Repr::Keys(map) => Box::new(map.values()),
+            Repr::Hashed { slots, .. } => Box::new(slots.values().flat_map(Slot::entries)),
+        }
+    }
+
+    /// Keep only the entries `keep` returns true for.
+    pub fn retain(&mut self, mut keep: impl FnMut(&IndexEntry) -> bool) {
+        match &mut self.0 {
+            Repr::Keys(map) => map.retain(|_, e| keep(e)),
+            Repr::Hashed { slots, .. } => slots.retain(|_, slot| {
+                let mut kept: Vec<IndexEntry> = slot.entries().iter().copied().filter(|e| keep(e)).collect();
+                match kept.len() {
+                    0 => return false,
+                    1 => *slot = Slot::One(kept.pop().unwrap()),
+                    _ => *slot = Slot::Many(kept),
+                }
+                true
+            }),
+        }
+    }
+
+    /// The entries with keys in `(lo, hi)`, in key order. An ordered index yields them as
+    /// it goes; a hashed one reads every key back through `src` and sorts them first.
+    pub fn range<'a>(&'a self, lo: Bound<&[u8]>, hi: Bound<&[u8]>, mut src: impl KeySource)
+        -> Result<Entries<'a>>
+    {
+        match &self.0 {
+            Repr::Keys(map) => Ok(Box::new(map.range::<[u8], _>((lo, hi)).map(|(k, e)| (Cow::Borrowed(&k[..]), *e)))),
+            Repr::Hashed { slots, .. } => {
+                let mut all: Vec<(Cow<[u8]>, IndexEntry)> = Vec::new();
+                for e in slots.values().flat_map(Slot::entries) {
+                    let key = src.key_at(e)?;
+                    if (lo, hi).contains(&key[..]) {
+                        all.push((Cow::Owned(key), *e));
+                    }
+                }
+                all.sort_by(|a, b| a.0.cmp(&b.0));
+                Ok(Box::new(all.into_iter()))
+            }
+        }
+    }
+}
+
+/// Key and entry pairs, the key borrowed from the index when it holds keys.
+pub type Entries<'a> = Box<dyn Iterator<Item = (Cow<'a, [u8]>, IndexEntry)> + 'a>;
+
+/// Where in `slot` the entry for `key` is, if it has one.
+fn position(slot: &Slot, key: &[u8], src: &mut impl KeySource) -> Result<Option<usize>> {
+    for (i, e) in slot.entries().iter().enumerate() {
+        if src.key_at(e)? == key {
+            return Ok(Some(i));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,21 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::engine::Engine;
+use crate::error::Result;
+
+/// JSON-valued keys on top of the byte API (the `serde` feature): values are encoded with
+/// serde_json and stored as plain bytes, so they mix freely with `set`/`get`.
+impl Engine {
+    pub fn set_json<T: Serialize + ?Sized>(&self, key: &[u8], val: &T) -> Result<()> {
+        self.set(key, &serde_json::to_vec(val)?)
+    }
+
+    /// `None` if the key is absent; an error if its value isn't JSON for a `T`.
+    pub fn get_json<T: DeserializeOwned>(&self, key: &[u8]) -> Result<Option<T>> {
+        match self.get(key)? {
+            Some(b) => Ok(Some(serde_json::from_slice(&b)?)),
+            None => Ok(None),
+        }
+    }
+}
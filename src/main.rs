@@ -5,27 +5,40 @@ use std::path::PathBuf;
 
 mod wal;
 mod pager;
+mod storage;
+mod error;
 mod engine;
+mod event_log;
+mod index;
 mod util;
 mod bench;
+mod dump;
+mod repl;
+#[cfg(feature = "serde")]
+mod json;
+#[cfg(feature = "async")]
+mod async_engine;
 
-use engine::Engine;
+use engine::{Engine, DATA_DIR_ENV};
 
 fn main() -> anyhow::Result<()> {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        println!("Usage: {} <cmd> [args]\n cmds: set|get|recovery|run_tests",
+        println!("Usage: {} <cmd> [args]\n cmds: set|get|delete|scan|repl|dump|restore|verify|repair|check|stats|metrics|recovery|run_tests
+ stats, metrics and verify take --json",
         args[0]);
         return Ok(());
     }
 
     let mut data_dir = PathBuf::from("./tinydb_data");
-    if let Ok(dir) = env::var("TINYDB_DATA_DIR") {
+    if let Ok(dir) = env::var(DATA_DIR_ENV) {
         data_dir = PathBuf::from(dir);
     }
     std::fs::create_dir_all(&data_dir)?;
 
     let cmd = args[1].as_str();
+    // machine-readable output, for the commands that report a struct
+    let json = args[2..].iter().any(|a| a == "--json");
     match cmd{
         "set" => {
             if args.len() != 4 {
@@ -33,8 +46,8 @@ fn main() -> anyhow::Result<()> {
             }
             let key = args[2].clone();
             let value = args[3].clone();
-            let mut db = Engine::open(&data_dir)?;
-            db.set(&key, value.as_bytes())?;
+            let db = Engine::open(&data_dir)?;
+            db.set(key.as_bytes(), value.as_bytes())?;
             println!("OK");
         }
         "get" => {
@@ -42,30 +55,217 @@ fn main() -> anyhow::Result<()> {
                 println!("Usage : get <key>"); return Ok(());
             }
             let key = args[2].clone();
-            let mut db = Engine::open(&data_dir)?;
-            match db.get(&key)? {
+            let db = Engine::open(&data_dir)?;
+            match db.get(key.as_bytes())? {
                 Some(v) => println!("Value: {}", String::from_utf8_lossy(&v)),
                 None => println!("Not found"),
             }
         }
+        "delete" => {
+            if args.len() != 3 {
+                println!("Usage : delete <key>"); return Ok(());
+            }
+            let key = args[2].clone();
+            let db = Engine::open(&data_dir)?;
+            if db.delete(key.as_bytes())? {
+                println!("Deleted");
+            } else {
+                println!("Not found");
+            }
+        }
+        "scan" => {
+            // usage: scan <prefix> [limit]
+            let limit = args.get(3).map(|s| s.parse::<usize>());
+            if args.len() < 3 || args.len() > 4 || matches!(limit, Some(Err(_))) {
+                println!("Usage : scan <prefix> [limit]"); return Ok(());
+            }
+            let limit = limit.and_then(Result::ok);
+            let db = Engine::open(&data_dir)?;
+            repl::scan(&db, args[2].as_bytes(), limit, &mut std::io::stdout().lock())?;
+        }
+        "repl" => {
+            // one engine for the whole session; quit or EOF closes it cleanly
+            use std::io::IsTerminal;
+            let stdin = std::io::stdin();
+            let prompt = stdin.is_terminal();
+            // a long WAL replay shouldn't look like a hang; overwrite one status line on stderr
+            let shown = std::cell::Cell::new(false);
+            let db = Engine::open_with_progress(&data_dir, |p| {
+                if prompt {
+                    eprint!("\rreplaying WAL: {} records ({} KiB)", p.records, p.bytes / 1024);
+                    shown.set(true);
+                }
+            })?;
+            if shown.get() {
+                eprintln!(", recovered in {:.1?}", db.recovery_time());
+            }
+            repl::run(&db, stdin.lock(), &mut std::io::stdout().lock(), prompt)?;
+            db.close()?;
+        }
+        "dump" => {
+            // usage: dump [file]  (stdout when no file is given)
+            let db = Engine::open(&data_dir)?;
+            let n = match args.get(2) {
+                Some(path) => dump::dump(&db, &mut std::io::BufWriter::new(std::fs::File::create(path)?))?,
+                None => dump::dump(&db, &mut std::io::stdout().lock())?,
+            };
+            eprintln!("dumped {} keys", n);
+        }
+        "restore" => {
+            if args.len() != 3 {
+                println!("Usage : restore <file>"); return Ok(());
+            }
+            if std::fs::read_dir(&data_dir)?.next().is_some() {
+                return Err(anyhow::anyhow!("restore target {} is not empty", data_dir.display()));
+            }
+            let db = Engine::open(&data_dir)?;
+            let mut input = std::io::BufReader::new(std::fs::File::open(&args[2])?);
+            let n = dump::restore(&db, &mut input)?;
+            println!("restored {} keys", n);
+        }
+        "verify" => {
+            let mut pager = pager::Pager::open_read_only(data_dir.join("tinydb_data.db"))?;
+            let report = pager.verify()?;
+            if json {
+                print_json(&report)?;
+            } else if report.corrupt.is_empty() {
+                println!("OK");
+            } else {
+                let ids: Vec<String> = report.corrupt.iter().map(|pid| pid.to_string()).collect();
+                println!("corrupt pages: {}", ids.join(", "));
+            }
+        }
+        "repair" => {
+            // rewrite the pages `verify` reports from the WAL, for a file that no longer reads
+            let out = Engine::repair(&data_dir)?;
+            let ids = |pids: &[u64]| pids.iter().map(|pid| pid.to_string()).collect::<Vec<_>>().join(", ");
+            if out.repaired.is_empty() && out.lost.is_empty() {
+                println!("OK");
+            }
+            if !out.repaired.is_empty() {
+                println!("repaired pages: {}", ids(&out.repaired));
+            }
+            if !out.lost.is_empty() {
+                println!("lost pages (not covered by the WAL): {}", ids(&out.lost));
+            }
+        }
+        "check" => {
+            // the index against the pages, where `verify` checks the pages alone
+            let db = Engine::open(&data_dir)?;
+            let problems = db.check_consistency()?;
+            if problems.is_empty() {
+                println!("OK");
+            } else {
+                for problem in &problems {
+                    println!("{}", problem);
+                }
+                println!("{} problems", problems.len());
+            }
+        }
+        "stats" => {
+            let db = Engine::open(&data_dir)?;
+            let st = db.stats()?;
+            if json {
+                print_json(&st)?;
+                return Ok(());
+            }
+            println!("live keys: {}", st.live_keys);
+            println!("pages: {}", st.pages);
+            println!("used bytes: {}", st.used_bytes);
+            println!("live bytes: {}", st.live_bytes);
+            println!("dead bytes: {}", st.dead_bytes);
+        }
+        "metrics" => {
+            // counted since open, so these are what opening the database cost
+            let db = Engine::open(&data_dir)?;
+            let m = db.metrics();
+            if json {
+                print_json(&m)?;
+                return Ok(());
+            }
+            println!("sets: {}, gets: {}, deletes: {}", m.sets, m.gets, m.deletes);
+            println!("wal: {} appends, {} fsyncs, {} bytes", m.wal_appends, m.wal_fsyncs, m.wal_bytes);
+            println!("pages: {} reads, {} writes, {} bytes written", m.page_reads, m.page_writes, m.data_bytes);
+            println!("cache: {} hits, {} misses", m.cache_hits, m.cache_misses);
+        }
         "recovery" => {
             let _db = Engine::open(&data_dir)?;
             println!("Recovery complete");
         }
         "run_tests" => {
-            dev_tests::simple_crash_recovery()?;
+            dev_tests::run_all()?;
             println!("Tests passed");
         }
         "bench" => {
-            // usage: cargo run --release -- bench <ops> <key_prefix> <value_size>
+            // usage: cargo run --release -- bench <ops> <key_prefix> <value_size> [--threads <n>] [--warmup <n>] [--trim]
             let ops: usize = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(10000);
             let key_prefix = args.get(3).cloned().unwrap_or_else(|| "k".to_string());
             let val_size: usize = args.get(4).and_then(|s| s.parse().ok()).unwrap_or(100);
-            bench::run_bench(&data_dir, ops, &key_prefix, val_size)?;
+            let flag = |name: &str| args.iter().position(|a| a == name)
+                .and_then(|i| args.get(i + 1)).and_then(|s| s.parse::<usize>().ok());
+            let threads = flag("--threads").unwrap_or(1);
+            let warmup = flag("--warmup").unwrap_or(0);
+            let trim = args.iter().any(|a| a == "--trim");
+            bench::run_bench(&data_dir, ops, &key_prefix, val_size, threads, warmup, trim)?;
+            println!("bench done");
+        }
+        "bench_read" => {
+            // usage: cargo run --release -- bench_read <ops> <key_prefix> [--dist uniform|zipf[:theta]]
+            let ops: usize = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(10000);
+            let key_prefix = args.get(3).cloned().unwrap_or_else(|| "k".to_string());
+            bench::run_read_bench(&data_dir, ops, &key_prefix, key_dist(&args)?)?;
+            println!("bench done");
+        }
+        "bench_mixed" => {
+            // usage: cargo run --release -- bench_mixed <ops> <read_pct> <value_size> [--dist uniform|zipf[:theta]]
+            let ops: usize = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(10000);
+            let read_pct: u8 = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(90).min(100);
+            let val_size: usize = args.get(4).and_then(|s| s.parse().ok()).unwrap_or(100);
+            bench::run_mixed_bench(&data_dir, ops, read_pct, val_size, key_dist(&args)?)?;
+            println!("bench done");
+        }
+        "bench_sync" => {
+            // usage: cargo run --release -- bench_sync <ops> <value_size>
+            let ops: usize = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(1000);
+            let val_size: usize = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(100);
+            bench::run_sync_bench(&data_dir, ops, val_size)?;
             println!("bench done");
         }
+        "bench_bulk" => {
+            // usage: cargo run --release -- bench_bulk <ops> <value_size>
+            let ops: usize = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(1000);
+            let val_size: usize = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(100);
+            bench::run_bulk_bench(&data_dir, ops, val_size)?;
+            println!("bench done");
+        }
+        "bench_crc" => {
+            // usage: cargo run --release -- bench_crc <buf_size> <iters>
+            let buf_size: usize = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(8192);
+            let iters: usize = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(10000);
+            bench::run_crc_bench(buf_size, iters);
+        }
 
         _ => println!("Unknown Command {}", cmd),
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Print `val` as one line of JSON, for `--json`.
+#[cfg(feature = "serde")]
+fn print_json(val: &impl serde::Serialize) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string(val)?);
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn print_json<T>(_: &T) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("--json needs the serde feature"))
+}
+
+/// The `--dist` option of the read and mixed benchmarks, uniform when absent.
+fn key_dist(args: &[String]) -> anyhow::Result<bench::KeyDist> {
+    match args.iter().position(|a| a == "--dist") {
+        Some(i) => bench::KeyDist::parse(args.get(i + 1).map_or("", String::as_str)),
+        None => Ok(bench::KeyDist::Uniform),
+    }
+}
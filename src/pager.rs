@@ -1,14 +1,24 @@
+use std::collections::{BTreeSet, HashMap};
 use std::fs::{OpenOptions, File};
 use std::io::{ Seek, SeekFrom, Write, Read};
 use std::path::Path;
-use crate::util::crc32;
+use crate::storage::Storage;
+#[cfg(target_os = "linux")]
+use crate::storage::{DirectFile, DIRECT_ALIGN};
+use crate::error::{Result, TinyDbError};
+use crate::util::{read_bytes, read_u32_le, read_u64_le, Checksum};
 use crate::wal::Lsn;
 
+/// Default page size, used for new files unless `open_with_page_size` picks another.
 pub const PAGE_SIZE: usize = 8192;
+/// Accepted page sizes: powers of two in this range.
+pub const MIN_PAGE_SIZE: usize = 512;
+pub const MAX_PAGE_SIZE: usize = 1 << 20;
 pub type PageId = u64;
 
 
-pub const HDR_SZ: usize = 28;
+/// Bytes reserved at the front of every page for the header; the rest is `Page::data`.
+pub const HDR_SZ: usize = Page::CRC_OFF + Page::CRC_SZ; // 28
 
 #[derive(Clone)]
 pub struct Page {
@@ -32,23 +42,31 @@ impl Page {
     const USED_SZ: usize = 4;
     const CRC_OFF: usize = Self::USED_OFF + Self::USED_SZ; // 24
     const CRC_SZ: usize = 4;
-    const HDR_SZ2: usize = Self::CRC_OFF + Self::CRC_SZ; // 28
-    // We keep HDR_SZ constant at 32 as previously used; the extra 4 bytes are padding/reserved.
-    // DATA starts at HDR_SZ.
-    // (Keep HDR_SZ defined earlier and equal to 32.)
-    // We'll use HDR_SZ constant from top-level (32).
+    // DATA starts at HDR_SZ, right after the CRC.
     pub fn new(id: PageId) -> Self {
+        Self::with_size(id, PAGE_SIZE)
+    }
+
+    /// Empty page of `page_size` bytes on disk, header included.
+    pub fn with_size(id: PageId, page_size: usize) -> Self {
         Self {
             id,
             lsn: 0,
             used: 0,
-            data: vec![0u8; PAGE_SIZE - HDR_SZ],
+            data: vec![0u8; page_size - HDR_SZ],
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// Size of this page on disk, header included.
+    pub fn size(&self) -> usize {
+        HDR_SZ + self.data.len()
+    }
+
+    /// Encode the page, checksummed with `sum`.
+    pub fn encode(&self, sum: Checksum) -> Vec<u8> {
         // Create full-size buffer initialized to zeros
-        let mut buf = vec![0u8; PAGE_SIZE];
+        let page_size = self.size();
+        let mut buf = vec![0u8; page_size];
 
         // Write header fields
         buf[Self::MAGIC_OFF..Self::MAGIC_OFF + Self::MAGIC_SZ]
@@ -63,51 +81,54 @@ impl Page {
 
         // Write page payload into DATA region (DATA starts at HDR_SZ)
         let data_start = HDR_SZ;
-        if self.data.len() != PAGE_SIZE - HDR_SZ {
-            // ensure invariant
-            panic!("page.data length mismatch: {} != {}", self.data.len(), PAGE_SIZE - HDR_SZ);
-        }
-        buf[data_start..PAGE_SIZE].copy_from_slice(&self.data);
+        buf[data_start..page_size].copy_from_slice(&self.data);
 
         // Build CRC source: header bytes excluding the CRC slot (0..CRC_OFF)
-        // concatenated with the data region (DATA_START .. PAGE_SIZE)
-        let mut crc_src = Vec::with_capacity(Self::CRC_OFF + (PAGE_SIZE - HDR_SZ));
+        // concatenated with the data region (DATA_START .. page_size)
+        let mut crc_src = Vec::with_capacity(Self::CRC_OFF + (page_size - HDR_SZ));
         crc_src.extend_from_slice(&buf[0..Self::CRC_OFF]); // magic,id,lsn,used
-        crc_src.extend_from_slice(&buf[data_start..PAGE_SIZE]); // data
+        crc_src.extend_from_slice(&buf[data_start..page_size]); // data
 
-        let crc = crc32(&crc_src);
+        let crc = sum.compute(&crc_src);
         buf[Self::CRC_OFF..Self::CRC_OFF + Self::CRC_SZ].copy_from_slice(&crc.to_le_bytes());
 
-        // final sanity: buffer length == PAGE_SIZE
-        assert_eq!(buf.len(), PAGE_SIZE);
+        // final sanity: buffer length == page size
+        assert_eq!(buf.len(), page_size);
         buf
     }
 
-    pub fn from_bytes(b: &[u8]) -> anyhow::Result<Self> {
-        if b.len() != PAGE_SIZE {
-            return Err(anyhow::anyhow!("page size mismatch (expected {}, got {})", PAGE_SIZE, b.len()));
+    /// Decode a CRC-32 (IEEE) page; its size is the length of `b`.
+    pub fn from_bytes(b: &[u8]) -> Result<Self> {
+        Self::decode(b, Checksum::Ieee)
+    }
+
+    /// Decode a page checksummed with `sum`.
+    pub fn decode(b: &[u8], sum: Checksum) -> Result<Self> {
+        if b.len() < MIN_PAGE_SIZE {
+            return Err(TinyDbError::ShortRead { got: b.len(), expected: MIN_PAGE_SIZE });
         }
+        let page_size = b.len();
 
         // Validate magic
-        let magic = u32::from_le_bytes(b[Self::MAGIC_OFF..Self::MAGIC_OFF + Self::MAGIC_SZ].try_into().unwrap());
+        let magic = read_u32_le(b, Self::MAGIC_OFF, "page header")?;
         if magic != 0xDEADBEEF {
-            return Err(anyhow::anyhow!("bad page magic: {:08x}", magic));
+            return Err(TinyDbError::BadMagic { what: "page" });
         }
 
-        let id = u64::from_le_bytes(b[Self::ID_OFF..Self::ID_OFF + Self::ID_SZ].try_into().unwrap());
-        let lsn = u64::from_le_bytes(b[Self::LSN_OFF..Self::LSN_OFF + Self::LSN_SZ].try_into().unwrap());
-        let used = u32::from_le_bytes(b[Self::USED_OFF..Self::USED_OFF + Self::USED_SZ].try_into().unwrap());
-        let crc_stored = u32::from_le_bytes(b[Self::CRC_OFF..Self::CRC_OFF + Self::CRC_SZ].try_into().unwrap());
+        let id = read_u64_le(b, Self::ID_OFF, "page header")?;
+        let lsn = read_u64_le(b, Self::LSN_OFF, "page header")?;
+        let used = read_u32_le(b, Self::USED_OFF, "page header")?;
+        let crc_stored = read_u32_le(b, Self::CRC_OFF, "page header")?;
 
         // Extract data
-        let mut data = vec![0u8; PAGE_SIZE - HDR_SZ];
-        data.copy_from_slice(&b[HDR_SZ..PAGE_SIZE]);
+        let mut data = vec![0u8; page_size - HDR_SZ];
+        data.copy_from_slice(&b[HDR_SZ..page_size]);
 
         // Recompute CRC over same bytes we used in to_bytes
-        let mut crc_src = Vec::with_capacity(Self::CRC_OFF + (PAGE_SIZE - HDR_SZ));
+        let mut crc_src = Vec::with_capacity(Self::CRC_OFF + (page_size - HDR_SZ));
         crc_src.extend_from_slice(&b[0..Self::CRC_OFF]);
-        crc_src.extend_from_slice(&b[HDR_SZ..PAGE_SIZE]);
-        let crc_calc = crc32(&crc_src);
+        crc_src.extend_from_slice(&b[HDR_SZ..page_size]);
+        let crc_calc = sum.compute(&crc_src);
 
         if crc_calc != crc_stored {
             // Print helpful debug — hex dump of first 64 bytes and the CRC mismatch
@@ -122,49 +143,722 @@ impl Page {
                 for byte in chunk { eprint!("{:02x} ", byte); }
                 eprintln!();
             }
-            return Err(anyhow::anyhow!("page crc mismatch id={}", id));
+            return Err(TinyDbError::PageCrcMismatch { id });
         }
 
         Ok(Self { id, lsn, used, data })
     }
 }
 
+/// Hit/miss counters for the pager's page cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// What `Pager::verify` found, as the `verify` command reports it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VerifyReport {
+    /// Pages in the file, the metadata page included.
+    pub pages: u64,
+    /// Ids of the pages that failed to decode, in order.
+    pub corrupt: Vec<PageId>,
+}
+
+/// Pages held by the engine's pager by default.
+pub const DEFAULT_CACHE_PAGES: usize = 64;
+
+/// Bounded LRU of decoded pages. Each entry carries the tick of its last use;
+/// eviction drops the entry with the smallest tick. A linear scan is fine at these sizes.
+struct PageCache {
+    capacity: usize,
+    tick: u64,
+    pages: HashMap<PageId, (u64, Page)>,
+    stats: CacheStats,
+}
+
+impl PageCache {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, tick: 0, pages: HashMap::new(), stats: CacheStats::default() }
+    }
+
+    fn get(&mut self, pid: PageId) -> Option<Page> {
+        self.tick += 1;
+        match self.pages.get_mut(&pid) {
+            Some((used, page)) => {
+                *used = self.tick;
+                self.stats.hits += 1;
+                Some(page.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn put(&mut self, page: &Page) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if !self.pages.contains_key(&page.id) && self.pages.len() >= self.capacity {
+            let lru = self.pages.iter().min_by_key(|(_, (used, _))| *used).map(|(pid, _)| *pid);
+            if let Some(pid) = lru {
+                self.pages.remove(&pid);
+            }
+        }
+        self.pages.insert(page.id, (self.tick, page.clone()));
+    }
+}
+
+/// Page 0 is the superblock, holding pager metadata rather than kv data:
+/// b"TDBM"<u32 page_size><u32 version><u32 checksum><u64 next_page><u64 clean><u32 count>
+/// <u32 name_len><name, MAX_NAME_LEN bytes zero-padded>(<u64 page_id>)*count,
+/// where `checksum` is the `Checksum` id every page is summed with, the ids are the free-page
+/// list, `clean` is the clean-shutdown LSN plus one (0: the last writer didn't close
+/// cleanly) and `name` is the database name (see `claim_name`), empty if it has none.
+/// Data pages start at 1.
+/// The page size, version and checksum sit at fixed offsets so they can be read before the
+/// page itself is verified; a file of another version is refused before its layout is
+/// trusted. Every integer in the file, here and in page headers, is little-endian whatever
+/// the host, so files move between machines.
+pub const META_PAGE: PageId = 0;
+const META_MAGIC: &[u8; 4] = b"TDBM";
+/// v6: overflow pages. v5: gap entries on data pages. v4: database name. v3: checksum id.
+const FORMAT_VERSION: u32 = 6;
+const META_FIXED_SZ: usize = 40 + MAX_NAME_LEN;
+/// Longest database name the superblock holds.
+pub const MAX_NAME_LEN: usize = 64;
+
 pub struct Pager {
-    file: File,
+    file: Box<dyn Storage>,
+    cache: PageCache,
+    read_only: bool,
+    // read-only mode: pages rebuilt by WAL replay, held in memory since they can't be written
+    overlay: HashMap<PageId, Page>,
+    // freed pages, handed out again (lowest id first) before the file grows
+    free: BTreeSet<PageId>,
+    // first id past every allocated page
+    page_count: PageId,
+    // one-page sidecar each page goes through first; see `open_with_double_write`
+    double_write: Option<File>,
+    page_size: usize,
+    // data page appends continue on; META_PAGE until one has been allocated
+    next_page: PageId,
+    // WAL position of the last clean shutdown, if the superblock has one
+    clean_lsn: Option<Lsn>,
+    // page checksum, fixed when the file is created
+    checksum: Checksum,
+    // bytes written to the data file by page writes since open
+    bytes_written: u64,
+    // pages read from / written to the data file since open
+    page_reads: u64,
+    page_writes: u64,
+    // database name from the superblock; empty if it has none
+    name: String,
+    // read a partial page at the end of the file as empty; see `set_tolerate_partial_tail`
+    tolerate_partial_tail: bool,
 }
 
 impl Pager {
-    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let f = OpenOptions::new().create(true).read(true).write(true).open(path)?;
-        Ok(Self { file: f })
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_cache(path, 0)
+    }
+
+    /// Open with an LRU cache of up to `capacity` pages (0 disables caching).
+    /// The cache is write-through: `write_page` always hits the file, so durability is unchanged.
+    pub fn open_with_cache<P: AsRef<Path>>(path: P, capacity: usize) -> Result<Self> {
+        Self::open_rw(path.as_ref(), capacity, false, None, Checksum::default())
+    }
+
+    /// Like `open_with_cache`, creating a new file with pages checksummed by `checksum`.
+    /// An existing file keeps the checksum it was created with.
+    pub fn open_with_checksum<P: AsRef<Path>>(path: P, capacity: usize, checksum: Checksum) -> Result<Self> {
+        Self::open_rw(path.as_ref(), capacity, false, None, checksum)
+    }
+
+    /// Open with `page_size`-byte pages (a power of two in MIN_PAGE_SIZE..=MAX_PAGE_SIZE).
+    /// The size is recorded in the metadata page of a new file, and every later open reads
+    /// it from there; opening an existing file with a different size is an error.
+    pub fn open_with_page_size<P: AsRef<Path>>(path: P, page_size: usize) -> Result<Self> {
+        Self::open_rw(path.as_ref(), 0, false, Some(page_size), Checksum::default())
+    }
+
+    /// Open with a double-write buffer: a one-page sidecar file (`<path>.dwb`) that each page
+    /// is written and fsynced to before it is written in place, and the data file is fsynced
+    /// right after. A crash mid-write can then tear at most one of the two copies. On open,
+    /// if the page held by the buffer is torn in its main slot, it is copied back from the
+    /// buffer. Costs two fsyncs per page write.
+    pub fn open_with_double_write<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_rw(path.as_ref(), 0, true, None, Checksum::default())
+    }
+
+    /// Open with `O_DIRECT`, so page reads and writes skip the OS page cache and hit the
+    /// disk, e.g. to benchmark the real cost of page IO. O_DIRECT needs buffers, offsets and
+    /// lengths aligned to the device's block size; the file's page size must be a multiple of
+    /// DIRECT_ALIGN (the default 8192 is). No page cache is kept here either.
+    #[cfg(target_os = "linux")]
+    pub fn open_direct<P: AsRef<Path>>(path: P) -> Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let f = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+        let mut p = Self::from_file(Box::new(DirectFile::new(f)), 0, false);
+        p.init_meta(None)?;
+        if !p.page_size.is_multiple_of(DIRECT_ALIGN) {
+            return Err(TinyDbError::Invalid(format!("{}-byte pages are not aligned for O_DIRECT ({})", p.page_size, DIRECT_ALIGN)));
+        }
+        Ok(p)
+    }
+
+    fn open_rw(path: &Path, capacity: usize, double_write: bool, page_size: Option<usize>, checksum: Checksum) -> Result<Self> {
+        if let Some(sz) = page_size {
+            check_page_size(sz)?;
+        }
+        let f = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(path)?;
+        let mut p = Self::from_file(Box::new(f), capacity, false);
+        p.page_size = page_size.unwrap_or(PAGE_SIZE);
+        p.checksum = checksum;
+        if double_write {
+            let mut dwb_path = path.as_os_str().to_owned();
+            dwb_path.push(".dwb");
+            let dwb = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(dwb_path)?;
+            p.double_write = Some(dwb);
+            p.recover_from_double_write()?;
+        }
+        p.init_meta(page_size)?;
+        Ok(p)
+    }
+
+    /// A pager over `storage` instead of a file: empty storage is set up as a new file with
+    /// default-size pages, anything else must hold one. No cache, no double-write buffer.
+    pub fn open_with_storage(storage: impl Storage + 'static) -> Result<Self> {
+        let mut p = Self::from_file(Box::new(storage), 0, false);
+        p.init_meta(None)?;
+        Ok(p)
+    }
+
+    fn init_meta(&mut self, page_size: Option<usize>) -> Result<()> {
+        if self.file.len()? == 0 {
+            // new file: lay down an empty metadata page
+            self.write_meta()?;
+            self.sync()?;
+        }
+        self.load_meta(page_size)
+    }
+
+    /// Open an existing data file without write access; `write_page` returns an error.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let f = OpenOptions::new().read(true).open(path)?;
+        let mut p = Self::from_file(Box::new(f), DEFAULT_CACHE_PAGES, true);
+        p.load_meta(None)?;
+        Ok(p)
+    }
+
+    fn from_file(file: Box<dyn Storage>, capacity: usize, read_only: bool) -> Self {
+        Self {
+            file,
+            cache: PageCache::new(capacity),
+            read_only,
+            overlay: HashMap::new(),
+            free: BTreeSet::new(),
+            page_count: META_PAGE + 1,
+            double_write: None,
+            page_size: PAGE_SIZE,
+            next_page: META_PAGE,
+            clean_lsn: None,
+            checksum: Checksum::default(),
+            bytes_written: 0,
+            page_reads: 0,
+            page_writes: 0,
+            name: String::new(),
+            tolerate_partial_tail: true,
+        }
+    }
+
+    /// If the double-write buffer holds an intact page whose main slot fails to decode (the
+    /// crash hit while writing it in place), put the buffered copy back. A main slot that is
+    /// intact, or lies past the end of the file, is left alone: the buffer may be stale.
+    fn recover_from_double_write(&mut self) -> Result<()> {
+        // runs before the metadata page is read, so the page size comes from the buffered page.
+        // The checksum never changes once the file exists, so even a torn superblock has it.
+        if self.double_write.is_none() {
+            return Ok(());
+        }
+        let sum = self.read_meta_header()?.map_or(self.checksum, |(_, sum)| sum);
+        let dwb = self.double_write.as_mut().unwrap();
+        dwb.seek(SeekFrom::Start(0))?;
+        let mut buf = Vec::new();
+        dwb.read_to_end(&mut buf)?;
+        let Ok(page) = Page::decode(&buf, sum) else { return Ok(()) };
+        let off = page.id * buf.len() as u64;
+        let file_len = self.file.len()?;
+        if off >= file_len {
+            return Ok(());
+        }
+        let mut main = vec![0u8; buf.len()];
+        let n = self.file.read_at(&mut main, off)?;
+        if n == main.len() && Page::decode(&main, sum).is_ok() {
+            return Ok(());
+        }
+        self.file.write_at(&buf, off)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    /// The page size and checksum from the fixed part of the metadata page, read without
+    /// verifying the page; None if the file is too short to hold them.
+    fn read_meta_header(&mut self) -> Result<Option<(usize, Checksum)>> {
+        let mut fixed = [0u8; HDR_SZ + META_FIXED_SZ];
+        if self.file.read_at(&mut fixed, 0)? < fixed.len() {
+            return Ok(None);
+        }
+        let fixed = &fixed[HDR_SZ..];
+        if &fixed[0..4] != META_MAGIC {
+            return Err(TinyDbError::BadMagic { what: "metadata page" });
+        }
+        let version = read_u32_le(fixed, 8, "metadata page")?;
+        if version != FORMAT_VERSION {
+            return Err(TinyDbError::UnsupportedVersion { what: "data file", found: version, supported: FORMAT_VERSION });
+        }
+        let page_size = read_u32_le(fixed, 4, "metadata page")? as usize;
+        check_page_size(page_size)?;
+        let id = read_u32_le(fixed, 12, "metadata page")?;
+        let sum = Checksum::from_id(id).ok_or_else(|| TinyDbError::Corrupt(format!("unknown page checksum {}", id)))?;
+        Ok(Some((page_size, sum)))
+    }
+
+    /// Read the page size, checksum, next_page and free list off the metadata page, checking
+    /// the size against `expected` if the caller asked for one. A file too short to hold the
+    /// page (a reader racing a writer's first open) keeps the defaults and has no free pages.
+    fn load_meta(&mut self, expected: Option<usize>) -> Result<()> {
+        if let Some((stored, sum)) = self.read_meta_header()? {
+            if expected.is_some_and(|sz| sz != stored) {
+                return Err(TinyDbError::Invalid(format!("file has {}-byte pages, not {}", stored, expected.unwrap())));
+            }
+            self.page_size = stored;
+            self.checksum = sum;
+        }
+        let file_pages = self.file.len()? / self.page_size as u64;
+        self.page_count = file_pages.max(META_PAGE + 1);
+        let Some(meta) = self.read_from_disk(META_PAGE)? else {
+            return Ok(());
+        };
+        self.next_page = read_u64_le(&meta.data, 16, "metadata page")?;
+        // allocated but never written pages don't extend the file; still count it as taken
+        self.page_count = self.page_count.max(self.next_page + 1);
+        let clean = read_u64_le(&meta.data, 24, "metadata page")?;
+        self.clean_lsn = clean.checked_sub(1);
+        let name_len = read_u32_le(&meta.data, 36, "metadata page")? as usize;
+        if name_len > MAX_NAME_LEN {
+            return Err(TinyDbError::Corrupt(format!("corrupt database name: {} bytes", name_len)));
+        }
+        self.name = String::from_utf8(read_bytes(&meta.data, 40, name_len, "metadata page")?.to_vec())
+            .map_err(|_| TinyDbError::Corrupt("database name is not UTF-8".into()))?;
+        let count = read_u32_le(&meta.data, 32, "metadata page")? as usize;
+        if count > self.free_list_cap() {
+            return Err(TinyDbError::Corrupt(format!("corrupt free list: {} entries", count)));
+        }
+        self.free = (0..count)
+            .map(|i| read_u64_le(&meta.data, META_FIXED_SZ + i * 8, "free list"))
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    fn write_meta(&mut self) -> Result<()> {
+        let mut meta = Page::with_size(META_PAGE, self.page_size);
+        meta.data[0..4].copy_from_slice(META_MAGIC);
+        meta.data[4..8].copy_from_slice(&(self.page_size as u32).to_le_bytes());
+        meta.data[8..12].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        meta.data[12..16].copy_from_slice(&self.checksum.id().to_le_bytes());
+        meta.data[16..24].copy_from_slice(&self.next_page.to_le_bytes());
+        let clean = self.clean_lsn.map_or(0, |lsn| lsn + 1);
+        meta.data[24..32].copy_from_slice(&clean.to_le_bytes());
+        meta.data[32..36].copy_from_slice(&(self.free.len() as u32).to_le_bytes());
+        meta.data[36..40].copy_from_slice(&(self.name.len() as u32).to_le_bytes());
+        meta.data[40..40 + self.name.len()].copy_from_slice(self.name.as_bytes());
+        for (i, pid) in self.free.iter().enumerate() {
+            let off = META_FIXED_SZ + i * 8;
+            meta.data[off..off + 8].copy_from_slice(&pid.to_le_bytes());
+        }
+        meta.used = (META_FIXED_SZ + self.free.len() * 8) as u32;
+        self.write_to_disk(&meta)
+    }
+
+    /// Freed ids beyond this don't fit on the metadata page; those pages simply stay unused.
+    fn free_list_cap(&self) -> usize {
+        (self.page_size - HDR_SZ - META_FIXED_SZ) / 8
+    }
+
+    /// Name of the database this file belongs to; empty if it has none.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Check that this file belongs to the database called `name` ("" for an unnamed one),
+    /// recording the name if the file is new: no page allocated yet and no name stored.
+    /// Catches a data file opened under the wrong name, e.g. after a rename.
+    pub fn claim_name(&mut self, name: &str) -> Result<()> {
+        if self.name == name {
+            return Ok(());
+        }
+        if name.len() > MAX_NAME_LEN {
+            return Err(TinyDbError::Invalid(format!("database name is {} bytes, the limit is {}", name.len(), MAX_NAME_LEN)));
+        }
+        if self.name.is_empty() && self.next_page == META_PAGE && !self.read_only {
+            self.name = name.to_string();
+            self.write_meta()?;
+            return self.sync();
+        }
+        Err(TinyDbError::Invalid(format!("data file belongs to database {:?}, not {:?}", self.name, name)))
+    }
+
+    /// The checksum pages in this file are summed with.
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    /// Size of every page in the file, header included.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// The data page appends continue on, as recorded in the superblock; META_PAGE if no
+    /// data page has been allocated yet.
+    pub fn next_page(&self) -> PageId {
+        self.next_page
+    }
+
+    /// Record the page appends continue on. Written to the superblock without a sync; the
+    /// data-file sync at the next checkpoint makes it durable.
+    pub fn set_next_page(&mut self, pid: PageId) -> Result<()> {
+        if self.read_only {
+            return Err(TinyDbError::ReadOnly);
+        }
+        if pid == self.next_page {
+            return Ok(());
+        }
+        self.next_page = pid;
+        self.page_count = self.page_count.max(pid + 1);
+        self.write_meta()
+    }
+
+    /// The WAL position the last writer recorded when it closed cleanly, if it did.
+    pub fn clean_shutdown_lsn(&self) -> Option<Lsn> {
+        self.clean_lsn
+    }
+
+    /// Record (or clear) the clean-shutdown marker, synced before returning.
+    pub fn set_clean_shutdown(&mut self, lsn: Option<Lsn>) -> Result<()> {
+        if self.read_only {
+            return Err(TinyDbError::ReadOnly);
+        }
+        self.clean_lsn = lsn;
+        self.write_meta()?;
+        self.sync()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Read-only mode: keep `page` in memory in place of its on-disk version. Used by WAL
+    /// replay so a reader sees changes a writer logged but hadn't written to pages yet.
+    pub fn stage_page(&mut self, page: Page) {
+        self.overlay.insert(page.id, page);
     }
 
-    pub fn read_page(&mut self, pid: PageId) -> anyhow::Result<Page> {
-        let off = pid as u64 * PAGE_SIZE as u64;
-        self.file.seek(SeekFrom::Start(off))?;
-        let mut buf = vec![0u8; PAGE_SIZE];
-        let n = self.file.read(&mut buf)?;
+    /// Whether `read_page` takes a partial page at the end of the file for an empty one, as
+    /// it does by default, or fails with `ShortRead`. A crash while a write was extending
+    /// the file leaves such a page; the double-write buffer, if on, restores it at open.
+    pub fn set_tolerate_partial_tail(&mut self, tolerate: bool) {
+        self.tolerate_partial_tail = tolerate;
+    }
+
+    pub fn read_page(&mut self, pid: PageId) -> Result<Page> {
+        if let Some(page) = self.overlay.get(&pid) {
+            return Ok(page.clone());
+        }
+        if let Some(page) = self.cache.get(pid) {
+            return Ok(page);
+        }
+        match self.read_from_disk(pid) {
+            Ok(Some(page)) => {
+                self.cache.put(&page);
+                Ok(page)
+            }
+            // not present: return empty page. Neither is a partial page at the end of the
+            // file: it is one whose first write, the one extending the file, was cut short,
+            // so nothing was there before it (`verify_all` still reports it)
+            Ok(None) => Ok(Page::with_size(pid, self.page_size)),
+            Err(TinyDbError::ShortRead { .. }) if self.tolerate_partial_tail => Ok(Page::with_size(pid, self.page_size)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read and verify page `pid` from the file; None if it lies past the end.
+    fn read_from_disk(&mut self, pid: PageId) -> Result<Option<Page>> {
+        let off = pid * self.page_size as u64;
+        let mut buf = vec![0u8; self.page_size];
+        let n = self.file.read_at(&mut buf, off)?;
         if n == 0 {
-            // not present: return empty page
-            return Ok(Page::new(pid));
+            return Ok(None);
+        }
+        self.page_reads += 1;
+        if n != self.page_size {
+            return Err(TinyDbError::ShortRead { got: n, expected: self.page_size });
+        }
+        // a hole: allocated, then passed over by writes to later pages
+        if buf.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+        Ok(Some(Page::decode(&buf, self.checksum)?))
+    }
+
+    pub fn write_page(&mut self, page: &Page) -> Result<()> {
+        if page.id == META_PAGE {
+            return Err(TinyDbError::Invalid(format!("page {} is reserved for pager metadata", META_PAGE)));
+        }
+        self.write_to_disk(page)?;
+        self.cache.put(page);
+        Ok(())
+    }
+
+    /// Write several pages, with one write per run of consecutive ids instead of one per
+    /// page. If an id appears twice the later page
+    /// wins. With a double-write buffer every page still goes through it one at a time.
+    pub fn write_pages(&mut self, pages: &[Page]) -> Result<()> {
+        if self.read_only {
+            return Err(TinyDbError::ReadOnly);
+        }
+        let mut sorted: Vec<&Page> = Vec::with_capacity(pages.len());
+        for page in pages {
+            if page.id == META_PAGE {
+                return Err(TinyDbError::Invalid(format!("page {} is reserved for pager metadata", META_PAGE)));
+            }
+            if page.size() != self.page_size {
+                return Err(TinyDbError::Invalid(format!("page {} is {} bytes, file pages are {}", page.id, page.size(), self.page_size)));
+            }
+            sorted.push(page);
+        }
+        // stable, so duplicates keep their order and dedup_by keeps the last one
+        sorted.sort_by_key(|p| p.id);
+        sorted.reverse();
+        sorted.dedup_by_key(|p| p.id);
+        sorted.reverse();
+        if self.double_write.is_some() {
+            for page in sorted {
+                self.write_page(page)?;
+            }
+            return Ok(());
+        }
+        let mut run_start = 0;
+        for i in 1..=sorted.len() {
+            if i < sorted.len() && sorted[i].id == sorted[i - 1].id + 1 {
+                continue;
+            }
+            let run = &sorted[run_start..i];
+            let mut buf = Vec::with_capacity(run.len() * self.page_size);
+            for page in run {
+                buf.extend_from_slice(&page.encode(self.checksum));
+            }
+            self.file.write_at(&buf, run[0].id * self.page_size as u64)?;
+            self.bytes_written += buf.len() as u64;
+            self.page_writes += run.len() as u64;
+            run_start = i;
+        }
+        for page in sorted {
+            self.page_count = self.page_count.max(page.id + 1);
+            self.cache.put(page);
+        }
+        Ok(())
+    }
+
+    fn write_to_disk(&mut self, page: &Page) -> Result<()> {
+        if self.read_only {
+            return Err(TinyDbError::ReadOnly);
+        }
+        if page.size() != self.page_size {
+            return Err(TinyDbError::Invalid(format!("page {} is {} bytes, file pages are {}", page.id, page.size(), self.page_size)));
+        }
+        let off = page.id * self.page_size as u64;
+        let b = page.encode(self.checksum);
+        if let Some(dwb) = self.double_write.as_mut() {
+            dwb.seek(SeekFrom::Start(0))?;
+            dwb.write_all(&b)?;
+            dwb.sync_data()?;
+        }
+        self.file.write_at(&b, off)?;
+        self.bytes_written += b.len() as u64;
+        self.page_writes += 1;
+        if self.double_write.is_some() {
+            // the buffer's only slot gets reused by the next write; this copy must be durable first
+            self.file.sync_data()?;
+        }
+        self.page_count = self.page_count.max(page.id + 1);
+        Ok(())
+    }
+
+    /// Hand out a page for new data: the lowest freed id if there is one, otherwise the
+    /// next id past the end of the file. A reused page is reset to empty and dropped from
+    /// the on-disk free list (both fsynced) before returning, so nothing written to it
+    /// afterwards can land on top of its old contents or be handed out twice.
+    /// Either way the id becomes the superblock's `next_page`.
+    pub fn allocate_page(&mut self) -> Result<PageId> {
+        self.allocate(true)
+    }
+
+    /// Like `allocate_page`, but leaving `next_page` where it is, for a page appends must
+    /// never continue on (an overflow page, whose data isn't entries).
+    pub fn allocate_detached_page(&mut self) -> Result<PageId> {
+        self.allocate(false)
+    }
+
+    fn allocate(&mut self, append: bool) -> Result<PageId> {
+        if self.read_only {
+            return Err(TinyDbError::ReadOnly);
+        }
+        if let Some(pid) = self.free.pop_first() {
+            self.write_page(&Page::with_size(pid, self.page_size))?;
+            if append {
+                self.next_page = pid;
+            }
+            self.write_meta()?;
+            self.sync()?;
+            return Ok(pid);
         }
-        if n != PAGE_SIZE {
-            return Err(anyhow::anyhow!("short read {} != {}", n, PAGE_SIZE));
+        let pid = self.page_count;
+        self.page_count += 1;
+        if append {
+            self.next_page = pid;
+            self.write_meta()?;
         }
-        Page::from_bytes(&buf)
+        Ok(pid)
     }
 
-    pub fn write_page(&mut self, page: &Page) -> anyhow::Result<()> {
-        let off = page.id as u64 * PAGE_SIZE as u64;
-        self.file.seek(SeekFrom::Start(off))?;
-        let b = page.to_bytes();
-        self.file.write_all(&b)?;
-        self.file.flush()?;
+    /// Return `pid` to the free list for `allocate_page` to reuse. The caller must be done
+    /// with its contents. Durable after the next `sync`.
+    pub fn free_page(&mut self, pid: PageId) -> Result<()> {
+        if self.read_only {
+            return Err(TinyDbError::ReadOnly);
+        }
+        if pid == META_PAGE || pid >= self.page_count {
+            return Err(TinyDbError::Invalid(format!("page {} is not allocated", pid)));
+        }
+        if self.free.len() >= self.free_list_cap() {
+            return Ok(());
+        }
+        if !self.free.insert(pid) {
+            return Err(TinyDbError::Invalid(format!("page {} is already free", pid)));
+        }
+        self.write_meta()
+    }
+
+    pub fn is_free(&self, pid: PageId) -> bool {
+        self.free.contains(&pid)
+    }
+
+    /// Forget every freed page, e.g. once compaction has rewritten all pages below the end.
+    pub fn clear_free_list(&mut self) -> Result<()> {
+        if self.free.is_empty() {
+            return Ok(());
+        }
+        self.free.clear();
+        self.write_meta()
+    }
+
+    /// One past the highest allocated page id (the metadata page included).
+    pub fn page_count(&self) -> PageId {
+        self.page_count
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_all()?;
         Ok(())
     }
 
-    pub fn sync(&mut self) -> anyhow::Result<()> {
+    /// Shrink the file to exactly `page_count` pages, dropping cached copies and free-list
+    /// entries of cut pages. The metadata page is never cut.
+    pub fn truncate_to(&mut self, page_count: PageId) -> Result<()> {
+        if self.read_only {
+            return Err(TinyDbError::ReadOnly);
+        }
+        let page_count = page_count.max(META_PAGE + 1);
+        if self.free.iter().any(|pid| *pid >= page_count) || self.next_page >= page_count {
+            self.free.retain(|pid| *pid < page_count);
+            self.next_page = self.next_page.min(page_count - 1);
+            self.write_meta()?;
+        }
+        self.file.set_len(page_count * self.page_size as u64)?;
         self.file.sync_all()?;
+        self.cache.pages.retain(|pid, _| *pid < page_count);
+        self.page_count = page_count;
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Every data page in id order, through the cache like `read_page`: pages that were
+    /// never written or are holes come back empty, free pages are skipped, and a page that
+    /// fails to decode yields its error without ending the walk.
+    pub fn iter_pages(&mut self) -> impl Iterator<Item = Result<Page>> + '_ {
+        (META_PAGE + 1..self.page_count).filter_map(move |pid| {
+            if self.free.contains(&pid) { None } else { Some(self.read_page(pid)) }
+        })
+    }
+
+    /// Read every page in the file straight from disk (bypassing the cache) and return the
+    /// ids of those that fail to decode: bad CRC, bad magic, or a partial page at the end.
+    /// Keeps going past the first bad page so one scan reports all of them.
+    pub fn verify_all(&mut self) -> Result<Vec<PageId>> {
+        let pages = self.file.len()?.div_ceil(self.page_size as u64);
+        let mut bad = Vec::new();
+        for pid in 0..pages {
+            if self.read_from_disk(pid).is_err() {
+                bad.push(pid);
+            }
+        }
+        Ok(bad)
+    }
+
+    /// `verify_all`, with the number of pages it read.
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        let pages = self.file.len()?.div_ceil(self.page_size as u64);
+        Ok(VerifyReport { pages, corrupt: self.verify_all()? })
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats
+    }
+
+    /// Bytes written to the data file since open: every page write, the metadata page's
+    /// included. Copies through the double-write buffer and recovery repairs aren't counted.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Pages read from the data file since open; cache hits don't read any.
+    pub fn page_reads(&self) -> u64 {
+        self.page_reads
+    }
+
+    /// Pages written to the data file since open, counted like `bytes_written`.
+    pub fn page_writes(&self) -> u64 {
+        self.page_writes
+    }
+}
+
+fn check_page_size(page_size: usize) -> Result<()> {
+    if !page_size.is_power_of_two() || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+        return Err(TinyDbError::Invalid(format!(
+            "page size {} must be a power of two between {} and {}", page_size, MIN_PAGE_SIZE, MAX_PAGE_SIZE
+        )));
+    }
+    Ok(())
+}
+
@@ -0,0 +1,126 @@
+use std::io::{BufRead, Write};
+
+use crate::engine::Engine;
+
+/// Read commands from `input` one per line and run them against `db`, which stays open for
+/// the whole session:
+///   set <key> <value> | get <key> | del <key> | scan <prefix> [limit] | quit
+/// Arguments are split on whitespace; quote one ('...' or "...") to keep its spaces, and
+/// inside double quotes `\"` and `\\` stand for themselves. Output uses the same wording as
+/// the one-shot commands. Errors are printed and the session continues. `prompt` writes
+/// "> " before each line, for interactive use.
+pub fn run<R: BufRead, W: Write>(db: &Engine, input: R, out: &mut W, prompt: bool) -> anyhow::Result<()> {
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            write!(out, "> ")?;
+            out.flush()?;
+        }
+        let Some(line) = lines.next() else { break };
+        let args = match split_args(&line?) {
+            Ok(args) => args,
+            Err(e) => {
+                writeln!(out, "error: {}", e)?;
+                continue;
+            }
+        };
+        let Some(cmd) = args.first() else { continue };
+        if cmd == "quit" || cmd == "exit" {
+            break;
+        }
+        if let Err(e) = run_command(db, &args, out) {
+            writeln!(out, "error: {}", e)?;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn run_command<W: Write>(db: &Engine, args: &[String], out: &mut W) -> anyhow::Result<()> {
+    match (args[0].as_str(), &args[1..]) {
+        ("set", [key, val]) => {
+            db.set(key.as_bytes(), val.as_bytes())?;
+            writeln!(out, "OK")?;
+        }
+        ("get", [key]) => match db.get(key.as_bytes())? {
+            Some(v) => writeln!(out, "Value: {}", String::from_utf8_lossy(&v))?,
+            None => writeln!(out, "Not found")?,
+        },
+        ("del", [key]) => {
+            writeln!(out, "{}", if db.delete(key.as_bytes())? { "Deleted" } else { "Not found" })?;
+        }
+        ("scan", [prefix]) => scan(db, prefix.as_bytes(), None, out)?,
+        ("scan", [prefix, limit]) if limit.parse::<usize>().is_ok() => {
+            scan(db, prefix.as_bytes(), limit.parse().ok(), out)?
+        }
+        ("set", _) => writeln!(out, "Usage : set <key> <value>")?,
+        ("get", _) => writeln!(out, "Usage : get <key>")?,
+        ("del", _) => writeln!(out, "Usage : del <key>")?,
+        ("scan", _) => writeln!(out, "Usage : scan <prefix> [limit]")?,
+        (cmd, _) => writeln!(out, "Unknown Command {}", cmd)?,
+    }
+    Ok(())
+}
+
+/// Print the first `limit` (default all) live pairs whose keys start with `prefix`, in key
+/// order, as `key = value` lines and then a count. Values are shown lossily, so one that
+/// isn't UTF-8 is marked as such after it. Shared with the one-shot `scan` command.
+pub fn scan<W: Write>(db: &Engine, prefix: &[u8], limit: Option<usize>, out: &mut W) -> anyhow::Result<()> {
+    let mut n = 0;
+    for kv in db.scan_prefix(prefix).take(limit.unwrap_or(usize::MAX)) {
+        let (k, v) = kv?;
+        write!(out, "{} = {}", String::from_utf8_lossy(&k), String::from_utf8_lossy(&v))?;
+        if std::str::from_utf8(&v).is_err() {
+            write!(out, " (non-UTF-8, {} bytes)", v.len())?;
+        }
+        writeln!(out)?;
+        n += 1;
+    }
+    writeln!(out, "({} keys)", n)?;
+    Ok(())
+}
+
+/// Split a command line into arguments, honouring quotes. An unterminated quote is an error.
+pub fn split_args(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut cur = String::new();
+    // whether `cur` is an argument, so that "" counts as one
+    let mut in_arg = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') if c == '"' => match chars.next() {
+                            Some(e @ ('"' | '\\')) => cur.push(e),
+                            Some(e) => {
+                                cur.push('\\');
+                                cur.push(e);
+                            }
+                            None => return Err(anyhow::anyhow!("unterminated quote")),
+                        },
+                        Some(ch) => cur.push(ch),
+                        None => return Err(anyhow::anyhow!("unterminated quote")),
+                    }
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut cur));
+                    in_arg = false;
+                }
+            }
+            c => {
+                in_arg = true;
+                cur.push(c);
+            }
+        }
+    }
+    if in_arg {
+        args.push(cur);
+    }
+    Ok(args)
+}
@@ -0,0 +1,225 @@
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+
+/// What the pager and the WAL need from the bytes underneath them: a seekable file with
+/// a length, fsync, and `File::try_clone`-style handles onto the same data. Implement it to
+/// put an engine on something other than a file; see `Engine::open_with_storage`.
+pub trait Storage: Read + Write + Seek + Send {
+    fn len(&self) -> io::Result<u64>;
+    /// Read into `buf` from offset `off`, stopping short only at the end of the data;
+    /// returns how many bytes were read. Leaves the cursor after them.
+    fn read_at(&mut self, buf: &mut [u8], off: u64) -> io::Result<usize> {
+        self.seek(SeekFrom::Start(off))?;
+        let mut got = 0;
+        while got < buf.len() {
+            match self.read(&mut buf[got..]) {
+                Ok(0) => break,
+                Ok(n) => got += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(got)
+    }
+    /// Write all of `buf` at offset `off`, growing the data if it ends past it.
+    fn write_at(&mut self, buf: &[u8], off: u64) -> io::Result<()> {
+        self.seek(SeekFrom::Start(off))?;
+        self.write_all(buf)?;
+        self.flush()
+    }
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    fn sync_all(&self) -> io::Result<()>;
+    /// Like `sync_all`, but may skip metadata that doesn't affect reading the data back.
+    fn sync_data(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+    /// Another handle onto the same data, sharing the cursor the way a dup'd fd does.
+    fn try_clone(&self) -> io::Result<Box<dyn Storage>>;
+}
+
+impl Storage for File {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Storage>> {
+        Ok(Box::new(File::try_clone(self)?))
+    }
+}
+
+/// A growable in-memory buffer standing in for a file; nothing survives the last handle.
+/// Syncs are no-ops.
+#[derive(Clone, Default)]
+pub struct MemFile(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.lock().unwrap().seek(pos)
+    }
+}
+
+impl Storage for MemFile {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.lock().unwrap().get_ref().len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.0.lock().unwrap().get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Storage>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// Alignment O_DIRECT needs for buffer addresses, file offsets and transfer lengths. 4096
+/// covers both 512-byte and 4K-sector devices.
+#[cfg(target_os = "linux")]
+pub const DIRECT_ALIGN: usize = 4096;
+
+/// A file opened with `O_DIRECT`, bypassing the OS page cache. Every transfer goes through a
+/// DIRECT_ALIGN-aligned bounce buffer. Reads may start and end anywhere (the covering blocks
+/// are read and the wanted bytes copied out), but writes must start on a DIRECT_ALIGN offset
+/// and be a multiple of it long; anything else fails with InvalidInput. Page writes satisfy
+/// this whenever the page size is a multiple of DIRECT_ALIGN.
+#[cfg(target_os = "linux")]
+pub struct DirectFile {
+    file: File,
+    // shared between clones, like the cursor of a dup'd fd
+    pos: Arc<Mutex<u64>>,
+}
+
+#[cfg(target_os = "linux")]
+impl DirectFile {
+    pub fn new(file: File) -> Self {
+        Self { file, pos: Arc::new(Mutex::new(0)) }
+    }
+}
+
+/// A zeroed `len`-byte buffer starting at a DIRECT_ALIGN boundary, carved out of a larger
+/// allocation (returned alongside so it outlives the slice).
+#[cfg(target_os = "linux")]
+fn aligned_buf(len: usize) -> (Vec<u8>, usize) {
+    let raw = vec![0u8; len + DIRECT_ALIGN];
+    let start = raw.as_ptr().align_offset(DIRECT_ALIGN);
+    (raw, start)
+}
+
+#[cfg(target_os = "linux")]
+impl Read for DirectFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pos = self.pos.lock().unwrap();
+        let first = *pos - *pos % DIRECT_ALIGN as u64;
+        let skip = (*pos - first) as usize;
+        let span = (skip + buf.len()).div_ceil(DIRECT_ALIGN) * DIRECT_ALIGN;
+        let (mut raw, start) = aligned_buf(span);
+        let block = &mut raw[start..start + span];
+        let mut got = 0;
+        // a short read means end of file
+        while got < span {
+            match self.file.read_at(&mut block[got..], first + got as u64)? {
+                0 => break,
+                n => got += n,
+            }
+        }
+        let n = got.saturating_sub(skip).min(buf.len());
+        buf[..n].copy_from_slice(&block[skip..skip + n]);
+        *pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Write for DirectFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pos = self.pos.lock().unwrap();
+        if !pos.is_multiple_of(DIRECT_ALIGN as u64) || !buf.len().is_multiple_of(DIRECT_ALIGN) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unaligned direct write of {} bytes at {}", buf.len(), *pos),
+            ));
+        }
+        let (mut raw, start) = aligned_buf(buf.len());
+        raw[start..start + buf.len()].copy_from_slice(buf);
+        self.file.write_all_at(&raw[start..start + buf.len()], *pos)?;
+        *pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Seek for DirectFile {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        let mut pos = self.pos.lock().unwrap();
+        let new = match to {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(d) => self.file.metadata()?.len().checked_add_signed(d),
+            SeekFrom::Current(d) => pos.checked_add_signed(d),
+        };
+        *pos = new.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"))?;
+        Ok(*pos)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Storage for DirectFile {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn Storage>> {
+        Ok(Box::new(Self { file: self.file.try_clone()?, pos: self.pos.clone() }))
+    }
+}
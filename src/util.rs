@@ -1,8 +1,24 @@
-#[allow(dead_code)]
+#![allow(dead_code)]
 
 use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::error::{Result, TinyDbError};
+
+/// CRC-32 (IEEE, reflected polynomial 0xEDB88320), one table lookup per byte.
 pub fn crc32(data: &[u8]) -> u32 {
+    let table = crc32_table();
+    let mut crc: u32 = 0xffffffff;
+    for &b in data {
+        crc = (crc >> 8) ^ table[((crc ^ b as u32) & 0xff) as usize];
+    }
+    !crc
+}
+
+/// The original bit-at-a-time CRC-32; kept as the reference the table version must match.
+pub fn crc32_bitwise(data: &[u8]) -> u32 {
     let mut crc: u32 = 0xffffffff;
     for &b in data {
         crc ^= b as u32;
@@ -17,8 +33,258 @@ pub fn crc32(data: &[u8]) -> u32 {
     !crc
 }
 
+/// table[i] = CRC of the single byte i, i.e. the effect of 8 bitwise steps at once
+fn crc32_table() -> &'static [u32; 256] {
+    static TABLE: OnceLock<[u32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0u32; 256];
+        for (i, slot) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                if (crc & 1) != 0 {
+                    crc = (crc >> 1) ^ 0xedb88320;
+                } else {
+                    crc >>= 1;
+                }
+            }
+            *slot = crc;
+        }
+        table
+    })
+}
+
+/// Checksum algorithm for pages and WAL records, picked when a database is created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Checksum {
+    /// CRC-32 (IEEE), the original.
+    #[default]
+    Ieee,
+    /// CRC-32C (Castagnoli), computed with the SSE4.2 crc32 instruction where available.
+    Crc32c,
+}
+
+impl Checksum {
+    pub fn compute(self, data: &[u8]) -> u32 {
+        match self {
+            Checksum::Ieee => crc32(data),
+            Checksum::Crc32c => crc32c(data),
+        }
+    }
+
+    /// The id stored on disk.
+    pub fn id(self) -> u32 {
+        match self {
+            Checksum::Ieee => 0,
+            Checksum::Crc32c => 1,
+        }
+    }
+
+    pub fn from_id(id: u32) -> Option<Self> {
+        match id {
+            0 => Some(Checksum::Ieee),
+            1 => Some(Checksum::Crc32c),
+            _ => None,
+        }
+    }
+}
+
+/// CRC-32C (Castagnoli, reflected polynomial 0x82F63B78): in hardware if the CPU has it,
+/// otherwise by table.
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_hw(data).unwrap_or_else(|| crc32c_sw(data))
+}
+
+/// Table-driven CRC-32C, one lookup per byte.
+pub fn crc32c_sw(data: &[u8]) -> u32 {
+    let table = crc32c_table();
+    let mut crc: u32 = 0xffffffff;
+    for &b in data {
+        crc = (crc >> 8) ^ table[((crc ^ b as u32) & 0xff) as usize];
+    }
+    !crc
+}
+
+/// CRC-32C with the SSE4.2 crc32 instruction, 8 bytes at a time; None without SSE4.2.
+pub fn crc32c_hw(data: &[u8]) -> Option<u32> {
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("sse4.2") {
+        // SAFETY: the CPU supports SSE4.2, checked just above
+        return Some(unsafe { crc32c_sse42(data) });
+    }
+    let _ = data;
+    None
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(data: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+    let mut chunks = data.chunks_exact(8);
+    let mut crc: u64 = 0xffffffff;
+    for c in &mut chunks {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(c.try_into().unwrap()));
+    }
+    let mut crc = crc as u32;
+    for &b in chunks.remainder() {
+        crc = _mm_crc32_u8(crc, b);
+    }
+    !crc
+}
+
+fn crc32c_table() -> &'static [u32; 256] {
+    static TABLE: OnceLock<[u32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0u32; 256];
+        for (i, slot) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                if (crc & 1) != 0 {
+                    crc = (crc >> 1) ^ 0x82f63b78;
+                } else {
+                    crc >>= 1;
+                }
+            }
+            *slot = crc;
+        }
+        table
+    })
+}
+
+/// Small seeded xorshift64* PRNG so tests and benchmarks are reproducible.
+pub struct XorShift64(u64);
+
+impl XorShift64 {
+    pub fn new(seed: u64) -> Self {
+        // state must be non-zero
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for b in buf {
+            *b = self.next_u64() as u8;
+        }
+    }
+}
+
+/// Seeded Zipfian sampler over ranks `0..n`: rank `i` comes up with probability
+/// proportional to `1 / (i + 1)^theta`, so rank 0 is the hottest. Uses the constant-time
+/// method of Gray et al. ("Quickly Generating Billion-Record Synthetic Databases"), as YCSB
+/// does, after an O(n) setup; `theta` must lie in (0, 1). Ranks 0 and 1 come up exactly as
+/// often as they should, the rest approximately (within a few percent). Ranks aren't
+/// scrambled, so the hot ones are adjacent.
+pub struct Zipf {
+    rng: XorShift64,
+    n: usize,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipf {
+    pub fn new(n: usize, theta: f64, seed: u64) -> Self {
+        assert!(theta > 0.0 && theta < 1.0, "zipf theta must be in (0, 1), got {}", theta);
+        let n = n.max(1);
+        let zeta = |n: usize| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let (zetan, zeta2) = (zeta(n), zeta(2.min(n)));
+        let eta = (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan);
+        Zipf { rng: XorShift64::new(seed), n, theta, alpha: 1.0 / (1.0 - theta), zetan, eta }
+    }
+
+    /// The probability of rank `i`.
+    pub fn probability(&self, i: usize) -> f64 {
+        1.0 / ((i + 1) as f64).powf(self.theta) / self.zetan
+    }
+
+    pub fn next_rank(&mut self) -> usize {
+        // 53 random bits make a uniform float in [0, 1)
+        let u = (self.rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.n - 1);
+        }
+        let rank = (self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as usize;
+        rank.min(self.n - 1)
+    }
+}
+
+/// `len` bytes at `off` in `buf`, or a `Truncated` error naming `what` if they aren't all
+/// there. For parsing on-disk structures, where a damaged length must not become a panic.
+pub fn read_bytes<'a>(buf: &'a [u8], off: usize, len: usize, what: &'static str) -> Result<&'a [u8]> {
+    buf.get(off..).and_then(|b| b.get(..len)).ok_or(TinyDbError::Truncated { what, off, need: len, len: buf.len() })
+}
+
+fn read_array<const N: usize>(buf: &[u8], off: usize, what: &'static str) -> Result<[u8; N]> {
+    buf.get(off..).and_then(|b| b.first_chunk::<N>()).copied()
+        .ok_or(TinyDbError::Truncated { what, off, need: N, len: buf.len() })
+}
+
+/// The little-endian u32 at `off` in `buf`, checked like `read_bytes`.
+pub fn read_u32_le(buf: &[u8], off: usize, what: &'static str) -> Result<u32> {
+    read_array(buf, off, what).map(u32::from_le_bytes)
+}
+
+/// The little-endian u64 at `off` in `buf`, checked like `read_bytes`.
+pub fn read_u64_le(buf: &[u8], off: usize, what: &'static str) -> Result<u64> {
+    read_array(buf, off, what).map(u64::from_le_bytes)
+}
+
 pub fn read_all<R: Read> (r: &mut R) -> std::io::Result<Vec<u8>>{
     let mut b = Vec::new();
     r.read_to_end(&mut b)?;
     Ok(b)
-}
\ No newline at end of file
+}
+
+/// Wall-clock time as unix-epoch milliseconds.
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// The per-user directory applications keep their data in: `$XDG_DATA_HOME` (if absolute)
+/// or `~/.local/share` on Linux and other Unixes, `~/Library/Application Support` on macOS,
+/// `%APPDATA%` on Windows. None if the environment doesn't say where that is.
+///
+/// These are the rules of `directories::BaseDirs::data_dir`, written out here instead of
+/// taking that crate on: the tree builds offline against a fixed set of dependencies, and
+/// `directories` (with `dirs-sys` and, on Windows, `windows-sys` under it) isn't among them.
+/// The one difference is that Windows reads `%APPDATA%` rather than asking the shell for
+/// the roaming AppData folder, which is where `%APPDATA%` points unless it has been changed.
+/// Swapping the crate in later moves no existing database.
+pub fn user_data_dir() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+    if cfg!(windows) {
+        var("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        Some(var("HOME")?.join("Library/Application Support"))
+    } else {
+        var("XDG_DATA_HOME").filter(|p| p.is_absolute()).or_else(|| Some(var("HOME")?.join(".local/share")))
+    }
+}
+
+/// fsync the directory containing `path` so a rename into it is durable.
+/// Directories can't be opened for syncing on Windows; NTFS metadata is journaled there anyway.
+pub fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
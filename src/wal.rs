@@ -1,85 +1,833 @@
+use std::borrow::Cow;
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom, Write, Read};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::util::crc32;
+use crate::error::{Result, TinyDbError};
+use crate::storage::{MemFile, Storage};
+use crate::util::{sync_parent_dir, Checksum};
 
 pub type Lsn = u64;
 
+const WAL_MAGIC: &[u8; 4] = b"TWAL";
+/// v8: OVF records. v7: CKP records without a dirty-page list. v6: UPD records. v5: CLR
+/// records. v4: record flags. v3: checksum id in the file header. v2: record header CRC.
+/// (v1 logs had no file header at all.)
+const WAL_VERSION: u32 = 8;
+const FILE_HDR_SZ: u64 = 12;
+/// Fixed bytes in front of every payload.
+const REC_HDR_SZ: u64 = 8 + 8 + 1 + 4 + 4;
+/// Record flag: the payload is zstd-compressed.
+const FLAG_ZSTD: u8 = 1;
+/// With compression on, payloads shorter than this are stored as they are.
+#[cfg(feature = "compression")]
+const COMPRESS_MIN_BYTES: usize = 256;
+/// Segment size `open` rotates at.
+pub const DEFAULT_SEGMENT_BYTES: u64 = 16 << 20;
+/// Replay reports progress after this many records or this much time, whichever comes first.
+const PROGRESS_RECORDS: u64 = 1000;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
 /*
 Simple  WAL File with append, fsync and sequential replay
+The log is a directory of numbered segment files, wal-000001.log, wal-000002.log, ...;
+records are appended to the highest-numbered one, and a new segment is started once it has
+grown past the size limit. LSNs keep increasing across segments, so every record in a
+segment is older than every record in the next one.
+Segment: WAL_MAGIC + u32 version + u32 checksum id, then records back to back. All integers
+are little-endian.
+Record: total_len(8) + lsn(8) + flags(1) + hdr_crc(4) + crc(4) + payload. total_len covers
+everything after itself; hdr_crc covers total_len + lsn + flags so a corrupt length is caught
+before it is used; crc covers the payload as stored, i.e. compressed if FLAG_ZSTD is set.
+Both use the segment's checksum.
+Group commit: concurrent `sync` calls share fsyncs. One caller becomes the leader and
+fsyncs everything appended so far; the others wait on a condvar until the "synced up to"
+watermark covers their records.
 */
 
+/// How far a replay has got, passed to the progress callback of `replay_with_progress`.
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayProgress {
+    /// Records visited so far.
+    pub records: u64,
+    /// Bytes of those records, headers included.
+    pub bytes: u64,
+    /// LSN of the last record visited.
+    pub lsn: Lsn,
+    /// Time since the replay started.
+    pub elapsed: Duration,
+}
+
+struct SyncState {
+    // every LSN below this is durable
+    synced_lsn: Lsn,
+    // a leader is currently inside fsync
+    in_progress: bool,
+}
+
+/// The segments of a log, oldest first; only the last one is open.
+struct Segments {
+    seqs: Vec<u64>,
+    file: Box<dyn Storage>,
+    // bytes in `file`
+    len: u64,
+    // what `file`'s records are summed with, from its header
+    checksum: Checksum,
+}
+
 pub struct Wal{
-    file: Arc<Mutex<File>>,
+    // segment directory; None for an in-memory log, which is a single segment that never rotates
+    dir: Option<PathBuf>,
+    max_segment_bytes: u64,
+    // checksum for segments this log creates
+    checksum: Checksum,
+    // zstd-compress large payloads; see `open_with_compression`
+    compress: bool,
+    // segments `truncate_before` always leaves, newest first; see `with_retention`
+    retain_segments: usize,
+    log: Mutex<Segments>,
     next_lsn: Arc<Mutex<Lsn>>,
+    sync_state: Mutex<SyncState>,
+    synced: Condvar,
+    fsyncs: AtomicU64,
+    // record bytes appended since open, headers included
+    bytes_written: AtomicU64,
+    // records appended since open
+    appends: AtomicU64,
+    // record bytes appended since open or since the last truncation that dropped every record
+    unckpt_bytes: AtomicU64,
+    read_only: bool,
 }
 
 impl Wal {
-    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self>{
-        let f = OpenOptions::new().create(true).append(true).read(true).open(path)?;
-        let mut reader = f.try_clone()?;
-        let next = compute_next_lsn(&mut reader)?;
-        Ok(Self {file: Arc::new(Mutex::new(f)), next_lsn: Arc::new(Mutex::new(next)) })
+    /// Open (or create) the log in directory `dir`, rotating segments at DEFAULT_SEGMENT_BYTES.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self>{
+        Self::open_with_segment_size(dir, DEFAULT_SEGMENT_BYTES)
+    }
+
+    /// Open (or create) the log in directory `dir`, starting a new segment once the current
+    /// one holds `max_segment_bytes` or more. A segment can overshoot by one record.
+    pub fn open_with_segment_size<P: AsRef<Path>>(dir: P, max_segment_bytes: u64) -> Result<Self> {
+        Self::open_with_checksum(dir, max_segment_bytes, Checksum::default())
+    }
+
+    /// Like `open_with_segment_size`, summing records in new segments with `checksum`.
+    /// Existing segments keep the checksum in their header, and appends to the live one
+    /// follow it until the next rotation.
+    pub fn open_with_checksum<P: AsRef<Path>>(dir: P, max_segment_bytes: u64, checksum: Checksum) -> Result<Self> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let mut seqs = list_segments(dir)?;
+        if seqs.is_empty() {
+            create_segment(dir, 1, checksum)?;
+            seqs.push(1);
+        }
+        let path = segment_path(dir, *seqs.last().unwrap());
+        let mut f: Box<dyn Storage> = Box::new(OpenOptions::new().append(true).read(true).open(path)?);
+        let (next, len, sum) = prepare_tail(&mut *f, checksum)?;
+        let next = if next == 0 { last_lsn_before(dir, &seqs)? } else { next };
+        let log = Segments { seqs, file: f, len, checksum: sum };
+        Ok(Self::from_parts(Some(dir), max_segment_bytes, checksum, log, next, false))
+    }
+
+    /// Like `open`, zstd-compressing payloads of COMPRESS_MIN_BYTES or more when `enabled`
+    /// (and when that makes them shorter). Reading a log never needs this: compressed
+    /// records are flagged and decompressed on replay either way.
+    #[cfg(feature = "compression")]
+    pub fn open_with_compression<P: AsRef<Path>>(dir: P, enabled: bool) -> Result<Self> {
+        Ok(Self::open(dir)?.with_compression(enabled))
+    }
+
+    /// This log, compressing as `open_with_compression` describes when `enabled`.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compress = enabled;
+        self
+    }
+
+    /// This log, keeping the newest `segments` segments (the live one included) through
+    /// `truncate_before` even when every record in them is older than it asks for, as a
+    /// window of history for `Engine::get_as_of` or backups. Segments newer than that are
+    /// kept as always, so 0 and 1 keep only what is needed. An in-memory log has one segment
+    /// and nothing to keep.
+    pub fn with_retention(mut self, segments: usize) -> Self {
+        self.retain_segments = segments;
+        self
+    }
+
+    /// A log kept in an in-memory buffer rather than a file; it is gone once dropped.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::open_with_storage(MemFile::default(), Checksum::default())
     }
 
-    pub fn append(&self, payload: &[u8]) -> anyhow::Result<Lsn> {
-        let mut f = self.file.lock().unwrap();
+    /// A log in `storage` rather than a segment directory: a single segment that never
+    /// rotates. Empty storage gets a segment header summing with `checksum`; otherwise the
+    /// existing records are kept (a torn tail cut off) and appends continue after them.
+    pub fn open_with_storage(storage: impl Storage + 'static, checksum: Checksum) -> Result<Self> {
+        let mut f: Box<dyn Storage> = Box::new(storage);
+        let (next, len, sum) = prepare_tail(&mut *f, checksum)?;
+        Ok(Self::from_parts(None, u64::MAX, checksum, Segments { seqs: vec![1], file: f, len, checksum: sum }, next, false))
+    }
+
+    /// Open an existing log for reading only: no torn-tail repair, and `append` errors.
+    pub fn open_read_only<P: AsRef<Path>>(dir: P) -> Result<Self>{
+        let dir = dir.as_ref();
+        let seqs = list_segments(dir)?;
+        let Some(&last) = seqs.last() else {
+            return Err(TinyDbError::Invalid(format!("no WAL segments in {}", dir.display())));
+        };
+        let mut f = File::open(segment_path(dir, last))?;
+        let (next, len) = compute_next_lsn(&mut f)?;
+        let next = if next == 0 { last_lsn_before(dir, &seqs)? } else { next };
+        let sum = read_file_header(&mut f)?.unwrap_or_default();
+        let log = Segments { seqs, file: Box::new(f), len, checksum: sum };
+        Ok(Self::from_parts(Some(dir), u64::MAX, sum, log, next, true))
+    }
+
+    fn from_parts(dir: Option<&Path>, max_segment_bytes: u64, checksum: Checksum, log: Segments, next: Lsn, read_only: bool) -> Self {
+        Self {
+            dir: dir.map(Path::to_path_buf),
+            max_segment_bytes,
+            checksum,
+            compress: false,
+            retain_segments: 0,
+            log: Mutex::new(log),
+            next_lsn: Arc::new(Mutex::new(next)),
+            sync_state: Mutex::new(SyncState { synced_lsn: next, in_progress: false }),
+            synced: Condvar::new(),
+            fsyncs: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            appends: AtomicU64::new(0),
+            unckpt_bytes: AtomicU64::new(0),
+            read_only,
+        }
+    }
+
+    /// LSN that the next `append` will be assigned.
+    pub fn next_lsn(&self) -> Lsn {
+        *self.next_lsn.lock().unwrap()
+    }
+
+    /// Make sure future appends are numbered at or above `lsn`. Needed after truncation
+    /// empties the log, since `open` can no longer derive the counter from the records.
+    pub fn ensure_next_lsn(&self, lsn: Lsn) {
+        let mut g = self.next_lsn.lock().unwrap();
+        *g = (*g).max(lsn);
+        let mut st = self.sync_state.lock().unwrap();
+        st.synced_lsn = st.synced_lsn.max(lsn);
+    }
+
+    /// Number of segment files the log currently spans.
+    pub fn segment_count(&self) -> usize {
+        self.log.lock().unwrap().seqs.len()
+    }
+
+    /// Drop the records with an LSN below `lsn`, a whole segment at a time: each segment
+    /// holding nothing at or above `lsn` is deleted, oldest first, so a crash midway leaves a
+    /// log that is still contiguous. Records below `lsn` that share a segment with newer ones
+    /// stay; callers skip them by LSN. When every record is below `lsn` the live segment is
+    /// closed first so it can go too. The newest segments `with_retention` asks for stay
+    /// whatever they hold.
+    /// An in-memory log is rewritten in place instead, keeping exactly the records >= `lsn`.
+    pub fn truncate_before(&self, lsn: Lsn) -> Result<()> {
+        if self.read_only {
+            return Err(TinyDbError::ReadOnly);
+        }
+        let mut log = self.log.lock().unwrap();
+        let Some(dir) = &self.dir else {
+            let mut kept = file_header(log.checksum).to_vec();
+            for rec in records_in(log.file.try_clone().map_err(TinyDbError::from)) {
+                let (rec_lsn, payload, _) = rec?;
+                if rec_lsn >= lsn {
+                    kept.extend_from_slice(&encode_record(rec_lsn, &payload, log.checksum, self.compress));
+                }
+            }
+            log.file.set_len(0)?;
+            log.file.seek(SeekFrom::Start(0))?;
+            log.file.write_all(&kept)?;
+            log.len = kept.len() as u64;
+            self.unckpt_bytes.store(log.len - FILE_HDR_SZ, Ordering::Relaxed);
+            return Ok(());
+        };
+        let next = self.next_lsn();
+        if next <= lsn {
+            self.unckpt_bytes.store(0, Ordering::Relaxed);
+        }
+        if next <= lsn && log.len > FILE_HDR_SZ {
+            self.rotate(&mut log)?;
+        }
+        // every record in a segment is below the first LSN of the segment after it
+        let mut bound = next;
+        let mut bounds = vec![0; log.seqs.len()];
+        for (i, &seq) in log.seqs.iter().enumerate().rev() {
+            bounds[i] = bound;
+            if let Some(first) = first_lsn(&segment_path(dir, seq))? {
+                bound = first;
+            }
+        }
+        let obsolete = bounds[..log.seqs.len() - 1].iter().take_while(|&&b| b <= lsn).count()
+            .min(log.seqs.len().saturating_sub(self.retain_segments));
+        for &seq in &log.seqs[..obsolete] {
+            std::fs::remove_file(segment_path(dir, seq))?;
+        }
+        if obsolete > 0 {
+            sync_parent_dir(&segment_path(dir, log.seqs[0]))?;
+            log.seqs.drain(..obsolete);
+        }
+        Ok(())
+    }
+
+    /// Close the live segment and start the next one. The closed segment is fsynced first:
+    /// `sync_to` only ever fsyncs the live segment, so nothing else would.
+    fn rotate(&self, log: &mut Segments) -> Result<()> {
+        let Some(dir) = &self.dir else { return Ok(()) };
+        log.file.sync_all()?;
+        let seq = log.seqs.last().unwrap() + 1;
+        log.file = Box::new(create_segment(dir, seq, self.checksum)?);
+        log.len = FILE_HDR_SZ;
+        log.checksum = self.checksum;
+        log.seqs.push(seq);
+        Ok(())
+    }
+
+    /// Append a record and return its LSN; durable once `sync_to` passes it. The record is
+    /// encoded whole and goes to the file in one write. Deliberately there is no userspace
+    /// buffer holding records back until `sync`: the OS has every appended record, so a
+    /// process crash loses none under any sync policy, and readers of the file see them.
+    pub fn append(&self, payload: &[u8]) -> Result<Lsn> {
+        if self.read_only {
+            return Err(TinyDbError::ReadOnly);
+        }
+        let mut log = self.log.lock().unwrap();
+        if log.len >= self.max_segment_bytes && log.len > FILE_HDR_SZ {
+            self.rotate(&mut log)?;
+        }
         let mut lsn_g = self.next_lsn.lock().unwrap();
         let lsn = *lsn_g;
-        // construct record
-        let crc = crc32(payload);
-        let total_len = 8 + 4 + (payload.len() as u64); // lsn(8) + crc(4) + payload
-        f.write_all(&total_len.to_le_bytes())?;
-        f.write_all(&lsn.to_le_bytes())?;
-        f.write_all(&crc.to_le_bytes())?;
-        f.write_all(payload)?;
+        let rec = encode_record(lsn, payload, log.checksum, self.compress);
+        // a file opened for append ignores the cursor, but replay may have moved it and
+        // an in-memory log has no append mode
+        log.file.seek(SeekFrom::End(0))?;
+        log.file.write_all(&rec)?;
+        log.len += rec.len() as u64;
+        self.bytes_written.fetch_add(rec.len() as u64, Ordering::Relaxed);
+        self.appends.fetch_add(1, Ordering::Relaxed);
+        self.unckpt_bytes.fetch_add(rec.len() as u64, Ordering::Relaxed);
         *lsn_g += 1;
         Ok(lsn)
     }
 
-    pub fn sync(&self) -> anyhow::Result<()> {
-        let f = self.file.lock().unwrap();
-        f.sync_all()?;
-        Ok(())
+    /// Append `payload` as the first record of a fresh segment, fsync it, then drop every
+    /// record before it as `truncate_before` does. This is how a checkpoint record is written:
+    /// a crash at any point leaves either that record durable or every older segment still
+    /// there. Its own bytes don't count towards `unckpt_bytes`. Nothing may append meanwhile.
+    pub fn append_checkpoint(&self, payload: &[u8]) -> Result<Lsn> {
+        if self.read_only {
+            return Err(TinyDbError::ReadOnly);
+        }
+        {
+            let mut log = self.log.lock().unwrap();
+            if log.len > FILE_HDR_SZ {
+                self.rotate(&mut log)?;
+            }
+        }
+        let lsn = self.append(payload)?;
+        self.sync_to(lsn + 1)?;
+        self.truncate_before(lsn)?;
+        self.unckpt_bytes.store(0, Ordering::Relaxed);
+        Ok(lsn)
+    }
+
+    /// Make every record appended so far durable.
+    pub fn sync(&self) -> Result<()> {
+        self.sync_to(self.next_lsn())
+    }
+
+    /// Block until every LSN below `lsn` is durable, fsyncing at most once on behalf of
+    /// all the appends that piled up since the last fsync.
+    pub fn sync_to(&self, lsn: Lsn) -> Result<()> {
+        let mut st = self.sync_state.lock().unwrap();
+        loop {
+            if st.synced_lsn >= lsn { return Ok(()); }
+            if !st.in_progress { break; }
+            st = self.synced.wait(st).unwrap();
+        }
+        st.in_progress = true;
+        drop(st);
+
+        // snapshot the covered LSN under the log lock (so those writes are complete), then
+        // fsync a dup of the handle so appenders aren't blocked for the duration. Records in
+        // segments closed since were fsynced by the rotation.
+        let res = (|| -> Result<Lsn> {
+            let log = self.log.lock().unwrap();
+            let covered = self.next_lsn();
+            let handle = log.file.try_clone()?;
+            drop(log);
+            handle.sync_all()?;
+            self.fsyncs.fetch_add(1, Ordering::Relaxed);
+            Ok(covered)
+        })();
+
+        let mut st = self.sync_state.lock().unwrap();
+        st.in_progress = false;
+        if let Ok(covered) = res {
+            st.synced_lsn = st.synced_lsn.max(covered);
+        }
+        self.synced.notify_all();
+        res.map(|_| ())
+    }
+
+    /// Every LSN below this is known to be durable. Records in segments that have since
+    /// been closed may be too, as a rotation fsyncs them, without this moving.
+    pub fn synced_lsn(&self) -> Lsn {
+        self.sync_state.lock().unwrap().synced_lsn
+    }
+
+    /// The checksum the live segment sums its records with.
+    pub fn checksum(&self) -> Checksum {
+        self.log.lock().unwrap().checksum
+    }
+
+    /// Number of fsyncs issued on the log so far.
+    pub fn fsync_count(&self) -> u64 {
+        self.fsyncs.load(Ordering::Relaxed)
+    }
+
+    /// Bytes appended to the log since open, record headers included. Segment headers and
+    /// the rewrites done by truncation aren't counted.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Records appended to the log since open.
+    pub fn append_count(&self) -> u64 {
+        self.appends.load(Ordering::Relaxed)
+    }
+
+    /// Record bytes a checkpoint has yet to truncate away: those appended since open or
+    /// since the last `truncate_before` that dropped every record. A truncation that keeps
+    /// some records of a directory log doesn't lower it, so it can overstate, never under-.
+    pub fn unckpt_bytes(&self) -> u64 {
+        self.unckpt_bytes.load(Ordering::Relaxed)
     }
     
-    pub fn replay_from_start<P: AsRef<Path>> (path:P, mut visitor: impl FnMut(Lsn, Vec<u8>) -> anyhow::Result<()>) -> anyhow::Result<()> {
-        let mut f = File::open(path)?;
+    /// Visit every record in order, across all segments. A truncated record at the tail
+    /// (crash mid-append) ends the log cleanly; a CRC mismatch on a fully-read record is
+    /// corruption and an error.
+    pub fn replay_from_start<P: AsRef<Path>> (path:P, visitor: impl FnMut(Lsn, Vec<u8>) -> Result<()>) -> Result<()> {
+        Self::replay_from_start_with_progress(path, &|_| {}, visitor)
+    }
+
+    /// Like `replay_from_start`, also calling `progress` every PROGRESS_RECORDS records or
+    /// PROGRESS_INTERVAL, whichever comes first, and once more at the end if any record was
+    /// visited. Not called at all for an empty log.
+    pub fn replay_from_start_with_progress<P: AsRef<Path>>(
+        path: P,
+        progress: &dyn Fn(ReplayProgress),
+        visitor: impl FnMut(Lsn, Vec<u8>) -> Result<()>,
+    ) -> Result<()> {
+        replay_records(sized_records(path.as_ref(), 0), progress, visitor)
+    }
+
+    /// Like `replay_from_start`, visiting only the records with an LSN of at least
+    /// `start_lsn`, e.g. those a checkpoint hasn't covered. Segments that hold nothing that
+    /// new are skipped without being read; earlier records sharing a segment with the first
+    /// one visited are still read (and checked) to get past them.
+    pub fn replay_from<P: AsRef<Path>>(path: P, start_lsn: Lsn, visitor: impl FnMut(Lsn, Vec<u8>) -> Result<()>) -> Result<()> {
+        replay_records(sized_records(path.as_ref(), start_lsn), &|_| {}, visitor)
+    }
+
+    /// Like `replay_from_start`, over this log rather than one reopened by path; works for
+    /// in-memory logs too, reading a copy taken at the call (see `memory_copy`).
+    pub fn replay(&self, visitor: impl FnMut(Lsn, Vec<u8>) -> Result<()>) -> Result<()> {
+        self.replay_with_progress(&|_| {}, visitor)
+    }
+
+    /// `replay` with progress reports, as in `replay_from_start_with_progress`.
+    pub fn replay_with_progress(&self, progress: &dyn Fn(ReplayProgress), visitor: impl FnMut(Lsn, Vec<u8>) -> Result<()>) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            return Self::replay_from_start_with_progress(dir, progress, visitor);
+        }
+        replay_records(records_in(self.memory_copy()), progress, visitor)
+    }
+
+    /// The bytes of an in-memory log as they are now, copied under the log lock into a
+    /// buffer of their own. Reading through a clone of the append handle instead would share
+    /// its cursor, which an append then moves between seeking to the end and writing.
+    fn memory_copy(&self) -> Result<Box<dyn Storage>> {
+        let mut log = self.log.lock().unwrap();
+        let mut bytes = vec![0u8; log.file.len()? as usize];
+        let got = log.file.read_at(&mut bytes, 0)?;
+        bytes.truncate(got);
+        drop(log);
+        let mut copy = MemFile::default();
+        copy.write_all(&bytes)?;
+        copy.seek(SeekFrom::Start(0))?;
+        Ok(Box::new(copy))
+    }
+
+    /// Iterate over the records of the log at `path` (a segment directory, or a single
+    /// segment file), for tooling that wants to pull records rather than pass a visitor.
+    /// Ends the same way `replay_from_start` does: cleanly at a torn tail, and with one error
+    /// item (then nothing) on a CRC mismatch or I/O failure.
+    pub fn records<P: AsRef<Path>>(path: P) -> impl Iterator<Item = Result<(Lsn, Vec<u8>)>> {
+        sized_records(path.as_ref(), 0).map(|rec| rec.map(|(lsn, payload, _)| (lsn, payload)))
+    }
+
+    /// `records` for disaster recovery, over every segment in `dir`: a record that fails
+    /// its checksum (or won't decompress) is yielded as an error, then reading goes on past
+    /// it, and a segment that can't be opened or read is an error item followed by the next
+    /// segment. A bad record header still ends its segment, since nothing after it can be
+    /// located.
+    pub fn salvage_records<P: AsRef<Path>>(dir: P) -> impl Iterator<Item = Result<(Lsn, Vec<u8>)>> {
+        let dir = dir.as_ref();
+        let files = match list_segments(dir) {
+            Ok(seqs) => seqs.into_iter().map(|seq| Ok(segment_path(dir, seq))).collect(),
+            Err(e) => vec![Err(e)],
+        };
+        files.into_iter()
+            .flat_map(|file: Result<PathBuf>| {
+                let f = file.and_then(|p| Ok(Box::new(File::open(p)?) as Box<dyn Storage>));
+                read_records(f, true)
+            })
+            .map(|rec| rec.map(|(lsn, payload, _)| (lsn, payload)))
+    }
+
+    /// `records` over this log, from the first record with an LSN of at least `start_lsn`,
+    /// skipping older segments as `replay_from` does. Like `replay`, an in-memory log is read
+    /// from a copy, so the iterator sees the records appended before the call and appends
+    /// can go on while it runs.
+    pub fn records_from(&self, start_lsn: Lsn) -> Box<dyn Iterator<Item = Result<(Lsn, Vec<u8>)>>> {
+        let strip = |rec: Result<(Lsn, Vec<u8>, u64)>| rec.map(|(lsn, payload, _)| (lsn, payload));
+        if let Some(dir) = &self.dir {
+            return Box::new(sized_records(dir, start_lsn).map(strip));
+        }
+        Box::new(records_in(self.memory_copy())
+            .filter(move |rec| rec.as_ref().map_or(true, |(lsn, _, _)| *lsn >= start_lsn))
+            .map(strip))
+    }
+}
+
+/// `Wal::records`, with each record's size on disk as well, from the first record with an
+/// LSN of at least `start_lsn`.
+fn sized_records(path: &Path, start_lsn: Lsn) -> impl Iterator<Item = Result<(Lsn, Vec<u8>, u64)>> + use<> {
+    let files = if path.is_dir() {
+        list_segments(path).and_then(|seqs| {
+            let files: Vec<PathBuf> = seqs.into_iter().map(|seq| segment_path(path, seq)).collect();
+            let skip = segments_below(&files, start_lsn)?;
+            Ok(files[skip..].to_vec())
+        })
+    } else {
+        Ok(vec![path.to_path_buf()])
+    };
+    let files = match files {
+        Ok(files) => files.into_iter().map(Ok).collect(),
+        Err(e) => vec![Err(e)],
+    };
+    let mut failed = false;
+    files.into_iter()
+        .flat_map(|file: Result<PathBuf>| {
+            let f = file.and_then(|p| Ok(Box::new(File::open(p)?) as Box<dyn Storage>));
+            records_in(f)
+        })
+        .take_while(move |rec| {
+            let go = !failed;
+            failed |= rec.is_err();
+            go
+        })
+        .filter(move |rec| rec.as_ref().map_or(true, |(lsn, _, _)| *lsn >= start_lsn))
+}
+
+/// How many of the leading `segments` hold only records below `lsn`: those before the last
+/// segment that starts at or below it. Every record in a segment is below the first LSN of
+/// the segments after it.
+fn segments_below(segments: &[PathBuf], lsn: Lsn) -> Result<usize> {
+    if lsn == 0 {
+        return Ok(0);
+    }
+    for (i, path) in segments.iter().enumerate().rev() {
+        if first_lsn(path)?.is_some_and(|first| first <= lsn) {
+            return Ok(i);
+        }
+    }
+    Ok(0)
+}
+
+/// Feed `records` to `visitor`, reporting to `progress` at most every PROGRESS_RECORDS
+/// records or PROGRESS_INTERVAL, plus a final report.
+fn replay_records(
+    records: impl Iterator<Item = Result<(Lsn, Vec<u8>, u64)>>,
+    progress: &dyn Fn(ReplayProgress),
+    mut visitor: impl FnMut(Lsn, Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut at = ReplayProgress { records: 0, bytes: 0, lsn: 0, elapsed: Duration::ZERO };
+    let (mut reported_records, mut reported_at) = (0, start);
+    for rec in records {
+        let (lsn, payload, size) = rec?;
+        at.records += 1;
+        at.bytes += size;
+        at.lsn = lsn;
+        visitor(lsn, payload)?;
+        if at.records - reported_records >= PROGRESS_RECORDS || reported_at.elapsed() >= PROGRESS_INTERVAL {
+            reported_at = Instant::now();
+            reported_records = at.records;
+            progress(ReplayProgress { elapsed: reported_at - start, ..at });
+        }
+    }
+    if at.records > reported_records {
+        progress(ReplayProgress { elapsed: start.elapsed(), ..at });
+    }
+    Ok(())
+}
+
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("wal-{:06}.log", seq))
+}
+
+/// Numbers of the segment files in `dir`, ascending.
+fn list_segments(dir: &Path) -> Result<Vec<u64>> {
+    let mut seqs = Vec::new();
+    for ent in std::fs::read_dir(dir)? {
+        let name = ent?.file_name();
+        let seq = name.to_str()
+            .and_then(|n| n.strip_prefix("wal-"))
+            .and_then(|n| n.strip_suffix(".log"))
+            .and_then(|n| n.parse::<u64>().ok());
+        seqs.extend(seq);
+    }
+    seqs.sort_unstable();
+    Ok(seqs)
+}
+
+/// Create segment `seq` holding just the file header, durably, and open it for appending.
+fn create_segment(dir: &Path, seq: u64, sum: Checksum) -> Result<File> {
+    let path = segment_path(dir, seq);
+    let mut f = OpenOptions::new().create_new(true).append(true).read(true).open(&path)?;
+    f.write_all(&file_header(sum))?;
+    f.sync_all()?;
+    sync_parent_dir(&path)?;
+    Ok(f)
+}
+
+/// Get the live segment ready for appends: write the file header if it doesn't have a whole
+/// one (summing with `sum`), and cut off a torn tail. Returns the next LSN (0 if it holds no
+/// records), its length, and the checksum its header names.
+fn prepare_tail(f: &mut dyn Storage, sum: Checksum) -> Result<(Lsn, u64, Checksum)> {
+    if f.len()? < FILE_HDR_SZ {
+        // new segment, or a crash while creating one: start over with just the file header
+        f.set_len(0)?;
         f.seek(SeekFrom::Start(0))?;
-        loop {
-            let mut lenb = [0u8; 8];
-            if f.read_exact(&mut lenb).is_err(){break;}
-            let total_len = u64::from_le_bytes(lenb);
-            let mut lsnb = [0u8; 8];
-            f.read_exact(&mut lsnb)?;
-            let lsn = u64::from_le_bytes(lsnb);
-            let mut crc_b = [0u8; 4];
-            f.read_exact(&mut crc_b)?;
-            let crc = u32::from_le_bytes(crc_b);
-            let payload_len = total_len - 12;
-            let mut payload = vec![0u8; payload_len as usize];
-            f.read_exact(&mut payload)?;
-            if crc32(&payload) != crc { return Err(anyhow::anyhow!("WAL Payload CRC Mismatch at LSN {}", lsn)); }
-            visitor(lsn, payload)?;
+        f.write_all(&file_header(sum))?;
+        f.sync_all()?;
+    }
+    let sum = read_file_header(f)?.unwrap_or(sum);
+    let (next, valid_len) = compute_next_lsn(f)?;
+    if valid_len < f.len()? {
+        // drop a torn tail record, otherwise new appends would land behind it and be unreachable
+        f.set_len(valid_len)?;
+        f.sync_all()?;
+    }
+    Ok((next, valid_len, sum))
+}
+
+/// Next LSN going by the newest segment that holds any records; 0 if none does.
+fn last_lsn_before(dir: &Path, seqs: &[u64]) -> Result<Lsn> {
+    for &seq in seqs.iter().rev() {
+        let (next, _) = compute_next_lsn(&mut File::open(segment_path(dir, seq))?)?;
+        if next > 0 {
+            return Ok(next);
         }
-        Ok(())
     }
+    Ok(0)
+}
+
+/// LSN of the first record in the segment at `path`, if it has one.
+fn first_lsn(path: &Path) -> Result<Option<Lsn>> {
+    let f = File::open(path).map(|f| Box::new(f) as Box<dyn Storage>);
+    records_in(f.map_err(TinyDbError::from)).next().transpose().map(|rec| rec.map(|(lsn, _, _)| lsn))
+}
+
+/// The record iterator behind `records` and `replay`, reading from the start of `f`.
+/// Yields each record's LSN, its payload (decompressed), and its size on disk.
+fn records_in(f: Result<Box<dyn Storage>>) -> impl Iterator<Item = Result<(Lsn, Vec<u8>, u64)>> {
+    read_records(f, false)
 }
 
-fn compute_next_lsn(f: &mut File) -> anyhow::Result<Lsn>{
+/// `records_in`, going on past a record that was read whole but is bad when `salvage`.
+fn read_records(f: Result<Box<dyn Storage>>, salvage: bool) -> impl Iterator<Item = Result<(Lsn, Vec<u8>, u64)>> {
+    let mut opened = Some(f.and_then(|mut f| {
+        let len = f.len()?;
+        let sum = read_file_header(&mut *f)?;
+        Ok((sum.map_or(0, |_| len - FILE_HDR_SZ), f, sum.unwrap_or_default()))
+    }));
+    let mut reader: Option<(Box<dyn Storage>, u64, Checksum)> = None;
+    std::iter::from_fn(move || {
+        if let Some(res) = opened.take() {
+            match res {
+                Ok((remaining, f, sum)) => reader = Some((f, remaining, sum)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let (f, remaining, sum) = reader.as_mut()?;
+        let rec = match read_record(&mut **f, *remaining, *sum) {
+            Ok(Some(RawRecord { lsn, flags, crc, payload })) => {
+                let size = REC_HDR_SZ + payload.len() as u64;
+                *remaining -= size;
+                let rec = if sum.compute(&payload) == crc {
+                    match unpack_payload(lsn, flags, payload) {
+                        Ok(payload) => return Some(Ok((lsn, payload, size))),
+                        Err(e) => Some(Err(e)),
+                    }
+                } else {
+                    Some(Err(TinyDbError::WalCrcMismatch { lsn }))
+                };
+                if salvage {
+                    // it was read whole, so the next record starts right after it
+                    return rec;
+                }
+                rec
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        };
+        reader = None;
+        rec
+    })
+}
+
+fn file_header(sum: Checksum) -> [u8; FILE_HDR_SZ as usize] {
+    let mut h = [0u8; FILE_HDR_SZ as usize];
+    h[0..4].copy_from_slice(WAL_MAGIC);
+    h[4..8].copy_from_slice(&WAL_VERSION.to_le_bytes());
+    h[8..12].copy_from_slice(&sum.id().to_le_bytes());
+    h
+}
+
+/// Check the file header and leave `f` positioned at the first record. Returns the segment's
+/// checksum, or None for a file too short to hold the header, which can only be an empty log.
+fn read_file_header(f: &mut dyn Storage) -> Result<Option<Checksum>> {
+    if f.len()? < FILE_HDR_SZ {
+        return Ok(None);
+    }
     f.seek(SeekFrom::Start(0))?;
+    let mut h = [0u8; FILE_HDR_SZ as usize];
+    f.read_exact(&mut h)?;
+    if &h[0..4] != WAL_MAGIC {
+        return Err(TinyDbError::BadMagic { what: "WAL" });
+    }
+    let version = u32::from_le_bytes(h[4..8].try_into().unwrap());
+    if version != WAL_VERSION {
+        return Err(TinyDbError::UnsupportedVersion { what: "WAL", found: version, supported: WAL_VERSION });
+    }
+    let id = u32::from_le_bytes(h[8..12].try_into().unwrap());
+    let sum = Checksum::from_id(id).ok_or_else(|| TinyDbError::Corrupt(format!("unknown WAL checksum {}", id)))?;
+    Ok(Some(sum))
+}
+
+/// record = total_len(8) + lsn(8) + flags(1) + hdr_crc(4) + crc(4) + payload, with the
+/// payload compressed if `compress` is set and it pays off.
+fn encode_record(lsn: Lsn, payload: &[u8], sum: Checksum, compress: bool) -> Vec<u8> {
+    let (payload, flags) = pack_payload(payload, compress);
+    let crc = sum.compute(&payload);
+    let total_len = REC_HDR_SZ - 8 + (payload.len() as u64);
+    let mut rec = Vec::with_capacity(8 + total_len as usize);
+    rec.extend_from_slice(&total_len.to_le_bytes());
+    rec.extend_from_slice(&lsn.to_le_bytes());
+    rec.push(flags);
+    let hdr_crc = sum.compute(&rec);
+    rec.extend_from_slice(&hdr_crc.to_le_bytes());
+    rec.extend_from_slice(&crc.to_le_bytes());
+    rec.extend_from_slice(&payload);
+    rec
+}
+
+/// The bytes to store for `payload` and the record flags saying how to read them back.
+fn pack_payload(payload: &[u8], compress: bool) -> (Cow<'_, [u8]>, u8) {
+    #[cfg(feature = "compression")]
+    if compress
+        && payload.len() >= COMPRESS_MIN_BYTES
+        && let Ok(packed) = zstd::bulk::compress(payload, 0)
+        && packed.len() < payload.len()
+    {
+        return (Cow::Owned(packed), FLAG_ZSTD);
+    }
+    let _ = compress;
+    (Cow::Borrowed(payload), 0)
+}
+
+/// Undo `pack_payload` for the record at `lsn`.
+fn unpack_payload(lsn: Lsn, flags: u8, stored: Vec<u8>) -> Result<Vec<u8>> {
+    match flags {
+        0 => Ok(stored),
+        #[cfg(feature = "compression")]
+        FLAG_ZSTD => zstd::decode_all(&stored[..])
+            .map_err(|e| TinyDbError::Corrupt(format!("WAL record {} doesn't decompress: {}", lsn, e))),
+        #[cfg(not(feature = "compression"))]
+        FLAG_ZSTD => Err(TinyDbError::Invalid(format!("WAL record {} is compressed; build with the compression feature to read it", lsn))),
+        _ => Err(TinyDbError::Corrupt(format!("WAL record {} has unknown flags {:#04x}", lsn, flags))),
+    }
+}
+
+/// Parse a record header, or None if its CRC doesn't match.
+fn decode_header(h: &[u8], sum: Checksum) -> Option<(u64, Lsn, u8, u32)> {
+    let hdr_crc = u32::from_le_bytes(h[17..21].try_into().unwrap());
+    if sum.compute(&h[0..17]) != hdr_crc {
+        return None;
+    }
+    let total_len = u64::from_le_bytes(h[0..8].try_into().unwrap());
+    let lsn = u64::from_le_bytes(h[8..16].try_into().unwrap());
+    let crc = u32::from_le_bytes(h[21..25].try_into().unwrap());
+    Some((total_len, lsn, h[16], crc))
+}
+
+/// A record as stored: payload still compressed if `flags` says so, CRC not yet checked.
+struct RawRecord {
+    lsn: Lsn,
+    flags: u8,
+    crc: u32,
+    payload: Vec<u8>,
+}
+
+/// Read one record given the bytes `remaining` in the file. Returns None at end of log,
+/// including a torn tail: a header or payload that runs past EOF, or a header that fails its
+/// CRC with no intact record header anywhere after it. A bad header that is followed by
+/// intact records is mid-log corruption and an error.
+fn read_record(f: &mut dyn Storage, remaining: u64, sum: Checksum) -> Result<Option<RawRecord>> {
+    if remaining < REC_HDR_SZ { return Ok(None); }
+    let mut h = [0u8; REC_HDR_SZ as usize];
+    f.read_exact(&mut h)?;
+    let Some((total_len, lsn, flags, crc)) = decode_header(&h, sum) else {
+        let at = f.stream_position()? - REC_HDR_SZ;
+        let mut rest = Vec::new();
+        f.read_to_end(&mut rest)?;
+        let rest = [&h[1..], &rest[..]].concat();
+        if (0..rest.len().saturating_sub(REC_HDR_SZ as usize - 1)).any(|i| decode_header(&rest[i..], sum).is_some()) {
+            return Err(TinyDbError::Corrupt(format!("corrupt WAL record header at offset {}", at)));
+        }
+        return Ok(None);
+    };
+    if total_len < REC_HDR_SZ - 8 || total_len > remaining - 8 { return Ok(None); }
+    let mut payload = vec![0u8; (total_len - (REC_HDR_SZ - 8)) as usize];
+    f.read_exact(&mut payload)?;
+    Ok(Some(RawRecord { lsn, flags, crc, payload }))
+}
+
+/// Scan the log for the next LSN. Also returns the length of the intact prefix so a
+/// torn tail can be cut off before new records are appended after it.
+fn compute_next_lsn(f: &mut dyn Storage) -> Result<(Lsn, u64)>{
+    let file_len = f.len()?;
+    let Some(sum) = read_file_header(f)? else {
+        return Ok((0, 0));
+    };
     let mut next = 0u64;
-    loop {
-        let mut lenb = [0u8;8];
-        if f.read_exact(&mut lenb).is_err(){break; }
-        let total_len = u64::from_le_bytes(lenb);
-        let mut lsnb = [0u8; 8];
-        f.read_exact(&mut lsnb)?;
-        let lsn = u64::from_le_bytes(lsnb);
-        f.seek(SeekFrom::Current(4+(total_len as i64 - 12)))?;
-        next = lsn +1;
-    }
-    Ok(next)
-}
\ No newline at end of file
+    let mut pos = FILE_HDR_SZ;
+    while let Some(rec) = read_record(f, file_len - pos, sum)? {
+        pos += REC_HDR_SZ + rec.payload.len() as u64;
+        next = rec.lsn +1;
+    }
+    Ok((next, pos))
+}